@@ -0,0 +1,16 @@
+
+def fib():
+    a, b = 0, 1
+    while True:
+        yield a
+        a, b = b, a + b
+
+def take(n, iterable):
+    for x in iterable:
+        if n == 0:
+            return
+        n = n - 1
+        yield x
+
+print(list(take(10, fib())))
+print(list(x * x for x in take(5, fib())))
@@ -0,0 +1,795 @@
+/*
+ * Procedural macros to declare native functions, classes and modules for
+ * rspython. They generate the conversion of arguments and results, and the
+ * registration of the declared items in a `NativeModule`:
+ *
+ * - `#[pyfunction]` on a function, whose arguments implement `FromPyObject`
+ *   or are `&str`, and which returns a `PyResult` of something implementing `IntoPyObject`.
+ *   Defaults are given with `#[args(name = value)]`.
+ * - `#[pyclass]` on a struct, whose values are carried by the instances.
+ * - `#[pymethods]` on the impl block of a class. A `#[new]` function creates
+ *   the value, methods take `&self` or `&mut self`, and `#[getter]` and
+ *   `#[setter]` functions give attributes.
+ * - `#[pymodule]` on an inline module, which gets a `make_module` function
+ *   creating a native module with the functions and classes in it.
+ *
+ * A first argument `vm: &mut VirtualMachine` is passed the virtual machine.
+ * Doc comments become docstrings.
+ */
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::spanned::Spanned;
+use syn::{
+    Attribute, AttributeArgs, FnArg, Ident, ImplItem, Item, ItemFn, ItemImpl, ItemMod, Lit, Meta,
+    NestedMeta, Pat, ReturnType, Signature, Type,
+};
+
+#[proc_macro_attribute]
+pub fn pyfunction(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let function = parse_macro_input!(item as ItemFn);
+    into_tokens(impl_pyfunction(&args, function))
+}
+
+#[proc_macro_attribute]
+pub fn pyclass(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let class = parse_macro_input!(item as syn::DeriveInput);
+    into_tokens(impl_pyclass(&args, &class))
+}
+
+#[proc_macro_attribute]
+pub fn pymethods(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let methods = parse_macro_input!(item as ItemImpl);
+    into_tokens(impl_pymethods(&args, methods))
+}
+
+#[proc_macro_attribute]
+pub fn pymodule(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let module = parse_macro_input!(item as ItemMod);
+    into_tokens(impl_pymodule(&args, module))
+}
+
+fn into_tokens(result: syn::Result<TokenStream2>) -> TokenStream {
+    match result {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+// The `name = "..."` option of a macro, the only one there is:
+fn name_option(args: &[NestedMeta]) -> syn::Result<Option<String>> {
+    let mut name = None;
+    for arg in args {
+        match *arg {
+            NestedMeta::Meta(Meta::NameValue(ref value)) if value.path.is_ident("name") => {
+                match value.lit {
+                    Lit::Str(ref lit) => name = Some(lit.value()),
+                    ref lit => return Err(syn::Error::new(lit.span(), "expected a string")),
+                }
+            }
+            _ => return Err(syn::Error::new(arg.span(), "expected `name = \"...\"`")),
+        }
+    }
+    Ok(name)
+}
+
+// The docstring from doc comments:
+fn doc_string(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::NameValue(syn::MetaNameValue {
+                lit: Lit::Str(ref lit),
+                ..
+            })) => {
+                let line = lit.value();
+                Some(match line.chars().next() {
+                    Some(' ') => line[1..].to_string(),
+                    _ => line,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+fn doc_tokens(doc: &Option<String>) -> TokenStream2 {
+    match *doc {
+        Some(ref doc) => quote!(::std::option::Option::Some(#doc)),
+        None => quote!(::std::option::Option::None),
+    }
+}
+
+fn has_attribute(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| attr.path.is_ident(name))
+}
+
+fn find_attribute<'a>(attrs: &'a [Attribute], name: &str) -> Option<&'a Attribute> {
+    attrs.iter().find(|attr| attr.path.is_ident(name))
+}
+
+// Remove the attributes which only mean something to these macros:
+fn strip_attributes(attrs: &mut Vec<Attribute>) {
+    attrs.retain(|attr| {
+        !["args", "new", "getter", "setter"]
+            .iter()
+            .any(|name| attr.path.is_ident(name))
+    });
+}
+
+// The name given in an attribute like `#[getter(name)]`:
+fn attribute_name(attr: &Attribute) -> syn::Result<Option<String>> {
+    if attr.tokens.is_empty() {
+        return Ok(None);
+    }
+    match attr.parse_meta()? {
+        Meta::List(ref list) if list.nested.len() == 1 => match list.nested[0] {
+            NestedMeta::Meta(Meta::Path(ref path)) if path.get_ident().is_some() => {
+                Ok(Some(path.get_ident().unwrap().to_string()))
+            }
+            ref nested => Err(syn::Error::new(nested.span(), "expected a name")),
+        },
+        meta => Err(syn::Error::new(meta.span(), "expected a name")),
+    }
+}
+
+/*
+ * The signature of a function callable from python.
+ */
+struct Param {
+    ident: Ident,
+    ty: Type,
+    default: Option<Lit>,
+}
+
+enum Receiver {
+    None,
+    Ref,
+    RefMut,
+}
+
+struct Parameters {
+    receiver: Receiver,
+    takes_vm: bool,
+    params: Vec<Param>,
+}
+
+// Whether the type is `&mut VirtualMachine`:
+fn is_vm_type(ty: &Type) -> syn::Result<bool> {
+    if let Type::Reference(ref reference) = *ty {
+        if let Type::Path(ref path) = *reference.elem {
+            let last = path.path.segments.last().unwrap();
+            if last.ident == "VirtualMachine" {
+                if reference.mutability.is_none() {
+                    return Err(syn::Error::new(
+                        ty.span(),
+                        "the virtual machine must be passed as `&mut VirtualMachine`",
+                    ));
+                }
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn parse_parameters(sig: &Signature, attrs: &[Attribute]) -> syn::Result<Parameters> {
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            sig.generics.span(),
+            "functions callable from python cannot be generic",
+        ));
+    }
+    if sig.asyncness.is_some() || sig.variadic.is_some() {
+        return Err(syn::Error::new(
+            sig.span(),
+            "functions callable from python cannot be async or variadic",
+        ));
+    }
+
+    let mut receiver = Receiver::None;
+    let mut takes_vm = false;
+    let mut params = vec![];
+    for (i, input) in sig.inputs.iter().enumerate() {
+        match *input {
+            FnArg::Receiver(ref value) => {
+                receiver = match value.reference {
+                    Some(_) if value.mutability.is_some() => Receiver::RefMut,
+                    Some(_) => Receiver::Ref,
+                    None => {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "methods must take `&self` or `&mut self`",
+                        ))
+                    }
+                };
+            }
+            FnArg::Typed(ref typed) => {
+                let first = i == 0 || (i == 1 && !matches!(receiver, Receiver::None));
+                if is_vm_type(&typed.ty)? {
+                    if !first {
+                        return Err(syn::Error::new(
+                            typed.span(),
+                            "the virtual machine must be the first argument",
+                        ));
+                    }
+                    takes_vm = true;
+                    continue;
+                }
+                let ident = match *typed.pat {
+                    Pat::Ident(ref pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                        pat.ident.clone()
+                    }
+                    ref pat => {
+                        return Err(syn::Error::new(
+                            pat.span(),
+                            "arguments must be plain identifiers",
+                        ))
+                    }
+                };
+                params.push(Param {
+                    ident,
+                    ty: (*typed.ty).clone(),
+                    default: None,
+                });
+            }
+        }
+    }
+
+    // Defaults are given by `#[args(name = value, ...)]`:
+    if let Some(attr) = find_attribute(attrs, "args") {
+        let nested = match attr.parse_meta()? {
+            Meta::List(list) => list.nested,
+            meta => return Err(syn::Error::new(meta.span(), "expected `args(name = value)`")),
+        };
+        for item in nested {
+            let value = match item {
+                NestedMeta::Meta(Meta::NameValue(value)) => value,
+                item => return Err(syn::Error::new(item.span(), "expected `name = value`")),
+            };
+            match params
+                .iter_mut()
+                .find(|param| value.path.is_ident(&param.ident))
+            {
+                Some(param) => param.default = Some(value.lit),
+                None => {
+                    return Err(syn::Error::new(
+                        value.path.span(),
+                        "default for an unknown argument",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(Parameters {
+        receiver,
+        takes_vm,
+        params,
+    })
+}
+
+// Functions and methods return a `PyResult`, so they can raise exceptions:
+fn check_returns_result(sig: &Signature) -> syn::Result<()> {
+    if let ReturnType::Type(_, ref ty) = sig.output {
+        if let Type::Path(ref path) = **ty {
+            let last = path.path.segments.last().unwrap();
+            if last.ident == "PyResult" || last.ident == "Result" {
+                return Ok(());
+            }
+        }
+    }
+    Err(syn::Error::new(
+        sig.output.span(),
+        "functions callable from python must return a PyResult",
+    ))
+}
+
+// Bind the call arguments `__args` to the parameters, and convert them:
+fn bind_arguments(function_name: &str, parameters: &Parameters) -> TokenStream2 {
+    if parameters.params.is_empty() {
+        return quote! {
+            ::rspython::bind_args(__vm, #function_name, &[], __args)?;
+        };
+    }
+    let names = parameters.params.iter().map(|param| param.ident.to_string());
+    let conversions = parameters.params.iter().map(|param| {
+        let ident = &param.ident;
+        let ty = &param.ty;
+        let name = ident.to_string();
+        let holder = Ident::new(&format!("__holder_{}", ident), ident.span());
+        let missing = match param.default {
+            Some(ref default) => quote! {
+                <#ty as ::rspython::FromPyArg>::from_default(::std::convert::From::from(#default))
+            },
+            None => quote!(::rspython::missing_argument::<#ty>(__vm, #function_name, #name)?),
+        };
+        quote! {
+            let mut #holder = match __values.next().unwrap() {
+                ::std::option::Option::Some(__value) => {
+                    <#ty as ::rspython::FromPyArg>::to_holder(__vm, ::std::option::Option::Some(&__value))?
+                }
+                ::std::option::Option::None => #missing,
+            };
+            let #ident: #ty = ::rspython::FromPyArg::from_holder(&mut #holder);
+        }
+    });
+    quote! {
+        let mut __values = ::rspython::bind_args(__vm, #function_name, &[#(#names),*], __args)?
+            .into_iter();
+        #(#conversions)*
+    }
+}
+
+// The arguments passed to the rust function:
+fn call_arguments(parameters: &Parameters) -> TokenStream2 {
+    let idents = parameters.params.iter().map(|param| &param.ident);
+    if parameters.takes_vm {
+        quote!(__vm, #(#idents),*)
+    } else {
+        quote!(#(#idents),*)
+    }
+}
+
+fn impl_pyfunction(args: &[NestedMeta], mut function: ItemFn) -> syn::Result<TokenStream2> {
+    let name = name_option(args)?.unwrap_or_else(|| function.sig.ident.to_string());
+    let parameters = parse_parameters(&function.sig, &function.attrs)?;
+    if !matches!(parameters.receiver, Receiver::None) {
+        return Err(syn::Error::new(
+            function.sig.span(),
+            "#[pyfunction] cannot take self, use #[pymethods]",
+        ));
+    }
+    check_returns_result(&function.sig)?;
+    strip_attributes(&mut function.attrs);
+
+    let ident = &function.sig.ident;
+    let vis = &function.vis;
+    let def_ident = Ident::new(&format!("__pyfunction_{}", ident), ident.span());
+    let doc = doc_tokens(&doc_string(&function.attrs));
+    let bind = bind_arguments(&name, &parameters);
+    let call = call_arguments(&parameters);
+    Ok(quote! {
+        #function
+
+        #[doc(hidden)]
+        #vis fn #def_ident() -> ::rspython::FunctionDef {
+            ::rspython::FunctionDef {
+                name: #name,
+                doc: #doc,
+                function: ::std::rc::Rc::new(
+                    |__vm: &mut ::rspython::VirtualMachine,
+                     __args: ::rspython::PyFuncArgs|
+                     -> ::rspython::PyResult {
+                        #bind
+                        let __result = #ident(#call)?;
+                        ::rspython::IntoPyObject::into_py_object(__result, __vm)
+                    },
+                ),
+            }
+        }
+    })
+}
+
+fn impl_pyclass(args: &[NestedMeta], class: &syn::DeriveInput) -> syn::Result<TokenStream2> {
+    if !class.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            class.generics.span(),
+            "#[pyclass] types cannot be generic",
+        ));
+    }
+    let ident = &class.ident;
+    let name = name_option(args)?.unwrap_or_else(|| ident.to_string());
+    let doc = doc_tokens(&doc_string(&class.attrs));
+    Ok(quote! {
+        #class
+
+        impl ::rspython::PyClass for #ident {
+            fn class_name() -> &'static str {
+                #name
+            }
+
+            fn class_doc() -> ::std::option::Option<&'static str> {
+                #doc
+            }
+        }
+    })
+}
+
+fn impl_pymethods(args: &[NestedMeta], mut methods: ItemImpl) -> syn::Result<TokenStream2> {
+    if !args.is_empty() {
+        return Err(syn::Error::new(args[0].span(), "#[pymethods] takes no options"));
+    }
+    if methods.trait_.is_some() || !methods.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            methods.span(),
+            "#[pymethods] must be on a plain impl block",
+        ));
+    }
+    let self_ty = methods.self_ty.clone();
+
+    let mut init = None;
+    let mut functions = vec![];
+    let mut getters = vec![];
+    let mut setters = vec![];
+    for item in &mut methods.items {
+        let method = match *item {
+            ImplItem::Method(ref mut method) => method,
+            _ => continue,
+        };
+        let ident = method.sig.ident.clone();
+        let attrs = method.attrs.clone();
+        strip_attributes(&mut method.attrs);
+        let parameters = parse_parameters(&method.sig, &attrs)?;
+        let doc = doc_string(&attrs);
+
+        if has_attribute(&attrs, "new") {
+            if !matches!(parameters.receiver, Receiver::None) {
+                return Err(syn::Error::new(method.sig.span(), "#[new] cannot take self"));
+            }
+            check_returns_result(&method.sig)?;
+            if init.is_some() {
+                return Err(syn::Error::new(method.sig.span(), "only one #[new] is allowed"));
+            }
+            let bind = bind_arguments("__init__", &parameters);
+            let call = call_arguments(&parameters);
+            init = Some(quote! {
+                .init(|__vm: &mut ::rspython::VirtualMachine,
+                       __args: ::rspython::PyFuncArgs|
+                       -> ::rspython::PyResult<#self_ty> {
+                    #bind
+                    <#self_ty>::#ident(#call)
+                })
+            });
+        } else if let Some(attr) = find_attribute(&attrs, "getter") {
+            let name = attribute_name(attr)?.unwrap_or_else(|| ident.to_string());
+            if !matches!(parameters.receiver, Receiver::Ref)
+                || parameters.takes_vm
+                || !parameters.params.is_empty()
+            {
+                return Err(syn::Error::new(
+                    method.sig.span(),
+                    "a #[getter] takes only `&self`",
+                ));
+            }
+            getters.push((
+                name.clone(),
+                quote! {
+                    .attribute(#name, |__this: &#self_ty| __this.#ident())
+                },
+            ));
+        } else if let Some(attr) = find_attribute(&attrs, "setter") {
+            let name = match attribute_name(attr)? {
+                Some(name) => name,
+                None => match ident.to_string() {
+                    ref name if name.starts_with("set_") => name[4..].to_string(),
+                    _ => {
+                        return Err(syn::Error::new(
+                            method.sig.span(),
+                            "a #[setter] is named `set_<attribute>`, or `#[setter(attribute)]`",
+                        ))
+                    }
+                },
+            };
+            if !matches!(parameters.receiver, Receiver::RefMut)
+                || parameters.takes_vm
+                || parameters.params.len() != 1
+                || !matches!(method.sig.output, ReturnType::Default)
+            {
+                return Err(syn::Error::new(
+                    method.sig.span(),
+                    "a #[setter] takes `&mut self` and the new value, and returns nothing",
+                ));
+            }
+            let ty = &parameters.params[0].ty;
+            setters.push((
+                name.clone(),
+                method.sig.span(),
+                quote! {
+                    .setter(#name, |__this: &mut #self_ty, __value: #ty| __this.#ident(__value))
+                },
+            ));
+        } else {
+            if matches!(parameters.receiver, Receiver::None) {
+                return Err(syn::Error::new(
+                    method.sig.span(),
+                    "methods must take `&self` or `&mut self`, or be marked #[new]",
+                ));
+            }
+            check_returns_result(&method.sig)?;
+            let name = ident.to_string();
+            let bind = bind_arguments(&name, &parameters);
+            let call = call_arguments(&parameters);
+            functions.push(quote! {
+                .method(#name, |__vm: &mut ::rspython::VirtualMachine,
+                                __this: &mut #self_ty,
+                                __args: ::rspython::PyFuncArgs|
+                                -> ::rspython::PyResult {
+                    #bind
+                    let __result = __this.#ident(#call)?;
+                    ::rspython::IntoPyObject::into_py_object(__result, __vm)
+                })
+            });
+            if let Some(doc) = doc {
+                functions.push(quote!(.document(#name, #doc)));
+            }
+        }
+    }
+
+    // Setters make attributes writable, which must be declared by a getter:
+    for &(ref name, span, _) in &setters {
+        if !getters.iter().any(|getter| getter.0 == *name) {
+            return Err(syn::Error::new(span, "a #[setter] needs a #[getter]"));
+        }
+    }
+    let getters = getters.iter().map(|getter| &getter.1);
+    let setters = setters.iter().map(|setter| &setter.2);
+    Ok(quote! {
+        #methods
+
+        impl ::rspython::PyMethods for #self_ty {
+            fn native_type() -> ::rspython::NativeType {
+                let __type = ::rspython::NativeType::new(
+                    <#self_ty as ::rspython::PyClass>::class_name(),
+                );
+                let __type = match <#self_ty as ::rspython::PyClass>::class_doc() {
+                    ::std::option::Option::Some(__doc) => __type.doc(__doc),
+                    ::std::option::Option::None => __type,
+                };
+                __type
+                    #init
+                    #(#functions)*
+                    #(#getters)*
+                    #(#setters)*
+            }
+        }
+    })
+}
+
+fn impl_pymodule(args: &[NestedMeta], mut module: ItemMod) -> syn::Result<TokenStream2> {
+    let name = name_option(args)?.unwrap_or_else(|| module.ident.to_string());
+    let doc = doc_string(&module.attrs).map(|doc| quote!(.doc(#doc)));
+    let items = match module.content {
+        Some((_, ref mut items)) => items,
+        None => {
+            return Err(syn::Error::new(
+                module.span(),
+                "#[pymodule] must be on a module with a body",
+            ))
+        }
+    };
+
+    let mut registrations = vec![];
+    for item in items.iter() {
+        match *item {
+            Item::Fn(ref function) if has_attribute(&function.attrs, "pyfunction") => {
+                let ident = &function.sig.ident;
+                let def_ident = Ident::new(&format!("__pyfunction_{}", ident), ident.span());
+                registrations.push(quote!(.function_def(#def_ident())));
+            }
+            Item::Struct(syn::ItemStruct {
+                ref attrs,
+                ref ident,
+                ..
+            })
+            | Item::Enum(syn::ItemEnum {
+                ref attrs,
+                ref ident,
+                ..
+            }) if has_attribute(attrs, "pyclass") => {
+                registrations.push(quote! {
+                    .native_type(<#ident as ::rspython::PyMethods>::native_type())
+                });
+            }
+            _ => {}
+        }
+    }
+    items.push(parse_quote! {
+        // Create the native module, to register with a virtual machine:
+        pub fn make_module() -> ::rspython::NativeModule {
+            ::rspython::NativeModule::new(#name)
+                #doc
+                #(#registrations)*
+        }
+    });
+    Ok(quote!(#module))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The message of the error which the macro gives for the item:
+    fn function_error(function: ItemFn) -> String {
+        impl_pyfunction(&[], function).unwrap_err().to_string()
+    }
+
+    fn methods_error(methods: ItemImpl) -> String {
+        impl_pymethods(&[], methods).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_accepted_signatures() {
+        let function: ItemFn = parse_quote! {
+            #[args(b = 2)]
+            fn add(vm: &mut VirtualMachine, a: i32, b: i32) -> PyResult<i32> {}
+        };
+        assert!(impl_pyfunction(&[], function).is_ok());
+        let methods: ItemImpl = parse_quote! {
+            impl Account {
+                #[new]
+                fn new(balance: i32) -> PyResult<Account> {}
+                fn deposit(&mut self, vm: &mut VirtualMachine, amount: i32) -> PyResult {}
+                #[getter]
+                fn balance(&self) -> i32 {}
+                #[setter]
+                fn set_balance(&mut self, value: i32) {}
+            }
+        };
+        assert!(impl_pymethods(&[], methods).is_ok());
+    }
+
+    #[test]
+    fn test_parameter_errors() {
+        let cases: Vec<(ItemFn, &str)> = vec![
+            (
+                parse_quote!(fn f<T>(x: T) -> PyResult {}),
+                "functions callable from python cannot be generic",
+            ),
+            (
+                parse_quote!(async fn f() -> PyResult {}),
+                "functions callable from python cannot be async or variadic",
+            ),
+            (
+                parse_quote!(fn f(vm: &VirtualMachine) -> PyResult {}),
+                "the virtual machine must be passed as `&mut VirtualMachine`",
+            ),
+            (
+                parse_quote!(fn f(x: i32, vm: &mut VirtualMachine) -> PyResult {}),
+                "the virtual machine must be the first argument",
+            ),
+            (
+                parse_quote!(fn f((a, b): (i32, i32)) -> PyResult {}),
+                "arguments must be plain identifiers",
+            ),
+            (
+                parse_quote!(#[args] fn f(x: i32) -> PyResult {}),
+                "expected `args(name = value)`",
+            ),
+            (
+                parse_quote!(#[args(x)] fn f(x: i32) -> PyResult {}),
+                "expected `name = value`",
+            ),
+            (
+                parse_quote!(#[args(y = 1)] fn f(x: i32) -> PyResult {}),
+                "default for an unknown argument",
+            ),
+        ];
+        for (function, message) in cases {
+            assert_eq!(function_error(function), message);
+        }
+        let methods: ItemImpl = parse_quote! {
+            impl Account {
+                fn take(self) -> PyResult {}
+            }
+        };
+        assert_eq!(methods_error(methods), "methods must take `&self` or `&mut self`");
+    }
+
+    #[test]
+    fn test_result_errors() {
+        let message = "functions callable from python must return a PyResult";
+        assert_eq!(function_error(parse_quote!(fn f() {})), message);
+        assert_eq!(function_error(parse_quote!(fn f() -> i32 {})), message);
+        let methods: ItemImpl = parse_quote! {
+            impl Account {
+                #[new]
+                fn new() -> Account {}
+            }
+        };
+        assert_eq!(methods_error(methods), message);
+    }
+
+    #[test]
+    fn test_methods_errors() {
+        let options: Vec<NestedMeta> = vec![parse_quote!(name = "A")];
+        let methods: ItemImpl = parse_quote!(impl Account {});
+        assert_eq!(
+            impl_pymethods(&options, methods).unwrap_err().to_string(),
+            "#[pymethods] takes no options"
+        );
+        let cases: Vec<(ItemImpl, &str)> = vec![
+            (
+                parse_quote!(impl Clone for Account {}),
+                "#[pymethods] must be on a plain impl block",
+            ),
+            (
+                parse_quote!(impl<T> Account<T> {}),
+                "#[pymethods] must be on a plain impl block",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        #[new]
+                        fn new(&self) -> PyResult<Account> {}
+                    }
+                },
+                "#[new] cannot take self",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        #[new]
+                        fn new() -> PyResult<Account> {}
+                        #[new]
+                        fn other() -> PyResult<Account> {}
+                    }
+                },
+                "only one #[new] is allowed",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        #[getter]
+                        fn balance(&self, x: i32) -> i32 {}
+                    }
+                },
+                "a #[getter] takes only `&self`",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        #[setter]
+                        fn balance(&mut self, value: i32) {}
+                    }
+                },
+                "a #[setter] is named `set_<attribute>`, or `#[setter(attribute)]`",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        #[setter]
+                        fn set_balance(&self, value: i32) {}
+                    }
+                },
+                "a #[setter] takes `&mut self` and the new value, and returns nothing",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        fn helper(x: i32) -> PyResult {}
+                    }
+                },
+                "methods must take `&self` or `&mut self`, or be marked #[new]",
+            ),
+            (
+                parse_quote! {
+                    impl Account {
+                        #[setter(balance)]
+                        fn update(&mut self, value: i32) {}
+                    }
+                },
+                "a #[setter] needs a #[getter]",
+            ),
+        ];
+        for (methods, message) in cases {
+            assert_eq!(methods_error(methods), message);
+        }
+    }
+}
@@ -0,0 +1,752 @@
+/*
+ * An assembler for bytecode written as text, so that the virtual machine can
+ * be tested without the parser and the compiler. Instructions are written as
+ * the disassembler lists them: by name, with the argument given by what it
+ * means. That is a name, a constant, an operator, a number, `defaults` for
+ * functions with defaults, or a label to jump to:
+ *
+ *         LoadConst 3
+ *         StoreName n
+ *     loop:
+ *         LoadName n
+ *         JumpIfFalse done
+ *         ...
+ *
+ * A line ending in a colon is a label, `#` starts a comment. Constants are
+ * written like python literals: numbers, strings, bytes, tuples, None, True,
+ * False and `...`.
+ *
+ * A code object nested in the current one is written between
+ * `.code name(a, b)` and `.end`, and `LoadConst name` loads it. Without the
+ * parentheses it is the body of a class. Within a code object, `.cellvars`
+ * and `.freevars` list the variables shared with nested functions, before
+ * the instructions, and `.generator` makes it a generator. The location of
+ * an instruction is that of its line.
+ */
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::ast::Location;
+use super::bytecode::{CodeObject, Constant, Instruction, InstructionList, Label};
+use super::dis;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockKind {
+    Module,
+    Function,
+    Class,
+}
+
+// A code object being assembled:
+struct Block {
+    kind: BlockKind,
+    code: CodeObject,
+    instructions: InstructionList,
+    // The labels by name, with the line defining them:
+    labels: HashMap<String, (Label, Option<usize>)>,
+    // The first line jumping to each label:
+    jumps: Vec<(String, usize)>,
+    // Nested code objects may be loaded before they are written. Their
+    // constants are reserved, and filled in when they are done:
+    nested: HashMap<String, (usize, Option<usize>)>,
+}
+
+impl Block {
+    fn new(kind: BlockKind, code: CodeObject) -> Block {
+        Block {
+            kind,
+            code,
+            instructions: InstructionList::new(),
+            labels: HashMap::new(),
+            jumps: vec![],
+            nested: HashMap::new(),
+        }
+    }
+
+    fn label(&mut self, name: &str) -> Label {
+        let count = self.labels.len();
+        self.labels.entry(String::from(name)).or_insert((count, None)).0
+    }
+
+    // Like `CodeObject::add_constant`, but the constants reserved for code
+    // objects are not reused:
+    fn constant(&mut self, value: Constant) -> usize {
+        let nested = &self.nested;
+        let found = self.code.consts.iter().enumerate().position(|(index, constant)| {
+            *constant == value && !nested.values().any(|(reserved, _)| *reserved == index)
+        });
+        match found {
+            Some(index) => index,
+            None => {
+                self.code.consts.push(value);
+                self.code.consts.len() - 1
+            }
+        }
+    }
+
+    // The constant of a nested code object:
+    fn nested_code(&mut self, name: &str, line: usize) -> usize {
+        if let Some((index, _)) = self.nested.get(name) {
+            return *index;
+        }
+        self.code.consts.push(Constant::None);
+        let index = self.code.consts.len() - 1;
+        self.nested.insert(String::from(name), (index, Some(line)));
+        index
+    }
+
+    fn finish(self) -> Result<CodeObject, String> {
+        for (name, line) in &self.jumps {
+            if self.labels[name].1.is_none() {
+                return Err(format!("line {}: undefined label '{}'", line, name));
+            }
+        }
+        for (name, (_, line)) in &self.nested {
+            if let Some(line) = line {
+                return Err(format!("line {}: undefined code object '{}'", line, name));
+            }
+        }
+        let mut code = self.code;
+        self.instructions.assemble(&mut code)?;
+        Ok(code)
+    }
+}
+
+// Assemble the text into the code object of a module:
+pub fn assemble(source: &str, filename: &str) -> Result<CodeObject, String> {
+    let mut module = CodeObject::new(vec![], String::from("<module>"));
+    module.filename = String::from(filename);
+    let mut blocks = vec![Block::new(BlockKind::Module, module)];
+
+    for (index, line) in source.lines().enumerate() {
+        let row = index + 1;
+        let text = strip_comment(line);
+        let statement = text.trim();
+        if statement.is_empty() {
+            continue;
+        }
+        let location = Location::new(row, text.len() - text.trim_start().len());
+        // The errors of a finished code object tell their own lines:
+        if statement == ".end" && blocks.len() > 1 {
+            end_code(&mut blocks, row)?;
+            continue;
+        }
+        let result = if statement.starts_with('.') {
+            directive(&mut blocks, statement, row, filename)
+        } else if let Some(name) = statement.strip_suffix(':') {
+            define_label(blocks.last_mut().unwrap(), name.trim(), row)
+        } else {
+            instruction(blocks.last_mut().unwrap(), statement, row, location)
+        };
+        result.map_err(|msg| format!("line {}: {}", row, msg))?;
+    }
+    if blocks.len() > 1 {
+        let name = &blocks.last().unwrap().code.name;
+        return Err(format!("missing .end of code object '{}'", name));
+    }
+    blocks.pop().unwrap().finish()
+}
+
+// Remove a comment, which does not start within a string:
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (position, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '#' => return &line[..position],
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None => {}
+        }
+    }
+    line
+}
+
+// Code objects may also have names like `<lambda>`:
+fn is_code_name(name: &str) -> bool {
+    is_identifier(name) || (name.len() > 2 && name.starts_with('<') && name.ends_with('>'))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// A list of names, separated by commas:
+fn names(text: &str) -> Result<Vec<String>, String> {
+    let mut names = vec![];
+    for name in text.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        if !is_identifier(name) {
+            return Err(format!("'{}' is not a name", name));
+        }
+        names.push(String::from(name));
+    }
+    Ok(names)
+}
+
+fn define_label(block: &mut Block, name: &str, row: usize) -> Result<(), String> {
+    if !is_identifier(name) {
+        return Err(format!("'{}' is not a label", name));
+    }
+    let label = block.label(name);
+    let entry = block.labels.get_mut(name).unwrap();
+    if let Some(line) = entry.1 {
+        return Err(format!("label '{}' is already defined on line {}", name, line));
+    }
+    entry.1 = Some(row);
+    block.instructions.set_label(label);
+    Ok(())
+}
+
+// Finish the current code object, which becomes a constant of its parent:
+fn end_code(blocks: &mut Vec<Block>, row: usize) -> Result<(), String> {
+    let code = blocks.pop().unwrap().finish()?;
+    let parent = blocks.last_mut().unwrap();
+    let index = parent.nested_code(&code.name, row);
+    if parent.nested[&code.name].1.is_none() {
+        return Err(format!("line {}: code object '{}' is already defined", row, code.name));
+    }
+    parent.nested.insert(code.name.clone(), (index, None));
+    parent.code.consts[index] = Constant::Code {
+        code: Rc::new(code),
+    };
+    Ok(())
+}
+
+fn directive(
+    blocks: &mut Vec<Block>,
+    statement: &str,
+    row: usize,
+    filename: &str,
+) -> Result<(), String> {
+    let (name, rest) = match statement.find(char::is_whitespace) {
+        Some(position) => (&statement[..position], statement[position..].trim()),
+        None => (statement, ""),
+    };
+    match name {
+        ".code" => {
+            // A function has its arguments in parentheses, a class body has
+            // none:
+            let (name, kind, args) = match rest.find('(') {
+                Some(position) if rest.ends_with(')') => (
+                    rest[..position].trim(),
+                    BlockKind::Function,
+                    names(&rest[position + 1..rest.len() - 1])?,
+                ),
+                Some(_) => return Err(String::from("missing ) after the arguments")),
+                None => (rest, BlockKind::Class, vec![]),
+            };
+            if !is_code_name(name) {
+                return Err(format!("bad name of code object '{}'", name));
+            }
+            let parent = blocks.last().unwrap();
+            let qualname = match parent.kind {
+                BlockKind::Module => String::from(name),
+                BlockKind::Function => format!("{}.<locals>.{}", parent.code.qualname, name),
+                BlockKind::Class => format!("{}.{}", parent.code.qualname, name),
+            };
+            let mut code = CodeObject::new(args, String::from(name));
+            code.qualname = qualname;
+            code.filename = String::from(filename);
+            code.firstlineno = row;
+            blocks.push(Block::new(kind, code));
+        }
+        ".end" => return Err(String::from(".end outside of a code object")),
+        ".cellvars" | ".freevars" => {
+            let block = blocks.last_mut().unwrap();
+            if !block.instructions.instructions.is_empty() {
+                return Err(format!("{} must come before the instructions", name));
+            }
+            let variables = names(rest)?;
+            if name == ".cellvars" {
+                block.code.cellvars.extend(variables);
+            } else {
+                block.code.freevars.extend(variables);
+            }
+        }
+        ".generator" if rest.is_empty() => blocks.last_mut().unwrap().code.is_generator = true,
+        _ => return Err(format!("unknown directive '{}'", statement)),
+    }
+    Ok(())
+}
+
+// The opcode of an instruction by its name:
+fn opcode(name: &str) -> Option<u8> {
+    (0..=255u8).find(|opcode| {
+        Instruction::from_opcode(*opcode, 0).is_some_and(|instruction| instruction.name() == name)
+    })
+}
+
+fn instruction(block: &mut Block, statement: &str, row: usize, location: Location) -> Result<(), String> {
+    let (name, text) = match statement.find(char::is_whitespace) {
+        Some(position) => (&statement[..position], statement[position..].trim()),
+        None => (statement, ""),
+    };
+    let opcode = match opcode(name) {
+        Some(opcode) => opcode,
+        None => return Err(format!("unknown instruction '{}'", name)),
+    };
+    let template = Instruction::from_opcode(opcode, 0).unwrap();
+    if template.argument_name().is_none() {
+        if !text.is_empty() {
+            return Err(format!("{} takes no argument", name));
+        }
+        block.instructions.push(template, location);
+        return Ok(());
+    }
+    // Functions without defaults are made by the instruction alone:
+    let is_function = matches!(
+        template,
+        Instruction::MakeFunction { .. } | Instruction::MakeClosure { .. }
+    );
+    if text.is_empty() && !is_function {
+        return Err(format!("{} needs an argument", name));
+    }
+
+    let argument = match template {
+        Instruction::LoadConst { .. } => match text {
+            "None" | "True" | "False" => block.constant(constant(text)?),
+            _ if is_code_name(text) => block.nested_code(text, row),
+            _ => block.constant(constant(text)?),
+        },
+        Instruction::LoadName { .. }
+        | Instruction::StoreName { .. }
+        | Instruction::DeleteName { .. }
+        | Instruction::LoadGlobal { .. }
+        | Instruction::StoreGlobal { .. }
+        | Instruction::DeleteGlobal { .. }
+        | Instruction::LoadAttr { .. }
+        | Instruction::StoreAttr { .. }
+        | Instruction::DeleteAttr { .. }
+        | Instruction::ImportName { .. }
+        | Instruction::ImportFrom { .. } => block.code.add_name(text),
+        Instruction::LoadFast { .. }
+        | Instruction::StoreFast { .. }
+        | Instruction::DeleteFast { .. } => block.code.add_varname(text),
+        Instruction::LoadDeref { .. }
+        | Instruction::StoreDeref { .. }
+        | Instruction::DeleteDeref { .. }
+        | Instruction::LoadClosure { .. } => match block.code.deref_index(text) {
+            Some(index) => index,
+            None => return Err(format!("'{}' is not a cell or free variable", text)),
+        },
+        Instruction::UnaryOperation { .. }
+        | Instruction::BinaryOperation { .. }
+        | Instruction::InplaceOperation { .. }
+        | Instruction::CompareOperation { .. }
+        | Instruction::MakeFunction { .. }
+        | Instruction::MakeClosure { .. } => {
+            // The arguments which the disassembler shows as words or
+            // operators are found by how they are shown:
+            let found = (0..)
+                .map_while(|argument| Instruction::from_opcode(opcode, argument))
+                .position(|instruction| dis::argument_repr(&block.code, &instruction) == text);
+            match found {
+                Some(argument) => argument,
+                None => return Err(format!("bad argument of {}: {}", name, text)),
+            }
+        }
+        _ if template.jump_target().is_some() => {
+            if !is_identifier(text) {
+                return Err(format!("'{}' is not a label", text));
+            }
+            if !block.jumps.iter().any(|(label, _)| label == text) {
+                block.jumps.push((String::from(text), row));
+            }
+            block.label(text)
+        }
+        _ => match text.parse() {
+            Ok(number) => number,
+            Err(_) => return Err(format!("bad argument of {}: {}", name, text)),
+        },
+    };
+    let instruction = Instruction::from_opcode(opcode, argument).unwrap();
+    block.instructions.push(instruction, location);
+    Ok(())
+}
+
+// A constant written like a python literal:
+fn constant(text: &str) -> Result<Constant, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut position = 0;
+    let value = literal(&chars, &mut position)?;
+    skip_spaces(&chars, &mut position);
+    if position < chars.len() {
+        return Err(format!("bad constant: {}", text));
+    }
+    Ok(value)
+}
+
+fn skip_spaces(chars: &[char], position: &mut usize) {
+    while chars.get(*position).is_some_and(|c| c.is_whitespace()) {
+        *position += 1;
+    }
+}
+
+fn literal(chars: &[char], position: &mut usize) -> Result<Constant, String> {
+    skip_spaces(chars, position);
+    let start = *position;
+    match chars.get(start) {
+        Some('(') => {
+            *position += 1;
+            let mut elements = vec![];
+            let mut comma = false;
+            loop {
+                skip_spaces(chars, position);
+                if chars.get(*position) == Some(&')') {
+                    *position += 1;
+                    break;
+                }
+                elements.push(literal(chars, position)?);
+                skip_spaces(chars, position);
+                match chars.get(*position) {
+                    Some(',') => {
+                        *position += 1;
+                        comma = true;
+                    }
+                    Some(')') => {}
+                    _ => return Err(String::from("missing ) after a tuple")),
+                }
+            }
+            // Parentheses around a single value without a comma only group:
+            if elements.len() == 1 && !comma {
+                return Ok(elements.pop().unwrap());
+            }
+            Ok(Constant::Tuple { elements })
+        }
+        Some('\'') | Some('"') => Ok(Constant::String {
+            value: string(chars, position)?,
+        }),
+        Some('b') if matches!(chars.get(start + 1), Some('\'') | Some('"')) => {
+            *position += 1;
+            let value = string(chars, position)?;
+            if !value.chars().all(|c| (c as u32) < 256) {
+                return Err(String::from("bytes can only contain ASCII characters"));
+            }
+            Ok(Constant::Bytes {
+                value: value.chars().map(|c| c as u8).collect(),
+            })
+        }
+        Some('.') if chars[start..].starts_with(&['.', '.', '.']) => {
+            *position += 3;
+            Ok(Constant::Ellipsis)
+        }
+        Some(c) if c.is_ascii_digit() || *c == '-' || *c == '.' => {
+            *position += 1;
+            while chars
+                .get(*position)
+                .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '_')
+            {
+                // An exponent may have a sign:
+                if matches!(chars[*position], 'e' | 'E')
+                    && matches!(chars.get(*position + 1), Some('+') | Some('-'))
+                {
+                    *position += 1;
+                }
+                *position += 1;
+            }
+            let text: String = chars[start..*position].iter().filter(|c| **c != '_').collect();
+            number(&text)
+        }
+        _ => {
+            while chars
+                .get(*position)
+                .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+            {
+                *position += 1;
+            }
+            let name: String = chars[start..*position].iter().collect();
+            match name.as_str() {
+                "None" => Ok(Constant::None),
+                "True" => Ok(Constant::Boolean { value: true }),
+                "False" => Ok(Constant::Boolean { value: false }),
+                _ => Err(format!("bad constant: {}", chars[start..].iter().collect::<String>())),
+            }
+        }
+    }
+}
+
+fn number(text: &str) -> Result<Constant, String> {
+    let bad = || format!("bad number: {}", text);
+    if let Some(imag) = text.strip_suffix('j').or_else(|| text.strip_suffix('J')) {
+        let imag = imag.parse().map_err(|_| bad())?;
+        return Ok(Constant::Complex { real: 0.0, imag });
+    }
+    if text.contains(['.', 'e', 'E']) {
+        let value = text.parse().map_err(|_| bad())?;
+        return Ok(Constant::Float { value });
+    }
+    let value = text.parse().map_err(|_| bad())?;
+    Ok(Constant::Integer { value })
+}
+
+// A quoted string, with the escapes of python for special characters:
+fn string(chars: &[char], position: &mut usize) -> Result<String, String> {
+    let quote = chars[*position];
+    *position += 1;
+    let mut value = String::new();
+    loop {
+        let c = match chars.get(*position) {
+            Some(c) => *c,
+            None => return Err(String::from("missing end of string")),
+        };
+        *position += 1;
+        if c == quote {
+            return Ok(value);
+        }
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        let escaped = match chars.get(*position) {
+            Some(c) => *c,
+            None => return Err(String::from("missing end of string")),
+        };
+        *position += 1;
+        match escaped {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            '0' => value.push('\0'),
+            'x' => {
+                let digits: String = chars.iter().skip(*position).take(2).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) if digits.len() == 2 => value.push(byte as char),
+                    _ => return Err(format!("bad escape \\x{}", digits)),
+                }
+                *position += 2;
+            }
+            '\\' | '\'' | '"' => value.push(escaped),
+            _ => {
+                value.push('\\');
+                value.push(escaped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame::Scope;
+    use super::super::vm::VirtualMachine;
+    use super::*;
+
+    // Run the assembled code, giving the repr of the variable x:
+    fn run(source: &str) -> String {
+        let code = assemble(source, "<test>").unwrap();
+        let mut vm = VirtualMachine::new();
+        let scope = Scope::new(None);
+        if let Err(exception) = vm.run_code(code, scope.clone()) {
+            vm.print_exception(&exception);
+            panic!("Program raised an exception");
+        }
+        let x = scope.borrow().get("x").unwrap();
+        let repr = x.borrow().repr();
+        repr
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+    LoadConst (1, 'a#b', b'\\x00', 2.5)  # a comment
+    StoreName x
+loop:
+    LoadName x
+    JumpIfFalse loop
+    LoadConst f
+    MakeFunction
+    StoreName f
+    LoadConst None
+    ReturnValue
+
+.code f(a, b)
+    .cellvars a
+    LoadFast b
+    ReturnValue
+.end
+";
+        let code = assemble(source, "test.s").unwrap();
+        assert_eq!(code.names, vec!["x", "f"]);
+        assert_eq!(code.filename, "test.s");
+        let text = dis::disassemble(&code).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "  2           0 LoadConst                0 ((1, 'a#b', b'\\x00', 2.5))");
+        assert!(lines.contains(&"  5     >>    2 LoadName                 0 (x)"));
+        assert!(lines.contains(&"  6           3 JumpIfFalse              2 (to 2)"));
+        assert!(lines.contains(&" 10           7 LoadConst                2 (None)"));
+        assert!(lines.contains(&"Disassembly of <code object f, file \"test.s\", line 13>:"));
+        assert!(lines.contains(&" 15           0 LoadFast                 1 (b)"));
+
+        let f = match &code.consts[1] {
+            Constant::Code { code } => code.clone(),
+            constant => panic!("{:?} is not a code object", constant),
+        };
+        assert_eq!(f.arg_names(), ["a", "b"]);
+        assert_eq!(f.qualname, "f");
+        assert_eq!(f.cellvars, vec!["a"]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |source| assemble(source, "<test>").unwrap_err();
+        assert_eq!(error("LoadName x\nStoreThing x"), "line 2: unknown instruction 'StoreThing'");
+        assert_eq!(error("Jump done"), "line 1: undefined label 'done'");
+        assert_eq!(error(".code f()\nPass"), "missing .end of code object 'f'");
+        assert_eq!(error("LoadConst (1, 2"), "line 1: missing ) after a tuple");
+        assert_eq!(error("Pop 1"), "line 1: Pop takes no argument");
+        assert_eq!(error("LoadName"), "line 1: LoadName needs an argument");
+        assert_eq!(error("LoadDeref a"), "line 1: 'a' is not a cell or free variable");
+        assert_eq!(error("a:\na:"), "line 2: label 'a' is already defined on line 1");
+        assert_eq!(
+            error(".code f()\nJump a\n.end"),
+            "line 2: undefined label 'a'"
+        );
+        assert_eq!(error("LoadConst g"), "line 1: undefined code object 'g'");
+    }
+
+    #[test]
+    fn test_stack_instructions() {
+        let source = "
+    LoadConst 1
+    LoadConst 2
+    LoadConst 3
+    Rotate 3            # 3 1 2
+    Duplicate           # 3 1 2 2
+    LoadConst (4, 5)
+    UnpackSequence 2    # 3 1 2 2 5 4
+    LoadConst 'k'
+    LoadConst 'v'
+    BuildMap 1
+    BuildTuple 7
+    StoreName x
+    LoadConst None
+    ReturnValue
+";
+        assert_eq!(run(source), "(3, 1, 2, 2, 5, 4, {'k': 'v'})");
+    }
+
+    #[test]
+    fn test_loop() {
+        // Sum the numbers of a list:
+        let source = "
+    LoadConst 0
+    StoreName x
+    LoadConst (1, 2, 3)
+    GetIter
+next:
+    ForIter done
+    LoadName x
+    BinaryOperation +
+    StoreName x
+    Jump next
+done:
+    LoadConst None
+    ReturnValue
+";
+        assert_eq!(run(source), "6");
+    }
+
+    #[test]
+    fn test_functions() {
+        // A function with a default, called with a keyword, and a closure:
+        let source = "
+    LoadConst (10,)
+    LoadConst add
+    MakeFunction defaults
+    StoreName add
+    LoadName add
+    LoadConst 1
+    CallFunction 1
+    LoadName add
+    LoadConst 1
+    LoadConst 2
+    LoadConst ('b',)
+    CallFunctionKw 2
+    LoadConst outer
+    MakeFunction
+    LoadConst 5
+    CallFunction 1
+    LoadConst 7
+    CallFunction 1
+    BuildList 3
+    StoreName x
+    LoadConst None
+    ReturnValue
+
+.code add(a, b)
+    LoadFast a
+    LoadFast b
+    BinaryOperation +
+    ReturnValue
+.end
+
+.code outer(a)
+    .cellvars a
+    LoadClosure a
+    BuildTuple 1
+    LoadConst inner
+    MakeClosure
+    ReturnValue
+
+    .code inner(b)
+        .freevars a
+        LoadDeref a
+        LoadFast b
+        BinaryOperation *
+        ReturnValue
+    .end
+.end
+";
+        assert_eq!(run(source), "[11, 3, 35]");
+        let code = assemble(source, "<test>").unwrap();
+        let codes: Vec<&Rc<CodeObject>> = code
+            .consts
+            .iter()
+            .filter_map(|constant| match constant {
+                Constant::Code { code } => Some(code),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(codes[1].name, "outer");
+        match &codes[1].consts[0] {
+            Constant::Code { code } => assert_eq!(code.qualname, "outer.<locals>.inner"),
+            constant => panic!("{:?} is not a code object", constant),
+        }
+    }
+
+    #[test]
+    fn test_generator() {
+        let source = "
+    LoadName list
+    LoadConst count
+    MakeFunction
+    CallFunction 0
+    CallFunction 1
+    StoreName x
+    LoadConst None
+    ReturnValue
+
+.code count()
+    .generator
+    LoadConst 1
+    YieldValue
+    Pop
+    LoadConst 2
+    YieldValue
+    Pop
+    LoadConst None
+    ReturnValue
+.end
+";
+        assert_eq!(run(source), "[1, 2]");
+    }
+}
@@ -2,33 +2,54 @@
  * Implement abstract syntax tree nodes for the python language.
  */
 
-/*
-#[derive(Debug)]
+// A position in the source code. Rows count from 1, columns from 0:
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Location {
-    pub row: i32,
-    pub column: i32,
+    pub row: usize,
+    pub column: usize,
 }
 
-#[derive(Debug)]
-pub struct Node {
-    pub location: Location,
+impl Location {
+    pub fn new(row: usize, column: usize) -> Location {
+        Location { row, column }
+    }
 }
-*/
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
-    pub statements: Vec<Statement>,
+    pub statements: Vec<LocatedStatement>,
 }
 
+// A statement, and where it starts in the source:
+#[derive(Debug, PartialEq)]
+pub struct LocatedStatement {
+    pub location: Location,
+    pub node: Statement,
+}
+
+// Not all statements are produced by the parser yet:
+#[allow(dead_code)]
 #[derive(Debug, PartialEq)]
 pub enum Statement {
     Break,
     Continue,
     Return {
-        value: Option<Vec<Expression>>,
+        value: Option<Expression>,
     },
     Import {
-        name: String,
+        names: Vec<ImportSymbol>,
+    },
+    // The level counts the leading dots of a relative import:
+    ImportFrom {
+        level: usize,
+        module: Option<String>,
+        names: Vec<ImportSymbol>,
+    },
+    Global {
+        names: Vec<String>,
+    },
+    Nonlocal {
+        names: Vec<String>,
     },
     Pass,
     Assert {
@@ -42,35 +63,64 @@ pub enum Statement {
         targets: Vec<Expression>,
         value: Expression,
     },
+    AugAssign {
+        target: Expression,
+        op: Operator,
+        value: Expression,
+    },
+    AnnAssign {
+        target: Expression,
+        annotation: Expression,
+        value: Option<Expression>,
+        // A name which is not in parentheses:
+        simple: bool,
+    },
     Expression {
         expression: Expression,
     },
     If {
         test: Expression,
-        body: Vec<Statement>,
+        body: Vec<LocatedStatement>,
+        or_else: Option<Vec<LocatedStatement>>,
     },
     While {
         test: Expression,
-        body: Vec<Statement>,
+        body: Vec<LocatedStatement>,
+        or_else: Option<Vec<LocatedStatement>>,
     },
     With {
-        items: Expression,
-        body: Vec<Statement>,
+        items: Vec<WithItem>,
+        body: Vec<LocatedStatement>,
     },
     For {
-        target: Vec<Expression>,
-        iter: Vec<Expression>,
-        body: Vec<Statement>,
-        or_else: Option<Vec<Statement>>,
+        target: Expression,
+        iter: Expression,
+        body: Vec<LocatedStatement>,
+        or_else: Option<Vec<LocatedStatement>>,
+    },
+    Raise {
+        exception: Option<Expression>,
+        cause: Option<Expression>,
+    },
+    Try {
+        body: Vec<LocatedStatement>,
+        handlers: Vec<ExceptHandler>,
+        or_else: Option<Vec<LocatedStatement>>,
+        finalbody: Option<Vec<LocatedStatement>>,
     },
     ClassDef {
         name: String,
+        bases: Vec<Expression>,
         // TODO: docstring: String,
+        body: Vec<LocatedStatement>,
+        decorator_list: Vec<Expression>,
     },
     FunctionDef {
         name: String,
+        args: Parameters,
         // docstring: String,
-        body: Vec<Statement>,
+        body: Vec<LocatedStatement>,
+        decorator_list: Vec<Expression>,
     },
 }
 
@@ -81,9 +131,50 @@ pub enum Expression {
         op: Operator,
         b: Box<Expression>,
     },
+    BoolOp {
+        a: Box<Expression>,
+        op: BooleanOperator,
+        b: Box<Expression>,
+    },
+    Unop {
+        op: UnaryOperator,
+        a: Box<Expression>,
+    },
+    Compare {
+        vals: Vec<Expression>,
+        ops: Vec<Comparison>,
+    },
     Call {
         function: Box<Expression>,
         args: Vec<Expression>,
+        keywords: Vec<Keyword>,
+    },
+    Subscript {
+        a: Box<Expression>,
+        b: Box<Expression>,
+    },
+    Attribute {
+        value: Box<Expression>,
+        name: String,
+    },
+    Yield {
+        value: Option<Box<Expression>>,
+    },
+    YieldFrom {
+        value: Box<Expression>,
+    },
+    Comprehension {
+        kind: Box<ComprehensionKind>,
+        generators: Vec<Comprehension>,
+    },
+    Lambda {
+        args: Parameters,
+        body: Box<Expression>,
+    },
+    IfExp {
+        test: Box<Expression>,
+        body: Box<Expression>,
+        orelse: Box<Expression>,
     },
     Number {
         value: i32,
@@ -94,6 +185,12 @@ pub enum Expression {
     Tuple {
         elements: Vec<Expression>,
     },
+    Dict {
+        elements: Vec<(Expression, Expression)>,
+    },
+    Set {
+        elements: Vec<Expression>,
+    },
     String {
         value: String,
     },
@@ -105,6 +202,59 @@ pub enum Expression {
     None,
 }
 
+/*
+ * The parameters of a function definition. Default values belong to the
+ * last parameters in `args`.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Parameters {
+    pub args: Vec<String>,
+    pub defaults: Vec<Expression>,
+}
+
+// A keyword argument of a call:
+#[derive(Debug, PartialEq)]
+pub struct Keyword {
+    pub name: String,
+    pub value: Expression,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ComprehensionKind {
+    GeneratorExpression { element: Expression },
+    List { element: Expression },
+    Set { element: Expression },
+    Dict { key: Expression, value: Expression },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Comprehension {
+    pub target: Expression,
+    pub iter: Expression,
+    pub ifs: Vec<Expression>,
+}
+
+// A star import is a single symbol named `*`:
+#[derive(Debug, PartialEq)]
+pub struct ImportSymbol {
+    pub symbol: String,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WithItem {
+    pub context_expr: Expression,
+    pub optional_vars: Option<Expression>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExceptHandler {
+    pub location: Location,
+    pub typ: Option<Expression>,
+    pub name: Option<String>,
+    pub body: Vec<LocatedStatement>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Operator {
     Add,
@@ -121,3 +271,31 @@ pub enum Operator {
     BitAnd,
     FloorDiv,
 }
+
+#[derive(Debug, PartialEq)]
+pub enum BooleanOperator {
+    And,
+    Or,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UnaryOperator {
+    Pos,
+    Neg,
+    Not,
+    Inv,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    In,
+    NotIn,
+    Is,
+    IsNot,
+}
@@ -6,23 +6,431 @@
  * https://github.com/python/cpython/blob/master/Python/bltinmodule.c
  */
 
-use super::pyobject::PyObjectRef;
+use std::collections::HashMap;
 use std::io::{self, Write};
+use std::rc::Rc;
 
-pub fn fill_scope() {
-    // scope[String::from("print")] = print;
+use super::compile::{compile_with_options, CompileOptions};
+use super::descriptor;
+use super::dict;
+use super::exceptions;
+use super::frame::{Frame, Scope};
+use super::generator;
+use super::list;
+use super::parser::parse_interactive;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::set;
+use super::vm::VirtualMachine;
+
+pub fn fill_scope(scope: &mut HashMap<String, PyObjectRef>) {
+    let functions: Vec<(&str, RustPyFunc)> = vec![
+        ("__build_class__", build_class),
+        ("bool", bool),
+        ("compile", compile),
+        ("dict", dict),
+        ("isinstance", isinstance),
+        ("float", float),
+        ("int", int),
+        ("iter", iter),
+        ("len", len),
+        ("list", list),
+        ("next", next),
+        ("print", print),
+        ("range", range),
+        ("repr", repr),
+        ("set", set),
+        ("sorted", sorted),
+        ("str", str),
+    ];
+    for (name, function) in functions {
+        scope.insert(
+            String::from(name),
+            PyObject::RustFunction {
+                function: Rc::new(function),
+                doc: None,
+            }.into_ref(),
+        );
+    }
+    descriptor::fill_scope(scope);
+    exceptions::fill_scope(scope);
+}
+
+// Check the number of positional arguments, of a function which does not
+// take keyword arguments.
+pub fn check_arg_count(
+    vm: &mut VirtualMachine,
+    name: &str,
+    args: &PyFuncArgs,
+    min: usize,
+    max: usize,
+) -> Result<(), PyObjectRef> {
+    if !args.kwargs.is_empty() {
+        let msg = format!("{}() takes no keyword arguments", name);
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    check_positional_count(vm, name, args, min, max)
+}
+
+pub fn check_positional_count(
+    vm: &mut VirtualMachine,
+    name: &str,
+    args: &PyFuncArgs,
+    min: usize,
+    max: usize,
+) -> Result<(), PyObjectRef> {
+    let count = args.args.len();
+    if count < min || count > max {
+        let msg = format!("{}() takes {} to {} arguments ({} given)", name, min, max, count);
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    Ok(())
+}
+
+fn to_int(vm: &mut VirtualMachine, obj: &PyObjectRef) -> Result<i32, PyObjectRef> {
+    match *obj.borrow() {
+        PyObject::Integer { value } => return Ok(value),
+        PyObject::Boolean { value } => return Ok(if value { 1 } else { 0 }),
+        _ => {}
+    }
+    let msg = format!("'{}' object cannot be interpreted as an integer", obj.borrow().type_name());
+    Err(vm.new_exception("TypeError", msg))
+}
+
+// __build_class__(func, name, *bases), used by the class statement. The
+// class body is compiled into `func`, and the names it binds become the
+// attributes of the new class.
+fn build_class(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    if args.args.len() < 2 {
+        let msg = String::from("__build_class__: not enough arguments");
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    let (code, scope, closure) = match *args.args[0].borrow() {
+        PyObject::Function {
+            ref code,
+            ref scope,
+            ref closure,
+            ..
+        } => (code.clone(), scope.clone(), closure.clone()),
+        _ => {
+            let msg = String::from("__build_class__: func must be a function");
+            return Err(vm.new_exception("TypeError", msg));
+        }
+    };
+    let name = match *args.args[1].borrow() {
+        PyObject::String { ref value } => value.clone(),
+        _ => {
+            let msg = String::from("__build_class__: name is not a string");
+            return Err(vm.new_exception("TypeError", msg));
+        }
+    };
+    let bases = args.args[2..].to_vec();
+    for base in &bases {
+        if !matches!(*base.borrow(), PyObject::Class { .. }) {
+            let msg = format!("cannot inherit from '{}' object", base.borrow().type_name());
+            return Err(vm.new_exception("TypeError", msg));
+        }
+    }
+
+    // Names of enclosing functions are only seen through cells:
+    let class_scope = Scope::new(Some(Scope::globals(&scope)));
+    Frame::with_closure(code, class_scope.clone(), closure).run(vm)?;
+    let dict = class_scope.borrow().locals.clone();
+    Ok(PyObject::Class { name, dict, bases }.into_ref())
 }
 
-pub fn print(args: Vec<PyObjectRef>) {
-    // println!("Woot: {:?}", args);
+// print(*objects, sep=' ', end='\n')
+pub fn print(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
     trace!("print called with {:?}", args);
-    for a in args {
-        print!("{} ", a.borrow_mut().str());
+    let mut sep = String::from(" ");
+    let mut end = String::from("\n");
+    for (name, value) in &args.kwargs {
+        let value = if matches!(*value.borrow(), PyObject::None) {
+            None
+        } else {
+            Some(vm.str_of(value.clone())?)
+        };
+        match name.as_str() {
+            "sep" => sep = value.unwrap_or(sep),
+            "end" => end = value.unwrap_or(end),
+            _ => {
+                let msg = format!("'{}' is an invalid keyword argument for print()", name);
+                return Err(vm.new_exception("TypeError", msg));
+            }
+        }
     }
-    println!();
+    let mut parts = vec![];
+    for a in args.args {
+        parts.push(vm.str_of(a)?);
+    }
+    print!("{}{}", parts.join(&sep), end);
     io::stdout().flush().unwrap();
+    Ok(vm.get_none())
+}
+
+fn bool(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "bool", &args, 0, 1)?;
+    let value = match args.args.first() {
+        Some(obj) => vm.is_true(obj.clone())?,
+        None => false,
+    };
+    Ok(vm.new_bool(value))
+}
+
+// compile(source, filename, mode), where the mode is 'exec' for a module, or
+// 'single' for a statement typed at the interactive prompt.
+fn compile(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "compile", &args, 3, 3)?;
+    let mut strings = vec![];
+    for (position, arg) in args.args.iter().enumerate() {
+        match *arg.borrow() {
+            PyObject::String { ref value } => strings.push(value.clone()),
+            _ => {
+                let msg = format!(
+                    "compile() arg {} must be str, not {}",
+                    position + 1,
+                    arg.borrow().type_name()
+                );
+                return Err(vm.new_exception("TypeError", msg));
+            }
+        }
+    }
+    let (source, filename) = (&strings[0], &strings[1]);
+    let code = match strings[2].as_str() {
+        "exec" => vm.compile_source(source, filename)?,
+        "single" => {
+            let options = CompileOptions {
+                interactive: true,
+                ..vm.compile_options()
+            };
+            let code = parse_interactive(source).and_then(|program| match program {
+                Some(program) => compile_with_options(program, filename, options),
+                None => Err(String::from("unexpected EOF while parsing")),
+            });
+            match code {
+                Ok(code) => code,
+                Err(msg) => return Err(vm.new_exception("SyntaxError", msg)),
+            }
+        }
+        _ => {
+            let msg = String::from("compile() mode must be 'exec' or 'single'");
+            return Err(vm.new_exception("ValueError", msg));
+        }
+    };
+    Ok(PyObject::Code {
+        code: Rc::new(code),
+    }.into_ref())
+}
+
+// dict([iterable]), where the iterable gives key value pairs.
+fn dict(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "dict", &args, 0, 1)?;
+    let dict_obj = dict::new_dict();
+    if let Some(iterable) = args.args.first() {
+        for pair in vm.extract_elements(iterable)? {
+            let elements = vm.extract_elements(&pair)?;
+            if elements.len() != 2 {
+                let msg = format!(
+                    "dictionary update sequence element has length {}; 2 is required",
+                    elements.len()
+                );
+                return Err(vm.new_exception("ValueError", msg));
+            }
+            let mut elements = elements.into_iter();
+            let key = elements.next().unwrap();
+            let value = elements.next().unwrap();
+            dict::set_item(vm, &dict_obj, key, value)?;
+        }
+    }
+    Ok(dict_obj)
 }
 
-fn any() {}
+fn isinstance(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "isinstance", &args, 2, 2)?;
+    let value = vm.isinstance(&args.args[0], &args.args[1]);
+    Ok(vm.new_bool(value))
+}
+
+fn int(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "int", &args, 0, 1)?;
+    let obj = match args.args.first() {
+        Some(obj) => obj.clone(),
+        None => return Ok(PyObject::Integer { value: 0 }.into_ref()),
+    };
+    let parsed = match *obj.borrow() {
+        PyObject::String { ref value } => Some(value.trim().parse::<i32>().ok()),
+        PyObject::Float { value } => {
+            let value = value.trunc();
+            if value.is_nan() || value < f64::from(i32::MIN) || value > f64::from(i32::MAX) {
+                let msg = String::from("cannot convert float to integer");
+                return Err(vm.new_exception("OverflowError", msg));
+            }
+            Some(Some(value as i32))
+        }
+        _ => None,
+    };
+    let value = match parsed {
+        Some(Some(value)) => value,
+        Some(None) => {
+            let msg = format!("invalid literal for int(): {}", obj.borrow().repr());
+            return Err(vm.new_exception("ValueError", msg));
+        }
+        None => to_int(vm, &obj)?,
+    };
+    Ok(PyObject::Integer { value }.into_ref())
+}
 
-fn all() {}
+fn float(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "float", &args, 0, 1)?;
+    let obj = match args.args.first() {
+        Some(obj) => obj.clone(),
+        None => return Ok(PyObject::Float { value: 0.0 }.into_ref()),
+    };
+    let value = match *obj.borrow() {
+        PyObject::Float { value } => Some(value),
+        PyObject::Integer { value } => Some(f64::from(value)),
+        PyObject::Boolean { value } => Some(if value { 1.0 } else { 0.0 }),
+        PyObject::String { ref value } => match value.trim().parse::<f64>() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                let msg = format!("could not convert string to float: {}", obj.borrow().repr());
+                return Err(vm.new_exception("ValueError", msg));
+            }
+        },
+        _ => None,
+    };
+    match value {
+        Some(value) => Ok(PyObject::Float { value }.into_ref()),
+        None => {
+            let msg = format!(
+                "float() argument must be a string or a number, not '{}'",
+                obj.borrow().type_name()
+            );
+            Err(vm.new_exception("TypeError", msg))
+        }
+    }
+}
+
+fn iter(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "iter", &args, 1, 1)?;
+    vm.get_iter(args.args[0].clone())
+}
+
+fn len(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "len", &args, 1, 1)?;
+    let length = match *args.args[0].borrow() {
+        PyObject::String { ref value } => Some(value.chars().count()),
+        PyObject::Bytes { ref value } => Some(value.len()),
+        PyObject::List { ref elements }
+        | PyObject::Tuple { ref elements }
+        | PyObject::Set { ref elements }
+        | PyObject::FrozenSet { ref elements } => Some(elements.len()),
+        PyObject::Dict { ref elements } => Some(elements.len()),
+        _ => None,
+    };
+    match length {
+        Some(value) => Ok(PyObject::Integer {
+            value: value as i32,
+        }.into_ref()),
+        None => {
+            let msg = format!("object of type '{}' has no len()", args.args[0].borrow().type_name());
+            Err(vm.new_exception("TypeError", msg))
+        }
+    }
+}
+
+fn list(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "list", &args, 0, 1)?;
+    let elements = match args.args.first() {
+        Some(iterable) => vm.extract_elements(iterable)?,
+        None => vec![],
+    };
+    Ok(PyObject::List { elements }.into_ref())
+}
+
+fn next(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "next", &args, 1, 2)?;
+    let is_iterator = matches!(*args.args[0].borrow(), PyObject::Iterator { .. } | PyObject::Generator { .. });
+    if !is_iterator {
+        let msg = format!("'{}' object is not an iterator", args.args[0].borrow().type_name());
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    let is_generator = matches!(*args.args[0].borrow(), PyObject::Generator { .. });
+    if is_generator {
+        // Keep the StopIteration of a generator, it carries the return value:
+        let none = vm.get_none();
+        return match generator::send(vm, &args.args[0], none) {
+            Err(exception) => {
+                let stop_iteration = vm.get_builtin("StopIteration");
+                match args.args.get(1) {
+                    Some(default) if vm.isinstance(&exception, &stop_iteration) => {
+                        Ok(default.clone())
+                    }
+                    _ => Err(exception),
+                }
+            }
+            result => result,
+        };
+    }
+    match vm.next(args.args[0].clone())? {
+        Some(value) => Ok(value),
+        None => match args.args.get(1) {
+            Some(default) => Ok(default.clone()),
+            None => Err(vm.new_exception("StopIteration", String::new())),
+        },
+    }
+}
+
+fn range(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "range", &args, 1, 3)?;
+    let mut values = vec![];
+    for arg in &args.args {
+        values.push(to_int(vm, arg)?);
+    }
+    let (start, stop, step) = match values.len() {
+        1 => (0, values[0], 1),
+        2 => (values[0], values[1], 1),
+        _ => (values[0], values[1], values[2]),
+    };
+    if step == 0 {
+        return Err(vm.new_exception(
+            "ValueError",
+            String::from("range() arg 3 must not be zero"),
+        ));
+    }
+    Ok(PyObject::Range { start, stop, step }.into_ref())
+}
+
+fn repr(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "repr", &args, 1, 1)?;
+    let value = vm.repr_of(args.args[0].clone())?;
+    Ok(PyObject::String { value }.into_ref())
+}
+
+fn set(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "set", &args, 0, 1)?;
+    let set_obj = set::new_set();
+    if let Some(iterable) = args.args.first() {
+        for element in vm.extract_elements(iterable)? {
+            set::add(vm, &set_obj, element)?;
+        }
+    }
+    Ok(set_obj)
+}
+
+fn str(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "str", &args, 0, 1)?;
+    let value = match args.args.first() {
+        Some(obj) => vm.str_of(obj.clone())?,
+        None => String::new(),
+    };
+    Ok(PyObject::String { value }.into_ref())
+}
+
+// sorted(iterable, key=None, reverse=False)
+fn sorted(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_positional_count(vm, "sorted", &args, 1, 1)?;
+    let (key, reverse) = list::sort_options(vm, "sorted", &args)?;
+    let elements = vm.extract_elements(&args.args[0])?;
+    let elements = list::sort(vm, elements, key, reverse)?;
+    Ok(PyObject::List { elements }.into_ref())
+}
@@ -1,54 +1,915 @@
 /*
  * Implement python as a virtual machine with bytecodes.
+ *
+ * The instructions of a code object are encoded as wordcode, like CPython
+ * does: every instruction is a word of two bytes, the opcode and an
+ * argument. An argument which does not fit in a byte is extended by
+ * EXTENDED_ARG words before the instruction, each holding the next higher
+ * byte. Jump targets are word offsets.
  */
 
-/*
-let load_const_string = 0x16;
-let call_function = 0x64;
-*/
-
-/*
- * Primitive instruction type, which can be encoded and decoded.
- */
+use super::ast::Location;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct CodeObject {
-    pub instructions: Vec<Instruction>,
+    pub code: Vec<u8>,
+    // The arguments are the first local variables:
+    pub arg_count: usize,
+    // The tables which the arguments of instructions index into. The names
+    // are those of globals, attributes and imports, and of the variables in
+    // module and class bodies:
+    pub consts: Vec<Constant>,
+    pub names: Vec<String>,
+    pub varnames: Vec<String>,
+    // Variables of enclosing functions, passed in the closure:
+    pub freevars: Vec<String>,
+    // Locals which are shared with nested functions:
+    pub cellvars: Vec<String>,
+    pub is_generator: bool,
+    // The source position of each instruction:
+    pub linetable: LineTable,
+    pub filename: String,
+    pub name: String,
+    // The dotted path from the module, like `C.f.<locals>.g`:
+    pub qualname: String,
+    pub firstlineno: usize,
+    // Where the entries of the tables are, to add them without a scan:
+    const_index: TableIndex<Constant>,
+    name_index: TableIndex<String>,
+    varname_index: TableIndex<String>,
 }
 
 impl CodeObject {
-    pub fn new() -> CodeObject {
+    pub fn new(arg_names: Vec<String>, name: String) -> CodeObject {
         CodeObject {
-            instructions: Vec::new(),
+            code: Vec::new(),
+            arg_count: arg_names.len(),
+            consts: Vec::new(),
+            names: Vec::new(),
+            varnames: arg_names,
+            freevars: Vec::new(),
+            cellvars: Vec::new(),
+            is_generator: false,
+            linetable: LineTable::new(),
+            filename: String::from("<string>"),
+            qualname: name.clone(),
+            name,
+            firstlineno: 1,
+            const_index: TableIndex::new(),
+            name_index: TableIndex::new(),
+            varname_index: TableIndex::new(),
+        }
+    }
+
+    // Decode the instruction at a word offset:
+    pub fn instruction_at(&self, offset: usize) -> Result<Instruction, String> {
+        decode_instruction(&self.code, &mut offset.clone())
+    }
+
+    // All instructions with their offsets:
+    pub fn instructions(&self) -> Result<Vec<(usize, Instruction)>, String> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < self.code.len() / 2 {
+            let start = offset;
+            instructions.push((start, decode_instruction(&self.code, &mut offset)?));
+        }
+        Ok(instructions)
+    }
+
+    // Check that code which was not compiled here, like code read from a
+    // file, only refers to the entries of its tables and jumps within the
+    // code. It is trusted otherwise, like CPython trusts .pyc files.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.code.len().is_multiple_of(2) {
+            return Err(String::from("code is not made of words"));
+        }
+        if self.arg_count > self.varnames.len() {
+            return Err(String::from("more arguments than local variables"));
+        }
+        let words = self.code.len() / 2;
+        if self.linetable.locations().len() != words {
+            return Err(String::from("line table does not match the code"));
+        }
+        let derefs = self.cellvars.len() + self.freevars.len();
+        for (offset, instruction) in self.instructions()? {
+            if let Some(target) = instruction.jump_target() {
+                if target >= words {
+                    return Err(format!("jump out of the code at offset {}", offset));
+                }
+            }
+            let (index, count) = match instruction {
+                Instruction::LoadConst { consti } => (consti, self.consts.len()),
+                Instruction::LoadName { namei }
+                | Instruction::StoreName { namei }
+                | Instruction::DeleteName { namei }
+                | Instruction::LoadGlobal { namei }
+                | Instruction::StoreGlobal { namei }
+                | Instruction::DeleteGlobal { namei }
+                | Instruction::LoadAttr { namei }
+                | Instruction::StoreAttr { namei }
+                | Instruction::DeleteAttr { namei }
+                | Instruction::ImportName { namei }
+                | Instruction::ImportFrom { namei } => (namei, self.names.len()),
+                Instruction::LoadFast { var_num }
+                | Instruction::StoreFast { var_num }
+                | Instruction::DeleteFast { var_num } => (var_num, self.varnames.len()),
+                Instruction::LoadDeref { i }
+                | Instruction::StoreDeref { i }
+                | Instruction::DeleteDeref { i }
+                | Instruction::LoadClosure { i } => (i, derefs),
+                _ => continue,
+            };
+            if index >= count {
+                return Err(format!(
+                    "{} argument {} out of range at offset {}",
+                    instruction.name(),
+                    index,
+                    offset
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The line of the word at an offset, which is the first line for
+    // offsets past the code:
+    pub fn line_number(&self, index: usize) -> usize {
+        match self.linetable.location(index) {
+            Some(location) => location.row,
+            None => self.firstlineno,
+        }
+    }
+
+    pub fn arg_names(&self) -> &[String] {
+        &self.varnames[..self.arg_count]
+    }
+
+    // Get the index of a constant, equal constants are stored once:
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        match self.const_index.position(&self.consts, &constant) {
+            Some(index) => index,
+            None => self.const_index.push(&mut self.consts, constant),
+        }
+    }
+
+    pub fn add_name(&mut self, name: &str) -> usize {
+        match self.name_index.position(&self.names, name) {
+            Some(index) => index,
+            None => self.name_index.push(&mut self.names, String::from(name)),
+        }
+    }
+
+    pub fn add_varname(&mut self, name: &str) -> usize {
+        match self.varname_index.position(&self.varnames, name) {
+            Some(index) => index,
+            None => self
+                .varname_index
+                .push(&mut self.varnames, String::from(name)),
+        }
+    }
+
+    // Cell and free variables are numbered together, the cell variables
+    // first:
+    pub fn deref_index(&self, name: &str) -> Option<usize> {
+        match self.cellvars.iter().position(|n| n == name) {
+            Some(index) => Some(index),
+            None => self
+                .freevars
+                .iter()
+                .position(|n| n == name)
+                .map(|index| self.cellvars.len() + index),
+        }
+    }
+
+    pub fn deref_name(&self, index: usize) -> &str {
+        if index < self.cellvars.len() {
+            &self.cellvars[index]
+        } else {
+            &self.freevars[index - self.cellvars.len()]
+        }
+    }
+}
+
+/*
+ * The first position of each entry of a table. The tables are public, so
+ * the index catches up with entries pushed past it, and a position is only
+ * trusted when the table still holds the entry there.
+ */
+#[derive(Debug)]
+struct TableIndex<T: Hash + Eq> {
+    positions: HashMap<T, usize>,
+    indexed: usize,
+}
+
+impl<T: Hash + Eq + Clone> TableIndex<T> {
+    fn new() -> Self {
+        TableIndex {
+            positions: HashMap::new(),
+            indexed: 0,
+        }
+    }
+
+    fn position<Q>(&mut self, table: &[T], key: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(table);
+        match self.positions.get(key) {
+            Some(&index) if table[index].borrow() == key => Some(index),
+            Some(_) => {
+                // The table was changed in place, start over:
+                self.positions.clear();
+                self.indexed = 0;
+                self.update(table);
+                self.positions
+                    .get(key)
+                    .cloned()
+                    .filter(|&index| table[index].borrow() == key)
+            }
+            None => None,
+        }
+    }
+
+    fn push(&mut self, table: &mut Vec<T>, value: T) -> usize {
+        table.push(value);
+        self.update(table);
+        table.len() - 1
+    }
+
+    fn update(&mut self, table: &[T]) {
+        if self.indexed > table.len() {
+            self.positions.clear();
+            self.indexed = 0;
+        }
+        for (index, value) in table.iter().enumerate().skip(self.indexed) {
+            self.positions.entry(value.clone()).or_insert(index);
+        }
+        self.indexed = table.len();
+    }
+}
+
+/*
+ * The source locations of the instructions of a code object. Runs of
+ * instructions at the same location share an entry, which is the length of
+ * the run followed by the row and the column relative to the previous
+ * entry. The deltas are zigzag encoded variable length integers, so most
+ * entries take three bytes.
+ */
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineTable {
+    data: Vec<u8>,
+    // The offset and location of the last entry, which is extended by
+    // instructions at the same location:
+    last: Option<(usize, Location)>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        Default::default()
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<LineTable, String> {
+        let mut table = LineTable { data, last: None };
+        let entries = table.entries()?;
+        table.last = entries.last().cloned().map(|(offset, _, location)| (offset, location));
+        Ok(table)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn push(&mut self, location: Location) {
+        let previous = match self.last {
+            Some((offset, last)) => {
+                if last == location && self.data[offset] < 255 {
+                    self.data[offset] += 1;
+                    return;
+                }
+                last
+            }
+            None => Location::default(),
+        };
+        let offset = self.data.len();
+        self.data.push(1);
+        write_signed(&mut self.data, location.row as i64 - previous.row as i64);
+        write_signed(&mut self.data, location.column as i64 - previous.column as i64);
+        self.last = Some((offset, location));
+    }
+
+    // Looking up many instructions is better done with a `LineCursor`:
+    pub fn location(&self, index: usize) -> Option<Location> {
+        LineCursor::new().location(self, index)
+    }
+
+    // The location of every instruction:
+    pub fn locations(&self) -> Vec<Location> {
+        let mut locations = vec![];
+        for (_, count, location) in self.entries().unwrap() {
+            for _ in 0..count {
+                locations.push(location);
+            }
+        }
+        locations
+    }
+
+    // Decode the offset, length and location of the entries:
+    fn entries(&self) -> Result<Vec<(usize, usize, Location)>, String> {
+        let mut entries = vec![];
+        let mut location = Location::default();
+        let mut offset = 0;
+        while offset < self.data.len() {
+            let start = offset;
+            let (count, next) = self.read_entry(&mut offset, location)?;
+            location = next;
+            entries.push((start, count, location));
+        }
+        Ok(entries)
+    }
+
+    // Decode the entry at an offset, which follows an entry at `previous`:
+    fn read_entry(
+        &self,
+        offset: &mut usize,
+        previous: Location,
+    ) -> Result<(usize, Location), String> {
+        let count = self.data[*offset] as usize;
+        *offset += 1;
+        let row = previous.row as i64 + read_signed(&self.data, offset)?;
+        let column = previous.column as i64 + read_signed(&self.data, offset)?;
+        if count == 0 || row < 0 || column < 0 {
+            return Err(String::from("bad line table"));
+        }
+        Ok((count, Location::new(row as usize, column as usize)))
+    }
+}
+
+/*
+ * A position in a line table, which looks up the following instructions
+ * without decoding the table again. It starts over for an earlier
+ * instruction, like after a jump back.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct LineCursor {
+    // The offset of the next entry, and the instructions and location of
+    // the current one:
+    offset: usize,
+    start: usize,
+    end: usize,
+    location: Location,
+}
+
+impl LineCursor {
+    pub fn new() -> LineCursor {
+        Default::default()
+    }
+
+    pub fn location(&mut self, table: &LineTable, index: usize) -> Option<Location> {
+        if index < self.start {
+            *self = LineCursor::new();
+        }
+        while index >= self.end {
+            if self.offset >= table.data.len() {
+                return None;
+            }
+            let (count, location) = table.read_entry(&mut self.offset, self.location).unwrap();
+            self.start = self.end;
+            self.end += count;
+            self.location = location;
+        }
+        Some(self.location)
+    }
+}
+
+pub fn write_signed(data: &mut Vec<u8>, value: i64) {
+    write_unsigned(data, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+pub fn write_unsigned(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+pub fn read_signed(data: &[u8], offset: &mut usize) -> Result<i64, String> {
+    let value = read_unsigned(data, offset)?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+pub fn read_unsigned(data: &[u8], offset: &mut usize) -> Result<u64, String> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match data.get(*offset) {
+            Some(byte) => *byte,
+            None => return Err(String::from("truncated integer")),
+        };
+        *offset += 1;
+        if shift > 63 {
+            return Err(String::from("integer too large"));
         }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
     }
 }
 
 pub type Label = usize;
 
-#[derive(Debug)]
+pub const EXTENDED_ARG: u8 = 255;
+
+// Decode the instruction at a word offset, and move the offset past it:
+pub fn decode_instruction(code: &[u8], offset: &mut usize) -> Result<Instruction, String> {
+    let mut argument: usize = 0;
+    loop {
+        let (opcode, byte) = match code.get(2 * *offset..2 * *offset + 2) {
+            Some(word) => (word[0], word[1]),
+            None => return Err(format!("no instruction at offset {}", offset)),
+        };
+        *offset += 1;
+        argument = argument << 8 | byte as usize;
+        if opcode != EXTENDED_ARG {
+            return match Instruction::from_opcode(opcode, argument) {
+                Some(instruction) => Ok(instruction),
+                None => Err(format!("bad instruction {} {}", opcode, argument)),
+            };
+        }
+        if argument > 0xff_ffff {
+            return Err(String::from("too many EXTENDED_ARG prefixes"));
+        }
+    }
+}
+
+/*
+ * Instructions as the compiler emits them, with labels as jump targets.
+ * Assembling them gives the wordcode and the line table of a code object.
+ */
+#[derive(Debug, Default)]
+pub struct InstructionList {
+    pub instructions: Vec<Instruction>,
+    pub locations: Vec<Location>,
+    // The index of the instruction at each label:
+    pub label_map: HashMap<Label, usize>,
+}
+
+impl InstructionList {
+    pub fn new() -> InstructionList {
+        Default::default()
+    }
+
+    pub fn push(&mut self, instruction: Instruction, location: Location) {
+        self.instructions.push(instruction);
+        self.locations.push(location);
+    }
+
+    // Put a label at the next instruction:
+    pub fn set_label(&mut self, label: Label) {
+        let position = self.instructions.len();
+        assert!(!self.label_map.contains_key(&label));
+        self.label_map.insert(label, position);
+    }
+
+    // Encode the instructions into the code object. Prefixes for large
+    // arguments move the instructions after them, which can make the
+    // arguments of jumps larger in turn. So the sizes are worked out until
+    // they do not change; they only grow, a word too many is padded.
+    pub fn assemble(&self, code: &mut CodeObject) -> Result<(), String> {
+        let mut sizes = vec![1; self.instructions.len()];
+        let mut arguments = vec![0; self.instructions.len()];
+        loop {
+            let mut offsets = vec![0];
+            for size in &sizes {
+                let offset = offsets[offsets.len() - 1] + size;
+                offsets.push(offset);
+            }
+            let mut changed = false;
+            for (index, instruction) in self.instructions.iter().enumerate() {
+                let argument = match instruction.jump_target() {
+                    Some(label) => match self.label_map.get(&label) {
+                        Some(position) => offsets[*position],
+                        None => return Err(format!("undefined label {}", label)),
+                    },
+                    None => instruction.argument(),
+                };
+                if argument > u32::MAX as usize {
+                    return Err(format!("argument {} is too large", argument));
+                }
+                let mut size = 1;
+                while argument >> (8 * size) > 0 {
+                    size += 1;
+                }
+                if size > sizes[index] {
+                    sizes[index] = size;
+                    changed = true;
+                }
+                arguments[index] = argument;
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        code.code.clear();
+        code.linetable = LineTable::new();
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            for word in (0..sizes[index]).rev() {
+                let opcode = if word == 0 {
+                    instruction.opcode()
+                } else {
+                    EXTENDED_ARG
+                };
+                code.code.push(opcode);
+                code.code.push((arguments[index] >> (8 * word)) as u8);
+                code.linetable.push(self.locations[index]);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
-    LoadName { name: String },
-    StoreName { name: String },
-    LoadConst { value: i32 },
-    LoadStringConstant { value: String },
+    // Names in module and class bodies, indexing the names:
+    LoadName { namei: usize },
+    StoreName { namei: usize },
+    DeleteName { namei: usize },
+    // Local variables of functions, indexing the varnames:
+    LoadFast { var_num: usize },
+    StoreFast { var_num: usize },
+    DeleteFast { var_num: usize },
+    LoadGlobal { namei: usize },
+    StoreGlobal { namei: usize },
+    DeleteGlobal { namei: usize },
+    // Variables of enclosing functions, or used by nested functions. These
+    // index the cell variables followed by the free variables:
+    LoadDeref { i: usize },
+    StoreDeref { i: usize },
+    DeleteDeref { i: usize },
+    LoadAttr { namei: usize },
+    StoreAttr { namei: usize },
+    DeleteAttr { namei: usize },
+    LoadConst { consti: usize },
+    // Pop the names of a from-import, or None, and the level, which is the
+    // number of leading dots of a relative import. Push the module:
+    ImportName { namei: usize },
+    // Push an attribute of the module on top of the stack:
+    ImportFrom { namei: usize },
+    // Pop a module, and bind all its public names:
+    ImportStar,
+    UnaryOperation { op: UnaryOperator },
     BinaryOperation { op: BinaryOperator },
+    // In-place operations come from augmented assignments, like `a += b`:
+    InplaceOperation { op: BinaryOperator },
+    CompareOperation { op: ComparisonOperator },
+    StoreSubscript,
+    DeleteSubscript,
     Pop,
+    // Print the value of an expression statement typed interactively:
+    PrintExpr,
+    Duplicate,
+    DuplicateTwo,
+    Rotate { amount: usize },
     GetIter,
     Pass,
-    Continue,
+    // Unwind to the loop, and jump to its start:
+    Continue { target: Label },
     Break,
+    Jump { target: Label },
+    JumpIf { target: Label },
+    JumpIfFalse { target: Label },
+    MakeFunction { has_defaults: bool },
+    // Like MakeFunction, with a tuple of cells below the code object:
+    MakeClosure { has_defaults: bool },
+    LoadClosure { i: usize },
+    LoadBuildClass,
+    SetupAnnotations,
     CallFunction { count: usize },
-    ForIter,
+    // The names of the keyword arguments are a tuple on top of the stack:
+    CallFunctionKw { count: usize },
+    ForIter { target: Label },
     ReturnValue,
-    PushBlock { start: Label, end: Label },
+    YieldValue,
+    YieldFrom,
+    // Enter a loop, a break jumps to the end:
+    PushBlock { end: Label },
+    SetupExcept { handler: Label },
+    SetupFinally { handler: Label },
+    // Enter a context manager, leaving its `__exit__` below a finally block:
+    SetupWith { handler: Label },
+    // Call `__exit__` from within the finally handler:
+    WithCleanup,
+    EnterFinally,
+    EndFinally,
     PopBlock,
+    Raise { argc: usize },
     BuildTuple { size: usize },
     BuildList { size: usize },
+    BuildSet { size: usize },
     BuildMap { size: usize },
+    ListAppend { i: usize },
+    SetAdd { i: usize },
+    MapAdd { i: usize },
+    UnpackSequence { size: usize },
 }
 
-#[derive(Debug)]
+// The opcode of each instruction, and the field which is its argument:
+macro_rules! opcodes {
+    ($($opcode:literal => $variant:ident $({ $field:ident })*,)*) => {
+        impl Instruction {
+            pub fn opcode(&self) -> u8 {
+                match *self {
+                    $(Instruction::$variant { .. } => $opcode,)*
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $(Instruction::$variant { .. } => stringify!($variant),)*
+                }
+            }
+
+            pub fn argument(&self) -> usize {
+                match *self {
+                    $(Instruction::$variant { $($field)* } => 0 $(+ $field.to_argument())*,)*
+                }
+            }
+
+            // The name of the field, for instructions which have one:
+            pub fn argument_name(&self) -> Option<&'static str> {
+                match *self {
+                    $(Instruction::$variant { .. } => None $(.or(Some(stringify!($field))))*,)*
+                }
+            }
+
+            pub fn from_opcode(opcode: u8, argument: usize) -> Option<Instruction> {
+                match opcode {
+                    $($opcode => Some(Instruction::$variant {
+                        $($field: Argument::from_argument(argument)?)*
+                    }),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    1 => LoadName { namei },
+    2 => StoreName { namei },
+    3 => DeleteName { namei },
+    4 => LoadFast { var_num },
+    5 => StoreFast { var_num },
+    6 => DeleteFast { var_num },
+    7 => LoadGlobal { namei },
+    8 => StoreGlobal { namei },
+    9 => DeleteGlobal { namei },
+    10 => LoadDeref { i },
+    11 => StoreDeref { i },
+    12 => DeleteDeref { i },
+    13 => LoadAttr { namei },
+    14 => StoreAttr { namei },
+    15 => DeleteAttr { namei },
+    16 => LoadConst { consti },
+    17 => ImportName { namei },
+    18 => ImportFrom { namei },
+    19 => ImportStar,
+    20 => UnaryOperation { op },
+    21 => BinaryOperation { op },
+    22 => InplaceOperation { op },
+    23 => CompareOperation { op },
+    24 => StoreSubscript,
+    25 => DeleteSubscript,
+    26 => Pop,
+    27 => PrintExpr,
+    28 => Duplicate,
+    29 => DuplicateTwo,
+    30 => Rotate { amount },
+    31 => GetIter,
+    32 => Pass,
+    33 => Continue { target },
+    34 => Break,
+    35 => Jump { target },
+    36 => JumpIf { target },
+    37 => JumpIfFalse { target },
+    38 => MakeFunction { has_defaults },
+    39 => MakeClosure { has_defaults },
+    40 => LoadClosure { i },
+    41 => LoadBuildClass,
+    42 => SetupAnnotations,
+    43 => CallFunction { count },
+    44 => CallFunctionKw { count },
+    45 => ForIter { target },
+    46 => ReturnValue,
+    47 => YieldValue,
+    48 => YieldFrom,
+    49 => PushBlock { end },
+    50 => SetupExcept { handler },
+    51 => SetupFinally { handler },
+    52 => SetupWith { handler },
+    53 => WithCleanup,
+    54 => EnterFinally,
+    55 => EndFinally,
+    56 => PopBlock,
+    57 => Raise { argc },
+    58 => BuildTuple { size },
+    59 => BuildList { size },
+    60 => BuildSet { size },
+    61 => BuildMap { size },
+    62 => ListAppend { i },
+    63 => SetAdd { i },
+    64 => MapAdd { i },
+    65 => UnpackSequence { size },
+}
+
+impl Instruction {
+    // The label or offset which an instruction jumps to:
+    pub fn jump_target(&self) -> Option<Label> {
+        match *self {
+            Instruction::Continue { target }
+            | Instruction::Jump { target }
+            | Instruction::JumpIf { target }
+            | Instruction::JumpIfFalse { target }
+            | Instruction::ForIter { target } => Some(target),
+            Instruction::PushBlock { end } => Some(end),
+            Instruction::SetupExcept { handler }
+            | Instruction::SetupFinally { handler }
+            | Instruction::SetupWith { handler } => Some(handler),
+            _ => None,
+        }
+    }
+}
+
+// The types of the fields of instructions, which are encoded as numbers:
+trait Argument: Sized {
+    fn to_argument(self) -> usize;
+    fn from_argument(argument: usize) -> Option<Self>;
+}
+
+impl Argument for usize {
+    fn to_argument(self) -> usize {
+        self
+    }
+
+    fn from_argument(argument: usize) -> Option<usize> {
+        Some(argument)
+    }
+}
+
+impl Argument for bool {
+    fn to_argument(self) -> usize {
+        self as usize
+    }
+
+    fn from_argument(argument: usize) -> Option<bool> {
+        match argument {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+// Operators are numbered in the order of their declaration:
+macro_rules! operator_argument {
+    ($operator:ident, $($variant:ident),*) => {
+        impl Argument for $operator {
+            fn to_argument(self) -> usize {
+                self as usize
+            }
+
+            fn from_argument(argument: usize) -> Option<$operator> {
+                [$($operator::$variant),*].get(argument).cloned()
+            }
+        }
+    };
+}
+
+operator_argument!(
+    ComparisonOperator,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    In,
+    NotIn,
+    Is,
+    IsNot,
+    ExceptionMatch
+);
+operator_argument!(
+    BinaryOperator,
+    Power,
+    Multiply,
+    MatrixMultiply,
+    Divide,
+    FloorDivide,
+    Modulo,
+    Add,
+    Subtract,
+    Subscript,
+    Lshift,
+    Rshift,
+    And,
+    Xor,
+    Or
+);
+operator_argument!(UnaryOperator, Not, Invert, Minus, Plus);
+
+#[derive(Debug, Clone)]
+pub enum Constant {
+    None,
+    Boolean { value: bool },
+    Integer { value: i32 },
+    Float { value: f64 },
+    Complex { real: f64, imag: f64 },
+    String { value: String },
+    Bytes { value: Vec<u8> },
+    Tuple { elements: Vec<Constant> },
+    FrozenSet { elements: Vec<Constant> },
+    Code { code: Rc<CodeObject> },
+    Ellipsis,
+}
+
+// Constants are only equal when they are of the same type. Floats are
+// compared by their bits, so that 0.0 and -0.0 are kept apart, and code
+// objects by identity.
+impl PartialEq for Constant {
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::None, Constant::None) | (Constant::Ellipsis, Constant::Ellipsis) => true,
+            (Constant::Boolean { value: a }, Constant::Boolean { value: b }) => a == b,
+            (Constant::Integer { value: a }, Constant::Integer { value: b }) => a == b,
+            (Constant::Float { value: a }, Constant::Float { value: b }) => {
+                a.to_bits() == b.to_bits()
+            }
+            (
+                Constant::Complex { real: a, imag: b },
+                Constant::Complex { real: c, imag: d },
+            ) => a.to_bits() == c.to_bits() && b.to_bits() == d.to_bits(),
+            (Constant::String { value: a }, Constant::String { value: b }) => a == b,
+            (Constant::Bytes { value: a }, Constant::Bytes { value: b }) => a == b,
+            (Constant::Tuple { elements: a }, Constant::Tuple { elements: b })
+            | (Constant::FrozenSet { elements: a }, Constant::FrozenSet { elements: b }) => a == b,
+            (Constant::Code { code: a }, Constant::Code { code: b }) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+// Hashes agree with the equality above, so floats hash their bits and code
+// objects their address.
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Constant::None | Constant::Ellipsis => {}
+            Constant::Boolean { value } => value.hash(state),
+            Constant::Integer { value } => value.hash(state),
+            Constant::Float { value } => value.to_bits().hash(state),
+            Constant::Complex { real, imag } => {
+                real.to_bits().hash(state);
+                imag.to_bits().hash(state);
+            }
+            Constant::String { value } => value.hash(state),
+            Constant::Bytes { value } => value.hash(state),
+            Constant::Tuple { elements } | Constant::FrozenSet { elements } => elements.hash(state),
+            Constant::Code { code } => Rc::as_ptr(code).hash(state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComparisonOperator {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    In,
+    NotIn,
+    Is,
+    IsNot,
+    ExceptionMatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinaryOperator {
     Power,
     Multiply,
@@ -58,6 +919,7 @@ pub enum BinaryOperator {
     Modulo,
     Add,
     Subtract,
+    Subscript,
     Lshift,
     Rshift,
     And,
@@ -65,10 +927,129 @@ pub enum BinaryOperator {
     Or,
 }
 
-/*
-Maintain a stack of blocks on the VM.
-pub enum BlockType {
-    Loop,
-    Except,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Not,
+    Invert,
+    Minus,
+    Plus,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ast::Location;
+    use super::{
+        CodeObject, Constant, Instruction, InstructionList, LineCursor, LineTable, EXTENDED_ARG,
+    };
+
+    #[test]
+    fn test_constant_table() {
+        let mut code = CodeObject::new(vec![String::from("a")], String::from("f"));
+        let constants = vec![
+            Constant::Integer { value: 1 },
+            Constant::Boolean { value: true },
+            Constant::Float { value: 0.0 },
+            Constant::Float { value: -0.0 },
+            Constant::Tuple {
+                elements: vec![Constant::None, Constant::Ellipsis],
+            },
+            Constant::Integer { value: 1 },
+            Constant::Float { value: 0.0 },
+            Constant::Tuple {
+                elements: vec![Constant::None, Constant::Ellipsis],
+            },
+        ];
+        let indices: Vec<usize> = constants
+            .into_iter()
+            .map(|constant| code.add_constant(constant))
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 0, 2, 4]);
+
+        assert_eq!(code.add_varname("b"), 1);
+        assert_eq!(code.add_varname("a"), 0);
+        assert_eq!(code.arg_names(), &[String::from("a")]);
+
+        // Tables which are changed directly are still found in:
+        code.names = vec![String::from("x"), String::from("y")];
+        assert_eq!(code.add_name("y"), 1);
+        code.names[1] = String::from("z");
+        assert_eq!(code.add_name("y"), 2);
+        code.consts.truncate(1);
+        assert_eq!(code.add_constant(Constant::Float { value: 0.0 }), 1);
+
+        code.cellvars = vec![String::from("c")];
+        code.freevars = vec![String::from("d")];
+        assert_eq!(code.deref_index("d"), Some(1));
+        assert_eq!(code.deref_name(0), "c");
+        assert_eq!(code.deref_index("a"), None);
+    }
+
+    #[test]
+    fn test_line_table() {
+        let mut table = LineTable::new();
+        let mut locations = vec![Location::new(1, 0); 300];
+        locations.push(Location::new(3, 4));
+        locations.push(Location::new(2, 8));
+        locations.push(Location::new(1000, 0));
+        for location in &locations {
+            table.push(*location);
+        }
+        assert_eq!(table.locations(), locations);
+        assert_eq!(table.location(299), Some(Location::new(1, 0)));
+        assert_eq!(table.location(301), Some(Location::new(2, 8)));
+        assert_eq!(table.location(303), None);
+        // The run of 300 takes two entries, and the last row delta two
+        // bytes:
+        assert_eq!(table.as_bytes().len(), 16);
+
+        let mut decoded = LineTable::from_bytes(table.as_bytes().to_vec()).unwrap();
+        assert_eq!(decoded, table);
+        decoded.push(Location::new(1000, 0));
+        assert_eq!(decoded.location(303), Some(Location::new(1000, 0)));
+        assert!(LineTable::from_bytes(vec![1, 0x80]).is_err());
+
+        let mut cursor = LineCursor::new();
+        assert_eq!(cursor.location(&table, 300), Some(Location::new(3, 4)));
+        assert_eq!(cursor.location(&table, 302), Some(Location::new(1000, 0)));
+        assert_eq!(cursor.location(&table, 0), Some(Location::new(1, 0)));
+        assert_eq!(cursor.location(&table, 301), Some(Location::new(2, 8)));
+        assert_eq!(cursor.location(&table, 303), None);
+    }
+
+    #[test]
+    fn test_wordcode() {
+        let mut instructions = InstructionList::new();
+        let location = Location::new(1, 0);
+        instructions.set_label(0);
+        instructions.push(Instruction::JumpIf { target: 1 }, location);
+        // Enough instructions to push the label past the first 256 words,
+        // once the jump to it takes two words and the constant three:
+        for _ in 0..254 {
+            instructions.push(Instruction::Pop, location);
+        }
+        instructions.push(Instruction::LoadConst { consti: 0x1_0203 }, Location::new(2, 0));
+        instructions.set_label(1);
+        instructions.push(Instruction::Jump { target: 0 }, location);
+        instructions.push(Instruction::ReturnValue, location);
+
+        let mut code = CodeObject::new(vec![], String::from("<module>"));
+        instructions.assemble(&mut code).unwrap();
+        assert_eq!(&code.code[..4], &[EXTENDED_ARG, 1, 36, 3]);
+        let decoded = code.instructions().unwrap();
+        assert_eq!(decoded[0], (0, Instruction::JumpIf { target: 259 }));
+        assert_eq!(decoded[255], (256, Instruction::LoadConst { consti: 0x1_0203 }));
+        assert_eq!(decoded[256], (259, Instruction::Jump { target: 0 }));
+        assert_eq!(code.line_number(258), 2);
+        assert_eq!(code.linetable.locations().len(), 261);
+
+        assert!(code.instruction_at(1000).is_err());
+        code.code[0] = 0;
+        assert!(code.instructions().is_err());
+        for opcode in 1..66 {
+            let instruction = Instruction::from_opcode(opcode, 1).unwrap();
+            assert_eq!(instruction.opcode(), opcode);
+        }
+        assert_eq!(Instruction::from_opcode(66, 0), None);
+        assert_eq!(Instruction::from_opcode(38, 2), None);
+    }
 }
-*/
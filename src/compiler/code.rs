@@ -0,0 +1,109 @@
+/*
+ * The attributes of code objects, functions, frames and tracebacks, which
+ * describe the running program to python code.
+ */
+
+use super::bytecode::CodeObject;
+use super::frame::ScopeRef;
+use super::pyobject::{PyObject, PyObjectRef};
+use super::vm::VirtualMachine;
+
+pub fn get_attribute(vm: &mut VirtualMachine, obj: &PyObjectRef, name: &str) -> Option<PyObjectRef> {
+    match *obj.borrow() {
+        PyObject::Code { ref code } => code_attribute(vm, code, name),
+        PyObject::Function { ref code, .. } => match name {
+            "__code__" => Some(PyObject::Code { code: code.clone() }.into_ref()),
+            "__name__" => Some(new_string(&code.name)),
+            "__qualname__" => Some(new_string(&code.qualname)),
+            _ => None,
+        },
+        PyObject::Frame {
+            ref code,
+            ref scope,
+            lineno,
+            ref back,
+        } => match name {
+            "f_code" => Some(PyObject::Code { code: code.clone() }.into_ref()),
+            "f_lineno" => Some(new_int(lineno)),
+            "f_back" => Some(back.clone().unwrap_or_else(|| vm.get_none())),
+            "f_locals" => Some(scope_dict(scope)),
+            "f_globals" => {
+                let mut globals = scope.clone();
+                loop {
+                    let parent = globals.borrow().parent.clone();
+                    match parent {
+                        Some(parent) => globals = parent,
+                        None => break,
+                    }
+                }
+                Some(scope_dict(&globals))
+            }
+            _ => None,
+        },
+        PyObject::Traceback {
+            ref frame,
+            lineno,
+            ref next,
+        } => match name {
+            "tb_frame" => Some(frame.clone()),
+            "tb_lineno" => Some(new_int(lineno)),
+            "tb_next" => Some(next.clone().unwrap_or_else(|| vm.get_none())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn code_attribute(vm: &mut VirtualMachine, code: &CodeObject, name: &str) -> Option<PyObjectRef> {
+    let value = match name {
+        "co_name" => new_string(&code.name),
+        "co_qualname" => new_string(&code.qualname),
+        "co_filename" => new_string(&code.filename),
+        "co_firstlineno" => new_int(code.firstlineno),
+        "co_argcount" => new_int(code.arg_count),
+        "co_consts" => PyObject::Tuple {
+            elements: code.consts.iter().map(|c| vm.unwrap_constant(c)).collect(),
+        }.into_ref(),
+        "co_names" => string_tuple(&code.names),
+        "co_varnames" => string_tuple(&code.varnames),
+        "co_cellvars" => string_tuple(&code.cellvars),
+        "co_freevars" => string_tuple(&code.freevars),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// A snapshot of the names in a scope, sorted by name:
+fn scope_dict(scope: &ScopeRef) -> PyObjectRef {
+    let mut names: Vec<(String, PyObjectRef)> = scope
+        .borrow()
+        .locals
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+    PyObject::Dict {
+        elements: names
+            .into_iter()
+            .map(|(name, value)| (new_string(&name), value))
+            .collect(),
+    }.into_ref()
+}
+
+fn new_string(value: &str) -> PyObjectRef {
+    PyObject::String {
+        value: String::from(value),
+    }.into_ref()
+}
+
+fn new_int(value: usize) -> PyObjectRef {
+    PyObject::Integer {
+        value: value as i32,
+    }.into_ref()
+}
+
+fn string_tuple(names: &[String]) -> PyObjectRef {
+    PyObject::Tuple {
+        elements: names.iter().map(|name| new_string(name)).collect(),
+    }.into_ref()
+}
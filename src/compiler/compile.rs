@@ -3,41 +3,162 @@
  */
 
 use super::ast;
-use std::collections::HashMap;
-use super::bytecode::{self, CodeObject, Instruction};
+use super::bytecode::{self, CodeObject, Instruction, InstructionList};
+use super::symboltable::{make_symbol_table, SymbolScope, SymbolTable, SymbolTableType};
+use std::rc::Rc;
 
 struct Compiler {
-    codeobject: CodeObject,
-    label_map: HashMap<Label, usize>,
+    code_object_stack: Vec<CodeObject>,
+    // The instructions of the code objects, which are assembled when the
+    // code object is done:
+    instruction_stack: Vec<InstructionList>,
     nxt_label: usize,
+    // The start of the innermost loop, which `continue` jumps to:
+    current_loop: Option<Label>,
+    options: CompileOptions,
+    // The symbols of the code blocks being compiled, innermost last:
+    symbol_table_stack: Vec<SymbolTable>,
+    filename: String,
+    // The location of the statement being compiled:
+    current_location: ast::Location,
 }
 
-pub fn compile(p: ast::Program) -> CodeObject {
-    let mut compiler = Compiler::new();
+// How code is compiled:
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompileOptions {
+    // Print the values of expression statements, for the interactive prompt:
+    pub interactive: bool,
+    // Leave out assert statements, like `python -O`:
+    pub optimize: bool,
+}
 
-    compiler.compile_program(p);
-    compiler.codeobject
+pub fn compile(p: ast::Program) -> Result<CodeObject, String> {
+    compile_with_options(p, "<string>", CompileOptions::default())
+}
+
+pub fn compile_with_options(
+    p: ast::Program,
+    filename: &str,
+    options: CompileOptions,
+) -> Result<CodeObject, String> {
+    let mut compiler = Compiler::new();
+    compiler.options = options;
+    compiler.filename = String::from(filename);
+    let symbol_table = make_symbol_table(&p, options.optimize)?;
+    compiler.symbol_table_stack.push(symbol_table);
+    compiler.push_new_code_object(vec![], String::from("<module>"));
+    compiler.compile_program(p)?;
+    compiler.pop_code_object()
 }
 
 type Label = usize;
 
+enum NameUsage {
+    Load,
+    Store,
+    Delete,
+}
+
 impl Compiler {
     fn new() -> Self {
         Compiler {
-            codeobject: CodeObject::new(),
-            label_map: HashMap::new(),
+            code_object_stack: Vec::new(),
+            instruction_stack: Vec::new(),
             nxt_label: 0,
+            current_loop: None,
+            options: CompileOptions::default(),
+            symbol_table_stack: Vec::new(),
+            filename: String::new(),
+            current_location: ast::Location::new(1, 0),
+        }
+    }
+
+    // Start a code object for the block at the current location, which is
+    // nested in the current code block:
+    fn push_new_code_object(&mut self, arg_names: Vec<String>, name: String) {
+        let qualname = match self.code_object_stack.last() {
+            Some(parent) => match self.symbol_table_stack.last().unwrap().typ {
+                SymbolTableType::Function => format!("{}.<locals>.{}", parent.qualname, name),
+                SymbolTableType::Class => format!("{}.{}", parent.qualname, name),
+                SymbolTableType::Module => name.clone(),
+            },
+            None => name.clone(),
+        };
+        let mut code = CodeObject::new(arg_names, name);
+        code.qualname = qualname;
+        code.filename = self.filename.clone();
+        code.firstlineno = self.current_location.row;
+        self.code_object_stack.push(code);
+        self.instruction_stack.push(InstructionList::new());
+    }
+
+    fn pop_code_object(&mut self) -> Result<CodeObject, String> {
+        let mut code = self.code_object_stack.pop().unwrap();
+        self.instruction_stack.pop().unwrap().assemble(&mut code)?;
+        Ok(code)
+    }
+
+    fn current_code_object(&mut self) -> &mut CodeObject {
+        self.code_object_stack.last_mut().unwrap()
+    }
+
+    // Enter the next nested code block, the symbol table pass visited them
+    // in the same order:
+    fn enter_scope(&mut self) {
+        let table = self.symbol_table_stack.last_mut().unwrap().sub_tables.remove(0);
+        let mut cell_vars = vec![];
+        let mut free_vars = vec![];
+        for symbol in table.symbols.values() {
+            match symbol.scope {
+                SymbolScope::Cell => cell_vars.push(symbol.name.clone()),
+                SymbolScope::Free => free_vars.push(symbol.name.clone()),
+                _ if symbol.is_free_class => free_vars.push(symbol.name.clone()),
+                _ => {}
+            }
         }
+        cell_vars.sort();
+        free_vars.sort();
+        let code = self.current_code_object();
+        code.cellvars = cell_vars;
+        code.freevars = free_vars;
+        self.symbol_table_stack.push(table);
+    }
+
+    fn leave_scope(&mut self) {
+        self.symbol_table_stack.pop().unwrap();
+    }
+
+    // Are we compiling the body of a function?
+    fn in_function(&self) -> bool {
+        self.symbol_table_stack.last().unwrap().typ == SymbolTableType::Function
     }
 
-    fn compile_program(&mut self, program: ast::Program) {
-        self.compile_statements(program.statements);
+    fn compile_program(&mut self, program: ast::Program) -> Result<(), String> {
+        if has_annotations(&program.statements) {
+            self.current_location = program.statements[0].location;
+            self.emit(Instruction::SetupAnnotations);
+        }
+        self.compile_statements(program.statements)?;
+        self.emit_constant(bytecode::Constant::None);
+        self.emit(Instruction::ReturnValue);
+        Ok(())
     }
 
-    fn compile_statements(&mut self, statements: Vec<ast::Statement>) {
+    fn compile_statements(&mut self, statements: Vec<ast::LocatedStatement>) -> Result<(), String> {
         for statement in statements {
-            self.compile_statement(statement)
+            self.current_location = statement.location;
+            self.compile_statement(statement.node)?
         }
+        Ok(())
+    }
+
+    // The instructions following a nested suite belong to the statement
+    // containing it again:
+    fn compile_suite(&mut self, statements: Vec<ast::LocatedStatement>) -> Result<(), String> {
+        let location = self.current_location;
+        self.compile_statements(statements)?;
+        self.current_location = location;
+        Ok(())
     }
 
     // Generate a new label
@@ -49,31 +170,116 @@ impl Compiler {
 
     // Assign current position the given label
     fn set_label(&mut self, label: Label) {
-        let position = self.codeobject.instructions.len();
-        // assert!(label not in self.label_map)
-        self.label_map.insert(label, position);
+        self.instruction_stack.last_mut().unwrap().set_label(label);
     }
 
-    fn compile_statement(&mut self, statement: ast::Statement) {
+    fn compile_statement(&mut self, statement: ast::Statement) -> Result<(), String> {
         trace!("Compiling {:?}", statement);
         match statement {
-            ast::Statement::Import { name } => {}
+            ast::Statement::Import { names } => {
+                for name in names {
+                    self.compile_import(&name.symbol, vec![], 0);
+                    match name.alias {
+                        Some(alias) => {
+                            // The module itself is bound, not its package:
+                            for part in name.symbol.split('.').skip(1) {
+                                let namei = self.name_index(part);
+                                self.emit(Instruction::LoadAttr { namei });
+                            }
+                            self.compile_name(alias, NameUsage::Store);
+                        }
+                        None => {
+                            let package = name.symbol.split('.').next().unwrap();
+                            self.compile_name(String::from(package), NameUsage::Store);
+                        }
+                    }
+                }
+            }
+            ast::Statement::ImportFrom {
+                level,
+                module,
+                names,
+            } => {
+                let symbols = names.iter().map(|n| n.symbol.clone()).collect();
+                self.compile_import(&module.unwrap_or_default(), symbols, level);
+                if names.len() == 1 && names[0].symbol == "*" {
+                    self.emit(Instruction::ImportStar);
+                } else {
+                    for name in names {
+                        let namei = self.name_index(&name.symbol);
+                        self.emit(Instruction::ImportFrom { namei });
+                        self.compile_name(name.alias.unwrap_or(name.symbol), NameUsage::Store);
+                    }
+                    // Drop the module:
+                    self.emit(Instruction::Pop);
+                }
+            }
             ast::Statement::Expression { expression } => {
-                self.compile_expression(expression);
+                self.compile_expression(expression)?;
 
-                // Pop result of stack, since we not use it:
-                self.emit(Instruction::Pop);
+                if self.options.interactive && self.code_object_stack.len() == 1 {
+                    self.emit(Instruction::PrintExpr);
+                } else {
+                    // Pop result of stack, since we not use it:
+                    self.emit(Instruction::Pop);
+                }
             }
-            ast::Statement::If { test, body } => {
-                self.compile_expression(test);
-                self.compile_statements(body);
+            ast::Statement::If {
+                test,
+                body,
+                or_else,
+            } => {
+                let end_label = self.new_label();
+                match or_else {
+                    None => {
+                        self.compile_test(test, end_label)?;
+                        self.compile_suite(body)?;
+                    }
+                    Some(statements) => {
+                        let else_label = self.new_label();
+                        self.compile_test(test, else_label)?;
+                        // Jumping over the else branch ends the body:
+                        self.compile_statements(body)?;
+                        self.emit(Instruction::Jump { target: end_label });
+                        self.set_label(else_label);
+                        self.compile_suite(statements)?;
+                    }
+                }
+                self.set_label(end_label);
             }
-            ast::Statement::While { test, body } => {
-                self.compile_expression(test);
-                self.compile_statements(body);
+            ast::Statement::While {
+                test,
+                body,
+                or_else,
+            } => {
+                let location = self.current_location;
+                let start_label = self.new_label();
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+                self.emit(Instruction::PushBlock { end: end_label });
+
+                self.set_label(start_label);
+                self.compile_test(test, else_label)?;
+
+                let outer_loop = self.current_loop;
+                self.current_loop = Some(start_label);
+                self.compile_statements(body)?;
+                self.current_loop = outer_loop;
+                self.emit(Instruction::Jump {
+                    target: start_label,
+                });
+                // Leaving the loop belongs to the while statement:
+                self.current_location = location;
+
+                self.set_label(else_label);
+                self.emit(Instruction::PopBlock);
+                if let Some(statements) = or_else {
+                    self.compile_statements(statements)?;
+                }
+                self.set_label(end_label);
             }
             ast::Statement::With { items, body } => {
-                // TODO
+                self.compile_with_statement(items, body)?;
             }
             ast::Statement::For {
                 target,
@@ -81,182 +287,1149 @@ impl Compiler {
                 body,
                 or_else,
             } => {
+                // Start loop
+                let location = self.current_location;
+                let start_label = self.new_label();
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+                self.emit(Instruction::PushBlock { end: end_label });
+
                 // The thing iterated:
-                for i in iter {
-                    self.compile_expression(i);
-                }
+                self.compile_expression(iter)?;
 
                 // Retrieve iterator
                 self.emit(Instruction::GetIter);
 
-                // Start loop
-                let start_label = self.new_label();
-                let end_label = self.new_label();
-                self.emit(Instruction::PushBlock {
-                    start: start_label,
-                    end: end_label,
-                });
                 self.set_label(start_label);
-                self.emit(Instruction::ForIter);
+                self.emit(Instruction::ForIter { target: else_label });
 
                 // Start of loop iteration, set targets:
-                for t in target {
-                    match t {
-                        ast::Expression::Identifier { name } => {
-                            self.emit(Instruction::StoreName { name: name });
-                        }
-                        _ => panic!("Not impl"),
-                    }
-                }
+                self.compile_store(target)?;
 
                 // Body of loop:
-                self.compile_statements(body);
-                self.set_label(end_label);
+                let outer_loop = self.current_loop;
+                self.current_loop = Some(start_label);
+                self.compile_statements(body)?;
+                self.current_loop = outer_loop;
+                self.emit(Instruction::Jump {
+                    target: start_label,
+                });
+                self.current_location = location;
+
+                self.set_label(else_label);
                 self.emit(Instruction::PopBlock);
+                if let Some(statements) = or_else {
+                    self.compile_statements(statements)?;
+                }
+                self.set_label(end_label);
             }
-            ast::Statement::FunctionDef { name, body } => {
-                self.compile_statements(body);
+            ast::Statement::Raise { exception, cause } => match exception {
+                Some(value) => {
+                    self.compile_expression(value)?;
+                    match cause {
+                        Some(cause) => {
+                            self.compile_expression(cause)?;
+                            self.emit(Instruction::Raise { argc: 2 });
+                        }
+                        None => {
+                            self.emit(Instruction::Raise { argc: 1 });
+                        }
+                    }
+                }
+                None => {
+                    self.emit(Instruction::Raise { argc: 0 });
+                }
+            },
+            ast::Statement::Try {
+                body,
+                handlers,
+                or_else,
+                finalbody,
+            } => {
+                self.compile_try_statement(body, handlers, or_else, finalbody)?;
             }
-            ast::Statement::ClassDef { name } => {
-                // TODO?
+            ast::Statement::FunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+            } => {
+                let count = decorator_list.len();
+                self.compile_decorators(decorator_list)?;
+                self.compile_function(name.clone(), args, body)?;
+                self.apply_decorators(count);
+                self.compile_name(name, NameUsage::Store);
             }
-            ast::Statement::Assert { test, msg } => {
-                // TODO: if some flag, ignore all assert statements!
-
-                self.compile_expression(test);
-
-                // if true, jump over raise:
+            ast::Statement::ClassDef {
+                name,
+                bases,
+                body,
+                decorator_list,
+            } => {
+                let decorator_count = decorator_list.len();
+                self.compile_decorators(decorator_list)?;
 
-                self.emit(Instruction::LoadName {
-                    name: String::from("AssertionError"),
+                // The class body runs as a function, whose names become the
+                // attributes of the class:
+                self.emit(Instruction::LoadBuildClass);
+                let code = self.compile_code_block(vec![], name.clone(), body)?;
+                self.make_function(code, false);
+                self.emit_constant(bytecode::Constant::String {
+                    value: name.clone(),
                 });
+                let count = bases.len() + 2;
+                for base in bases {
+                    self.compile_expression(base)?;
+                }
+                self.emit(Instruction::CallFunction { count });
+                self.apply_decorators(decorator_count);
+                self.compile_name(name, NameUsage::Store);
+            }
+            ast::Statement::Assert { .. } if self.options.optimize => {}
+            ast::Statement::Assert { test, msg } => {
+                // The message is only evaluated when the test fails:
+                let end_label = self.new_label();
+                self.compile_expression(test)?;
+                self.emit(Instruction::JumpIf { target: end_label });
+                self.compile_name(String::from("AssertionError"), NameUsage::Load);
                 match msg {
                     Some(e) => {
-                        self.compile_expression(e);
+                        self.compile_expression(e)?;
                         self.emit(Instruction::CallFunction { count: 1 });
                     }
                     None => {
                         self.emit(Instruction::CallFunction { count: 0 });
                     }
                 }
-                // TODO?
+                self.emit(Instruction::Raise { argc: 1 });
+                self.set_label(end_label);
             }
             ast::Statement::Break => {
+                if self.current_loop.is_none() {
+                    return Err(String::from("'break' outside loop"));
+                }
                 self.emit(Instruction::Break);
             }
-            ast::Statement::Continue => {
-                self.emit(Instruction::Continue);
-            }
+            ast::Statement::Continue => match self.current_loop {
+                Some(target) => self.emit(Instruction::Continue { target }),
+                None => return Err(String::from("'continue' not properly in loop")),
+            },
             ast::Statement::Return { value } => {
+                if !self.in_function() {
+                    return Err(String::from("'return' outside function"));
+                }
                 match value {
                     Some(e) => {
-                        let size = e.len();
-                        for v in e {
-                            self.compile_expression(v);
-                        }
-
-                        // If we have more than 1 return value, make it a tuple:
-                        if size > 1 {
-                            self.emit(Instruction::BuildTuple { size });
-                        }
+                        self.compile_expression(e)?;
                     }
                     None => {
-                        // TODO: Put none on stack
+                        self.emit_constant(bytecode::Constant::None);
                     }
                 }
 
                 self.emit(Instruction::ReturnValue);
             }
             ast::Statement::Assign { targets, value } => {
-                self.compile_expression(value);
+                self.compile_expression(value)?;
 
-                for target in targets {
-                    match target {
-                        ast::Expression::Identifier { name } => {
-                            self.emit(Instruction::StoreName { name: name });
-                        }
-                        _ => {
-                            panic!("WTF");
-                        }
+                let count = targets.len();
+                for (i, target) in targets.into_iter().enumerate() {
+                    // Every target but the last one needs its own copy:
+                    if i + 1 < count {
+                        self.emit(Instruction::Duplicate);
                     }
+                    self.compile_store(target)?;
                 }
             }
+            ast::Statement::AugAssign { target, op, value } => {
+                self.compile_aug_assign(target, op, value)?;
+            }
+            ast::Statement::AnnAssign {
+                target,
+                annotation,
+                value,
+                simple,
+            } => {
+                self.compile_ann_assign(target, annotation, value, simple)?;
+            }
+            ast::Statement::Global { .. } | ast::Statement::Nonlocal { .. } => {
+                // Handled by the symbol table.
+            }
             ast::Statement::Delete { targets } => {
-                // Remove the given names from the scope
-                // self.emit(Instruction::DeleteName);
+                for target in targets {
+                    self.compile_delete(target)?;
+                }
             }
             ast::Statement::Pass => {
                 self.emit(Instruction::Pass);
             }
         }
+        Ok(())
+    }
+
+    // Decorators are evaluated before the function or class is created, and
+    // applied to it from the bottom up:
+    fn compile_decorators(&mut self, decorator_list: Vec<ast::Expression>) -> Result<(), String> {
+        for decorator in decorator_list {
+            self.compile_expression(decorator)?;
+        }
+        Ok(())
+    }
+
+    fn apply_decorators(&mut self, count: usize) {
+        for _ in 0..count {
+            self.emit(Instruction::CallFunction { count: 1 });
+        }
+    }
+
+    // Create a function object from a function definition or lambda, and
+    // leave it on the stack.
+    fn compile_function(
+        &mut self,
+        name: String,
+        args: ast::Parameters,
+        body: Vec<ast::LocatedStatement>,
+    ) -> Result<(), String> {
+        let have_defaults = !args.defaults.is_empty();
+        if have_defaults {
+            let size = args.defaults.len();
+            for default in args.defaults {
+                self.compile_expression(default)?;
+            }
+            self.emit(Instruction::BuildTuple { size });
+        }
+
+        let code = self.compile_code_block(args.args, name, body)?;
+        self.make_function(code, have_defaults);
+        Ok(())
+    }
+
+    // Create a function from a code object, the cells of its free variables
+    // are captured from the current code block.
+    fn make_function(&mut self, code: CodeObject, has_defaults: bool) {
+        let free_vars = code.freevars.clone();
+        let code = bytecode::Constant::Code {
+            code: Rc::new(code),
+        };
+        if free_vars.is_empty() {
+            self.emit_constant(code);
+            self.emit(Instruction::MakeFunction { has_defaults });
+        } else {
+            let size = free_vars.len();
+            for name in free_vars {
+                let i = self.deref_index(&name);
+                self.emit(Instruction::LoadClosure { i });
+            }
+            self.emit(Instruction::BuildTuple { size });
+            self.emit_constant(code);
+            self.emit(Instruction::MakeClosure { has_defaults });
+        }
+    }
+
+    // Compile the body of a function or class into its own code object:
+    fn compile_code_block(
+        &mut self,
+        arg_names: Vec<String>,
+        name: String,
+        body: Vec<ast::LocatedStatement>,
+    ) -> Result<CodeObject, String> {
+        let location = self.current_location;
+        self.push_new_code_object(arg_names, name);
+        self.enter_scope();
+        // Class bodies keep the annotations of their variables:
+        if !self.in_function() && has_annotations(&body) {
+            self.emit(Instruction::SetupAnnotations);
+        }
+        let outer_loop = self.current_loop.take();
+        self.compile_statements(body)?;
+        self.current_loop = outer_loop;
+
+        // Emit None at end:
+        self.emit_constant(bytecode::Constant::None);
+        self.emit(Instruction::ReturnValue);
+        self.leave_scope();
+        self.current_location = location;
+        self.pop_code_object()
+    }
+
+    fn compile_try_statement(
+        &mut self,
+        body: Vec<ast::LocatedStatement>,
+        handlers: Vec<ast::ExceptHandler>,
+        or_else: Option<Vec<ast::LocatedStatement>>,
+        finalbody: Option<Vec<ast::LocatedStatement>>,
+    ) -> Result<(), String> {
+        let location = self.current_location;
+        let finally_label = self.new_label();
+        if finalbody.is_some() {
+            self.emit(Instruction::SetupFinally {
+                handler: finally_label,
+            });
+        }
+
+        if handlers.is_empty() {
+            self.compile_statements(body)?;
+        } else {
+            let handler_label = self.new_label();
+            let else_label = self.new_label();
+            let end_label = self.new_label();
+            self.emit(Instruction::SetupExcept {
+                handler: handler_label,
+            });
+            self.compile_statements(body)?;
+            self.emit(Instruction::PopBlock);
+            self.emit(Instruction::Jump { target: else_label });
+
+            // The exception is on top of the stack here:
+            self.set_label(handler_label);
+            for handler in handlers {
+                // Matching the exception belongs to the except clause:
+                self.current_location = handler.location;
+                let next_handler = self.new_label();
+                if let Some(typ) = handler.typ {
+                    self.emit(Instruction::Duplicate);
+                    self.compile_expression(typ)?;
+                    self.emit(Instruction::CompareOperation {
+                        op: bytecode::ComparisonOperator::ExceptionMatch,
+                    });
+                    self.emit(Instruction::JumpIfFalse {
+                        target: next_handler,
+                    });
+                }
+
+                match handler.name {
+                    Some(name) => {
+                        self.compile_name(name, NameUsage::Store);
+                    }
+                    None => {
+                        self.emit(Instruction::Pop);
+                    }
+                }
+                self.compile_statements(handler.body)?;
+
+                // Leave the exception handler block:
+                self.emit(Instruction::PopBlock);
+                self.emit(Instruction::Jump { target: end_label });
+                self.set_label(next_handler);
+            }
+
+            // None of the handlers matched, so re-raise:
+            self.current_location = location;
+            self.emit(Instruction::Raise { argc: 0 });
+
+            self.set_label(else_label);
+            if let Some(statements) = or_else {
+                self.compile_statements(statements)?;
+            }
+            self.set_label(end_label);
+        }
+
+        if let Some(statements) = finalbody {
+            self.emit(Instruction::PopBlock);
+            self.emit(Instruction::EnterFinally);
+            self.set_label(finally_label);
+            self.compile_statements(statements)?;
+            self.emit(Instruction::EndFinally);
+        }
+        Ok(())
+    }
+
+    // Several items are compiled as nested with statements:
+    fn compile_with_statement(
+        &mut self,
+        items: Vec<ast::WithItem>,
+        body: Vec<ast::LocatedStatement>,
+    ) -> Result<(), String> {
+        let mut items = items.into_iter();
+        let item = items.next().unwrap();
+        let handler_label = self.new_label();
+
+        self.compile_expression(item.context_expr)?;
+        self.emit(Instruction::SetupWith {
+            handler: handler_label,
+        });
+        match item.optional_vars {
+            Some(target) => self.compile_store(target)?,
+            None => self.emit(Instruction::Pop),
+        }
+
+        let rest: Vec<_> = items.collect();
+        if rest.is_empty() {
+            self.compile_suite(body)?;
+        } else {
+            self.compile_with_statement(rest, body)?;
+        }
+
+        self.emit(Instruction::PopBlock);
+        self.emit(Instruction::EnterFinally);
+        self.set_label(handler_label);
+        self.emit(Instruction::WithCleanup);
+        self.emit(Instruction::EndFinally);
+        Ok(())
+    }
+
+    // The target is evaluated only once, and the result of the in-place
+    // operation is stored back into it:
+    fn compile_aug_assign(
+        &mut self,
+        target: ast::Expression,
+        op: ast::Operator,
+        value: ast::Expression,
+    ) -> Result<(), String> {
+        match target {
+            ast::Expression::Identifier { name } => {
+                self.compile_name(name.clone(), NameUsage::Load);
+                self.compile_expression(value)?;
+                self.compile_op(op, true);
+                self.compile_name(name, NameUsage::Store);
+            }
+            ast::Expression::Attribute { value: obj, name } => {
+                self.compile_expression(*obj)?;
+                self.emit(Instruction::Duplicate);
+                let namei = self.name_index(&name);
+                self.emit(Instruction::LoadAttr { namei });
+                self.compile_expression(value)?;
+                self.compile_op(op, true);
+                self.emit(Instruction::Rotate { amount: 2 });
+                self.emit(Instruction::StoreAttr { namei });
+            }
+            ast::Expression::Subscript { a, b } => {
+                self.compile_expression(*a)?;
+                self.compile_expression(*b)?;
+                self.emit(Instruction::DuplicateTwo);
+                self.emit(Instruction::BinaryOperation {
+                    op: bytecode::BinaryOperator::Subscript,
+                });
+                self.compile_expression(value)?;
+                self.compile_op(op, true);
+                self.emit(Instruction::Rotate { amount: 3 });
+                self.emit(Instruction::StoreSubscript);
+            }
+            _ => {
+                return Err(format!(
+                    "illegal expression for augmented assignment: {:?}",
+                    target
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Annotations of simple names are recorded in `__annotations__`, but
+    // only outside of functions, where they are not evaluated at all. A
+    // name in parentheses is not simple.
+    fn compile_ann_assign(
+        &mut self,
+        target: ast::Expression,
+        annotation: ast::Expression,
+        value: Option<ast::Expression>,
+        simple: bool,
+    ) -> Result<(), String> {
+        let simple_name = match target {
+            ast::Expression::Identifier { ref name } if simple => Some(name.clone()),
+            ast::Expression::Identifier { .. } => None,
+            ast::Expression::Attribute { .. } | ast::Expression::Subscript { .. } => None,
+            _ => return Err(format!("illegal target for annotation: {:?}", target)),
+        };
+
+        if let Some(value) = value {
+            self.compile_expression(value)?;
+            self.compile_store(target)?;
+        }
+
+        if !self.in_function() {
+            self.compile_expression(annotation)?;
+            match simple_name {
+                Some(name) => {
+                    self.compile_name(String::from("__annotations__"), NameUsage::Load);
+                    self.emit_constant(bytecode::Constant::String { value: name });
+                    self.emit(Instruction::StoreSubscript);
+                }
+                None => {
+                    self.emit(Instruction::Pop);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_delete(&mut self, target: ast::Expression) -> Result<(), String> {
+        match target {
+            ast::Expression::Identifier { name } => {
+                self.compile_name(name, NameUsage::Delete);
+            }
+            ast::Expression::Subscript { a, b } => {
+                self.compile_expression(*a)?;
+                self.compile_expression(*b)?;
+                self.emit(Instruction::DeleteSubscript);
+            }
+            ast::Expression::Attribute { value, name } => {
+                self.compile_expression(*value)?;
+                let namei = self.name_index(&name);
+                self.emit(Instruction::DeleteAttr { namei });
+            }
+            ast::Expression::Tuple { elements } | ast::Expression::List { elements } => {
+                for element in elements {
+                    self.compile_delete(element)?;
+                }
+            }
+            _ => {
+                return Err(format!("can't delete {:?}", target));
+            }
+        }
+        Ok(())
+    }
+
+    // Emit the instruction which accesses a name, as determined by the
+    // symbol table:
+    fn compile_name(&mut self, name: String, usage: NameUsage) {
+        let (typ, scope) = {
+            let table = self.symbol_table_stack.last().unwrap();
+            let scope = table
+                .lookup(&name)
+                .map_or(SymbolScope::GlobalImplicit, |symbol| symbol.scope);
+            (table.typ, scope)
+        };
+        let instruction = match (typ, scope) {
+            (SymbolTableType::Function, SymbolScope::Local) => {
+                let var_num = self.current_code_object().add_varname(&name);
+                match usage {
+                    NameUsage::Load => Instruction::LoadFast { var_num },
+                    NameUsage::Store => Instruction::StoreFast { var_num },
+                    NameUsage::Delete => Instruction::DeleteFast { var_num },
+                }
+            }
+            (SymbolTableType::Function, SymbolScope::GlobalImplicit)
+            | (_, SymbolScope::GlobalExplicit) => {
+                let namei = self.name_index(&name);
+                match usage {
+                    NameUsage::Load => Instruction::LoadGlobal { namei },
+                    NameUsage::Store => Instruction::StoreGlobal { namei },
+                    NameUsage::Delete => Instruction::DeleteGlobal { namei },
+                }
+            }
+            (_, SymbolScope::Free) | (_, SymbolScope::Cell) => {
+                let i = self.deref_index(&name);
+                match usage {
+                    NameUsage::Load => Instruction::LoadDeref { i },
+                    NameUsage::Store => Instruction::StoreDeref { i },
+                    NameUsage::Delete => Instruction::DeleteDeref { i },
+                }
+            }
+            _ => {
+                let namei = self.name_index(&name);
+                match usage {
+                    NameUsage::Load => Instruction::LoadName { namei },
+                    NameUsage::Store => Instruction::StoreName { namei },
+                    NameUsage::Delete => Instruction::DeleteName { namei },
+                }
+            }
+        };
+        self.emit(instruction);
+    }
+
+    // Store the value on top of the stack into the given target:
+    fn compile_store(&mut self, target: ast::Expression) -> Result<(), String> {
+        match target {
+            ast::Expression::Identifier { name } => {
+                self.compile_name(name, NameUsage::Store);
+            }
+            ast::Expression::Subscript { a, b } => {
+                self.compile_expression(*a)?;
+                self.compile_expression(*b)?;
+                self.emit(Instruction::StoreSubscript);
+            }
+            ast::Expression::Attribute { value, name } => {
+                self.compile_expression(*value)?;
+                let namei = self.name_index(&name);
+                self.emit(Instruction::StoreAttr { namei });
+            }
+            ast::Expression::Tuple { elements } | ast::Expression::List { elements } => {
+                self.emit(Instruction::UnpackSequence {
+                    size: elements.len(),
+                });
+                for element in elements {
+                    self.compile_store(element)?;
+                }
+            }
+            _ => {
+                return Err(format!("can't assign to {:?}", target));
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_op(&mut self, op: ast::Operator, inplace: bool) {
+        let i = match op {
+            ast::Operator::Add => bytecode::BinaryOperator::Add,
+            ast::Operator::Sub => bytecode::BinaryOperator::Subtract,
+            ast::Operator::Mult => bytecode::BinaryOperator::Multiply,
+            ast::Operator::MatMult => bytecode::BinaryOperator::MatrixMultiply,
+            ast::Operator::Div => bytecode::BinaryOperator::Divide,
+            ast::Operator::FloorDiv => bytecode::BinaryOperator::FloorDivide,
+            ast::Operator::Mod => bytecode::BinaryOperator::Modulo,
+            ast::Operator::Pow => bytecode::BinaryOperator::Power,
+            ast::Operator::LShift => bytecode::BinaryOperator::Lshift,
+            ast::Operator::RShift => bytecode::BinaryOperator::Rshift,
+            ast::Operator::BitOr => bytecode::BinaryOperator::Or,
+            ast::Operator::BitXor => bytecode::BinaryOperator::Xor,
+            ast::Operator::BitAnd => bytecode::BinaryOperator::And,
+        };
+        if inplace {
+            self.emit(Instruction::InplaceOperation { op: i });
+        } else {
+            self.emit(Instruction::BinaryOperation { op: i });
+        }
+    }
+
+    // Evaluate a condition, and jump to the given label when it is false:
+    fn compile_test(&mut self, test: ast::Expression, false_label: Label) -> Result<(), String> {
+        self.compile_expression(test)?;
+        self.emit(Instruction::JumpIfFalse {
+            target: false_label,
+        });
+        Ok(())
     }
 
-    fn compile_expression(&mut self, expression: ast::Expression) {
+    fn compile_expression(&mut self, expression: ast::Expression) -> Result<(), String> {
         trace!("Compiling {:?}", expression);
         match expression {
-            ast::Expression::Call { function, args } => {
-                self.compile_expression(*function);
-                let count = args.len();
+            ast::Expression::Call {
+                function,
+                args,
+                keywords,
+            } => {
+                self.compile_expression(*function)?;
+                let count = args.len() + keywords.len();
                 for arg in args {
-                    self.compile_expression(arg)
+                    self.compile_expression(arg)?
+                }
+                if keywords.is_empty() {
+                    self.emit(Instruction::CallFunction { count });
+                } else {
+                    // The keyword arguments follow the positional ones:
+                    let mut names = vec![];
+                    for keyword in keywords {
+                        if names.contains(&keyword.name) {
+                            return Err(format!("keyword argument repeated: {}", keyword.name));
+                        }
+                        names.push(keyword.name);
+                        self.compile_expression(keyword.value)?;
+                    }
+                    self.emit_constant(string_tuple(names));
+                    self.emit(Instruction::CallFunctionKw { count });
                 }
-                self.emit(Instruction::CallFunction { count: count });
             }
             ast::Expression::Binop { a, op, b } => {
-                self.compile_expression(*a);
-                self.compile_expression(*b);
+                self.compile_expression(*a)?;
+                self.compile_expression(*b)?;
 
-                // Perform operation:
+                self.compile_op(op, false);
+            }
+            ast::Expression::BoolOp { a, op, b } => {
+                // Short circuit evaluation, leave the deciding value on the stack:
+                let end_label = self.new_label();
+                self.compile_expression(*a)?;
+                self.emit(Instruction::Duplicate);
+                match op {
+                    ast::BooleanOperator::And => {
+                        self.emit(Instruction::JumpIfFalse { target: end_label });
+                    }
+                    ast::BooleanOperator::Or => {
+                        self.emit(Instruction::JumpIf { target: end_label });
+                    }
+                }
+                self.emit(Instruction::Pop);
+                self.compile_expression(*b)?;
+                self.set_label(end_label);
+            }
+            ast::Expression::Unop { op, a } => {
+                self.compile_expression(*a)?;
                 let i = match op {
-                    ast::Operator::Add => bytecode::BinaryOperator::Add,
-                    ast::Operator::Sub => bytecode::BinaryOperator::Subtract,
-                    ast::Operator::Mult => bytecode::BinaryOperator::Multiply,
-                    ast::Operator::MatMult => bytecode::BinaryOperator::MatrixMultiply,
-                    ast::Operator::Div => bytecode::BinaryOperator::Divide,
-                    ast::Operator::FloorDiv => bytecode::BinaryOperator::FloorDivide,
-                    ast::Operator::Mod => bytecode::BinaryOperator::Modulo,
-                    ast::Operator::Pow => bytecode::BinaryOperator::Power,
-                    ast::Operator::LShift => bytecode::BinaryOperator::Lshift,
-                    ast::Operator::RShift => bytecode::BinaryOperator::Rshift,
-                    ast::Operator::BitOr => bytecode::BinaryOperator::Or,
-                    ast::Operator::BitXor => bytecode::BinaryOperator::Xor,
-                    ast::Operator::BitAnd => bytecode::BinaryOperator::And,
+                    ast::UnaryOperator::Pos => bytecode::UnaryOperator::Plus,
+                    ast::UnaryOperator::Neg => bytecode::UnaryOperator::Minus,
+                    ast::UnaryOperator::Not => bytecode::UnaryOperator::Not,
+                    ast::UnaryOperator::Inv => bytecode::UnaryOperator::Invert,
                 };
-                let i = Instruction::BinaryOperation { op: i };
-                self.emit(i);
+                self.emit(Instruction::UnaryOperation { op: i });
+            }
+            ast::Expression::Compare { vals, ops } => {
+                self.compile_compare(vals, ops)?;
+            }
+            ast::Expression::Subscript { a, b } => {
+                self.compile_expression(*a)?;
+                self.compile_expression(*b)?;
+                self.emit(Instruction::BinaryOperation {
+                    op: bytecode::BinaryOperator::Subscript,
+                });
+            }
+            ast::Expression::Attribute { value, name } => {
+                self.compile_expression(*value)?;
+                let namei = self.name_index(&name);
+                self.emit(Instruction::LoadAttr { namei });
+            }
+            ast::Expression::Yield { value } => {
+                self.mark_generator()?;
+                match value {
+                    Some(e) => self.compile_expression(*e)?,
+                    None => self.emit_constant(bytecode::Constant::None),
+                }
+                self.emit(Instruction::YieldValue);
+            }
+            ast::Expression::YieldFrom { value } => {
+                self.mark_generator()?;
+                self.compile_expression(*value)?;
+                self.emit(Instruction::GetIter);
+                self.emit_constant(bytecode::Constant::None);
+                self.emit(Instruction::YieldFrom);
+            }
+            ast::Expression::Comprehension { kind, generators } => {
+                self.compile_comprehension(*kind, generators)?;
+            }
+            ast::Expression::Lambda { args, body } => {
+                let body = vec![ast::LocatedStatement {
+                    location: self.current_location,
+                    node: ast::Statement::Return { value: Some(*body) },
+                }];
+                self.compile_function(String::from("<lambda>"), args, body)?;
+            }
+            ast::Expression::IfExp { test, body, orelse } => {
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+                self.compile_test(*test, else_label)?;
+                self.compile_expression(*body)?;
+                self.emit(Instruction::Jump { target: end_label });
+                self.set_label(else_label);
+                self.compile_expression(*orelse)?;
+                self.set_label(end_label);
             }
             ast::Expression::Number { value } => {
-                self.emit(Instruction::LoadConst { value });
+                self.emit_constant(bytecode::Constant::Integer { value });
             }
             ast::Expression::List { elements } => {
                 let size = elements.len();
                 for element in elements {
-                    self.compile_expression(element);
+                    self.compile_expression(element)?;
                 }
-                self.emit(Instruction::BuildList { size: size });
+                self.emit(Instruction::BuildList { size });
             }
             ast::Expression::Tuple { elements } => {
-                // Load const?
+                // A tuple of literals is a single constant:
+                let constants: Option<Vec<_>> = elements.iter().map(constant_of).collect();
+                if let Some(elements) = constants {
+                    self.emit_constant(bytecode::Constant::Tuple { elements });
+                    return Ok(());
+                }
+                let size = elements.len();
+                for element in elements {
+                    self.compile_expression(element)?;
+                }
+                self.emit(Instruction::BuildTuple { size });
+            }
+            ast::Expression::Dict { elements } => {
+                let size = elements.len();
+                for (key, value) in elements {
+                    self.compile_expression(key)?;
+                    self.compile_expression(value)?;
+                }
+                self.emit(Instruction::BuildMap { size });
+            }
+            ast::Expression::Set { elements } => {
+                let size = elements.len();
+                for element in elements {
+                    self.compile_expression(element)?;
+                }
+                self.emit(Instruction::BuildSet { size });
             }
             ast::Expression::True => {
-                self.emit(Instruction::LoadConst { value: 1 });
+                self.emit_constant(bytecode::Constant::Boolean { value: true });
             }
             ast::Expression::False => {
-                self.emit(Instruction::LoadConst { value: 0 });
+                self.emit_constant(bytecode::Constant::Boolean { value: false });
             }
             ast::Expression::None => {
-                self.emit(Instruction::LoadConst { value: 0 });
+                self.emit_constant(bytecode::Constant::None);
             }
             ast::Expression::String { value } => {
-                self.emit(Instruction::LoadStringConstant { value });
+                self.emit_constant(bytecode::Constant::String { value });
             }
             ast::Expression::Identifier { name } => {
-                self.emit(Instruction::LoadName { name });
+                self.compile_name(name, NameUsage::Load);
             }
         }
+        Ok(())
+    }
+
+    fn compile_compare(
+        &mut self,
+        vals: Vec<ast::Expression>,
+        ops: Vec<ast::Comparison>,
+    ) -> Result<(), String> {
+        // Chained comparisons like `a < b < c` evaluate every operand once,
+        // and stop at the first comparison which is false.
+        let cleanup_label = self.new_label();
+        let end_label = self.new_label();
+        let count = ops.len();
+        let mut vals = vals.into_iter();
+        self.compile_expression(vals.next().unwrap())?;
+        for (i, (op, val)) in ops.into_iter().zip(vals).enumerate() {
+            self.compile_expression(val)?;
+            let last = i + 1 == count;
+            if !last {
+                self.emit(Instruction::Duplicate);
+                self.emit(Instruction::Rotate { amount: 3 });
+            }
+            let op = match op {
+                ast::Comparison::Equal => bytecode::ComparisonOperator::Equal,
+                ast::Comparison::NotEqual => bytecode::ComparisonOperator::NotEqual,
+                ast::Comparison::Less => bytecode::ComparisonOperator::Less,
+                ast::Comparison::LessOrEqual => bytecode::ComparisonOperator::LessOrEqual,
+                ast::Comparison::Greater => bytecode::ComparisonOperator::Greater,
+                ast::Comparison::GreaterOrEqual => bytecode::ComparisonOperator::GreaterOrEqual,
+                ast::Comparison::In => bytecode::ComparisonOperator::In,
+                ast::Comparison::NotIn => bytecode::ComparisonOperator::NotIn,
+                ast::Comparison::Is => bytecode::ComparisonOperator::Is,
+                ast::Comparison::IsNot => bytecode::ComparisonOperator::IsNot,
+            };
+            self.emit(Instruction::CompareOperation { op });
+            if !last {
+                self.emit(Instruction::Duplicate);
+                self.emit(Instruction::JumpIfFalse {
+                    target: cleanup_label,
+                });
+                self.emit(Instruction::Pop);
+            }
+        }
+
+        if count > 1 {
+            self.emit(Instruction::Jump { target: end_label });
+
+            // A comparison failed, remove the left over operand:
+            self.set_label(cleanup_label);
+            self.emit(Instruction::Rotate { amount: 2 });
+            self.emit(Instruction::Pop);
+        }
+        self.set_label(end_label);
+        Ok(())
+    }
+
+    // Comprehensions are compiled into an implicit function, which gets the
+    // iterator of the outermost loop as its single argument. The loop
+    // variables are local to this function, so they do not leak out.
+    fn compile_comprehension(
+        &mut self,
+        kind: ast::ComprehensionKind,
+        generators: Vec<ast::Comprehension>,
+    ) -> Result<(), String> {
+        let name = match kind {
+            ast::ComprehensionKind::GeneratorExpression { .. } => "<genexpr>",
+            ast::ComprehensionKind::List { .. } => "<listcomp>",
+            ast::ComprehensionKind::Set { .. } => "<setcomp>",
+            ast::ComprehensionKind::Dict { .. } => "<dictcomp>",
+        };
+        let is_generator = matches!(kind, ast::ComprehensionKind::GeneratorExpression { .. });
+        let mut generators = generators.into_iter();
+        let first = generators.next().unwrap();
+
+        self.push_new_code_object(vec![String::from(".0")], String::from(name));
+        let outer_loop = self.current_loop.take();
+        self.enter_scope();
+
+        // Create the collection which is filled by the loops:
+        match kind {
+            ast::ComprehensionKind::GeneratorExpression { .. } => {}
+            ast::ComprehensionKind::List { .. } => {
+                self.emit(Instruction::BuildList { size: 0 });
+            }
+            ast::ComprehensionKind::Set { .. } => {
+                self.emit(Instruction::BuildSet { size: 0 });
+            }
+            ast::ComprehensionKind::Dict { .. } => {
+                self.emit(Instruction::BuildMap { size: 0 });
+            }
+        }
+
+        let mut loop_labels = vec![];
+        let first_loop = ast::Comprehension {
+            iter: ast::Expression::Identifier {
+                name: String::from(".0"),
+            },
+            ..first
+        };
+        for (i, generator) in vec![first_loop]
+            .into_iter()
+            .chain(generators)
+            .enumerate()
+        {
+            self.compile_expression(generator.iter)?;
+            if i > 0 {
+                self.emit(Instruction::GetIter);
+            }
+
+            let start_label = self.new_label();
+            let end_label = self.new_label();
+            self.set_label(start_label);
+            self.emit(Instruction::ForIter { target: end_label });
+            self.compile_store(generator.target)?;
+            for condition in generator.ifs {
+                self.compile_test(condition, start_label)?;
+            }
+            loop_labels.push((start_label, end_label));
+        }
+
+        // The collection is below the iterators of all loops:
+        let depth = loop_labels.len();
+        match kind {
+            ast::ComprehensionKind::GeneratorExpression { element } => {
+                self.mark_generator()?;
+                self.compile_expression(element)?;
+                self.emit(Instruction::YieldValue);
+                self.emit(Instruction::Pop);
+            }
+            ast::ComprehensionKind::List { element } => {
+                self.compile_expression(element)?;
+                self.emit(Instruction::ListAppend { i: depth });
+            }
+            ast::ComprehensionKind::Set { element } => {
+                self.compile_expression(element)?;
+                self.emit(Instruction::SetAdd { i: depth });
+            }
+            ast::ComprehensionKind::Dict { key, value } => {
+                self.compile_expression(key)?;
+                self.compile_expression(value)?;
+                self.emit(Instruction::MapAdd { i: depth });
+            }
+        }
+
+        for (start_label, end_label) in loop_labels.into_iter().rev() {
+            self.emit(Instruction::Jump {
+                target: start_label,
+            });
+            self.set_label(end_label);
+        }
+
+        // Return the collection, or None from a generator:
+        if is_generator {
+            self.emit_constant(bytecode::Constant::None);
+        }
+        self.emit(Instruction::ReturnValue);
+        self.current_loop = outer_loop;
+        self.leave_scope();
+        let code = self.pop_code_object()?;
+        self.make_function(code, false);
+
+        // The outermost iterator is evaluated in the enclosing scope:
+        self.compile_expression(first.iter)?;
+        self.emit(Instruction::GetIter);
+        self.emit(Instruction::CallFunction { count: 1 });
+        Ok(())
+    }
+
+    // A function containing a yield expression is a generator function:
+    fn mark_generator(&mut self) -> Result<(), String> {
+        if !self.in_function() {
+            return Err(String::from("'yield' outside function"));
+        }
+        self.current_code_object().is_generator = true;
+        Ok(())
     }
 
     fn emit(&mut self, instruction: Instruction) {
-        self.codeobject.instructions.push(instruction);
+        let location = self.current_location;
+        self.instruction_stack
+            .last_mut()
+            .unwrap()
+            .push(instruction, location);
+    }
+
+    fn emit_constant(&mut self, constant: bytecode::Constant) {
+        let consti = self.current_code_object().add_constant(constant);
+        self.emit(Instruction::LoadConst { consti });
+    }
+
+    fn name_index(&mut self, name: &str) -> usize {
+        self.current_code_object().add_name(name)
+    }
+
+    fn deref_index(&mut self, name: &str) -> usize {
+        match self.current_code_object().deref_index(name) {
+            Some(index) => index,
+            None => panic!("'{}' is not a cell or free variable", name),
+        }
+    }
+
+    // Like in CPython, the level and the imported names are constants:
+    fn compile_import(&mut self, name: &str, symbols: Vec<String>, level: usize) {
+        self.emit_constant(bytecode::Constant::Integer {
+            value: level as i32,
+        });
+        if symbols.is_empty() {
+            self.emit_constant(bytecode::Constant::None);
+        } else {
+            self.emit_constant(string_tuple(symbols));
+        }
+        let namei = self.name_index(name);
+        self.emit(Instruction::ImportName { namei });
+    }
+}
+
+fn string_tuple(values: Vec<String>) -> bytecode::Constant {
+    bytecode::Constant::Tuple {
+        elements: values
+            .into_iter()
+            .map(|value| bytecode::Constant::String { value })
+            .collect(),
+    }
+}
+
+// Does a module or class body have annotated assignments, which need the
+// `__annotations__` of the body? Nested functions and classes have their own.
+fn has_annotations(statements: &[ast::LocatedStatement]) -> bool {
+    statements.iter().any(|statement| match statement.node {
+        ast::Statement::AnnAssign { .. } => true,
+        ast::Statement::If {
+            ref body,
+            ref or_else,
+            ..
+        }
+        | ast::Statement::While {
+            ref body,
+            ref or_else,
+            ..
+        }
+        | ast::Statement::For {
+            ref body,
+            ref or_else,
+            ..
+        } => has_annotations(body) || or_else.as_ref().is_some_and(|s| has_annotations(s)),
+        ast::Statement::With { ref body, .. } => has_annotations(body),
+        ast::Statement::Try {
+            ref body,
+            ref handlers,
+            ref or_else,
+            ref finalbody,
+        } => {
+            has_annotations(body)
+                || handlers.iter().any(|handler| has_annotations(&handler.body))
+                || or_else.as_ref().is_some_and(|s| has_annotations(s))
+                || finalbody.as_ref().is_some_and(|s| has_annotations(s))
+        }
+        _ => false,
+    })
+}
+
+// The value of a literal, or of a tuple of literals:
+fn constant_of(expression: &ast::Expression) -> Option<bytecode::Constant> {
+    let constant = match *expression {
+        ast::Expression::Number { value } => bytecode::Constant::Integer { value },
+        ast::Expression::String { ref value } => bytecode::Constant::String {
+            value: value.clone(),
+        },
+        ast::Expression::True => bytecode::Constant::Boolean { value: true },
+        ast::Expression::False => bytecode::Constant::Boolean { value: false },
+        ast::Expression::None => bytecode::Constant::None,
+        ast::Expression::Tuple { ref elements } => {
+            let elements: Option<Vec<_>> = elements.iter().map(constant_of).collect();
+            bytecode::Constant::Tuple {
+                elements: elements?,
+            }
+        }
+        _ => return None,
+    };
+    Some(constant)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::bytecode::{Constant, Instruction};
+    use super::super::parser::parse_source;
+    use super::{compile, compile_with_options, CompileOptions};
+
+    #[test]
+    fn test_code_tables() {
+        let source = "
+import os.path
+x = (1, 'a', (None, True))
+def f(a, b=1):
+    c = a
+    def g():
+        return c + y
+    return g
+";
+        let code = compile(parse_source(source).unwrap()).unwrap();
+        assert_eq!(code.names, vec!["os.path", "os", "x", "f"]);
+        let tuple = Constant::Tuple {
+            elements: vec![
+                Constant::Integer { value: 1 },
+                Constant::String {
+                    value: String::from("a"),
+                },
+                Constant::Tuple {
+                    elements: vec![Constant::None, Constant::Boolean { value: true }],
+                },
+            ],
+        };
+        // The import level and the default of b are the same constant:
+        assert_eq!(code.consts[0], Constant::Integer { value: 0 });
+        assert_eq!(code.consts[1], Constant::None);
+        assert_eq!(code.consts[2], tuple);
+        assert_eq!(code.consts[3], Constant::Integer { value: 1 });
+
+        let f = match code.consts[4] {
+            Constant::Code { ref code } => code.clone(),
+            _ => panic!("Expected the code of f"),
+        };
+        assert_eq!(f.arg_names(), &[String::from("a"), String::from("b")]);
+        assert_eq!(f.varnames, vec!["a", "b", "g"]);
+        assert_eq!(f.cellvars, vec!["c"]);
+        let g = match f.consts[0] {
+            Constant::Code { ref code } => code.clone(),
+            _ => panic!("Expected the code of g"),
+        };
+        assert_eq!(g.freevars, vec!["c"]);
+        assert_eq!(g.names, vec!["y"]);
+        assert_eq!(
+            g.instructions().unwrap()[0],
+            (0, Instruction::LoadDeref { i: 0 })
+        );
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let source = "
+class C:
+    @staticmethod
+    def f(a):
+        return lambda: (a,
+            a)
+";
+        let program = parse_source(source).unwrap();
+        let code = compile_with_options(program, "c.py", CompileOptions::default()).unwrap();
+        let class = match code.consts[0] {
+            Constant::Code { ref code } => code.clone(),
+            _ => panic!("Expected the code of C"),
+        };
+        let f = class
+            .consts
+            .iter()
+            .filter_map(|constant| match *constant {
+                Constant::Code { ref code } => Some(code.clone()),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        let lambda = match f.consts[0] {
+            Constant::Code { ref code } => code.clone(),
+            _ => panic!("Expected the code of the lambda"),
+        };
+        assert_eq!((class.qualname.as_str(), class.firstlineno), ("C", 2));
+        // A decorated function starts at its first decorator:
+        assert_eq!((f.qualname.as_str(), f.firstlineno), ("C.f", 3));
+        assert_eq!(lambda.qualname, "C.f.<locals>.<lambda>");
+        assert_eq!(lambda.filename, "c.py");
+        assert_eq!(code.line_number(0), 2);
+        assert_eq!(f.line_number(0), 5);
+        assert_eq!(lambda.linetable.locations().len(), lambda.code.len() / 2);
     }
 }
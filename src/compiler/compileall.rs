@@ -0,0 +1,154 @@
+/*
+ * The compileall module, which compiles the python files of directory trees
+ * into their caches ahead of time. Run as `rspython -m compileall [-f] [-q]
+ * [--invalidation-mode mode] [directory|file ...]`.
+ */
+
+use std::path::Path;
+
+use super::convert::{bind_args, FromPyObject};
+use super::native::NativeModule;
+use super::pycache::{self, Invalidation};
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult};
+use super::vm::VirtualMachine;
+
+pub fn make_module() -> NativeModule {
+    NativeModule::new("compileall")
+        .doc("Compile the python files of directory trees ahead of time.")
+        .function("compile_dir", compileall_compile_dir)
+        .function("compile_file", compileall_compile_file)
+        .function("main", compileall_main)
+}
+
+// The path and the optional force and quiet arguments of the functions:
+fn compile_args(
+    vm: &mut VirtualMachine,
+    function: &str,
+    path_name: &str,
+    args: PyFuncArgs,
+) -> PyResult<(String, bool, u32)> {
+    let values = bind_args(vm, function, &[path_name, "force", "quiet"], args)?;
+    let path = match values[0] {
+        Some(ref path) => String::from_py_object(vm, path)?,
+        None => {
+            let msg = format!("{}() missing required argument: '{}'", function, path_name);
+            return Err(vm.new_exception("TypeError", msg));
+        }
+    };
+    let force = match values[1] {
+        Some(ref force) => vm.is_true(force.clone())?,
+        None => false,
+    };
+    let quiet = match values[2] {
+        Some(ref quiet) => i32::from_py_object(vm, quiet)?.max(0) as u32,
+        None => 0,
+    };
+    Ok((path, force, quiet))
+}
+
+// compile_dir(dir, force=False, quiet=0)
+fn compileall_compile_dir(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let (directory, force, quiet) = compile_args(vm, "compile_dir", "dir", args)?;
+    let options = vm.compile_options();
+    let success = pycache::compile_dir(
+        Path::new(&directory),
+        options,
+        Invalidation::Timestamp,
+        force,
+        quiet,
+    );
+    Ok(vm.new_bool(success))
+}
+
+// compile_file(fullname, force=False, quiet=0)
+fn compileall_compile_file(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let (file, force, quiet) = compile_args(vm, "compile_file", "fullname", args)?;
+    let options = vm.compile_options();
+    let success = pycache::compile_file(
+        Path::new(&file),
+        options,
+        Invalidation::Timestamp,
+        force,
+        quiet,
+    );
+    Ok(vm.new_bool(success))
+}
+
+fn system_exit(vm: &mut VirtualMachine, status: i32) -> PyObjectRef {
+    let system_exit = vm.get_builtin("SystemExit");
+    let status = PyObject::Integer { value: status }.into_ref();
+    match vm.invoke(system_exit, vec![status]) {
+        Ok(exception) => exception,
+        Err(exception) => exception,
+    }
+}
+
+// The command line, from sys.argv. Without directories, those of sys.path
+// are compiled. A failure exits with status 1, bad arguments with 2.
+fn compileall_main(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    if !args.args.is_empty() || !args.kwargs.is_empty() {
+        let msg = String::from("main() takes no arguments");
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    let sys = vm.sys_module.clone();
+    let argv = vm.get_attribute(sys.clone(), "argv")?;
+    let mut arguments = vec![];
+    for argument in vm.extract_elements(&argv)?.iter().skip(1) {
+        arguments.push(String::from_py_object(vm, argument)?);
+    }
+
+    let mut force = false;
+    let mut quiet = 0;
+    let mut invalidation = Invalidation::Timestamp;
+    let mut targets = vec![];
+    let mut arguments = arguments.into_iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "-f" => force = true,
+            "-q" => quiet += 1,
+            "--invalidation-mode" => {
+                match arguments.next().and_then(|mode| Invalidation::from_name(&mode)) {
+                    Some(mode) => invalidation = mode,
+                    None => {
+                        eprintln!("compileall: --invalidation-mode must be one of timestamp, checked-hash or unchecked-hash");
+                        return Err(system_exit(vm, 2));
+                    }
+                }
+            }
+            _ if argument.starts_with('-') => {
+                eprintln!("usage: compileall [-f] [-q] [--invalidation-mode mode] [directory|file ...]");
+                return Err(system_exit(vm, 2));
+            }
+            _ => targets.push(argument),
+        }
+    }
+    if targets.is_empty() {
+        let path = vm.get_attribute(sys, "path")?;
+        for directory in vm.extract_elements(&path)? {
+            if let PyObject::String { ref value } = *directory.borrow() {
+                if !value.is_empty() && Path::new(value).is_dir() {
+                    targets.push(value.clone());
+                }
+            }
+        }
+        if targets.is_empty() {
+            targets.push(String::from("."));
+        }
+    }
+
+    let options = vm.compile_options();
+    let mut success = true;
+    for target in targets {
+        let path = Path::new(&target);
+        success &= if path.is_dir() {
+            pycache::compile_dir(path, options, invalidation, force, quiet)
+        } else {
+            pycache::compile_file(path, options, invalidation, force, quiet)
+        };
+    }
+    if success {
+        Ok(vm.get_none())
+    } else {
+        Err(system_exit(vm, 1))
+    }
+}
@@ -0,0 +1,505 @@
+/*
+ * Conversions between rust values and python objects. A value which does
+ * not have the expected type gives a TypeError.
+ *
+ * Rust functions with converted arguments and results can be wrapped into
+ * native functions, which are callable from python. Their arguments are
+ * owned values, such as a String; the functions declared with #[pyfunction]
+ * may also take a `&str`.
+ */
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use super::dict;
+use super::pyobject::{NativeFunc, PyFuncArgs, PyObject, PyObjectRef, PyResult};
+use super::vm::VirtualMachine;
+
+pub trait FromPyObject: Sized {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self>;
+
+    // The value of an argument which was not passed:
+    fn from_missing(vm: &mut VirtualMachine) -> PyResult<Self> {
+        Err(vm.new_exception(
+            "TypeError",
+            String::from("function missing required argument"),
+        ))
+    }
+}
+
+pub trait IntoPyObject {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult;
+}
+
+fn type_error<T>(vm: &mut VirtualMachine, expected: &str, obj: &PyObjectRef) -> PyResult<T> {
+    let msg = format!("expected {}, got {}", expected, obj.borrow().type_name());
+    Err(vm.new_exception("TypeError", msg))
+}
+
+impl FromPyObject for PyObjectRef {
+    fn from_py_object(_vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        Ok(obj.clone())
+    }
+}
+
+impl IntoPyObject for PyObjectRef {
+    fn into_py_object(self, _vm: &mut VirtualMachine) -> PyResult {
+        Ok(self)
+    }
+}
+
+impl IntoPyObject for () {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+        Ok(vm.get_none())
+    }
+}
+
+impl FromPyObject for i32 {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        match *obj.borrow() {
+            PyObject::Integer { value } => return Ok(value),
+            PyObject::Boolean { value } => return Ok(if value { 1 } else { 0 }),
+            _ => {}
+        }
+        type_error(vm, "int", obj)
+    }
+}
+
+impl IntoPyObject for i32 {
+    fn into_py_object(self, _vm: &mut VirtualMachine) -> PyResult {
+        Ok(PyObject::Integer { value: self }.into_ref())
+    }
+}
+
+impl FromPyObject for i64 {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        i32::from_py_object(vm, obj).map(i64::from)
+    }
+}
+
+// Integers are limited to 32 bits:
+impl IntoPyObject for i64 {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+        if self < i64::from(i32::MIN) || self > i64::from(i32::MAX) {
+            let msg = String::from("Python int too large to convert");
+            return Err(vm.new_exception("OverflowError", msg));
+        }
+        (self as i32).into_py_object(vm)
+    }
+}
+
+impl FromPyObject for f64 {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        match *obj.borrow() {
+            PyObject::Float { value } => return Ok(value),
+            PyObject::Integer { value } => return Ok(f64::from(value)),
+            PyObject::Boolean { value } => return Ok(if value { 1.0 } else { 0.0 }),
+            _ => {}
+        }
+        type_error(vm, "float", obj)
+    }
+}
+
+impl IntoPyObject for f64 {
+    fn into_py_object(self, _vm: &mut VirtualMachine) -> PyResult {
+        Ok(PyObject::Float { value: self }.into_ref())
+    }
+}
+
+impl FromPyObject for bool {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        if let PyObject::Boolean { value } = *obj.borrow() {
+            return Ok(value);
+        }
+        type_error(vm, "bool", obj)
+    }
+}
+
+impl IntoPyObject for bool {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+        Ok(vm.new_bool(self))
+    }
+}
+
+impl FromPyObject for String {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        if let PyObject::String { ref value } = *obj.borrow() {
+            return Ok(value.clone());
+        }
+        type_error(vm, "str", obj)
+    }
+}
+
+impl IntoPyObject for String {
+    fn into_py_object(self, _vm: &mut VirtualMachine) -> PyResult {
+        Ok(PyObject::String { value: self }.into_ref())
+    }
+}
+
+impl IntoPyObject for &str {
+    fn into_py_object(self, _vm: &mut VirtualMachine) -> PyResult {
+        Ok(PyObject::String {
+            value: String::from(self),
+        }.into_ref())
+    }
+}
+
+// Lists and tuples convert into vectors:
+impl<T: FromPyObject> FromPyObject for Vec<T> {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        let elements = match *obj.borrow() {
+            PyObject::List { ref elements } | PyObject::Tuple { ref elements } => {
+                Some(elements.clone())
+            }
+            _ => None,
+        };
+        let elements = match elements {
+            Some(elements) => elements,
+            None => return type_error(vm, "list", obj),
+        };
+        elements
+            .iter()
+            .map(|element| T::from_py_object(vm, element))
+            .collect()
+    }
+}
+
+impl<T: IntoPyObject> IntoPyObject for Vec<T> {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+        let elements = self
+            .into_iter()
+            .map(|element| element.into_py_object(vm))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(PyObject::List { elements }.into_ref())
+    }
+}
+
+impl<K, V> FromPyObject for HashMap<K, V>
+where
+    K: FromPyObject + Eq + Hash,
+    V: FromPyObject,
+{
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        let elements = match *obj.borrow() {
+            PyObject::Dict { ref elements } => Some(elements.clone()),
+            _ => None,
+        };
+        let elements = match elements {
+            Some(elements) => elements,
+            None => return type_error(vm, "dict", obj),
+        };
+        let mut map = HashMap::new();
+        for (key, value) in elements {
+            map.insert(K::from_py_object(vm, &key)?, V::from_py_object(vm, &value)?);
+        }
+        Ok(map)
+    }
+}
+
+impl<K: IntoPyObject, V: IntoPyObject> IntoPyObject for HashMap<K, V> {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+        let result = dict::new_dict();
+        for (key, value) in self {
+            let key = key.into_py_object(vm)?;
+            let value = value.into_py_object(vm)?;
+            dict::set_item(vm, &result, key, value)?;
+        }
+        Ok(result)
+    }
+}
+
+// None converts into nothing, and so does an argument which was not passed:
+impl<T: FromPyObject> FromPyObject for Option<T> {
+    fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+        if let PyObject::None = *obj.borrow() {
+            return Ok(None);
+        }
+        T::from_py_object(vm, obj).map(Some)
+    }
+
+    fn from_missing(_vm: &mut VirtualMachine) -> PyResult<Self> {
+        Ok(None)
+    }
+}
+
+impl<T: IntoPyObject> IntoPyObject for Option<T> {
+    fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+        match self {
+            Some(value) => value.into_py_object(vm),
+            None => Ok(vm.get_none()),
+        }
+    }
+}
+
+macro_rules! tuple_conversions {
+    ($size:expr, $($T:ident $value:ident),+) => {
+        impl<$($T: FromPyObject),+> FromPyObject for ($($T,)+) {
+            fn from_py_object(vm: &mut VirtualMachine, obj: &PyObjectRef) -> PyResult<Self> {
+                let elements = match *obj.borrow() {
+                    PyObject::Tuple { ref elements } if elements.len() == $size => {
+                        Some(elements.clone())
+                    }
+                    _ => None,
+                };
+                let elements = match elements {
+                    Some(elements) => elements,
+                    None => return type_error(vm, concat!("tuple of size ", $size), obj),
+                };
+                let mut elements = elements.iter();
+                Ok(($($T::from_py_object(vm, elements.next().unwrap())?,)+))
+            }
+        }
+
+        impl<$($T: IntoPyObject),+> IntoPyObject for ($($T,)+) {
+            fn into_py_object(self, vm: &mut VirtualMachine) -> PyResult {
+                let ($($value,)+) = self;
+                let elements = vec![$($value.into_py_object(vm)?),+];
+                Ok(PyObject::Tuple { elements }.into_ref())
+            }
+        }
+    };
+}
+
+tuple_conversions!(1, A a);
+tuple_conversions!(2, A a, B b);
+tuple_conversions!(3, A a, B b, C c);
+tuple_conversions!(4, A a, B b, C c, D d);
+
+/*
+ * Functions which can be called from python. These are functions taking the
+ * call arguments as they are, or functions with arguments and a result which
+ * are converted.
+ */
+pub trait IntoNativeFunc<Args> {
+    fn into_func(self) -> NativeFunc;
+}
+
+// The arguments of a function which converts its arguments itself:
+pub struct RawArgs;
+
+impl<F> IntoNativeFunc<RawArgs> for F
+where
+    F: Fn(&mut VirtualMachine, PyFuncArgs) -> PyResult + 'static,
+{
+    fn into_func(self) -> NativeFunc {
+        Rc::new(self)
+    }
+}
+
+// Arguments are positional, missing trailing arguments may be optional:
+fn check_native_args(vm: &mut VirtualMachine, args: &PyFuncArgs, max: usize) -> PyResult<()> {
+    if !args.kwargs.is_empty() {
+        let msg = String::from("function takes no keyword arguments");
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    if args.args.len() > max {
+        let msg = format!(
+            "function takes at most {} arguments ({} given)",
+            max,
+            args.args.len()
+        );
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    Ok(())
+}
+
+fn convert_arg<T: FromPyObject>(vm: &mut VirtualMachine, arg: Option<&PyObjectRef>) -> PyResult<T> {
+    match arg {
+        Some(arg) => T::from_py_object(vm, arg),
+        None => T::from_missing(vm),
+    }
+}
+
+macro_rules! native_func {
+    ($size:expr, $($T:ident $value:ident),*) => {
+        impl<F, $($T,)* R> IntoNativeFunc<($($T,)*)> for F
+        where
+            F: Fn($($T),*) -> PyResult<R> + 'static,
+            $($T: FromPyObject,)*
+            R: IntoPyObject,
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn into_func(self) -> NativeFunc {
+                Rc::new(move |vm, args| {
+                    check_native_args(vm, &args, $size)?;
+                    let mut args = args.args.iter();
+                    $(let $value = convert_arg::<$T>(vm, args.next())?;)*
+                    (self)($($value),*)?.into_py_object(vm)
+                })
+            }
+        }
+    };
+}
+
+native_func!(0,);
+native_func!(1, A a);
+native_func!(2, A a, B b);
+native_func!(3, A a, B b, C c);
+native_func!(4, A a, B b, C c, D d);
+native_func!(5, A a, B b, C c, D d, E e);
+
+/*
+ * Argument handling for the functions declared with #[pyfunction] and
+ * #[pymethods]. Arguments are bound to the parameters by position or by
+ * keyword, like for functions defined in python.
+ */
+pub fn bind_args(
+    vm: &mut VirtualMachine,
+    function: &str,
+    params: &[&str],
+    args: PyFuncArgs,
+) -> PyResult<Vec<Option<PyObjectRef>>> {
+    if args.args.len() > params.len() {
+        let msg = format!(
+            "{}() takes {} positional arguments but {} were given",
+            function,
+            params.len(),
+            args.args.len()
+        );
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    let mut values: Vec<Option<PyObjectRef>> = vec![None; params.len()];
+    for (i, value) in args.args.into_iter().enumerate() {
+        values[i] = Some(value);
+    }
+    for (name, value) in args.kwargs {
+        let msg = match params.iter().position(|param| *param == name) {
+            Some(i) if values[i].is_none() => {
+                values[i] = Some(value);
+                continue;
+            }
+            Some(_) => format!("{}() got multiple values for argument '{}'", function, name),
+            None => format!("{}() got an unexpected keyword argument '{}'", function, name),
+        };
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    Ok(values)
+}
+
+/*
+ * The parameters of these functions may also borrow from the converted
+ * argument, like a `&str` from a String. The argument is converted into a
+ * holder, which lives during the call, and the parameter is taken from it.
+ */
+pub trait FromPyArg<'a>: Sized {
+    type Holder;
+
+    fn to_holder(vm: &mut VirtualMachine, arg: Option<&PyObjectRef>) -> PyResult<Self::Holder>;
+    fn from_default(value: Self) -> Self::Holder;
+    fn from_holder(holder: &'a mut Self::Holder) -> Self;
+}
+
+impl<'a, T: FromPyObject> FromPyArg<'a> for T {
+    type Holder = Option<T>;
+
+    fn to_holder(vm: &mut VirtualMachine, arg: Option<&PyObjectRef>) -> PyResult<Option<T>> {
+        convert_arg(vm, arg).map(Some)
+    }
+
+    fn from_default(value: T) -> Option<T> {
+        Some(value)
+    }
+
+    fn from_holder(holder: &'a mut Option<T>) -> T {
+        holder.take().unwrap()
+    }
+}
+
+impl<'a> FromPyArg<'a> for &'a str {
+    type Holder = String;
+
+    fn to_holder(vm: &mut VirtualMachine, arg: Option<&PyObjectRef>) -> PyResult<String> {
+        convert_arg(vm, arg)
+    }
+
+    fn from_default(value: &str) -> String {
+        value.to_string()
+    }
+
+    fn from_holder(holder: &'a mut String) -> &'a str {
+        holder
+    }
+}
+
+// The holder of a parameter without default, for which no argument was given:
+pub fn missing_argument<'a, T: FromPyArg<'a>>(
+    vm: &mut VirtualMachine,
+    function: &str,
+    param: &str,
+) -> PyResult<T::Holder> {
+    T::to_holder(vm, None).map_err(|_| {
+        let msg = format!("{}() missing required argument: '{}'", function, param);
+        vm.new_exception("TypeError", msg)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame::Scope;
+    use super::super::native::NativeModule;
+    use super::*;
+
+    fn repeat(text: String, count: Option<i64>) -> PyResult<Vec<String>> {
+        Ok(vec![text; count.unwrap_or(2) as usize])
+    }
+
+    fn scale(values: Vec<i64>, factor: f64) -> PyResult<(f64, bool)> {
+        let total: i64 = values.iter().sum();
+        Ok((total as f64 * factor, total > 0))
+    }
+
+    fn lookup(map: HashMap<String, i32>, key: String) -> PyResult<Option<i32>> {
+        Ok(map.get(&key).cloned())
+    }
+
+    #[test]
+    fn test_native_functions() {
+        let mut vm = VirtualMachine::new();
+        let offset = 100;
+        vm.add_native_module(
+            NativeModule::new("typed")
+                .function("repeat", repeat)
+                .function("scale", scale)
+                .function("lookup", lookup)
+                .function("add_offset", move |value: i64| Ok(value + offset)),
+        );
+        let source = "
+import typed
+errors = []
+calls = [lambda: typed.repeat(1), lambda: typed.repeat('a', 'b'), lambda: typed.repeat('a', 1, 2)]
+for call in calls:
+    try:
+        call()
+    except TypeError as e:
+        errors.append(str(e))
+x = [typed.repeat('a'), typed.repeat('b', 3), typed.scale((1, 2), 1 / 2), typed.lookup({'k': 3}, 'k'), typed.lookup({}, 'k'), typed.add_offset(1), errors]
+";
+        let scope = Scope::new(None);
+        let code = vm.compile_source(source, "<string>").unwrap();
+        vm.run_code(code, scope.clone()).unwrap();
+        let x = scope.borrow().get("x").unwrap();
+        assert_eq!(
+            x.borrow().repr(),
+            "[['a', 'a'], ['b', 'b', 'b'], (1.5, True), 3, None, 101, \
+             ['expected str, got int', 'expected int, got str', \
+             'function takes at most 2 arguments (3 given)']]"
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut vm = VirtualMachine::new();
+        let value = (String::from("a"), vec![1i64, 2], Some(2.5f64))
+            .into_py_object(&mut vm)
+            .unwrap();
+        assert_eq!(value.borrow().repr(), "('a', [1, 2], 2.5)");
+        let back = <(String, Vec<i64>, Option<f64>)>::from_py_object(&mut vm, &value).unwrap();
+        assert_eq!(back, (String::from("a"), vec![1, 2], Some(2.5)));
+
+        let too_large = (1i64 << 40).into_py_object(&mut vm).unwrap_err();
+        assert_eq!(too_large.borrow().type_name(), "OverflowError");
+        let not_bool = bool::from_py_object(&mut vm, &value).unwrap_err();
+        assert_eq!(not_bool.borrow().type_name(), "TypeError");
+    }
+}
@@ -0,0 +1,1193 @@
+/*
+ * Load the .pyc files of CPython 3.11. The marshal format of CPython is
+ * read, and its code objects are translated to the instructions of this
+ * virtual machine, which are close to those of CPython.
+ *
+ * CPython 3.11 pushes a NULL below a function which is called, which is left
+ * out here. The translation keeps track of where the NULLs are, since
+ * instructions like COPY and SWAP count them. Code with exception handlers,
+ * from try and with statements, cannot be translated, since CPython keeps
+ * these in a table and this machine uses blocks.
+ */
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::ast::Location;
+use super::bytecode::{
+    BinaryOperator, CodeObject, ComparisonOperator, Constant, Instruction, InstructionList,
+    Label, UnaryOperator,
+};
+
+// The start of the .pyc files of CPython 3.11, which are followed by flags,
+// and the source modification time and size, or a hash:
+pub const MAGIC: [u8; 4] = [0xa7, 0x0d, 0x0d, 0x0a];
+const HEADER_SIZE: usize = 16;
+
+const MAX_DEPTH: usize = 200;
+
+// The flags of code objects:
+const CO_VARARGS: u32 = 0x04;
+const CO_VARKEYWORDS: u32 = 0x08;
+const CO_GENERATOR: u32 = 0x20;
+const CO_COROUTINE: u32 = 0x80;
+const CO_ITERABLE_COROUTINE: u32 = 0x100;
+const CO_ASYNC_GENERATOR: u32 = 0x200;
+
+// The kinds of the local variables:
+const CO_FAST_LOCAL: u8 = 0x20;
+const CO_FAST_CELL: u8 = 0x40;
+const CO_FAST_FREE: u8 = 0x80;
+
+// The marshal type codes, an object which is referred to later has the flag
+// bit set:
+const FLAG_REF: u8 = 0x80;
+
+macro_rules! opcodes {
+    ($($name:ident = $opcode:literal,)*) => {
+        // Opcodes which are never translated only serve for their names:
+        $(#[allow(dead_code)]
+        const $name: u8 = $opcode;)*
+
+        fn opcode_name(opcode: u8) -> String {
+            match opcode {
+                $($opcode => String::from(stringify!($name)),)*
+                _ => format!("opcode {}", opcode),
+            }
+        }
+    };
+}
+
+opcodes! {
+    CACHE = 0,
+    POP_TOP = 1,
+    PUSH_NULL = 2,
+    NOP = 9,
+    UNARY_POSITIVE = 10,
+    UNARY_NEGATIVE = 11,
+    UNARY_NOT = 12,
+    UNARY_INVERT = 15,
+    BINARY_SUBSCR = 25,
+    GET_LEN = 30,
+    MATCH_MAPPING = 31,
+    MATCH_SEQUENCE = 32,
+    MATCH_KEYS = 33,
+    PUSH_EXC_INFO = 35,
+    CHECK_EXC_MATCH = 36,
+    CHECK_EG_MATCH = 37,
+    WITH_EXCEPT_START = 49,
+    GET_AITER = 50,
+    GET_ANEXT = 51,
+    BEFORE_ASYNC_WITH = 52,
+    BEFORE_WITH = 53,
+    END_ASYNC_FOR = 54,
+    STORE_SUBSCR = 60,
+    DELETE_SUBSCR = 61,
+    GET_ITER = 68,
+    GET_YIELD_FROM_ITER = 69,
+    PRINT_EXPR = 70,
+    LOAD_BUILD_CLASS = 71,
+    LOAD_ASSERTION_ERROR = 74,
+    RETURN_GENERATOR = 75,
+    LIST_TO_TUPLE = 82,
+    RETURN_VALUE = 83,
+    IMPORT_STAR = 84,
+    SETUP_ANNOTATIONS = 85,
+    YIELD_VALUE = 86,
+    ASYNC_GEN_WRAP = 87,
+    PREP_RERAISE_STAR = 88,
+    POP_EXCEPT = 89,
+    STORE_NAME = 90,
+    DELETE_NAME = 91,
+    UNPACK_SEQUENCE = 92,
+    FOR_ITER = 93,
+    UNPACK_EX = 94,
+    STORE_ATTR = 95,
+    DELETE_ATTR = 96,
+    STORE_GLOBAL = 97,
+    DELETE_GLOBAL = 98,
+    SWAP = 99,
+    LOAD_CONST = 100,
+    LOAD_NAME = 101,
+    BUILD_TUPLE = 102,
+    BUILD_LIST = 103,
+    BUILD_SET = 104,
+    BUILD_MAP = 105,
+    LOAD_ATTR = 106,
+    COMPARE_OP = 107,
+    IMPORT_NAME = 108,
+    IMPORT_FROM = 109,
+    JUMP_FORWARD = 110,
+    JUMP_IF_FALSE_OR_POP = 111,
+    JUMP_IF_TRUE_OR_POP = 112,
+    POP_JUMP_FORWARD_IF_FALSE = 114,
+    POP_JUMP_FORWARD_IF_TRUE = 115,
+    LOAD_GLOBAL = 116,
+    IS_OP = 117,
+    CONTAINS_OP = 118,
+    RERAISE = 119,
+    COPY = 120,
+    BINARY_OP = 122,
+    SEND = 123,
+    LOAD_FAST = 124,
+    STORE_FAST = 125,
+    DELETE_FAST = 126,
+    POP_JUMP_FORWARD_IF_NOT_NONE = 128,
+    POP_JUMP_FORWARD_IF_NONE = 129,
+    RAISE_VARARGS = 130,
+    GET_AWAITABLE = 131,
+    MAKE_FUNCTION = 132,
+    BUILD_SLICE = 133,
+    JUMP_BACKWARD_NO_INTERRUPT = 134,
+    MAKE_CELL = 135,
+    LOAD_CLOSURE = 136,
+    LOAD_DEREF = 137,
+    STORE_DEREF = 138,
+    DELETE_DEREF = 139,
+    JUMP_BACKWARD = 140,
+    CALL_FUNCTION_EX = 142,
+    EXTENDED_ARG = 144,
+    LIST_APPEND = 145,
+    SET_ADD = 146,
+    MAP_ADD = 147,
+    LOAD_CLASSDEREF = 148,
+    COPY_FREE_VARS = 149,
+    RESUME = 151,
+    MATCH_CLASS = 152,
+    FORMAT_VALUE = 155,
+    BUILD_CONST_KEY_MAP = 156,
+    BUILD_STRING = 157,
+    LOAD_METHOD = 160,
+    LIST_EXTEND = 162,
+    SET_UPDATE = 163,
+    DICT_MERGE = 164,
+    DICT_UPDATE = 165,
+    PRECALL = 166,
+    CALL = 171,
+    KW_NAMES = 172,
+    POP_JUMP_BACKWARD_IF_NOT_NONE = 173,
+    POP_JUMP_BACKWARD_IF_NONE = 174,
+    POP_JUMP_BACKWARD_IF_FALSE = 175,
+    POP_JUMP_BACKWARD_IF_TRUE = 176,
+}
+
+// The operators of BINARY_OP, the in-place ones follow in the same order:
+const BINARY_OPERATORS: [BinaryOperator; 13] = [
+    BinaryOperator::Add,
+    BinaryOperator::And,
+    BinaryOperator::FloorDivide,
+    BinaryOperator::Lshift,
+    BinaryOperator::MatrixMultiply,
+    BinaryOperator::Multiply,
+    BinaryOperator::Modulo,
+    BinaryOperator::Or,
+    BinaryOperator::Power,
+    BinaryOperator::Rshift,
+    BinaryOperator::Subtract,
+    BinaryOperator::Divide,
+    BinaryOperator::Xor,
+];
+
+const COMPARISON_OPERATORS: [ComparisonOperator; 6] = [
+    ComparisonOperator::Less,
+    ComparisonOperator::LessOrEqual,
+    ComparisonOperator::Equal,
+    ComparisonOperator::NotEqual,
+    ComparisonOperator::Greater,
+    ComparisonOperator::GreaterOrEqual,
+];
+
+// The code of a .pyc file, after its header:
+pub fn load_pyc(data: &[u8]) -> Result<CodeObject, String> {
+    if data.len() < HEADER_SIZE || data[..4] != MAGIC {
+        return Err(String::from(
+            "bad magic number, only .pyc files of CPython 3.11 can be loaded",
+        ));
+    }
+    loads(&data[HEADER_SIZE..])
+}
+
+// A code object in the marshal format of CPython 3.11:
+pub fn loads(data: &[u8]) -> Result<CodeObject, String> {
+    let mut reader = Reader {
+        data,
+        offset: 0,
+        refs: vec![],
+    };
+    let object = reader.object(0)?;
+    if reader.offset != data.len() {
+        return Err(String::from("extra data after the code object"));
+    }
+    // The reader may keep a reference to the code:
+    drop(reader);
+    match object {
+        Constant::Code { code } => {
+            Rc::try_unwrap(code).map_err(|_| String::from("code object is referred to twice"))
+        }
+        _ => Err(String::from("marshal data is not a code object")),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    // The objects which were flagged to be referred to later:
+    refs: Vec<Option<Constant>>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        match self.data.get(self.offset..self.offset + size) {
+            Some(bytes) => {
+                self.offset += size;
+                Ok(bytes)
+            }
+            None => Err(String::from("marshal data too short")),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut bits = [0; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn string(&mut self, size: usize) -> Result<String, String> {
+        // Lone surrogates, which python strings may hold, are refused:
+        String::from_utf8(self.take(size)?.to_vec())
+            .map_err(|_| String::from("string constant is not valid utf-8"))
+    }
+
+    // Integers which do not fit 32 bits are stored as digits of 15 bits:
+    fn long(&mut self) -> Result<Constant, String> {
+        let size = self.u32()? as i32;
+        let mut value: i64 = 0;
+        for i in 0..size.unsigned_abs() {
+            let digit = self.take(2)?;
+            if i >= 3 {
+                return Err(String::from("integer constant too large"));
+            }
+            value |= (i64::from(digit[0]) | i64::from(digit[1]) << 8) << (15 * i);
+        }
+        if size < 0 {
+            value = -value;
+        }
+        if value < i64::from(i32::MIN) || value > i64::from(i32::MAX) {
+            return Err(String::from("integer constant too large"));
+        }
+        Ok(Constant::Integer {
+            value: value as i32,
+        })
+    }
+
+    fn elements(&mut self, count: usize, depth: usize) -> Result<Vec<Constant>, String> {
+        (0..count).map(|_| self.object(depth + 1)).collect()
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Constant, String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("marshal data nested too deeply"));
+        }
+        let code = self.byte()?;
+        // The index of a flagged object is taken before its contents are
+        // read, which may be flagged as well:
+        let index = if code & FLAG_REF != 0 {
+            self.refs.push(None);
+            Some(self.refs.len() - 1)
+        } else {
+            None
+        };
+        let object = match code & !FLAG_REF {
+            b'N' => Constant::None,
+            b'F' => Constant::Boolean { value: false },
+            b'T' => Constant::Boolean { value: true },
+            b'.' => Constant::Ellipsis,
+            b'i' => Constant::Integer {
+                value: self.u32()? as i32,
+            },
+            b'l' => self.long()?,
+            b'g' => Constant::Float { value: self.f64()? },
+            b'y' => Constant::Complex {
+                real: self.f64()?,
+                imag: self.f64()?,
+            },
+            b's' => {
+                let size = self.u32()? as usize;
+                Constant::Bytes {
+                    value: self.take(size)?.to_vec(),
+                }
+            }
+            b'u' | b't' | b'a' | b'A' => {
+                let size = self.u32()? as usize;
+                Constant::String {
+                    value: self.string(size)?,
+                }
+            }
+            b'z' | b'Z' => {
+                let size = self.byte()? as usize;
+                Constant::String {
+                    value: self.string(size)?,
+                }
+            }
+            b'(' => {
+                let count = self.u32()? as usize;
+                Constant::Tuple {
+                    elements: self.elements(count, depth)?,
+                }
+            }
+            b')' => {
+                let count = self.byte()? as usize;
+                Constant::Tuple {
+                    elements: self.elements(count, depth)?,
+                }
+            }
+            b'>' => {
+                let count = self.u32()? as usize;
+                Constant::FrozenSet {
+                    elements: self.elements(count, depth)?,
+                }
+            }
+            b'r' => {
+                let index = self.u32()? as usize;
+                match self.refs.get(index) {
+                    Some(Some(object)) => object.clone(),
+                    _ => return Err(String::from("bad marshal reference")),
+                }
+            }
+            b'c' => Constant::Code {
+                code: Rc::new(self.code(depth)?),
+            },
+            code => return Err(format!("unsupported marshal type {:?}", code as char)),
+        };
+        if let Some(index) = index {
+            self.refs[index] = Some(object.clone());
+        }
+        Ok(object)
+    }
+
+    fn bytes_field(&mut self, depth: usize) -> Result<Vec<u8>, String> {
+        match self.object(depth + 1)? {
+            Constant::Bytes { value } => Ok(value),
+            _ => Err(String::from("code object field is not bytes")),
+        }
+    }
+
+    fn string_field(&mut self, depth: usize) -> Result<String, String> {
+        match self.object(depth + 1)? {
+            Constant::String { value } => Ok(value),
+            _ => Err(String::from("code object field is not a string")),
+        }
+    }
+
+    fn strings_field(&mut self, depth: usize) -> Result<Vec<String>, String> {
+        match self.object(depth + 1)? {
+            Constant::Tuple { elements } => elements
+                .into_iter()
+                .map(|element| match element {
+                    Constant::String { value } => Ok(value),
+                    _ => Err(String::from("name which is not a string")),
+                })
+                .collect(),
+            _ => Err(String::from("code object field is not a tuple")),
+        }
+    }
+
+    fn code(&mut self, depth: usize) -> Result<CodeObject, String> {
+        let arg_count = self.u32()? as usize;
+        let _posonly_arg_count = self.u32()?;
+        let kwonly_arg_count = self.u32()?;
+        let _stack_size = self.u32()?;
+        let flags = self.u32()?;
+        let code = self.bytes_field(depth)?;
+        let consts = match self.object(depth + 1)? {
+            Constant::Tuple { elements } => elements,
+            _ => return Err(String::from("constants are not a tuple")),
+        };
+        let names = self.strings_field(depth)?;
+        let localsplus_names = self.strings_field(depth)?;
+        let localsplus_kinds = self.bytes_field(depth)?;
+        let filename = self.string_field(depth)?;
+        let name = self.string_field(depth)?;
+        let qualname = self.string_field(depth)?;
+        let firstlineno = self.u32()? as usize;
+        let linetable = self.bytes_field(depth)?;
+        let exception_table = self.bytes_field(depth)?;
+
+        let unsupported = if !exception_table.is_empty() {
+            Some("try and with statements")
+        } else if flags & (CO_COROUTINE | CO_ITERABLE_COROUTINE | CO_ASYNC_GENERATOR) != 0 {
+            Some("coroutines")
+        } else if flags & (CO_VARARGS | CO_VARKEYWORDS) != 0 || kwonly_arg_count > 0 {
+            Some("variable and keyword only arguments")
+        } else {
+            None
+        };
+        if let Some(what) = unsupported {
+            return Err(format!("{} are not supported in {}", what, qualname));
+        }
+        if localsplus_names.len() != localsplus_kinds.len()
+            || arg_count > localsplus_names.len()
+            || !code.len().is_multiple_of(2)
+        {
+            return Err(format!("bad code object {}", qualname));
+        }
+
+        let mut target = CodeObject::new(localsplus_names[..arg_count].to_vec(), name);
+        for (name, kind) in localsplus_names.iter().zip(&localsplus_kinds) {
+            if kind & CO_FAST_LOCAL != 0 {
+                target.add_varname(name);
+            }
+            if kind & CO_FAST_CELL != 0 {
+                target.cellvars.push(name.clone());
+            }
+            if kind & CO_FAST_FREE != 0 {
+                target.freevars.push(name.clone());
+            }
+        }
+        target.consts = consts;
+        target.names = names;
+        target.is_generator = flags & CO_GENERATOR != 0;
+        target.filename = filename;
+        target.qualname = qualname;
+        target.firstlineno = firstlineno;
+
+        let locations = line_table(&linetable, firstlineno, code.len() / 2)?;
+        let function = target.qualname.clone();
+        let translator = Translator {
+            code: &code,
+            localsplus: &localsplus_names,
+            locations,
+            target,
+            instructions: InstructionList::new(),
+            states: HashMap::new(),
+            stack: Some(vec![]),
+            kw_names: None,
+            last_const: None,
+        };
+        translator
+            .translate()
+            .map_err(|msg| format!("{} in {}", msg, function))
+    }
+}
+
+// The location of every word of the code, from the line table of CPython
+// 3.11. Its entries start with a byte with the high bit set, holding a kind
+// and the number of words. Words without location get the previous one.
+fn line_table(data: &[u8], firstlineno: usize, words: usize) -> Result<Vec<Location>, String> {
+    fn varint(data: &[u8], offset: &mut usize) -> Result<i64, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = match data.get(*offset) {
+                Some(byte) => i64::from(*byte),
+                None => return Err(String::from("truncated line table")),
+            };
+            *offset += 1;
+            if shift > 60 {
+                return Err(String::from("bad line table"));
+            }
+            value |= (byte & 63) << shift;
+            if byte & 64 == 0 {
+                return Ok(value);
+            }
+            shift += 6;
+        }
+    }
+
+    fn svarint(data: &[u8], offset: &mut usize) -> Result<i64, String> {
+        let value = varint(data, offset)?;
+        Ok(if value & 1 != 0 { -(value >> 1) } else { value >> 1 })
+    }
+
+    let mut locations = vec![];
+    let mut line = firstlineno as i64;
+    let mut location = Location::new(firstlineno, 0);
+    let mut offset = 0;
+    while offset < data.len() {
+        let first = data[offset];
+        offset += 1;
+        let kind = (first >> 3) & 15;
+        let length = (first & 7) as usize + 1;
+        let column = match kind {
+            15 => None,
+            14 => {
+                line += svarint(data, &mut offset)?;
+                varint(data, &mut offset)?;
+                let column = varint(data, &mut offset)? - 1;
+                varint(data, &mut offset)?;
+                Some(column.max(0) as usize)
+            }
+            13 => {
+                line += svarint(data, &mut offset)?;
+                Some(0)
+            }
+            10..=12 => {
+                line += i64::from(kind) - 10;
+                let column = data.get(offset).cloned().unwrap_or(0);
+                offset += 2;
+                Some(column as usize)
+            }
+            _ => {
+                let second = data.get(offset).cloned().unwrap_or(0);
+                offset += 1;
+                Some(kind as usize * 8 + (second >> 4) as usize)
+            }
+        };
+        if let Some(column) = column {
+            location = Location::new(line.max(0) as usize, column);
+        }
+        for _ in 0..length {
+            locations.push(location);
+        }
+    }
+    if offset > data.len() {
+        return Err(String::from("truncated line table"));
+    }
+    locations.resize(words, location);
+    Ok(locations)
+}
+
+/*
+ * The translation of the instructions of a code object. The stack of CPython
+ * is followed, with a flag for each entry which is a NULL. Jumps record the
+ * stack at their target, code after an unconditional jump has the stack of
+ * the jumps to it, or is unreachable and left out.
+ */
+struct Translator<'a> {
+    code: &'a [u8],
+    localsplus: &'a [String],
+    locations: Vec<Location>,
+    target: CodeObject,
+    instructions: InstructionList,
+    states: HashMap<usize, Vec<bool>>,
+    stack: Option<Vec<bool>>,
+    // The names of the keyword arguments of the next CALL:
+    kw_names: Option<usize>,
+    // The constant loaded by the previous instruction:
+    last_const: Option<usize>,
+}
+
+impl<'a> Translator<'a> {
+    fn translate(mut self) -> Result<CodeObject, String> {
+        let words = self.code.len() / 2;
+        let mut offset = 0;
+        while offset < words {
+            let start = offset;
+            let mut argument = 0;
+            let mut opcode = EXTENDED_ARG;
+            while opcode == EXTENDED_ARG && offset < words {
+                opcode = self.code[2 * offset];
+                argument = argument << 8 | self.code[2 * offset + 1] as usize;
+                offset += 1;
+            }
+            if opcode == CACHE {
+                continue;
+            }
+
+            self.instructions.set_label(start);
+            if let Some(state) = self.states.get(&start) {
+                if let Some(ref stack) = self.stack {
+                    if stack != state {
+                        return Err(format!("inconsistent stack at offset {}", start));
+                    }
+                }
+                self.stack = Some(state.clone());
+            }
+            if self.stack.is_none() {
+                continue;
+            }
+            let location = self.locations[offset - 1];
+            let last_const = self.last_const.take();
+            self.instruction(opcode, argument, offset, last_const, location)?;
+        }
+        self.instructions.assemble(&mut self.target)?;
+        Ok(self.target)
+    }
+
+    fn stack(&mut self) -> &mut Vec<bool> {
+        self.stack.as_mut().unwrap()
+    }
+
+    fn push(&mut self, count: usize) {
+        for _ in 0..count {
+            self.stack().push(false);
+        }
+    }
+
+    fn pop(&mut self, count: usize) -> Result<(), String> {
+        let stack = self.stack();
+        if count > stack.len() {
+            return Err(String::from("stack underflow"));
+        }
+        let size = stack.len() - count;
+        stack.truncate(size);
+        Ok(())
+    }
+
+    // The position among the values without NULLs, of the entry `depth`
+    // from the top, counting from 1:
+    fn depth(&mut self, depth: usize) -> Result<usize, String> {
+        let stack = self.stack();
+        if depth == 0 || depth > stack.len() || stack[stack.len() - depth] {
+            return Err(String::from("stack entry is not a value"));
+        }
+        Ok(stack[stack.len() - depth..].iter().filter(|null| !**null).count())
+    }
+
+    fn jump_to(&mut self, target: usize) -> Result<(), String> {
+        let stack = self.stack.clone().unwrap();
+        match self.states.get(&target) {
+            Some(state) if *state != stack => {
+                Err(format!("inconsistent stack at offset {}", target))
+            }
+            _ => {
+                self.states.insert(target, stack);
+                Ok(())
+            }
+        }
+    }
+
+    fn load_constant(&mut self, constant: Constant) -> Instruction {
+        Instruction::LoadConst {
+            consti: self.target.add_constant(constant),
+        }
+    }
+
+    fn load_global(&mut self, name: &str) -> Instruction {
+        Instruction::LoadGlobal {
+            namei: self.target.add_name(name),
+        }
+    }
+
+    fn local_name(&self, index: usize) -> Result<&'a str, String> {
+        match self.localsplus.get(index) {
+            Some(name) => Ok(name),
+            None => Err(format!("local variable {} out of range", index)),
+        }
+    }
+
+    fn deref(&self, index: usize) -> Result<usize, String> {
+        let name = self.local_name(index)?;
+        match self.target.deref_index(name) {
+            Some(i) => Ok(i),
+            None => Err(format!("'{}' is not a cell or free variable", name)),
+        }
+    }
+
+    // The elements of the constant loaded just before:
+    fn constant_elements(&self, last_const: Option<usize>) -> Option<Vec<Constant>> {
+        match last_const.and_then(|consti| self.target.consts.get(consti)) {
+            Some(Constant::Tuple { elements }) | Some(Constant::FrozenSet { elements }) => {
+                Some(elements.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Translate one instruction. `next` is the offset after it, where
+    // relative jumps are counted from.
+    fn instruction(
+        &mut self,
+        opcode: u8,
+        argument: usize,
+        next: usize,
+        last_const: Option<usize>,
+        location: Location,
+    ) -> Result<(), String> {
+        let mut emitted = vec![];
+        match opcode {
+            NOP | RESUME | PRECALL | MAKE_CELL | COPY_FREE_VARS => {}
+            PUSH_NULL => self.stack().push(true),
+            POP_TOP => {
+                self.pop(1)?;
+                emitted.push(Instruction::Pop);
+            }
+            UNARY_POSITIVE | UNARY_NEGATIVE | UNARY_NOT | UNARY_INVERT => {
+                let op = match opcode {
+                    UNARY_POSITIVE => UnaryOperator::Plus,
+                    UNARY_NEGATIVE => UnaryOperator::Minus,
+                    UNARY_NOT => UnaryOperator::Not,
+                    _ => UnaryOperator::Invert,
+                };
+                emitted.push(Instruction::UnaryOperation { op });
+            }
+            BINARY_SUBSCR => {
+                self.pop(1)?;
+                emitted.push(Instruction::BinaryOperation {
+                    op: BinaryOperator::Subscript,
+                });
+            }
+            BINARY_OP => {
+                let op = match BINARY_OPERATORS.get(argument % 13) {
+                    Some(op) if argument < 26 => *op,
+                    _ => return Err(format!("bad binary operator {}", argument)),
+                };
+                self.pop(1)?;
+                emitted.push(if argument < 13 {
+                    Instruction::BinaryOperation { op }
+                } else {
+                    Instruction::InplaceOperation { op }
+                });
+            }
+            COMPARE_OP | IS_OP | CONTAINS_OP => {
+                let op = match (opcode, argument) {
+                    (COMPARE_OP, _) => match COMPARISON_OPERATORS.get(argument) {
+                        Some(op) => *op,
+                        None => return Err(format!("bad comparison {}", argument)),
+                    },
+                    (IS_OP, 0) => ComparisonOperator::Is,
+                    (IS_OP, _) => ComparisonOperator::IsNot,
+                    (_, 0) => ComparisonOperator::In,
+                    _ => ComparisonOperator::NotIn,
+                };
+                self.pop(1)?;
+                emitted.push(Instruction::CompareOperation { op });
+            }
+            STORE_SUBSCR => {
+                self.pop(3)?;
+                emitted.push(Instruction::StoreSubscript);
+            }
+            DELETE_SUBSCR => {
+                self.pop(2)?;
+                emitted.push(Instruction::DeleteSubscript);
+            }
+            GET_ITER | GET_YIELD_FROM_ITER => emitted.push(Instruction::GetIter),
+            PRINT_EXPR => {
+                self.pop(1)?;
+                emitted.push(Instruction::PrintExpr);
+            }
+            LOAD_BUILD_CLASS => {
+                self.push(1);
+                emitted.push(Instruction::LoadBuildClass);
+            }
+            LOAD_ASSERTION_ERROR => {
+                self.push(1);
+                emitted.push(self.load_global("AssertionError"));
+            }
+            // The generator is created when the function is called, this
+            // pushes the value which the first resume sends:
+            RETURN_GENERATOR => {
+                self.push(1);
+                emitted.push(self.load_constant(Constant::None));
+            }
+            RETURN_VALUE => {
+                self.pop(1)?;
+                self.stack = None;
+                emitted.push(Instruction::ReturnValue);
+            }
+            IMPORT_STAR => {
+                self.pop(1)?;
+                emitted.push(Instruction::ImportStar);
+            }
+            SETUP_ANNOTATIONS => emitted.push(Instruction::SetupAnnotations),
+            YIELD_VALUE => emitted.push(Instruction::YieldValue),
+            STORE_NAME | STORE_ATTR | STORE_GLOBAL => {
+                let namei = argument;
+                self.pop(if opcode == STORE_ATTR { 2 } else { 1 })?;
+                emitted.push(match opcode {
+                    STORE_NAME => Instruction::StoreName { namei },
+                    STORE_ATTR => Instruction::StoreAttr { namei },
+                    _ => Instruction::StoreGlobal { namei },
+                });
+            }
+            DELETE_NAME | DELETE_GLOBAL => {
+                let namei = argument;
+                emitted.push(if opcode == DELETE_NAME {
+                    Instruction::DeleteName { namei }
+                } else {
+                    Instruction::DeleteGlobal { namei }
+                });
+            }
+            DELETE_ATTR => {
+                self.pop(1)?;
+                emitted.push(Instruction::DeleteAttr { namei: argument });
+            }
+            LOAD_NAME => {
+                self.push(1);
+                emitted.push(Instruction::LoadName { namei: argument });
+            }
+            LOAD_ATTR => emitted.push(Instruction::LoadAttr { namei: argument }),
+            // The method is looked up bound, in place of a NULL and the
+            // function:
+            LOAD_METHOD => {
+                self.pop(1)?;
+                self.stack().push(true);
+                self.push(1);
+                emitted.push(Instruction::LoadAttr { namei: argument });
+            }
+            LOAD_GLOBAL => {
+                if argument & 1 != 0 {
+                    self.stack().push(true);
+                }
+                self.push(1);
+                emitted.push(Instruction::LoadGlobal {
+                    namei: argument >> 1,
+                });
+            }
+            LOAD_CONST => {
+                self.push(1);
+                self.last_const = Some(argument);
+                emitted.push(Instruction::LoadConst { consti: argument });
+            }
+            LOAD_FAST | STORE_FAST | DELETE_FAST => {
+                let var_num = self.target.add_varname(self.local_name(argument)?);
+                emitted.push(match opcode {
+                    LOAD_FAST => {
+                        self.push(1);
+                        Instruction::LoadFast { var_num }
+                    }
+                    STORE_FAST => {
+                        self.pop(1)?;
+                        Instruction::StoreFast { var_num }
+                    }
+                    _ => Instruction::DeleteFast { var_num },
+                });
+            }
+            LOAD_DEREF | LOAD_CLASSDEREF | LOAD_CLOSURE => {
+                let i = self.deref(argument)?;
+                self.push(1);
+                emitted.push(if opcode == LOAD_CLOSURE {
+                    Instruction::LoadClosure { i }
+                } else {
+                    Instruction::LoadDeref { i }
+                });
+            }
+            STORE_DEREF => {
+                let i = self.deref(argument)?;
+                self.pop(1)?;
+                emitted.push(Instruction::StoreDeref { i });
+            }
+            DELETE_DEREF => emitted.push(Instruction::DeleteDeref {
+                i: self.deref(argument)?,
+            }),
+            IMPORT_NAME => {
+                self.pop(1)?;
+                emitted.push(Instruction::ImportName { namei: argument });
+            }
+            IMPORT_FROM => {
+                self.push(1);
+                emitted.push(Instruction::ImportFrom { namei: argument });
+            }
+            UNPACK_SEQUENCE => {
+                self.pop(1)?;
+                self.push(argument);
+                emitted.push(Instruction::UnpackSequence { size: argument });
+            }
+            BUILD_TUPLE | BUILD_LIST | BUILD_SET | BUILD_MAP => {
+                let size = argument;
+                self.pop(if opcode == BUILD_MAP { 2 * size } else { size })?;
+                self.push(1);
+                emitted.push(match opcode {
+                    BUILD_TUPLE => Instruction::BuildTuple { size },
+                    BUILD_LIST => Instruction::BuildList { size },
+                    BUILD_SET => Instruction::BuildSet { size },
+                    _ => Instruction::BuildMap { size },
+                });
+            }
+            // The keys are a constant tuple above the values. The values are
+            // unpacked, and added to a new dict one by one:
+            BUILD_CONST_KEY_MAP => {
+                let size = argument;
+                let keys = match self.constant_elements(last_const) {
+                    Some(ref keys) if keys.len() == size => keys.clone(),
+                    _ => return Err(String::from("BUILD_CONST_KEY_MAP without constant keys")),
+                };
+                self.pop(size + 1)?;
+                self.push(1);
+                emitted.push(Instruction::Pop);
+                emitted.push(Instruction::BuildTuple { size });
+                emitted.push(Instruction::BuildMap { size: 0 });
+                emitted.push(Instruction::Rotate { amount: 2 });
+                emitted.push(Instruction::UnpackSequence { size });
+                for (j, key) in keys.into_iter().enumerate() {
+                    emitted.push(self.load_constant(key));
+                    emitted.push(Instruction::Rotate { amount: 2 });
+                    emitted.push(Instruction::MapAdd { i: size - j - 1 });
+                }
+            }
+            // Displays of constants are built from a constant tuple:
+            LIST_EXTEND | SET_UPDATE => {
+                let elements = match self.constant_elements(last_const) {
+                    Some(ref elements) if argument == 1 => elements.clone(),
+                    _ => return Err(format!("{} of a value which is not constant", opcode_name(opcode))),
+                };
+                self.pop(1)?;
+                emitted.push(Instruction::Pop);
+                for element in elements {
+                    emitted.push(self.load_constant(element));
+                    emitted.push(if opcode == LIST_EXTEND {
+                        Instruction::ListAppend { i: 0 }
+                    } else {
+                        Instruction::SetAdd { i: 0 }
+                    });
+                }
+            }
+            LIST_APPEND | SET_ADD | MAP_ADD => {
+                self.pop(if opcode == MAP_ADD { 2 } else { 1 })?;
+                let i = self.depth(argument)? - 1;
+                emitted.push(match opcode {
+                    LIST_APPEND => Instruction::ListAppend { i },
+                    SET_ADD => Instruction::SetAdd { i },
+                    _ => Instruction::MapAdd { i },
+                });
+            }
+            COPY => {
+                emitted.extend(match self.depth(argument)? {
+                    1 => vec![Instruction::Duplicate],
+                    2 => vec![Instruction::DuplicateTwo, Instruction::Pop],
+                    _ => return Err(format!("COPY {} is not supported", argument)),
+                });
+                self.push(1);
+            }
+            // Rotating the top down, and the rest of them up again, swaps
+            // the top with the entry at the depth:
+            SWAP => {
+                let depth = self.depth(argument)?;
+                if self.stack().last() == Some(&true) {
+                    return Err(String::from("stack entry is not a value"));
+                }
+                let stack = self.stack();
+                let size = stack.len();
+                stack.swap(size - 1, size - argument);
+                if depth > 1 {
+                    emitted.push(Instruction::Rotate { amount: depth });
+                    for _ in 0..depth - 2 {
+                        emitted.push(Instruction::Rotate { amount: depth - 1 });
+                    }
+                }
+            }
+            // Arguments are converted with str() or repr(), and joined:
+            FORMAT_VALUE => {
+                let function = match argument {
+                    0 | 1 => "str",
+                    2 => "repr",
+                    _ => return Err(String::from("format specifications and ascii() are not supported")),
+                };
+                emitted.push(self.load_global(function));
+                emitted.push(Instruction::Rotate { amount: 2 });
+                emitted.push(Instruction::CallFunction { count: 1 });
+            }
+            BUILD_STRING => {
+                if argument == 0 {
+                    self.push(1);
+                    let empty = Constant::String {
+                        value: String::new(),
+                    };
+                    emitted.push(self.load_constant(empty));
+                } else {
+                    self.pop(argument - 1)?;
+                    for _ in 1..argument {
+                        emitted.push(Instruction::BinaryOperation {
+                            op: BinaryOperator::Add,
+                        });
+                    }
+                }
+            }
+            // Annotations are left out, the defaults and the closure are
+            // below the code like here:
+            MAKE_FUNCTION => {
+                if argument & 0x02 != 0 {
+                    return Err(String::from("keyword only arguments are not supported"));
+                }
+                let has_defaults = argument & 0x01 != 0;
+                let has_closure = argument & 0x08 != 0;
+                if argument & 0x04 != 0 {
+                    self.pop(1)?;
+                    if has_closure {
+                        emitted.push(Instruction::Rotate { amount: 3 });
+                        emitted.push(Instruction::Rotate { amount: 3 });
+                    } else {
+                        emitted.push(Instruction::Rotate { amount: 2 });
+                    }
+                    emitted.push(Instruction::Pop);
+                }
+                self.pop(1 + has_defaults as usize + has_closure as usize)?;
+                self.push(1);
+                emitted.push(if has_closure {
+                    Instruction::MakeClosure { has_defaults }
+                } else {
+                    Instruction::MakeFunction { has_defaults }
+                });
+            }
+            KW_NAMES => self.kw_names = Some(argument),
+            // Below the arguments is a NULL and the function, or a method
+            // and the object, which is one more argument:
+            CALL => {
+                let stack = self.stack();
+                if argument + 2 > stack.len() {
+                    return Err(String::from("stack underflow"));
+                }
+                let is_method = !stack[stack.len() - argument - 2];
+                let count = argument + is_method as usize;
+                self.pop(argument + 2)?;
+                self.push(1);
+                match self.kw_names.take() {
+                    Some(consti) => {
+                        emitted.push(Instruction::LoadConst { consti });
+                        emitted.push(Instruction::CallFunctionKw { count });
+                    }
+                    None => emitted.push(Instruction::CallFunction { count }),
+                }
+            }
+            RAISE_VARARGS => {
+                self.pop(argument)?;
+                self.stack = None;
+                emitted.push(Instruction::Raise { argc: argument });
+            }
+            JUMP_FORWARD | JUMP_BACKWARD | JUMP_BACKWARD_NO_INTERRUPT => {
+                let target = self.jump_target(opcode, next, argument)?;
+                self.jump_to(target)?;
+                self.stack = None;
+                emitted.push(Instruction::Jump { target });
+            }
+            POP_JUMP_FORWARD_IF_FALSE
+            | POP_JUMP_BACKWARD_IF_FALSE
+            | POP_JUMP_FORWARD_IF_TRUE
+            | POP_JUMP_BACKWARD_IF_TRUE
+            | POP_JUMP_FORWARD_IF_NONE
+            | POP_JUMP_BACKWARD_IF_NONE
+            | POP_JUMP_FORWARD_IF_NOT_NONE
+            | POP_JUMP_BACKWARD_IF_NOT_NONE => {
+                let target = self.jump_target(opcode, next, argument)?;
+                self.pop(1)?;
+                self.jump_to(target)?;
+                match opcode {
+                    POP_JUMP_FORWARD_IF_FALSE | POP_JUMP_BACKWARD_IF_FALSE => {
+                        emitted.push(Instruction::JumpIfFalse { target })
+                    }
+                    POP_JUMP_FORWARD_IF_TRUE | POP_JUMP_BACKWARD_IF_TRUE => {
+                        emitted.push(Instruction::JumpIf { target })
+                    }
+                    _ => {
+                        emitted.push(self.load_constant(Constant::None));
+                        emitted.push(Instruction::CompareOperation {
+                            op: ComparisonOperator::Is,
+                        });
+                        emitted.push(match opcode {
+                            POP_JUMP_FORWARD_IF_NONE | POP_JUMP_BACKWARD_IF_NONE => {
+                                Instruction::JumpIf { target }
+                            }
+                            _ => Instruction::JumpIfFalse { target },
+                        });
+                    }
+                }
+            }
+            // The value stays when jumping, and is popped otherwise:
+            JUMP_IF_FALSE_OR_POP | JUMP_IF_TRUE_OR_POP => {
+                let target = self.jump_target(opcode, next, argument)?;
+                self.jump_to(target)?;
+                self.pop(1)?;
+                emitted.push(Instruction::Duplicate);
+                emitted.push(if opcode == JUMP_IF_FALSE_OR_POP {
+                    Instruction::JumpIfFalse { target }
+                } else {
+                    Instruction::JumpIf { target }
+                });
+                emitted.push(Instruction::Pop);
+            }
+            FOR_ITER => {
+                let target = self.jump_target(opcode, next, argument)?;
+                self.pop(1)?;
+                self.jump_to(target)?;
+                self.push(2);
+                emitted.push(Instruction::ForIter { target });
+            }
+            // The loop of a `yield from` around SEND is done by YieldFrom
+            // itself, the code of the loop is left out:
+            SEND => {
+                let target = self.jump_target(opcode, next, argument)?;
+                self.pop(1)?;
+                self.jump_to(target)?;
+                self.stack = None;
+                emitted.push(Instruction::YieldFrom);
+                emitted.push(Instruction::Jump { target });
+            }
+            _ => return Err(format!("{} is not supported", opcode_name(opcode))),
+        }
+        for instruction in emitted {
+            self.instructions.push(instruction, location);
+        }
+        Ok(())
+    }
+
+    fn jump_target(&self, opcode: u8, next: usize, argument: usize) -> Result<Label, String> {
+        let backward = matches!(
+            opcode,
+            JUMP_BACKWARD
+                | JUMP_BACKWARD_NO_INTERRUPT
+                | POP_JUMP_BACKWARD_IF_FALSE
+                | POP_JUMP_BACKWARD_IF_TRUE
+                | POP_JUMP_BACKWARD_IF_NONE
+                | POP_JUMP_BACKWARD_IF_NOT_NONE
+        );
+        if backward {
+            next.checked_sub(argument)
+                .ok_or_else(|| String::from("jump before the start of the code"))
+        } else {
+            Ok(next + argument)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame::Scope;
+    use super::super::pyobject::PyObject;
+    use super::super::vm::VirtualMachine;
+    use super::*;
+
+    // The fixtures are compiled by CPython 3.11 from the .py files next to
+    // them, with `py_compile.compile(name + '.py', name + '.pyc', dfile=name + '.py')`.
+    const PROGRAM: &[u8] = include_bytes!("../../tests/cpython311/program.pyc");
+    const HANDLERS: &[u8] = include_bytes!("../../tests/cpython311/handlers.pyc");
+    const SLICES: &[u8] = include_bytes!("../../tests/cpython311/slices.pyc");
+
+    #[test]
+    fn test_load_cpython_pyc() {
+        let code = load_pyc(PROGRAM).unwrap();
+        assert_eq!(code.filename, "program.py");
+        assert_eq!(code.qualname, "<module>");
+
+        let mut vm = VirtualMachine::new();
+        let scope = Scope::new(None);
+        // Class bodies take their __module__ from it:
+        let name = PyObject::String {
+            value: String::from("program"),
+        }.into_ref();
+        scope.borrow_mut().locals.insert(String::from("__name__"), name);
+        if let Err(exception) = vm.run_code(code, scope.clone()) {
+            vm.print_exception(&exception);
+            panic!("The translated program raised an exception");
+        }
+        let result = scope.borrow().get("result").unwrap();
+        assert_eq!(
+            result.borrow().repr(),
+            "[[0, 1, 2, 10], 7, [0, 1, 4, 9], {'a': 1, 'b': [0, 1, 4, 9]}, '7-[1, 2]', \
+             True, 'C.m.<locals>.g', 10, [1, 2, 3], {5}, 'ok', (3, 12)]"
+        );
+    }
+
+    #[test]
+    fn test_unsupported_cpython_code() {
+        let error = load_pyc(HANDLERS).unwrap_err();
+        assert_eq!(
+            error,
+            "try and with statements are not supported in guarded"
+        );
+        assert_eq!(
+            load_pyc(SLICES).unwrap_err(),
+            "BUILD_SLICE is not supported in sliced"
+        );
+        assert!(load_pyc(b"RSPY\x01\0\0\0").is_err());
+        assert!(load_pyc(&PROGRAM[..PROGRAM.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+/*
+ * The builtin decorators which change what looking up a function in a
+ * class gives: staticmethod, classmethod and property. The lookup itself is
+ * done by the virtual machine.
+ */
+
+use std::collections::HashMap;
+
+use super::builtins::{check_arg_count, check_positional_count};
+use super::exceptions::new_class;
+use super::pyobject::{PyFuncArgs, PyObjectRef, PyResult};
+use super::vm::VirtualMachine;
+
+pub fn fill_scope(scope: &mut HashMap<String, PyObjectRef>) {
+    let wrapper_methods = || vec![("__init__", wrapper_init as _)];
+    scope.insert(
+        String::from("staticmethod"),
+        new_class("staticmethod", vec![], wrapper_methods()),
+    );
+    scope.insert(
+        String::from("classmethod"),
+        new_class("classmethod", vec![], wrapper_methods()),
+    );
+    scope.insert(
+        String::from("property"),
+        new_class(
+            "property",
+            vec![],
+            vec![
+                ("__init__", property_init),
+                ("getter", property_getter),
+                ("setter", property_setter),
+            ],
+        ),
+    );
+}
+
+// Static and class methods keep the wrapped function in `__func__`:
+fn wrapper_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "__init__", &args, 2, 2)?;
+    vm.set_attribute(args.args[0].clone(), "__func__", args.args[1].clone())
+}
+
+// property(fget=None, fset=None)
+fn property_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_positional_count(vm, "property", &args, 1, 3)?;
+    for (i, name) in ["fget", "fset"].iter().enumerate() {
+        let value = match args.args.get(i + 1) {
+            Some(value) => value.clone(),
+            None => args.get_kwarg(name).unwrap_or_else(|| vm.get_none()),
+        };
+        vm.set_attribute(args.args[0].clone(), name, value)?;
+    }
+    Ok(vm.get_none())
+}
+
+// A copy of the property, with one of its functions replaced:
+fn copy_property(vm: &mut VirtualMachine, args: PyFuncArgs, method: &str, replaced: &str) -> PyResult {
+    check_arg_count(vm, method, &args, 2, 2)?;
+    let mut functions = vec![];
+    for name in &["fget", "fset"] {
+        functions.push(if replaced == *name {
+            args.args[1].clone()
+        } else {
+            vm.get_attribute(args.args[0].clone(), name)?
+        });
+    }
+    let class = vm.get_builtin("property");
+    vm.invoke(class, functions)
+}
+
+fn property_getter(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    copy_property(vm, args, "getter", "fget")
+}
+
+fn property_setter(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    copy_property(vm, args, "setter", "fset")
+}
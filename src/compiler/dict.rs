@@ -0,0 +1,154 @@
+/*
+ * Dictionary objects. Keys are compared with python equality, and only
+ * immutable objects can be used as a key.
+ */
+
+use super::builtins::check_arg_count;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::vm::VirtualMachine;
+
+pub fn new_dict() -> PyObjectRef {
+    PyObject::Dict { elements: vec![] }.into_ref()
+}
+
+pub fn get_method(name: &str) -> Option<RustPyFunc> {
+    match name {
+        "get" => Some(dict_get),
+        "items" => Some(dict_items),
+        "keys" => Some(dict_keys),
+        "values" => Some(dict_values),
+        _ => None,
+    }
+}
+
+// Mutable objects cannot be used as dictionary key or set element:
+pub fn check_hashable(vm: &mut VirtualMachine, key: &PyObjectRef) -> Result<(), PyObjectRef> {
+    let hashable = match *key.borrow() {
+        PyObject::List { .. } | PyObject::Dict { .. } | PyObject::Set { .. } => false,
+        PyObject::Tuple { ref elements } => {
+            for element in elements {
+                check_hashable(vm, element)?;
+            }
+            true
+        }
+        _ => true,
+    };
+    if hashable {
+        Ok(())
+    } else {
+        let msg = format!("unhashable type: '{}'", key.borrow().type_name());
+        Err(vm.new_exception("TypeError", msg))
+    }
+}
+
+// Find the position of an element equal to the given key:
+pub fn find(vm: &VirtualMachine, keys: &[&PyObjectRef], key: &PyObjectRef) -> Option<usize> {
+    keys.iter().position(|k| vm.equals(k, key))
+}
+
+pub fn get_item(
+    vm: &mut VirtualMachine,
+    dict: &PyObjectRef,
+    key: &PyObjectRef,
+) -> Result<Option<PyObjectRef>, PyObjectRef> {
+    check_hashable(vm, key)?;
+    match *dict.borrow() {
+        PyObject::Dict { ref elements } => {
+            let keys: Vec<&PyObjectRef> = elements.iter().map(|entry| &entry.0).collect();
+            Ok(find(vm, &keys, key).map(|position| elements[position].1.clone()))
+        }
+        _ => panic!("Expected a dict"),
+    }
+}
+
+pub fn set_item(
+    vm: &mut VirtualMachine,
+    dict: &PyObjectRef,
+    key: PyObjectRef,
+    value: PyObjectRef,
+) -> Result<(), PyObjectRef> {
+    check_hashable(vm, &key)?;
+    let position = match *dict.borrow() {
+        PyObject::Dict { ref elements } => {
+            let keys: Vec<&PyObjectRef> = elements.iter().map(|entry| &entry.0).collect();
+            find(vm, &keys, &key)
+        }
+        _ => panic!("Expected a dict"),
+    };
+    if let PyObject::Dict { ref mut elements } = *dict.borrow_mut() {
+        match position {
+            Some(position) => elements[position].1 = value,
+            None => elements.push((key, value)),
+        }
+    }
+    Ok(())
+}
+
+// Remove a key, returns whether it was present:
+pub fn del_item(
+    vm: &mut VirtualMachine,
+    dict: &PyObjectRef,
+    key: &PyObjectRef,
+) -> Result<bool, PyObjectRef> {
+    check_hashable(vm, key)?;
+    let position = match *dict.borrow() {
+        PyObject::Dict { ref elements } => {
+            let keys: Vec<&PyObjectRef> = elements.iter().map(|entry| &entry.0).collect();
+            find(vm, &keys, key)
+        }
+        _ => panic!("Expected a dict"),
+    };
+    match position {
+        Some(position) => {
+            if let PyObject::Dict { ref mut elements } = *dict.borrow_mut() {
+                elements.remove(position);
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn get_entries(dict: &PyObjectRef) -> Vec<(PyObjectRef, PyObjectRef)> {
+    match *dict.borrow() {
+        PyObject::Dict { ref elements } => elements.clone(),
+        _ => panic!("Expected a dict"),
+    }
+}
+
+// get(key[, default])
+fn dict_get(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "get", &args, 2, 3)?;
+    match get_item(vm, &args.args[0], &args.args[1])? {
+        Some(value) => Ok(value),
+        None => Ok(match args.args.get(2) {
+            Some(default) => default.clone(),
+            None => vm.get_none(),
+        }),
+    }
+}
+
+fn dict_items(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "items", &args, 1, 1)?;
+    let elements = get_entries(&args.args[0])
+        .into_iter()
+        .map(|(key, value)| {
+            PyObject::Tuple {
+                elements: vec![key, value],
+            }.into_ref()
+        })
+        .collect();
+    Ok(PyObject::List { elements }.into_ref())
+}
+
+fn dict_keys(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "keys", &args, 1, 1)?;
+    let elements = get_entries(&args.args[0]).into_iter().map(|entry| entry.0).collect();
+    Ok(PyObject::List { elements }.into_ref())
+}
+
+fn dict_values(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "values", &args, 1, 1)?;
+    let elements = get_entries(&args.args[0]).into_iter().map(|entry| entry.1).collect();
+    Ok(PyObject::List { elements }.into_ref())
+}
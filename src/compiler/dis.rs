@@ -0,0 +1,571 @@
+/*
+ * The disassembler, which lists the instructions of a code object with
+ * their arguments resolved to names and constants, and the dis module which
+ * gives python code access to it.
+ *
+ * The listing is laid out like that of CPython: the line number where a new
+ * line starts, `>>` at jump targets, the offset in words, the instruction,
+ * its argument and what the argument means. The code objects among the
+ * constants are listed after the code containing them.
+ */
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use super::builtins::check_arg_count;
+use super::bytecode::{
+    BinaryOperator, CodeObject, ComparisonOperator, Constant, Instruction, UnaryOperator,
+};
+use super::convert::bind_args;
+use super::import;
+use super::native::{new_native_instance, NativeModule, NativeType};
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult};
+use super::vm::VirtualMachine;
+
+// An instruction as it is listed:
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstructionInfo {
+    pub offset: usize,
+    pub instruction: Instruction,
+    pub arg: Option<usize>,
+    pub argrepr: String,
+    // The line, for the first instruction of a line:
+    pub starts_line: Option<usize>,
+    pub is_jump_target: bool,
+}
+
+impl InstructionInfo {
+    // A line of the listing. The widths of the columns are those of the
+    // largest line number and offset:
+    pub fn format(&self, line_width: usize, offset_width: usize) -> String {
+        let line = match self.starts_line {
+            Some(line) => line.to_string(),
+            None => String::new(),
+        };
+        let marker = if self.is_jump_target { ">>" } else { "  " };
+        let mut text = format!(
+            "{:>line_width$}     {} {:>offset_width$} {:<20}",
+            line,
+            marker,
+            self.offset,
+            self.instruction.name(),
+            line_width = line_width,
+            offset_width = offset_width
+        );
+        if let Some(arg) = self.arg {
+            text.push_str(&format!(" {:>5}", arg));
+        }
+        if !self.argrepr.is_empty() {
+            text.push_str(&format!(" ({})", self.argrepr));
+        }
+        text.trim_end().to_string()
+    }
+}
+
+pub fn get_instructions(code: &CodeObject) -> Result<Vec<InstructionInfo>, String> {
+    let instructions = code.instructions()?;
+    let targets: HashSet<usize> = instructions
+        .iter()
+        .filter_map(|(_, instruction)| instruction.jump_target())
+        .collect();
+    let locations = code.linetable.locations();
+    let mut line = None;
+    let mut infos = vec![];
+    for (offset, instruction) in instructions {
+        let row = locations.get(offset).map_or(code.firstlineno, |location| location.row);
+        let starts_line = if line == Some(row) { None } else { Some(row) };
+        line = Some(row);
+        infos.push(InstructionInfo {
+            offset,
+            instruction,
+            arg: instruction.argument_name().map(|_| instruction.argument()),
+            argrepr: argument_repr(code, &instruction),
+            starts_line,
+            is_jump_target: targets.contains(&offset),
+        });
+    }
+    Ok(infos)
+}
+
+// The listing of a code object, followed by those of its nested code:
+pub fn disassemble(code: &CodeObject) -> Result<String, String> {
+    let instructions = get_instructions(code)?;
+    let last_line = instructions.iter().filter_map(|info| info.starts_line).max();
+    let line_width = last_line.unwrap_or(0).to_string().len().max(3);
+    let last_offset = instructions.last().map_or(0, |info| info.offset);
+    let offset_width = last_offset.to_string().len().max(4);
+
+    let mut text = String::new();
+    for info in &instructions {
+        // Like CPython, lines are separated by an empty line:
+        if info.starts_line.is_some() && info.offset > 0 {
+            text.push('\n');
+        }
+        text.push_str(&info.format(line_width, offset_width));
+        text.push('\n');
+    }
+    for constant in &code.consts {
+        if let Constant::Code { ref code } = *constant {
+            text.push_str(&format!("\nDisassembly of {}:\n", code_repr(code)));
+            text.push_str(&disassemble(code)?);
+        }
+    }
+    Ok(text)
+}
+
+fn code_repr(code: &Rc<CodeObject>) -> String {
+    PyObject::Code { code: code.clone() }.repr()
+}
+
+// What the argument of an instruction means. Arguments outside the tables
+// are left unexplained, code which is not compiled here may have them.
+pub fn argument_repr(code: &CodeObject, instruction: &Instruction) -> String {
+    let entry = |table: &[String], index: usize| table.get(index).cloned().unwrap_or_default();
+    match *instruction {
+        Instruction::LoadConst { consti } => match code.consts.get(consti) {
+            Some(constant) => constant_repr(constant),
+            None => String::new(),
+        },
+        Instruction::LoadName { namei }
+        | Instruction::StoreName { namei }
+        | Instruction::DeleteName { namei }
+        | Instruction::LoadGlobal { namei }
+        | Instruction::StoreGlobal { namei }
+        | Instruction::DeleteGlobal { namei }
+        | Instruction::LoadAttr { namei }
+        | Instruction::StoreAttr { namei }
+        | Instruction::DeleteAttr { namei }
+        | Instruction::ImportName { namei }
+        | Instruction::ImportFrom { namei } => entry(&code.names, namei),
+        Instruction::LoadFast { var_num }
+        | Instruction::StoreFast { var_num }
+        | Instruction::DeleteFast { var_num } => entry(&code.varnames, var_num),
+        Instruction::LoadDeref { i }
+        | Instruction::StoreDeref { i }
+        | Instruction::DeleteDeref { i }
+        | Instruction::LoadClosure { i } => {
+            if i < code.cellvars.len() + code.freevars.len() {
+                code.deref_name(i).to_string()
+            } else {
+                String::new()
+            }
+        }
+        Instruction::UnaryOperation { op } => String::from(unary_symbol(op)),
+        Instruction::BinaryOperation { op } => String::from(binary_symbol(op)),
+        Instruction::InplaceOperation { op } => format!("{}=", binary_symbol(op)),
+        Instruction::CompareOperation { op } => String::from(comparison_symbol(op)),
+        Instruction::MakeFunction { has_defaults: true }
+        | Instruction::MakeClosure { has_defaults: true } => String::from("defaults"),
+        _ => match instruction.jump_target() {
+            Some(target) => format!("to {}", target),
+            None => String::new(),
+        },
+    }
+}
+
+fn constant_repr(constant: &Constant) -> String {
+    let value = match *constant {
+        Constant::None => PyObject::None,
+        Constant::Ellipsis => PyObject::Ellipsis,
+        Constant::Boolean { value } => PyObject::Boolean { value },
+        Constant::Integer { value } => PyObject::Integer { value },
+        Constant::Float { value } => PyObject::Float { value },
+        Constant::Complex { real, imag } => PyObject::Complex { real, imag },
+        Constant::String { ref value } => PyObject::String {
+            value: value.clone(),
+        },
+        Constant::Bytes { ref value } => PyObject::Bytes {
+            value: value.clone(),
+        },
+        Constant::Tuple { ref elements } => {
+            let parts: Vec<String> = elements.iter().map(constant_repr).collect();
+            return if parts.len() == 1 {
+                format!("({},)", parts[0])
+            } else {
+                format!("({})", parts.join(", "))
+            };
+        }
+        Constant::FrozenSet { ref elements } => {
+            let parts: Vec<String> = elements.iter().map(constant_repr).collect();
+            return if parts.is_empty() {
+                String::from("frozenset()")
+            } else {
+                format!("frozenset({{{}}})", parts.join(", "))
+            };
+        }
+        Constant::Code { ref code } => return code_repr(code),
+    };
+    value.repr()
+}
+
+pub fn unary_symbol(op: UnaryOperator) -> &'static str {
+    match op {
+        UnaryOperator::Not => "not",
+        UnaryOperator::Invert => "~",
+        UnaryOperator::Minus => "-",
+        UnaryOperator::Plus => "+",
+    }
+}
+
+pub fn binary_symbol(op: BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Power => "**",
+        BinaryOperator::Multiply => "*",
+        BinaryOperator::MatrixMultiply => "@",
+        BinaryOperator::Divide => "/",
+        BinaryOperator::FloorDivide => "//",
+        BinaryOperator::Modulo => "%",
+        BinaryOperator::Add => "+",
+        BinaryOperator::Subtract => "-",
+        BinaryOperator::Subscript => "[]",
+        BinaryOperator::Lshift => "<<",
+        BinaryOperator::Rshift => ">>",
+        BinaryOperator::And => "&",
+        BinaryOperator::Xor => "^",
+        BinaryOperator::Or => "|",
+    }
+}
+
+pub fn comparison_symbol(op: ComparisonOperator) -> &'static str {
+    match op {
+        ComparisonOperator::Greater => ">",
+        ComparisonOperator::GreaterOrEqual => ">=",
+        ComparisonOperator::Less => "<",
+        ComparisonOperator::LessOrEqual => "<=",
+        ComparisonOperator::Equal => "==",
+        ComparisonOperator::NotEqual => "!=",
+        ComparisonOperator::In => "in",
+        ComparisonOperator::NotIn => "not in",
+        ComparisonOperator::Is => "is",
+        ComparisonOperator::IsNot => "is not",
+        ComparisonOperator::ExceptionMatch => "exception match",
+    }
+}
+
+pub fn make_module() -> NativeModule {
+    // The names of the opcodes, unused ones are shown by number:
+    let mut opname = vec![];
+    let mut opmap = vec![];
+    for opcode in 0..=255u8 {
+        let name = match Instruction::from_opcode(opcode, 0) {
+            Some(instruction) => {
+                let name = new_string(instruction.name());
+                opmap.push((name.clone(), new_int(opcode as usize)));
+                name
+            }
+            None => new_string(&format!("<{}>", opcode)),
+        };
+        opname.push(name);
+    }
+    NativeModule::new("dis")
+        .doc("Disassembler of rspython bytecode.")
+        .function("dis", dis_dis)
+        .function("get_instructions", dis_get_instructions)
+        .constant("opname", PyObject::List { elements: opname }.into_ref())
+        .constant("opmap", PyObject::Dict { elements: opmap }.into_ref())
+        .native_type(instruction_type())
+        .native_type(bytecode_type())
+}
+
+fn new_string(value: &str) -> PyObjectRef {
+    PyObject::String {
+        value: String::from(value),
+    }.into_ref()
+}
+
+fn new_int(value: usize) -> PyObjectRef {
+    PyObject::Integer {
+        value: value as i32,
+    }.into_ref()
+}
+
+// The code to disassemble: a code object, the code of a function or method,
+// or source code which is compiled.
+fn code_of(vm: &mut VirtualMachine, x: &PyObjectRef) -> PyResult<Rc<CodeObject>> {
+    let source = match *x.borrow() {
+        PyObject::Code { ref code } | PyObject::Function { ref code, .. } => {
+            return Ok(code.clone())
+        }
+        PyObject::String { ref value } => Some(value.clone()),
+        _ => None,
+    };
+    if let Some(source) = source {
+        return Ok(Rc::new(vm.compile_source(&source, "<dis>")?));
+    }
+    let function = match *x.borrow() {
+        PyObject::BoundMethod { ref function, .. } => Some(function.clone()),
+        _ => None,
+    };
+    match function {
+        Some(function) => code_of(vm, &function),
+        None => {
+            let msg = format!("don't know how to disassemble {} objects", x.borrow().type_name());
+            Err(vm.new_exception("TypeError", msg))
+        }
+    }
+}
+
+fn listing(vm: &mut VirtualMachine, code: &CodeObject) -> PyResult<String> {
+    disassemble(code).map_err(|msg| vm.new_exception("ValueError", msg))
+}
+
+// The listing of anything dis() takes. Classes and modules are shown by the
+// listings of their functions and classes, sorted by name.
+fn listing_of(vm: &mut VirtualMachine, x: &PyObjectRef) -> PyResult<String> {
+    let mut members: Vec<(String, PyObjectRef)> = match *x.borrow() {
+        PyObject::Class { ref dict, .. } => dict
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        PyObject::Module { ref scope, .. } => scope
+            .borrow()
+            .locals
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        _ => {
+            let code = code_of(vm, x)?;
+            return listing(vm, &code);
+        }
+    };
+    members.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut text = String::new();
+    for (name, value) in members {
+        let has_code = matches!(
+            *value.borrow(),
+            PyObject::Code { .. } | PyObject::Function { .. } | PyObject::Class { .. }
+        );
+        if has_code {
+            text.push_str(&format!("Disassembly of {}:\n", name));
+            text.push_str(&listing_of(vm, &value)?);
+            text.push('\n');
+        }
+    }
+    Ok(text)
+}
+
+// dis(x, file=None) prints the listing, or writes it to the file:
+fn dis_dis(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let values = bind_args(vm, "dis", &["x", "file"], args)?;
+    let x = match values[0] {
+        Some(ref x) => x.clone(),
+        None => {
+            let msg = String::from("dis() missing required argument: 'x'");
+            return Err(vm.new_exception("TypeError", msg));
+        }
+    };
+    let text = listing_of(vm, &x)?;
+    match values[1] {
+        Some(ref file) if !matches!(*file.borrow(), PyObject::None) => {
+            vm.call_method(file.clone(), "write", vec![new_string(&text)])?;
+        }
+        _ => {
+            print!("{}", text);
+            io::stdout().flush().unwrap();
+        }
+    }
+    Ok(vm.get_none())
+}
+
+// get_instructions(x) gives an iterator over the instructions:
+fn dis_get_instructions(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "get_instructions", &args, 1, 1)?;
+    let code = code_of(vm, &args.args[0])?;
+    let instructions = instruction_objects(vm, &code)?;
+    vm.get_iter(instructions)
+}
+
+// The Instruction objects of the code, as a list:
+fn instruction_objects(vm: &mut VirtualMachine, code: &CodeObject) -> PyResult {
+    let infos = get_instructions(code).map_err(|msg| vm.new_exception("ValueError", msg))?;
+    let module = import::import_module(vm, "dis")?;
+    let class = vm.get_attribute(module, "Instruction")?;
+    let elements = infos
+        .into_iter()
+        .map(|info| {
+            let argval = argument_value(vm, code, &info);
+            new_native_instance(&class, InstructionValue { info, argval })
+        })
+        .collect();
+    Ok(PyObject::List { elements }.into_ref())
+}
+
+// The argument as a python value: the constant, the jump target, or the
+// name or operator as a string. Counts are the number itself.
+fn argument_value(vm: &VirtualMachine, code: &CodeObject, info: &InstructionInfo) -> PyObjectRef {
+    match info.instruction {
+        Instruction::LoadConst { consti } if consti < code.consts.len() => {
+            return vm.unwrap_constant(&code.consts[consti])
+        }
+        Instruction::MakeFunction { has_defaults } | Instruction::MakeClosure { has_defaults } => {
+            return vm.new_bool(has_defaults)
+        }
+        _ => {}
+    }
+    match (info.instruction.jump_target(), info.arg) {
+        (Some(target), _) => new_int(target),
+        (None, Some(_)) if !info.argrepr.is_empty() => new_string(&info.argrepr),
+        (None, Some(arg)) => new_int(arg),
+        (None, None) => vm.get_none(),
+    }
+}
+
+struct InstructionValue {
+    info: InstructionInfo,
+    argval: PyObjectRef,
+}
+
+fn instruction_type() -> NativeType {
+    NativeType::new("Instruction")
+        .doc("An instruction, with its argument resolved.")
+        .attribute("opname", |value: &InstructionValue| {
+            String::from(value.info.instruction.name())
+        })
+        .attribute("opcode", |value: &InstructionValue| {
+            i32::from(value.info.instruction.opcode())
+        })
+        .attribute("arg", |value: &InstructionValue| {
+            value.info.arg.map(|arg| arg as i32)
+        })
+        .attribute("argval", |value: &InstructionValue| value.argval.clone())
+        .attribute("argrepr", |value: &InstructionValue| value.info.argrepr.clone())
+        .attribute("offset", |value: &InstructionValue| value.info.offset as i32)
+        .attribute("starts_line", |value: &InstructionValue| {
+            value.info.starts_line.map(|line| line as i32)
+        })
+        .attribute("is_jump_target", |value: &InstructionValue| {
+            value.info.is_jump_target
+        })
+        .method("__repr__", |vm, value: &mut InstructionValue, args| {
+            check_arg_count(vm, "__repr__", &args, 0, 0)?;
+            let argval = vm.repr_of(value.argval.clone())?;
+            let info = &value.info;
+            let optional = |value: Option<usize>| match value {
+                Some(value) => value.to_string(),
+                None => String::from("None"),
+            };
+            let text = format!(
+                "Instruction(opname={}, opcode={}, arg={}, argval={}, argrepr={}, offset={}, starts_line={}, is_jump_target={})",
+                new_string(info.instruction.name()).borrow().repr(),
+                info.instruction.opcode(),
+                optional(info.arg),
+                argval,
+                new_string(&info.argrepr).borrow().repr(),
+                info.offset,
+                optional(info.starts_line),
+                if info.is_jump_target { "True" } else { "False" }
+            );
+            Ok(new_string(&text))
+        })
+}
+
+// Bytecode(x) iterates over the instructions of the code of x:
+struct Bytecode {
+    code: Rc<CodeObject>,
+}
+
+fn bytecode_type() -> NativeType {
+    NativeType::new("Bytecode")
+        .doc("The instructions of a code object, iterating gives Instructions.")
+        .init(|vm, args| {
+            check_arg_count(vm, "Bytecode", &args, 1, 1)?;
+            let code = code_of(vm, &args.args[0])?;
+            Ok(Bytecode { code })
+        })
+        .attribute("codeobj", |bytecode: &Bytecode| {
+            PyObject::Code {
+                code: bytecode.code.clone(),
+            }.into_ref()
+        })
+        .attribute("first_line", |bytecode: &Bytecode| bytecode.code.firstlineno as i32)
+        .method("dis", |vm, bytecode: &mut Bytecode, args| {
+            check_arg_count(vm, "dis", &args, 0, 0)?;
+            let text = listing(vm, &bytecode.code)?;
+            Ok(new_string(&text))
+        })
+        .method("__iter__", |vm, bytecode: &mut Bytecode, args| {
+            check_arg_count(vm, "__iter__", &args, 0, 0)?;
+            let instructions = instruction_objects(vm, &bytecode.code)?;
+            vm.get_iter(instructions)
+        })
+        .method("__repr__", |vm, bytecode: &mut Bytecode, args| {
+            check_arg_count(vm, "__repr__", &args, 0, 0)?;
+            Ok(new_string(&format!("Bytecode({})", code_repr(&bytecode.code))))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compile::compile;
+    use super::super::frame::Scope;
+    use super::super::parser::parse_source;
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let source = "
+x = 1
+while x < 300:
+    x += 2
+def f(a, b=25):
+    return a.y
+";
+        let code = compile(parse_source(source).unwrap()).unwrap();
+        let text = disassemble(&code).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "  2           0 LoadConst                0 (1)");
+        assert_eq!(lines[1], "              1 StoreName                0 (x)");
+        assert_eq!(lines[2], "");
+        assert_eq!(lines[3], "  3           2 PushBlock               13 (to 13)");
+        assert_eq!(lines[4], "        >>    3 LoadName                 0 (x)");
+        assert!(lines.contains(&"              6 JumpIfFalse             12 (to 12)"));
+        assert!(lines.contains(&"              9 InplaceOperation         6 (+=)"));
+        assert!(lines.contains(&"  3     >>   12 PopBlock"));
+        assert!(lines.contains(&"             16 MakeFunction             1 (defaults)"));
+        assert!(lines.contains(&"Disassembly of <code object f, file \"<string>\", line 5>:"));
+        assert!(lines.contains(&"  6           0 LoadFast                 0 (a)"));
+        assert!(lines.contains(&"              1 LoadAttr                 0 (y)"));
+        assert_eq!(lines.last(), Some(&"              4 ReturnValue"));
+    }
+
+    #[test]
+    fn test_dis_module() {
+        let source = "
+import dis
+class Writer:
+    def __init__(self):
+        self.text = ''
+    def write(self, text):
+        self.text = self.text + text
+def g(n):
+    return [n, None]
+out = Writer()
+dis.dis(g, file=out)
+bytecode = dis.Bytecode(g)
+names = [i.opname for i in bytecode]
+first = next(dis.get_instructions('y = 3'))
+x = [out.text == bytecode.dis(), names, first.argval, first.starts_line, first.offset,
+     dis.opname[first.opcode], dis.opmap['ReturnValue'], dis.Bytecode(g.__code__).first_line,
+     dis.Bytecode(compile('z = 1', 'z.py', 'exec')).codeobj.co_filename]
+";
+        let mut vm = VirtualMachine::new();
+        let code = compile(parse_source(source).unwrap()).unwrap();
+        let scope = Scope::new(None);
+        if let Err(exception) = vm.run_code(code, scope.clone()) {
+            vm.print_exception(&exception);
+            panic!("Program raised an exception");
+        }
+        let x = scope.borrow().get("x").unwrap();
+        assert_eq!(
+            x.borrow().repr(),
+            "[True, ['LoadFast', 'LoadConst', 'BuildList', 'ReturnValue', 'LoadConst', 'ReturnValue'], 3, 1, 0, 'LoadConst', 46, 8, 'z.py']"
+        );
+        let first = scope.borrow().get("first").unwrap();
+        assert_eq!(
+            vm.repr_of(first).unwrap(),
+            "Instruction(opname='LoadConst', opcode=16, arg=0, argval=3, argrepr='3', offset=0, starts_line=1, is_jump_target=False)"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * The builtin exception classes and their hierarchy.
+ *
+ * See also:
+ * https://docs.python.org/3/library/exceptions.html#exception-hierarchy
+ */
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::vm::VirtualMachine;
+
+pub fn fill_scope(scope: &mut HashMap<String, PyObjectRef>) {
+    let base_exception = new_class(
+        "BaseException",
+        vec![],
+        vec![
+            ("__init__", exception_init),
+            ("__str__", exception_str),
+            ("__repr__", exception_repr),
+        ],
+    );
+    scope.insert(String::from("BaseException"), base_exception.clone());
+
+    let mut add = |name: &str, base: &str, methods: Vec<(&str, RustPyFunc)>| {
+        let class = new_class(name, vec![scope[base].clone()], methods);
+        scope.insert(String::from(name), class);
+    };
+    add("Exception", "BaseException", vec![]);
+    add("GeneratorExit", "BaseException", vec![]);
+    add("SystemExit", "BaseException", vec![("__init__", system_exit_init)]);
+    add("StopIteration", "Exception", vec![("__init__", stop_iteration_init)]);
+    add("ArithmeticError", "Exception", vec![]);
+    add("AssertionError", "Exception", vec![]);
+    add("OverflowError", "ArithmeticError", vec![]);
+    add("ZeroDivisionError", "ArithmeticError", vec![]);
+    add("AttributeError", "Exception", vec![]);
+    add("ImportError", "Exception", vec![]);
+    add("ModuleNotFoundError", "ImportError", vec![]);
+    add("LookupError", "Exception", vec![]);
+    add("IndexError", "LookupError", vec![]);
+    add("KeyError", "LookupError", vec![("__str__", key_error_str)]);
+    add("NameError", "Exception", vec![]);
+    add("UnboundLocalError", "NameError", vec![]);
+    add("OSError", "Exception", vec![]);
+    add("FileNotFoundError", "OSError", vec![]);
+    add("RuntimeError", "Exception", vec![]);
+    add("SyntaxError", "Exception", vec![]);
+    add("NotImplementedError", "RuntimeError", vec![]);
+    add("TypeError", "Exception", vec![]);
+    add("ValueError", "Exception", vec![]);
+}
+
+pub fn new_class(name: &str, bases: Vec<PyObjectRef>, methods: Vec<(&str, RustPyFunc)>) -> PyObjectRef {
+    let mut dict = HashMap::new();
+    for (method_name, function) in methods {
+        dict.insert(
+            String::from(method_name),
+            PyObject::RustFunction {
+                function: Rc::new(function),
+                doc: None,
+            }.into_ref(),
+        );
+    }
+    PyObject::Class {
+        name: String::from(name),
+        dict,
+        bases,
+    }.into_ref()
+}
+
+fn set_attr(obj: &PyObjectRef, name: &str, value: PyObjectRef) {
+    match *obj.borrow_mut() {
+        PyObject::Instance { ref mut dict, .. } => {
+            dict.insert(String::from(name), value);
+        }
+        _ => panic!("Exception attributes can only be set on instances"),
+    }
+}
+
+fn exception_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let exception_args = PyObject::Tuple {
+        elements: args.args[1..].to_vec(),
+    }.into_ref();
+    set_attr(&args.args[0], "args", exception_args);
+    set_attr(&args.args[0], "__traceback__", vm.get_none());
+    Ok(vm.get_none())
+}
+
+// The value attribute holds the return value of a finished generator:
+fn stop_iteration_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let value = match args.args.get(1) {
+        Some(value) => value.clone(),
+        None => vm.get_none(),
+    };
+    set_attr(&args.args[0], "value", value);
+    exception_init(vm, args)
+}
+
+// The code attribute holds the exit status, or a message:
+fn system_exit_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let code = match args.args.len() {
+        1 => vm.get_none(),
+        2 => args.args[1].clone(),
+        _ => PyObject::Tuple {
+            elements: args.args[1..].to_vec(),
+        }.into_ref(),
+    };
+    set_attr(&args.args[0], "code", code);
+    exception_init(vm, args)
+}
+
+fn exception_args(vm: &mut VirtualMachine, exception: &PyObjectRef) -> Result<Vec<PyObjectRef>, PyObjectRef> {
+    let args = vm.get_attribute(exception.clone(), "args")?;
+    vm.extract_elements(&args)
+}
+
+fn exception_str(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let exception_args = exception_args(vm, &args.args[0])?;
+    let value = match exception_args.len() {
+        0 => String::new(),
+        1 => vm.str_of(exception_args[0].clone())?,
+        _ => {
+            let parts = exception_args
+                .into_iter()
+                .map(|arg| vm.repr_of(arg))
+                .collect::<Result<Vec<String>, PyObjectRef>>()?;
+            format!("({})", parts.join(", "))
+        }
+    };
+    Ok(PyObject::String { value }.into_ref())
+}
+
+// A missing key is shown by its repr, which keeps an empty string visible:
+fn key_error_str(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let exception_args = exception_args(vm, &args.args[0])?;
+    if exception_args.len() != 1 {
+        return exception_str(vm, args);
+    }
+    let value = vm.repr_of(exception_args[0].clone())?;
+    Ok(PyObject::String { value }.into_ref())
+}
+
+fn exception_repr(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let exception_args = exception_args(vm, &args.args[0])?;
+    let parts = exception_args
+        .into_iter()
+        .map(|arg| vm.repr_of(arg))
+        .collect::<Result<Vec<String>, PyObjectRef>>()?;
+    let value = format!("{}({})", args.args[0].borrow().type_name(), parts.join(", "));
+    Ok(PyObject::String { value }.into_ref())
+}
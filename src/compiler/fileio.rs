@@ -0,0 +1,181 @@
+/*
+ * The io module, with a minimal file object which is also the builtin open.
+ * The instance holds the open file as its native value.
+ */
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+
+use super::builtins::{check_arg_count, check_positional_count};
+use super::import;
+use super::native::{with_payload, NativeModule, NativeType};
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult};
+use super::vm::VirtualMachine;
+
+pub fn make_module() -> NativeModule {
+    NativeModule::new("io")
+        .constant(
+            "DEFAULT_BUFFER_SIZE",
+            PyObject::Integer { value: 8192 }.into_ref(),
+        )
+        .exception("UnsupportedOperation", vec!["OSError", "ValueError"])
+        .native_type(
+            NativeType::new("TextIOWrapper")
+                .init(file_init)
+                .attribute("name", |file: &File| file.name.clone())
+                .attribute("mode", |file: &File| file.mode.clone())
+                .attribute("closed", |file: &File| file.handle.is_none())
+                .function("__enter__", file_enter)
+                .method("__exit__", file_exit)
+                .method("close", file_close)
+                .method("read", file_read)
+                .method("write", file_write),
+        )
+}
+
+// The rust value of a file object:
+struct File {
+    name: String,
+    mode: String,
+    // Dropped when the file is closed:
+    handle: Option<fs::File>,
+}
+
+impl File {
+    // The open file, for an operation which the mode allows:
+    fn handle(
+        &mut self,
+        vm: &mut VirtualMachine,
+        allowed: bool,
+        operation: &str,
+    ) -> PyResult<&mut fs::File> {
+        let handle = match self.handle {
+            Some(ref mut handle) => handle,
+            None => {
+                let msg = String::from("I/O operation on closed file.");
+                return Err(vm.new_exception("ValueError", msg));
+            }
+        };
+        if !allowed {
+            return Err(unsupported_operation(vm, String::from(operation)));
+        }
+        Ok(handle)
+    }
+}
+
+fn unsupported_operation(vm: &mut VirtualMachine, msg: String) -> PyObjectRef {
+    let io = match import::import_module(vm, "io") {
+        Ok(io) => io,
+        Err(exception) => return exception,
+    };
+    let class = match vm.get_attribute(io, "UnsupportedOperation") {
+        Ok(class) => class,
+        Err(exception) => return exception,
+    };
+    let msg = PyObject::String { value: msg }.into_ref();
+    match vm.invoke(class, vec![msg]) {
+        Ok(exception) => exception,
+        Err(exception) => exception,
+    }
+}
+
+fn string_arg(vm: &mut VirtualMachine, name: &str, obj: &PyObjectRef) -> Result<String, PyObjectRef> {
+    match *obj.borrow() {
+        PyObject::String { ref value } => Ok(value.clone()),
+        _ => {
+            let msg = format!("{} must be str, not {}", name, obj.borrow().type_name());
+            Err(vm.new_exception("TypeError", msg))
+        }
+    }
+}
+
+fn io_error(vm: &mut VirtualMachine, error: io::Error, filename: &str) -> PyObjectRef {
+    let name = match error.kind() {
+        io::ErrorKind::NotFound => "FileNotFoundError",
+        _ => "OSError",
+    };
+    let msg = match error.raw_os_error() {
+        Some(code) => {
+            // Leave out the "(os error 2)" which Rust appends:
+            let text = error.to_string();
+            let description = text.split(" (os error").next().unwrap_or("");
+            format!("[Errno {}] {}: '{}'", code, description, filename)
+        }
+        None => format!("{}: '{}'", error, filename),
+    };
+    vm.new_exception(name, msg)
+}
+
+// open(file, mode='r')
+fn file_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult<File> {
+    check_positional_count(vm, "open", &args, 1, 2)?;
+    let name = string_arg(vm, "file", &args.args[0])?;
+    let mode = match args.args.get(1).cloned().or_else(|| args.get_kwarg("mode")) {
+        Some(mode) => string_arg(vm, "mode", &mode)?,
+        None => String::from("r"),
+    };
+
+    let result = match mode.as_str() {
+        "r" => fs::File::open(&name),
+        "w" => fs::File::create(&name),
+        "a" => OpenOptions::new().create(true).append(true).open(&name),
+        _ => {
+            let msg = format!("invalid mode: '{}'", mode);
+            return Err(vm.new_exception("ValueError", msg));
+        }
+    };
+    match result {
+        Ok(handle) => Ok(File {
+            name,
+            mode,
+            handle: Some(handle),
+        }),
+        Err(error) => Err(io_error(vm, error, &name)),
+    }
+}
+
+fn file_enter(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "__enter__", &args, 1, 1)?;
+    with_payload(vm, &args.args[0], |vm, file: &mut File| {
+        file.handle(vm, true, "__enter__").map(|_| ())
+    })?;
+    Ok(args.args[0].clone())
+}
+
+fn file_exit(vm: &mut VirtualMachine, file: &mut File, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "__exit__", &args, 3, 3)?;
+    file.handle = None;
+    // Exceptions are not suppressed:
+    Ok(vm.new_bool(false))
+}
+
+fn file_close(vm: &mut VirtualMachine, file: &mut File, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "close", &args, 0, 0)?;
+    file.handle = None;
+    Ok(vm.get_none())
+}
+
+// Read the rest of the file:
+fn file_read(vm: &mut VirtualMachine, file: &mut File, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "read", &args, 0, 0)?;
+    let readable = file.mode == "r";
+    let mut content = String::new();
+    let result = file.handle(vm, readable, "not readable")?.read_to_string(&mut content);
+    if let Err(error) = result {
+        return Err(io_error(vm, error, &file.name));
+    }
+    Ok(PyObject::String { value: content }.into_ref())
+}
+
+fn file_write(vm: &mut VirtualMachine, file: &mut File, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "write", &args, 1, 1)?;
+    let text = string_arg(vm, "write() argument", &args.args[0])?;
+    let writable = file.mode != "r";
+    let result = file.handle(vm, writable, "not writable")?.write_all(text.as_bytes());
+    if let Err(error) = result {
+        return Err(io_error(vm, error, &file.name));
+    }
+    Ok(PyObject::Integer {
+        value: text.chars().count() as i32,
+    }.into_ref())
+}
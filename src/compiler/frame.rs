@@ -0,0 +1,1068 @@
+/*
+ * A frame holds the execution state of a code object: the value stack, the
+ * block stack and the position of the next instruction. Frames of generator
+ * functions outlive a single call, and are resumed later on.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use super::bytecode;
+use super::dict;
+use super::import;
+use super::list;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef};
+use super::set;
+use super::vm::VirtualMachine;
+
+/*
+ * A scope holds the names bound in a code block. Names not found in a scope
+ * are looked up in the enclosing scope, and finally in the builtins.
+ */
+pub struct Scope {
+    pub locals: HashMap<String, PyObjectRef>,
+    pub parent: Option<ScopeRef>,
+}
+
+pub type ScopeRef = Rc<RefCell<Scope>>;
+
+impl Scope {
+    pub fn new(parent: Option<ScopeRef>) -> ScopeRef {
+        Rc::new(RefCell::new(Scope {
+            locals: HashMap::new(),
+            parent,
+        }))
+    }
+
+    // The module level scope, at the end of the chain:
+    pub fn globals(scope: &ScopeRef) -> ScopeRef {
+        let mut scope = scope.clone();
+        loop {
+            let parent = match scope.borrow().parent {
+                Some(ref parent) => parent.clone(),
+                None => break,
+            };
+            scope = parent;
+        }
+        scope
+    }
+
+    pub fn get(&self, name: &str) -> Option<PyObjectRef> {
+        match self.locals.get(name) {
+            Some(value) => Some(value.clone()),
+            None => match self.parent {
+                Some(ref parent) => parent.borrow().get(name),
+                None => None,
+            },
+        }
+    }
+}
+
+// Print only the names, scopes usually contain functions referring to
+// the scope itself:
+impl fmt::Debug for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&String> = self.locals.keys().collect();
+        write!(f, "Scope {:?}", names)
+    }
+}
+
+#[derive(Debug)]
+enum BlockType {
+    Loop {
+        end: bytecode::Label,
+    },
+    TryExcept {
+        handler: bytecode::Label,
+    },
+    Finally {
+        handler: bytecode::Label,
+    },
+    // Active while the finally clause runs, and remembers what to do after it:
+    FinallyHandler {
+        reason: Option<UnwindReason>,
+    },
+    // Active while an except clause runs, so that `raise` can re-raise:
+    ExceptHandler {
+        exception: PyObjectRef,
+    },
+}
+
+#[derive(Debug)]
+struct Block {
+    typ: BlockType,
+    // The size of the value stack when the block was entered:
+    level: usize,
+}
+
+// The reason why the block stack is being unwound:
+#[derive(Debug)]
+enum UnwindReason {
+    Returning { value: PyObjectRef },
+    Raising { exception: PyObjectRef },
+    Break,
+    Continue { target: bytecode::Label },
+}
+
+#[derive(Debug)]
+pub enum ExecutionResult {
+    Return(PyObjectRef),
+    Yield(PyObjectRef),
+}
+
+// Ok(None) means execution continues in this frame.
+type FrameResult = Result<Option<ExecutionResult>, PyObjectRef>;
+
+#[derive(Debug)]
+pub struct Frame {
+    pub code: Rc<bytecode::CodeObject>,
+    stack: Vec<PyObjectRef>,
+    blocks: Vec<Block>,
+    pub scope: ScopeRef,
+    // The cells of the cell variables of the code, followed by those of
+    // its free variables:
+    cells: Vec<PyObjectRef>,
+    lasti: usize,
+    // The local trace function, the position and line of the last traced
+    // instruction, and where its line was found in the line table:
+    tracer: Option<PyObjectRef>,
+    traced_line: Option<(usize, usize)>,
+    line_cursor: bytecode::LineCursor,
+}
+
+/*
+ * The virtual machine keeps a stack of the frames which are running, for
+ * tracebacks, `sys._getframe()` and the trace function.
+ */
+pub struct FrameInfo {
+    pub code: Rc<bytecode::CodeObject>,
+    pub scope: ScopeRef,
+    pub lasti: usize,
+    // The frame object, once python code asked for it:
+    pub object: Option<PyObjectRef>,
+}
+
+impl FrameInfo {
+    pub fn lineno(&self) -> usize {
+        line_of(&self.code, self.lasti)
+    }
+}
+
+// The line of the instruction before `lasti`, which is the one executing:
+fn line_of(code: &bytecode::CodeObject, lasti: usize) -> usize {
+    if lasti == 0 {
+        code.firstlineno
+    } else {
+        code.line_number(lasti - 1)
+    }
+}
+
+impl Frame {
+    pub fn new(code: Rc<bytecode::CodeObject>, scope: ScopeRef) -> Frame {
+        Frame::with_closure(code, scope, vec![])
+    }
+
+    // Create the frame of a function, the closure holds the cells of the
+    // free variables. Arguments which are cell variables are moved into
+    // their cell.
+    pub fn with_closure(
+        code: Rc<bytecode::CodeObject>,
+        scope: ScopeRef,
+        closure: Vec<PyObjectRef>,
+    ) -> Frame {
+        let mut cells = vec![];
+        for name in &code.cellvars {
+            let value = scope.borrow_mut().locals.remove(name);
+            cells.push(PyObject::Cell { value }.into_ref());
+        }
+        cells.extend(closure);
+        Frame {
+            code,
+            stack: Vec::new(),
+            blocks: Vec::new(),
+            scope,
+            cells,
+            lasti: 0,
+            tracer: None,
+            traced_line: None,
+            line_cursor: bytecode::LineCursor::new(),
+        }
+    }
+
+    // Did this frame execute any instruction yet?
+    pub fn is_started(&self) -> bool {
+        self.lasti > 0
+    }
+
+    // Run until the code returns or yields a value.
+    pub fn run(&mut self, vm: &mut VirtualMachine) -> Result<ExecutionResult, PyObjectRef> {
+        self.enter(vm, |frame, vm| frame.run_frame(vm))
+    }
+
+    // Resume a suspended frame by raising the given exception at the point
+    // where it was suspended.
+    pub fn throw(
+        &mut self,
+        vm: &mut VirtualMachine,
+        exception: PyObjectRef,
+    ) -> Result<ExecutionResult, PyObjectRef> {
+        self.enter(vm, |frame, vm| frame.throw_frame(vm, exception))
+    }
+
+    // Run with this frame on the stack of running frames. The trace
+    // function sees the frame being entered and left.
+    fn enter<F>(&mut self, vm: &mut VirtualMachine, f: F) -> Result<ExecutionResult, PyObjectRef>
+    where
+        F: FnOnce(&mut Frame, &mut VirtualMachine) -> Result<ExecutionResult, PyObjectRef>,
+    {
+        vm.frames.push(FrameInfo {
+            code: self.code.clone(),
+            scope: self.scope.clone(),
+            lasti: self.lasti,
+            object: None,
+        });
+        let result = match self.trace_call(vm) {
+            Ok(()) => f(self, vm),
+            Err(exception) => Err(exception),
+        };
+        let result = self.trace_return(vm, result);
+        vm.frames.pop();
+        result
+    }
+
+    fn run_frame(&mut self, vm: &mut VirtualMachine) -> Result<ExecutionResult, PyObjectRef> {
+        // Process instruction at the program counter until exception or finished.
+        loop {
+            let result = match self.trace_line(vm) {
+                Ok(()) => self.execute_instruction(vm),
+                Err(exception) => Err(exception),
+            };
+            let result = match result {
+                Err(exception) => {
+                    if !self.is_reraise() {
+                        self.add_traceback(vm, &exception);
+                    }
+                    self.unwind(UnwindReason::Raising { exception })
+                }
+                result => result,
+            };
+            if let Some(value) = result? {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn throw_frame(
+        &mut self,
+        vm: &mut VirtualMachine,
+        exception: PyObjectRef,
+    ) -> Result<ExecutionResult, PyObjectRef> {
+        let exception = match self.throw_into_delegate(vm, exception) {
+            Ok(Some(result)) => return Ok(result),
+            Ok(None) => return self.run_frame(vm),
+            Err(exception) => exception,
+        };
+        self.add_traceback(vm, &exception);
+        match self.unwind(UnwindReason::Raising { exception })? {
+            Some(result) => Ok(result),
+            None => self.run_frame(vm),
+        }
+    }
+
+    // An exception which is raised again keeps its traceback:
+    fn is_reraise(&self) -> bool {
+        if self.lasti == 0 {
+            return false;
+        }
+        // Both instructions fit in their last word:
+        match self.code.instruction_at(self.lasti - 1) {
+            Ok(bytecode::Instruction::Raise { argc: 0 }) => self.current_exception().is_some(),
+            Ok(bytecode::Instruction::EndFinally) => true,
+            _ => false,
+        }
+    }
+
+    // Add the line where the exception passes through this frame to its
+    // traceback:
+    fn add_traceback(&self, vm: &mut VirtualMachine, exception: &PyObjectRef) {
+        let frame = vm.frame_object(0).unwrap();
+        let lineno = line_of(&self.code, self.lasti);
+        if let PyObject::Instance { ref mut dict, .. } = *exception.borrow_mut() {
+            let next = match dict.get("__traceback__") {
+                Some(traceback) => match *traceback.borrow() {
+                    PyObject::Traceback { .. } => Some(traceback.clone()),
+                    _ => None,
+                },
+                None => None,
+            };
+            let traceback = PyObject::Traceback {
+                frame,
+                lineno,
+                next,
+            }.into_ref();
+            dict.insert(String::from("__traceback__"), traceback);
+        }
+    }
+
+    // Report a call to the global trace function, the local trace function
+    // of the frame is what it returns:
+    fn trace_call(&mut self, vm: &mut VirtualMachine) -> Result<(), PyObjectRef> {
+        self.tracer = None;
+        self.traced_line = None;
+        if vm.is_tracing() {
+            return Ok(());
+        }
+        if let Some(trace_function) = vm.trace_function() {
+            let tracer = vm.call_tracer(trace_function, "call", None)?;
+            if let PyObject::None = *tracer.borrow() {
+                return Ok(());
+            }
+            self.tracer = Some(tracer);
+        }
+        Ok(())
+    }
+
+    // Report the start of a line, or a jump back to an earlier instruction:
+    fn trace_line(&mut self, vm: &mut VirtualMachine) -> Result<(), PyObjectRef> {
+        let tracer = match self.tracer {
+            Some(ref tracer) => tracer.clone(),
+            None => return Ok(()),
+        };
+        let line = match self.line_cursor.location(&self.code.linetable, self.lasti) {
+            Some(location) => location.row,
+            None => self.code.firstlineno,
+        };
+        let is_new_line = match self.traced_line {
+            Some((lasti, traced_line)) => line != traced_line || self.lasti <= lasti,
+            None => true,
+        };
+        self.traced_line = Some((self.lasti, line));
+        if is_new_line {
+            vm.frames.last_mut().unwrap().lasti = self.lasti + 1;
+            let tracer = vm.call_tracer(tracer, "line", None)?;
+            self.tracer = match *tracer.borrow() {
+                PyObject::None => None,
+                _ => Some(tracer.clone()),
+            };
+        }
+        Ok(())
+    }
+
+    // Report the value which is returned or yielded, this is None when the
+    // frame is left by an exception:
+    fn trace_return(
+        &mut self,
+        vm: &mut VirtualMachine,
+        result: Result<ExecutionResult, PyObjectRef>,
+    ) -> Result<ExecutionResult, PyObjectRef> {
+        if let Some(tracer) = self.tracer.take() {
+            let value = match result {
+                Ok(ExecutionResult::Return(ref value)) | Ok(ExecutionResult::Yield(ref value)) => {
+                    Some(value.clone())
+                }
+                Err(_) => None,
+            };
+            vm.call_tracer(tracer, "return", value)?;
+        }
+        result
+    }
+
+    // A frame suspended in `yield from` first passes a thrown exception on
+    // to the iterator it delegates to. The error value is the exception to
+    // raise in this frame.
+    fn throw_into_delegate(&mut self, vm: &mut VirtualMachine, exception: PyObjectRef) -> FrameResult {
+        let delegating = match self.code.instruction_at(self.lasti) {
+            Ok(bytecode::Instruction::YieldFrom) => self.is_started(),
+            _ => false,
+        };
+        if !delegating {
+            return Err(exception);
+        }
+
+        let delegate = self.last_value();
+        let generator_exit = vm.get_builtin("GeneratorExit");
+        let result = if vm.isinstance(&exception, &generator_exit) {
+            // Close the delegate, and raise GeneratorExit here anyway:
+            if let Ok(close) = vm.get_attribute(delegate, "close") {
+                vm.invoke(close, vec![])?;
+            }
+            Err(exception)
+        } else {
+            match vm.get_attribute(delegate, "throw") {
+                Ok(throw) => vm.invoke(throw, vec![exception]),
+                Err(_) => Err(exception),
+            }
+        };
+
+        match result {
+            Ok(value) => Ok(Some(ExecutionResult::Yield(value))),
+            Err(exception) => {
+                // The delegation is over:
+                self.lasti += 1;
+                self.pop_value();
+                let stop_iteration = vm.get_builtin("StopIteration");
+                if vm.isinstance(&exception, &stop_iteration) {
+                    let value = vm.get_attribute(exception, "value")?;
+                    self.push_value(value);
+                    Ok(None)
+                } else {
+                    Err(exception)
+                }
+            }
+        }
+    }
+
+    // Execute a single instruction:
+    fn execute_instruction(&mut self, vm: &mut VirtualMachine) -> FrameResult {
+        let code = self.code.clone();
+        let offset = self.lasti;
+        let instruction = match bytecode::decode_instruction(&code.code, &mut self.lasti) {
+            Ok(instruction) => instruction,
+            Err(msg) => panic!("Invalid code: {}", msg),
+        };
+        trace!(
+            "Executing instruction: {:?} (stacksize={:?}, pc={:?}/{:?})",
+            instruction,
+            self.stack.len(),
+            offset,
+            code.code.len() / 2
+        );
+        vm.frames.last_mut().unwrap().lasti = self.lasti;
+
+        match instruction {
+            bytecode::Instruction::LoadConst { consti } => {
+                let obj = vm.unwrap_constant(&code.consts[consti]);
+                self.push_value(obj);
+            }
+            bytecode::Instruction::LoadName { namei } => {
+                // Lookup name in scope and put it onto the stack!
+                let obj = self.load_name(vm, &code.names[namei])?;
+                self.push_value(obj);
+            }
+            bytecode::Instruction::StoreName { namei } => {
+                // take top of stack and assign in scope:
+                let obj = self.pop_value();
+                let name = code.names[namei].clone();
+                self.scope.borrow_mut().locals.insert(name, obj);
+            }
+            bytecode::Instruction::DeleteName { namei } => {
+                let name = &code.names[namei];
+                let removed = self.scope.borrow_mut().locals.remove(name).is_some();
+                if !removed {
+                    let msg = format!("name '{}' is not defined", name);
+                    return Err(vm.new_exception("NameError", msg));
+                }
+            }
+            bytecode::Instruction::LoadFast { var_num } => {
+                let name = &code.varnames[var_num];
+                let value = self.scope.borrow().locals.get(name).cloned();
+                match value {
+                    Some(value) => self.push_value(value),
+                    None => return Err(unbound_local(vm, name)),
+                }
+            }
+            bytecode::Instruction::StoreFast { var_num } => {
+                let obj = self.pop_value();
+                let name = code.varnames[var_num].clone();
+                self.scope.borrow_mut().locals.insert(name, obj);
+            }
+            bytecode::Instruction::DeleteFast { var_num } => {
+                let name = &code.varnames[var_num];
+                let removed = self.scope.borrow_mut().locals.remove(name).is_some();
+                if !removed {
+                    return Err(unbound_local(vm, name));
+                }
+            }
+            bytecode::Instruction::LoadGlobal { namei } => {
+                let name = &code.names[namei];
+                let value = self.globals().borrow().locals.get(name).cloned();
+                let value = match value {
+                    Some(value) => value,
+                    None => match vm.builtins.get(name) {
+                        Some(value) => value.clone(),
+                        None => {
+                            let msg = format!("name '{}' is not defined", name);
+                            return Err(vm.new_exception("NameError", msg));
+                        }
+                    },
+                };
+                self.push_value(value);
+            }
+            bytecode::Instruction::StoreGlobal { namei } => {
+                let obj = self.pop_value();
+                let name = code.names[namei].clone();
+                self.globals().borrow_mut().locals.insert(name, obj);
+            }
+            bytecode::Instruction::DeleteGlobal { namei } => {
+                let name = &code.names[namei];
+                let removed = self.globals().borrow_mut().locals.remove(name).is_some();
+                if !removed {
+                    let msg = format!("name '{}' is not defined", name);
+                    return Err(vm.new_exception("NameError", msg));
+                }
+            }
+            bytecode::Instruction::LoadDeref { i } => {
+                let value = match *self.cells[i].borrow() {
+                    PyObject::Cell { ref value } => value.clone(),
+                    _ => panic!("Expected a cell"),
+                };
+                match value {
+                    Some(value) => self.push_value(value),
+                    None => return Err(self.unbound_deref(vm, i)),
+                }
+            }
+            bytecode::Instruction::StoreDeref { i } => {
+                let obj = self.pop_value();
+                if let PyObject::Cell { ref mut value } = *self.cells[i].borrow_mut() {
+                    *value = Some(obj);
+                }
+            }
+            bytecode::Instruction::DeleteDeref { i } => {
+                let removed = match *self.cells[i].borrow_mut() {
+                    PyObject::Cell { ref mut value } => value.take().is_some(),
+                    _ => panic!("Expected a cell"),
+                };
+                if !removed {
+                    return Err(self.unbound_deref(vm, i));
+                }
+            }
+            bytecode::Instruction::LoadClosure { i } => {
+                let cell = self.cells[i].clone();
+                self.push_value(cell);
+            }
+            bytecode::Instruction::ImportName { namei } => {
+                let symbols = self.pop_value();
+                let symbols = match *symbols.borrow() {
+                    PyObject::None => vec![],
+                    _ => vm.extract_elements(&symbols)?.iter().map(|s| s.borrow().str()).collect(),
+                };
+                let level = match *self.pop_value().borrow() {
+                    PyObject::Integer { value } => value as usize,
+                    _ => panic!("The level of an import must be an integer"),
+                };
+                let globals = self.globals();
+                let module = vm.import(&code.names[namei], &symbols, level, &globals)?;
+                self.push_value(module);
+            }
+            bytecode::Instruction::ImportFrom { namei } => {
+                let name = &code.names[namei];
+                let module = self.last_value();
+                let value = match vm.get_attribute(module, name) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        let msg = format!("cannot import name '{}'", name);
+                        return Err(vm.new_exception("ImportError", msg));
+                    }
+                };
+                self.push_value(value);
+            }
+            bytecode::Instruction::ImportStar => {
+                let module = self.pop_value();
+                let names = import::public_names(vm, &module)?;
+                self.scope.borrow_mut().locals.extend(names);
+            }
+            bytecode::Instruction::LoadAttr { namei } => {
+                let parent = self.pop_value();
+                let obj = vm.get_attribute(parent, &code.names[namei])?;
+                self.push_value(obj);
+            }
+            bytecode::Instruction::StoreAttr { namei } => {
+                let parent = self.pop_value();
+                let value = self.pop_value();
+                vm.set_attribute(parent, &code.names[namei], value)?;
+            }
+            bytecode::Instruction::DeleteAttr { namei } => {
+                let parent = self.pop_value();
+                vm.del_attribute(parent, &code.names[namei])?;
+            }
+            bytecode::Instruction::UnaryOperation { ref op } => {
+                let a = self.pop_value();
+                let result = vm.unary_op(a, op)?;
+                self.push_value(result);
+            }
+            bytecode::Instruction::BinaryOperation { ref op } => {
+                let b = self.pop_value();
+                let a = self.pop_value();
+                let result = vm.binary_op(a, b, op, false)?;
+                self.push_value(result);
+            }
+            bytecode::Instruction::InplaceOperation { ref op } => {
+                let b = self.pop_value();
+                let a = self.pop_value();
+                let result = vm.binary_op(a, b, op, true)?;
+                self.push_value(result);
+            }
+            bytecode::Instruction::CompareOperation { ref op } => {
+                let b = self.pop_value();
+                let a = self.pop_value();
+                let result = vm.compare(a, b, op)?;
+                self.push_value(result);
+            }
+            bytecode::Instruction::StoreSubscript => {
+                let index = self.pop_value();
+                let obj = self.pop_value();
+                let value = self.pop_value();
+                vm.set_item(obj, index, value)?;
+            }
+            bytecode::Instruction::DeleteSubscript => {
+                let index = self.pop_value();
+                let obj = self.pop_value();
+                vm.del_item(obj, index)?;
+            }
+            bytecode::Instruction::Pop => {
+                // Pop value from stack and ignore.
+                self.pop_value();
+            }
+            bytecode::Instruction::PrintExpr => {
+                // Like the interactive prompt of CPython, the last value is
+                // kept in `_`:
+                let value = self.pop_value();
+                if !matches!(*value.borrow(), PyObject::None) {
+                    println!("{}", vm.repr_of(value.clone())?);
+                    vm.builtins.insert(String::from("_"), value);
+                }
+            }
+            bytecode::Instruction::Duplicate => {
+                let value = self.last_value();
+                self.push_value(value);
+            }
+            bytecode::Instruction::DuplicateTwo => {
+                let b = self.nth_value(0);
+                let a = self.nth_value(1);
+                self.push_value(a);
+                self.push_value(b);
+            }
+            bytecode::Instruction::Rotate { amount } => {
+                // Move the top of the stack down, below `amount - 1` values:
+                let value = self.pop_value();
+                let position = self.stack.len() + 1 - amount;
+                self.stack.insert(position, value);
+            }
+            bytecode::Instruction::BuildList { size } => {
+                let elements = self.pop_multiple(size);
+                let list_obj = PyObject::List { elements }.into_ref();
+                self.push_value(list_obj);
+            }
+            bytecode::Instruction::BuildTuple { size } => {
+                let elements = self.pop_multiple(size);
+                let list_obj = PyObject::Tuple { elements }.into_ref();
+                self.push_value(list_obj);
+            }
+            bytecode::Instruction::BuildSet { size } => {
+                let elements = self.pop_multiple(size);
+                let set_obj = set::new_set();
+                for element in elements {
+                    set::add(vm, &set_obj, element)?;
+                }
+                self.push_value(set_obj);
+            }
+            bytecode::Instruction::BuildMap { size } => {
+                let elements = self.pop_multiple(2 * size);
+                let dict_obj = dict::new_dict();
+                let mut elements = elements.into_iter();
+                while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                    dict::set_item(vm, &dict_obj, key, value)?;
+                }
+                self.push_value(dict_obj);
+            }
+            bytecode::Instruction::ListAppend { i } => {
+                let value = self.pop_value();
+                let list_obj = self.nth_value(i);
+                list::append(&list_obj, value);
+            }
+            bytecode::Instruction::SetAdd { i } => {
+                let value = self.pop_value();
+                let set_obj = self.nth_value(i);
+                set::add(vm, &set_obj, value)?;
+            }
+            bytecode::Instruction::MapAdd { i } => {
+                let value = self.pop_value();
+                let key = self.pop_value();
+                let dict_obj = self.nth_value(i);
+                dict::set_item(vm, &dict_obj, key, value)?;
+            }
+            bytecode::Instruction::UnpackSequence { size } => {
+                let value = self.pop_value();
+                let elements = vm.extract_elements(&value)?;
+                if elements.len() != size {
+                    let msg = format!(
+                        "expected {} values to unpack, got {}",
+                        size,
+                        elements.len()
+                    );
+                    return Err(vm.new_exception("ValueError", msg));
+                }
+                for element in elements.into_iter().rev() {
+                    self.push_value(element);
+                }
+            }
+            bytecode::Instruction::GetIter => {
+                let iterated_obj = self.pop_value();
+                let iter_obj = vm.get_iter(iterated_obj)?;
+                self.push_value(iter_obj);
+            }
+            bytecode::Instruction::ForIter { target } => {
+                // The top of stack contains the iterator, lets push it forward:
+                let top_of_stack = self.last_value();
+                match vm.next(top_of_stack)? {
+                    Some(v) => {
+                        self.push_value(v);
+                    }
+                    None => {
+                        // End of for loop
+                        self.pop_value();
+                        self.jump(target);
+                    }
+                }
+            }
+            bytecode::Instruction::MakeFunction { has_defaults } => {
+                let code_obj = self.pop_value();
+                let function = self.make_function(vm, code_obj, vec![], has_defaults)?;
+                self.push_value(function);
+            }
+            bytecode::Instruction::MakeClosure { has_defaults } => {
+                let code_obj = self.pop_value();
+                let closure = self.pop_value();
+                let closure = vm.extract_elements(&closure)?;
+                let function = self.make_function(vm, code_obj, closure, has_defaults)?;
+                self.push_value(function);
+            }
+            bytecode::Instruction::LoadBuildClass => {
+                let build_class = vm.get_builtin("__build_class__");
+                self.push_value(build_class);
+            }
+            bytecode::Instruction::SetupAnnotations => {
+                let mut scope = self.scope.borrow_mut();
+                if !scope.locals.contains_key("__annotations__") {
+                    scope
+                        .locals
+                        .insert(String::from("__annotations__"), dict::new_dict());
+                }
+            }
+            bytecode::Instruction::CallFunction { count } => {
+                let args = self.pop_multiple(count);
+                let func_ref = self.pop_value();
+                let value = vm.invoke(func_ref, args)?;
+                self.push_value(value);
+            }
+            bytecode::Instruction::CallFunctionKw { count } => {
+                let names = self.pop_value();
+                let names: Vec<String> = vm
+                    .extract_elements(&names)?
+                    .iter()
+                    .map(|name| name.borrow().str())
+                    .collect();
+                let kwarg_values = self.pop_multiple(names.len());
+                let args = self.pop_multiple(count - names.len());
+                let func_ref = self.pop_value();
+                let kwargs = names.into_iter().zip(kwarg_values).collect();
+                let value = vm.invoke(func_ref, PyFuncArgs::new(args, kwargs))?;
+                self.push_value(value);
+            }
+            bytecode::Instruction::Jump { target } => {
+                self.jump(target);
+            }
+            bytecode::Instruction::JumpIf { target } => {
+                let obj = self.pop_value();
+                if vm.is_true(obj)? {
+                    self.jump(target);
+                }
+            }
+            bytecode::Instruction::JumpIfFalse { target } => {
+                let obj = self.pop_value();
+                if !vm.is_true(obj)? {
+                    self.jump(target);
+                }
+            }
+            bytecode::Instruction::ReturnValue => {
+                let value = self.pop_value();
+                return self.unwind(UnwindReason::Returning { value });
+            }
+            bytecode::Instruction::YieldValue => {
+                let value = self.pop_value();
+                return Ok(Some(ExecutionResult::Yield(value)));
+            }
+            bytecode::Instruction::YieldFrom => {
+                // Send the value on top of the stack to the iterator below it:
+                let value = self.pop_value();
+                let delegate = self.last_value();
+                match vm.send(delegate, value) {
+                    Ok(value) => {
+                        // Execute this instruction again when resumed:
+                        self.lasti -= 1;
+                        return Ok(Some(ExecutionResult::Yield(value)));
+                    }
+                    Err(exception) => {
+                        let stop_iteration = vm.get_builtin("StopIteration");
+                        if !vm.isinstance(&exception, &stop_iteration) {
+                            return Err(exception);
+                        }
+                        self.pop_value();
+                        let value = vm.get_attribute(exception, "value")?;
+                        self.push_value(value);
+                    }
+                }
+            }
+            bytecode::Instruction::PushBlock { end } => {
+                self.push_block(BlockType::Loop { end });
+            }
+            bytecode::Instruction::SetupExcept { handler } => {
+                self.push_block(BlockType::TryExcept { handler });
+            }
+            bytecode::Instruction::SetupFinally { handler } => {
+                self.push_block(BlockType::Finally { handler });
+            }
+            bytecode::Instruction::SetupWith { handler } => {
+                let manager = self.pop_value();
+                let enter = vm.get_attribute(manager.clone(), "__enter__")?;
+                let exit = vm.get_attribute(manager, "__exit__")?;
+                self.push_value(exit);
+                let value = vm.invoke(enter, vec![])?;
+                self.push_block(BlockType::Finally { handler });
+                self.push_value(value);
+            }
+            bytecode::Instruction::WithCleanup => {
+                let exit = self.pop_value();
+                let exception = match self.blocks.last().unwrap().typ {
+                    BlockType::FinallyHandler {
+                        reason: Some(UnwindReason::Raising { ref exception }),
+                    } => Some(exception.clone()),
+                    BlockType::FinallyHandler { .. } => None,
+                    _ => panic!("Block stack is incorrect, expected a finally handler"),
+                };
+                match exception {
+                    Some(exception) => {
+                        let typ = match *exception.borrow() {
+                            PyObject::Instance { ref class, .. } => class.clone(),
+                            _ => panic!("Exception which is not an instance"),
+                        };
+                        let traceback = vm.get_attribute(exception.clone(), "__traceback__")?;
+                        let args = vec![typ, exception.clone(), traceback];
+                        let suppress = vm.invoke(exit, args)?;
+                        // A true result from __exit__ swallows the exception:
+                        if vm.is_true(suppress)? {
+                            self.blocks.last_mut().unwrap().typ =
+                                BlockType::FinallyHandler { reason: None };
+                        }
+                    }
+                    None => {
+                        let args = vec![vm.get_none(), vm.get_none(), vm.get_none()];
+                        vm.invoke(exit, args)?;
+                    }
+                }
+            }
+            bytecode::Instruction::EnterFinally => {
+                self.push_block(BlockType::FinallyHandler { reason: None });
+            }
+            bytecode::Instruction::EndFinally => {
+                // Continue what was going on before the finally clause:
+                let block = self.blocks.pop().unwrap();
+                match block.typ {
+                    BlockType::FinallyHandler { reason } => match reason {
+                        None => {}
+                        Some(UnwindReason::Raising { exception }) => return Err(exception),
+                        Some(reason) => return self.unwind(reason),
+                    },
+                    _ => panic!("Block stack is incorrect, expected a finally handler"),
+                }
+            }
+            bytecode::Instruction::PopBlock => {
+                self.blocks.pop();
+            }
+            bytecode::Instruction::Raise { argc } => {
+                let exception = match argc {
+                    0 => match self.current_exception() {
+                        Some(exception) => exception,
+                        None => {
+                            return Err(vm.new_exception(
+                                "RuntimeError",
+                                String::from("No active exception to reraise"),
+                            ))
+                        }
+                    },
+                    1 | 2 => {
+                        let cause = if argc == 2 {
+                            Some(self.pop_value())
+                        } else {
+                            None
+                        };
+                        let exception = self.pop_value();
+                        let exception = vm.make_exception(exception)?;
+                        if let Some(cause) = cause {
+                            vm.set_attribute(exception.clone(), "__cause__", cause)?;
+                        }
+                        exception
+                    }
+                    _ => panic!("Invalid argument count for raise"),
+                };
+                return Err(exception);
+            }
+            bytecode::Instruction::Break => {
+                return self.unwind(UnwindReason::Break);
+            }
+            bytecode::Instruction::Pass => {
+                // Ah, this is nice, just relax!
+            }
+            bytecode::Instruction::Continue { target } => {
+                return self.unwind(UnwindReason::Continue { target });
+            }
+        }
+        Ok(None)
+    }
+
+    // Pop blocks until one of them handles the given reason. When no block
+    // does, the frame is left with a return value or an exception.
+    fn unwind(&mut self, reason: UnwindReason) -> FrameResult {
+        while let Some(block) = self.blocks.pop() {
+            if let BlockType::Loop { .. } = block.typ {
+                if let UnwindReason::Continue { target } = reason {
+                    // The loop is still active:
+                    self.blocks.push(block);
+                    self.jump(target);
+                    return Ok(None);
+                }
+            }
+
+            self.stack.truncate(block.level);
+            match block.typ {
+                BlockType::Loop { end } => {
+                    if let UnwindReason::Break = reason {
+                        self.jump(end);
+                        return Ok(None);
+                    }
+                }
+                BlockType::Finally { handler } => {
+                    self.push_block(BlockType::FinallyHandler {
+                        reason: Some(reason),
+                    });
+                    self.jump(handler);
+                    return Ok(None);
+                }
+                BlockType::TryExcept { handler } => {
+                    if let UnwindReason::Raising { ref exception } = reason {
+                        self.push_block(BlockType::ExceptHandler {
+                            exception: exception.clone(),
+                        });
+                        self.push_value(exception.clone());
+                        self.jump(handler);
+                        return Ok(None);
+                    }
+                }
+                BlockType::FinallyHandler { .. } | BlockType::ExceptHandler { .. } => {}
+            }
+        }
+
+        match reason {
+            UnwindReason::Returning { value } => Ok(Some(ExecutionResult::Return(value))),
+            UnwindReason::Raising { exception } => Err(exception),
+            UnwindReason::Break | UnwindReason::Continue { .. } => {
+                panic!("break or continue outside of a loop")
+            }
+        }
+    }
+
+    // The exception handled by the innermost active except clause:
+    fn current_exception(&self) -> Option<PyObjectRef> {
+        for block in self.blocks.iter().rev() {
+            if let BlockType::ExceptHandler { ref exception } = block.typ {
+                return Some(exception.clone());
+            }
+        }
+        None
+    }
+
+    fn load_name(&self, vm: &mut VirtualMachine, name: &str) -> Result<PyObjectRef, PyObjectRef> {
+        if let Some(value) = self.scope.borrow().get(name) {
+            return Ok(value);
+        }
+        match vm.builtins.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => {
+                let msg = format!("name '{}' is not defined", name);
+                Err(vm.new_exception("NameError", msg))
+            }
+        }
+    }
+
+    fn make_function(
+        &mut self,
+        vm: &mut VirtualMachine,
+        code_obj: PyObjectRef,
+        closure: Vec<PyObjectRef>,
+        has_defaults: bool,
+    ) -> Result<PyObjectRef, PyObjectRef> {
+        let defaults = if has_defaults {
+            let defaults = self.pop_value();
+            vm.extract_elements(&defaults)?
+        } else {
+            vec![]
+        };
+        let code = match *code_obj.borrow() {
+            PyObject::Code { ref code } => code.clone(),
+            _ => panic!("MakeFunction expects a code object"),
+        };
+        Ok(PyObject::Function {
+            code,
+            scope: self.scope.clone(),
+            defaults,
+            closure,
+        }.into_ref())
+    }
+
+    fn globals(&self) -> ScopeRef {
+        Scope::globals(&self.scope)
+    }
+
+    // Unbound cell variables are locals, free variables belong to an
+    // enclosing function:
+    fn unbound_deref(&self, vm: &mut VirtualMachine, i: usize) -> PyObjectRef {
+        let name = self.code.deref_name(i);
+        if i < self.code.cellvars.len() {
+            return unbound_local(vm, name);
+        }
+        let msg = format!(
+            "free variable '{}' referenced before assignment in enclosing scope",
+            name
+        );
+        vm.new_exception("NameError", msg)
+    }
+
+    // Jump targets are word offsets in the code:
+    fn jump(&mut self, target: bytecode::Label) {
+        self.lasti = target;
+    }
+
+    fn push_block(&mut self, typ: BlockType) {
+        let level = self.stack.len();
+        self.blocks.push(Block { typ, level });
+    }
+
+    pub fn push_value(&mut self, obj: PyObjectRef) {
+        self.stack.push(obj);
+    }
+
+    fn pop_value(&mut self) -> PyObjectRef {
+        self.stack.pop().unwrap()
+    }
+
+    // Get the value below the `depth` topmost values of the stack:
+    fn nth_value(&self, depth: usize) -> PyObjectRef {
+        self.stack[self.stack.len() - depth - 1].clone()
+    }
+
+    fn pop_multiple(&mut self, count: usize) -> Vec<PyObjectRef> {
+        let position = self.stack.len() - count;
+        self.stack.split_off(position)
+    }
+
+    fn last_value(&self) -> PyObjectRef {
+        self.stack.last().unwrap().clone()
+    }
+}
+
+fn unbound_local(vm: &mut VirtualMachine, name: &str) -> PyObjectRef {
+    let msg = format!("local variable '{}' referenced before assignment", name);
+    vm.new_exception("UnboundLocalError", msg)
+}
@@ -0,0 +1,298 @@
+/*
+ * Generator objects. A generator wraps the frame of a generator function,
+ * which is suspended at every yield, and resumed by send, throw or close.
+ */
+
+use super::frame::{ExecutionResult, Frame};
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::vm::VirtualMachine;
+
+pub fn new_generator(frame: Frame) -> PyObjectRef {
+    PyObject::Generator {
+        frame: Some(frame),
+        running: false,
+    }.into_ref()
+}
+
+pub fn get_method(name: &str) -> Option<RustPyFunc> {
+    match name {
+        "__iter__" => Some(generator_iter),
+        "__next__" => Some(generator_next),
+        "send" => Some(generator_send),
+        "throw" => Some(generator_throw),
+        "close" => Some(generator_close),
+        _ => None,
+    }
+}
+
+// How to resume the generator frame:
+enum Resume {
+    Send(PyObjectRef),
+    Throw(PyObjectRef),
+}
+
+pub fn send(vm: &mut VirtualMachine, gen: &PyObjectRef, value: PyObjectRef) -> PyResult {
+    resume(vm, gen, Resume::Send(value))
+}
+
+pub fn throw(vm: &mut VirtualMachine, gen: &PyObjectRef, exception: PyObjectRef) -> PyResult {
+    resume(vm, gen, Resume::Throw(exception))
+}
+
+pub fn close(vm: &mut VirtualMachine, gen: &PyObjectRef) -> PyResult {
+    let exception = vm.new_exception("GeneratorExit", String::new());
+    match throw(vm, gen, exception) {
+        Ok(_) => Err(vm.new_exception(
+            "RuntimeError",
+            String::from("generator ignored GeneratorExit"),
+        )),
+        Err(exception) => {
+            let generator_exit = vm.get_builtin("GeneratorExit");
+            let stop_iteration = vm.get_builtin("StopIteration");
+            if vm.isinstance(&exception, &generator_exit)
+                || vm.isinstance(&exception, &stop_iteration)
+            {
+                Ok(vm.get_none())
+            } else {
+                Err(exception)
+            }
+        }
+    }
+}
+
+fn resume(vm: &mut VirtualMachine, gen: &PyObjectRef, how: Resume) -> PyResult {
+    let frame = take_frame(vm, gen)?;
+    let mut frame = match frame {
+        Some(frame) => frame,
+        None => {
+            // The generator is exhausted:
+            return match how {
+                Resume::Send(_) => Err(vm.new_exception("StopIteration", String::new())),
+                Resume::Throw(exception) => Err(exception),
+            };
+        }
+    };
+
+    let result = match how {
+        Resume::Send(value) => {
+            if frame.is_started() {
+                // This becomes the value of the yield expression:
+                frame.push_value(value);
+                frame.run(vm)
+            } else if is_none(&value) {
+                frame.run(vm)
+            } else {
+                put_frame(gen, Some(frame));
+                return Err(vm.new_exception(
+                    "TypeError",
+                    String::from("can't send non-None value to a just-started generator"),
+                ));
+            }
+        }
+        Resume::Throw(exception) => frame.throw(vm, exception),
+    };
+
+    match result {
+        Ok(ExecutionResult::Yield(value)) => {
+            put_frame(gen, Some(frame));
+            Ok(value)
+        }
+        Ok(ExecutionResult::Return(value)) => {
+            put_frame(gen, None);
+            let stop_iteration = vm.get_builtin("StopIteration");
+            let args = if is_none(&value) { vec![] } else { vec![value] };
+            Err(vm.invoke(stop_iteration, args)?)
+        }
+        Err(exception) => {
+            put_frame(gen, None);
+            let stop_iteration = vm.get_builtin("StopIteration");
+            if vm.isinstance(&exception, &stop_iteration) {
+                Err(vm.new_exception(
+                    "RuntimeError",
+                    String::from("generator raised StopIteration"),
+                ))
+            } else {
+                Err(exception)
+            }
+        }
+    }
+}
+
+// Take the frame out of the generator while it runs:
+fn take_frame(vm: &mut VirtualMachine, gen: &PyObjectRef) -> Result<Option<Frame>, PyObjectRef> {
+    let taken = match *gen.borrow_mut() {
+        PyObject::Generator {
+            ref mut frame,
+            ref mut running,
+        } => {
+            if *running {
+                None
+            } else {
+                let frame = frame.take();
+                *running = frame.is_some();
+                Some(frame)
+            }
+        }
+        _ => panic!("Expected a generator"),
+    };
+    match taken {
+        Some(frame) => Ok(frame),
+        None => Err(vm.new_exception(
+            "ValueError",
+            String::from("generator already executing"),
+        )),
+    }
+}
+
+fn put_frame(gen: &PyObjectRef, new_frame: Option<Frame>) {
+    match *gen.borrow_mut() {
+        PyObject::Generator {
+            ref mut frame,
+            ref mut running,
+        } => {
+            *frame = new_frame;
+            *running = false;
+        }
+        _ => panic!("Expected a generator"),
+    }
+}
+
+fn is_none(obj: &PyObjectRef) -> bool {
+    matches!(*obj.borrow(), PyObject::None)
+}
+
+fn generator_iter(_vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    Ok(args.args[0].clone())
+}
+
+fn generator_next(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let none = vm.get_none();
+    send(vm, &args.args[0], none)
+}
+
+fn generator_send(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    if args.args.len() != 2 {
+        return Err(vm.new_exception(
+            "TypeError",
+            String::from("send() takes exactly one argument"),
+        ));
+    }
+    send(vm, &args.args[0], args.args[1].clone())
+}
+
+// throw(type[, value]) or throw(instance)
+fn generator_throw(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    let exception = match args.args.len() {
+        2 => vm.make_exception(args.args[1].clone())?,
+        3 => {
+            let base_exception = vm.get_builtin("BaseException");
+            if vm.isinstance(&args.args[2], &base_exception) {
+                args.args[2].clone()
+            } else {
+                vm.invoke(args.args[1].clone(), vec![args.args[2].clone()])?
+            }
+        }
+        _ => {
+            return Err(vm.new_exception(
+                "TypeError",
+                String::from("throw expected 1 or 2 arguments"),
+            ))
+        }
+    };
+    throw(vm, &args.args[0], exception)
+}
+
+fn generator_close(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    close(vm, &args.args[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::vm::tests::run_and_get;
+
+    #[test]
+    fn test_yield_and_return_value() {
+        let source = "
+def count(n):
+    i = 0
+    while i < n:
+        yield i
+        i = i + 1
+    return 'done'
+
+g = count(2)
+x = [next(g), next(g)]
+try:
+    next(g)
+except StopIteration as e:
+    x = x + [e.value]
+";
+        assert_eq!(run_and_get(source, "x"), "[0, 1, 'done']");
+    }
+
+    #[test]
+    fn test_send_throw_close() {
+        let source = "
+def echo():
+    value = yield
+    while True:
+        try:
+            value = yield value * 2
+        except ValueError:
+            value = yield 'caught'
+
+g = echo()
+next(g)
+x = [g.send(3), g.throw(ValueError), g.send(5)]
+g.close()
+try:
+    next(g)
+except StopIteration:
+    x = x + ['closed']
+";
+        assert_eq!(run_and_get(source, "x"), "[6, 'caught', 10, 'closed']");
+    }
+
+    #[test]
+    fn test_yield_from() {
+        let source = "
+def inner():
+    received = yield 1
+    yield received
+    return 'result'
+
+def outer():
+    result = yield from inner()
+    yield result
+
+g = outer()
+x = [next(g), g.send('sent'), next(g)]
+";
+        assert_eq!(run_and_get(source, "x"), "[1, 'sent', 'result']");
+    }
+
+    #[test]
+    fn test_generator_expression() {
+        let source = "
+n = 3
+x = list(i * n for i in range(5) if i != 2)
+";
+        assert_eq!(run_and_get(source, "x"), "[0, 3, 9, 12]");
+    }
+
+    #[test]
+    fn test_stop_iteration_inside_generator() {
+        let source = "
+def gen():
+    yield 1
+    raise StopIteration
+
+x = None
+try:
+    list(gen())
+except RuntimeError:
+    x = 'converted'
+";
+        assert_eq!(run_and_get(source, "x"), "'converted'");
+    }
+}
@@ -0,0 +1,522 @@
+/*
+ * The import system. Modules are found as `.py` files, or as packages with
+ * an `__init__.py`, in the directories of `sys.path`. Without the source, a
+ * `.pyc` file in its place is loaded instead. A module is executed
+ * once, and cached in `sys.modules`. Its compiled code is cached on disk, in
+ * the `__pycache__` directory next to the file.
+ */
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::builtins::check_arg_count;
+
+use super::dict;
+use super::frame::{Scope, ScopeRef};
+use super::native::NativeModule;
+use super::pycache;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult};
+use super::vm::VirtualMachine;
+
+fn new_string(value: &str) -> PyObjectRef {
+    PyObject::String {
+        value: String::from(value),
+    }.into_ref()
+}
+
+pub fn new_module(name: &str) -> PyObjectRef {
+    let scope = Scope::new(None);
+    scope
+        .borrow_mut()
+        .locals
+        .insert(String::from("__name__"), new_string(name));
+    PyObject::Module {
+        name: String::from(name),
+        scope,
+    }.into_ref()
+}
+
+// The sys module starts out with the directories of PYTHONPATH:
+pub fn make_sys_module(vm: &mut VirtualMachine) -> PyObjectRef {
+    let python_path = if vm.settings.ignore_environment {
+        None
+    } else {
+        env::var_os("PYTHONPATH")
+    };
+    let path: Vec<PyObjectRef> = match python_path {
+        Some(paths) => env::split_paths(&paths)
+            .map(|path| new_string(&path.to_string_lossy()))
+            .collect(),
+        None => vec![],
+    };
+    let argv = vm
+        .settings
+        .argv
+        .iter()
+        .map(|arg| new_string(arg))
+        .collect();
+    let modules = dict::new_dict();
+    let module = NativeModule::new("sys")
+        .constant("path", PyObject::List { elements: path }.into_ref())
+        .constant("argv", PyObject::List { elements: argv }.into_ref())
+        .constant("modules", modules.clone())
+        .constant("maxsize", PyObject::Integer { value: i32::MAX }.into_ref())
+        .constant(
+            "dont_write_bytecode",
+            PyObject::Boolean {
+                value: vm.settings.dont_write_bytecode,
+            }.into_ref(),
+        )
+        .function("getrefcount", sys_getrefcount)
+        .function("exit", sys_exit)
+        .function("_getframe", sys_getframe)
+        .function("settrace", sys_settrace)
+        .function("gettrace", sys_gettrace)
+        .make_module(vm);
+    if let PyObject::Dict { ref mut elements } = *modules.borrow_mut() {
+        elements.push((new_string("sys"), module.clone()));
+    }
+    module
+}
+
+fn sys_getrefcount(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "getrefcount", &args, 1, 1)?;
+    Ok(PyObject::Integer {
+        value: Rc::strong_count(&args.args[0]) as i32,
+    }.into_ref())
+}
+
+// exit(status=None) raises SystemExit, with the exit status as its code:
+fn sys_exit(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "exit", &args, 0, 1)?;
+    let system_exit = vm.get_builtin("SystemExit");
+    let exception = vm.invoke(system_exit, args.args)?;
+    Err(exception)
+}
+
+// _getframe(depth=0) gives the frame of the caller, or of the function
+// `depth` calls further out:
+fn sys_getframe(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "_getframe", &args, 0, 1)?;
+    let depth = match args.args.first() {
+        None => 0,
+        Some(depth) => match *depth.borrow() {
+            PyObject::Integer { value } if value >= 0 => Some(value as usize),
+            PyObject::Integer { .. } => None,
+            _ => {
+                let msg = String::from("_getframe() argument must be an integer");
+                return Err(vm.new_exception("TypeError", msg));
+            }
+        }.unwrap_or(usize::MAX),
+    };
+    match vm.frame_object(depth) {
+        Some(frame) => Ok(frame),
+        None => Err(vm.new_exception(
+            "ValueError",
+            String::from("call stack is not deep enough"),
+        )),
+    }
+}
+
+// The trace function is called with a frame, an event and its argument.
+// The events are 'call' when a frame is entered, and 'line', and 'return',
+// which go to the function it returns for the frame.
+fn sys_settrace(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "settrace", &args, 1, 1)?;
+    let function = match *args.args[0].borrow() {
+        PyObject::None => None,
+        _ => Some(args.args[0].clone()),
+    };
+    vm.set_trace_function(function);
+    Ok(vm.get_none())
+}
+
+fn sys_gettrace(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "gettrace", &args, 0, 0)?;
+    Ok(vm.trace_function().unwrap_or_else(|| vm.get_none()))
+}
+
+fn sys_attribute(vm: &mut VirtualMachine, name: &str) -> PyResult {
+    let sys = vm.sys_module.clone();
+    vm.get_attribute(sys, name)
+}
+
+pub fn get_cached_module(
+    vm: &mut VirtualMachine,
+    name: &str,
+) -> Result<Option<PyObjectRef>, PyObjectRef> {
+    let modules = sys_attribute(vm, "modules")?;
+    dict::get_item(vm, &modules, &new_string(name))
+}
+
+pub fn set_cached_module(vm: &mut VirtualMachine, name: &str, module: PyObjectRef) -> PyResult {
+    let modules = sys_attribute(vm, "modules")?;
+    dict::set_item(vm, &modules, new_string(name), module)?;
+    Ok(vm.get_none())
+}
+
+// Directories from a list of strings, like `sys.path` or `__path__`:
+fn directories(vm: &mut VirtualMachine, paths: &PyObjectRef) -> Result<Vec<PathBuf>, PyObjectRef> {
+    let mut directories = vec![];
+    for path in vm.extract_elements(paths)? {
+        if let PyObject::String { ref value } = *path.borrow() {
+            directories.push(PathBuf::from(value));
+        }
+    }
+    Ok(directories)
+}
+
+// The absolute name of a module, from the package of the importing module:
+fn resolve_name(
+    vm: &mut VirtualMachine,
+    name: &str,
+    level: usize,
+    globals: &ScopeRef,
+) -> Result<String, PyObjectRef> {
+    if level == 0 {
+        return Ok(String::from(name));
+    }
+    let package = match globals.borrow().locals.get("__package__") {
+        Some(package) => match *package.borrow() {
+            PyObject::String { ref value } if !value.is_empty() => Some(value.clone()),
+            _ => None,
+        },
+        None => None,
+    };
+    let package = match package {
+        Some(package) => package,
+        None => {
+            let msg = String::from("attempted relative import with no known parent package");
+            return Err(vm.new_exception("ImportError", msg));
+        }
+    };
+    let parts: Vec<&str> = package.rsplitn(level, '.').collect();
+    if parts.len() < level {
+        let msg = String::from("attempted relative import beyond top-level package");
+        return Err(vm.new_exception("ImportError", msg));
+    }
+    let base = parts.last().unwrap();
+    if name.is_empty() {
+        Ok(base.to_string())
+    } else {
+        Ok(format!("{}.{}", base, name))
+    }
+}
+
+// Import a module for an import instruction. Without symbols, the top level
+// package is given, since `import a.b` binds the name `a`.
+pub fn import(
+    vm: &mut VirtualMachine,
+    name: &str,
+    symbols: &[String],
+    level: usize,
+    globals: &ScopeRef,
+) -> PyResult {
+    let full_name = resolve_name(vm, name, level, globals)?;
+    let module = import_module(vm, &full_name)?;
+    if symbols.is_empty() {
+        let package = full_name.split('.').next().unwrap();
+        return Ok(get_cached_module(vm, package)?.unwrap_or(module));
+    }
+
+    // A from-import of a package can name its submodules:
+    let is_package = match *module.borrow() {
+        PyObject::Module { ref scope, .. } => scope.borrow().locals.contains_key("__path__"),
+        _ => false,
+    };
+    if is_package {
+        for symbol in symbols {
+            if symbol == "*" || vm.get_attribute(module.clone(), symbol).is_ok() {
+                continue;
+            }
+            let submodule = format!("{}.{}", full_name, symbol);
+            if let Err(exception) = import_module(vm, &submodule) {
+                let not_found = vm.get_builtin("ModuleNotFoundError");
+                if !vm.isinstance(&exception, &not_found) {
+                    return Err(exception);
+                }
+            }
+        }
+    }
+    Ok(module)
+}
+
+// The file of a module in a directory. Without a source file, a compiled
+// file next to where the source would be is used.
+fn module_file(directory: &Path, name: &str) -> Option<PathBuf> {
+    ["py", "pyc"]
+        .iter()
+        .map(|extension| directory.join(format!("{}.{}", name, extension)))
+        .find(|file| file.is_file())
+}
+
+// Import a module by its absolute name, after importing its packages:
+pub fn import_module(vm: &mut VirtualMachine, name: &str) -> PyResult {
+    if let Some(module) = get_cached_module(vm, name)? {
+        return Ok(module);
+    }
+
+    // Native modules take precedence over the file system:
+    let native = vm.native_modules.get(name).cloned();
+    if let Some(native) = native {
+        let module = native.make_module(vm);
+        set_cached_module(vm, name, module.clone())?;
+        if let Some(position) = name.rfind('.') {
+            let parent = import_module(vm, &name[..position])?;
+            vm.set_attribute(parent, &name[position + 1..], module.clone())?;
+        }
+        return Ok(module);
+    }
+
+    let (parent, short_name, search_path) = match name.rfind('.') {
+        Some(position) => {
+            let parent = import_module(vm, &name[..position])?;
+            let path = match vm.get_attribute(parent.clone(), "__path__") {
+                Ok(path) => path,
+                Err(_) => {
+                    let msg = format!(
+                        "No module named '{}'; '{}' is not a package",
+                        name,
+                        &name[..position]
+                    );
+                    return Err(vm.new_exception("ModuleNotFoundError", msg));
+                }
+            };
+            (Some(parent), &name[position + 1..], path)
+        }
+        None => (None, name, sys_attribute(vm, "path")?),
+    };
+
+    // Importing the package may have imported this module as well:
+    if let Some(module) = get_cached_module(vm, name)? {
+        return Ok(module);
+    }
+
+    for directory in directories(vm, &search_path)? {
+        let package_directory = directory.join(short_name);
+        let module = if let Some(init_file) = module_file(&package_directory, "__init__") {
+            load_module(vm, name, &init_file, Some(&package_directory))?
+        } else if let Some(file) = module_file(&directory, short_name) {
+            load_module(vm, name, &file, None)?
+        } else {
+            continue;
+        };
+        if let Some(parent) = parent {
+            vm.set_attribute(parent, short_name, module.clone())?;
+        }
+        return Ok(module);
+    }
+
+    let msg = format!("No module named '{}'", name);
+    Err(vm.new_exception("ModuleNotFoundError", msg))
+}
+
+// The name and the file of a module, without executing it. The packages
+// containing it are imported, and a package is found as its __main__
+// submodule.
+pub fn find_module_file(vm: &mut VirtualMachine, name: &str) -> PyResult<(String, PathBuf)> {
+    let (short_name, search_path) = match name.rfind('.') {
+        Some(position) => {
+            let parent = import_module(vm, &name[..position])?;
+            let path = match vm.get_attribute(parent, "__path__") {
+                Ok(path) => path,
+                Err(_) => {
+                    let msg = format!("No module named '{}'", name);
+                    return Err(vm.new_exception("ModuleNotFoundError", msg));
+                }
+            };
+            (&name[position + 1..], path)
+        }
+        None => (name, sys_attribute(vm, "path")?),
+    };
+    for directory in directories(vm, &search_path)? {
+        let package_directory = directory.join(short_name);
+        if module_file(&package_directory, "__init__").is_some() {
+            return find_module_file(vm, &format!("{}.__main__", name));
+        }
+        if let Some(file) = module_file(&directory, short_name) {
+            return Ok((String::from(name), file));
+        }
+    }
+    let msg = format!("No module named '{}'", name);
+    Err(vm.new_exception("ModuleNotFoundError", msg))
+}
+
+// Execute the file of a module in a new namespace:
+fn load_module(
+    vm: &mut VirtualMachine,
+    name: &str,
+    file: &Path,
+    package_directory: Option<&Path>,
+) -> PyResult {
+    let module = new_module(name);
+    let package = match package_directory {
+        Some(directory) => {
+            let path = vec![new_string(&directory.to_string_lossy())];
+            vm.set_attribute(
+                module.clone(),
+                "__path__",
+                PyObject::List { elements: path }.into_ref(),
+            )?;
+            name
+        }
+        None => match name.rfind('.') {
+            Some(position) => &name[..position],
+            None => "",
+        },
+    };
+    vm.set_attribute(module.clone(), "__package__", new_string(package))?;
+    vm.set_attribute(
+        module.clone(),
+        "__file__",
+        new_string(&file.to_string_lossy()),
+    )?;
+
+    // A module without source is its own cache:
+    let sourceless = file.extension().is_some_and(|extension| extension == "pyc");
+    let cached = if sourceless {
+        file.to_path_buf()
+    } else {
+        pycache::cache_path(file, vm.settings.optimize)
+    };
+    vm.set_attribute(
+        module.clone(),
+        "__cached__",
+        new_string(&cached.to_string_lossy()),
+    )?;
+
+    let code = if sourceless {
+        match pycache::load_compiled_file(file) {
+            Ok(code) => code,
+            Err(msg) => return Err(vm.new_exception("ImportError", msg)),
+        }
+    } else {
+        let options = vm.compile_options();
+        let write_cache = !vm.settings.dont_write_bytecode;
+        match pycache::load_source_file(file, options, write_cache) {
+            Ok(code) => code,
+            Err(msg) => return Err(vm.new_exception("SyntaxError", msg)),
+        }
+    };
+
+    // The module is cached before it runs, so that circular imports find the
+    // partially initialized module:
+    set_cached_module(vm, name, module.clone())?;
+    let scope = match *module.borrow() {
+        PyObject::Module { ref scope, .. } => scope.clone(),
+        _ => unreachable!(),
+    };
+    if let Err(exception) = vm.run_code(code, scope) {
+        let modules = sys_attribute(vm, "modules")?;
+        dict::del_item(vm, &modules, &new_string(name))?;
+        return Err(exception);
+    }
+    Ok(get_cached_module(vm, name)?.unwrap_or(module))
+}
+
+// The names bound by `from module import *`, which are those listed in
+// `__all__`, or otherwise all names not starting with an underscore:
+pub fn public_names(
+    vm: &mut VirtualMachine,
+    module: &PyObjectRef,
+) -> Result<Vec<(String, PyObjectRef)>, PyObjectRef> {
+    let scope = match *module.borrow() {
+        PyObject::Module { ref scope, .. } => scope.clone(),
+        _ => return Ok(vec![]),
+    };
+    let all = scope.borrow().locals.get("__all__").cloned();
+    match all {
+        Some(all) => {
+            let mut names = vec![];
+            for name in vm.extract_elements(&all)? {
+                let name = vm.str_of(name)?;
+                let value = vm.get_attribute(module.clone(), &name)?;
+                names.push((name, value));
+            }
+            Ok(names)
+        }
+        None => Ok(scope
+            .borrow()
+            .locals
+            .iter()
+            .filter(|(name, _)| !name.starts_with('_'))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compile::compile;
+    use super::super::parser::parse_source;
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_import_modules() {
+        let directory = env::temp_dir().join(format!("rspython_import_{}", process::id()));
+        let files = vec![
+            ("pkg/__init__.py", "value = 40\n"),
+            ("pkg/sub/__init__.py", "from .. import value\n__all__ = ['shared']\nshared = value + 2\n"),
+            ("pkg/sub/leaf.py", "from ..sub import shared\nname = __name__ + ' ' + __package__\n"),
+            ("circular_a.py", "import circular_b\nloaded = True\n"),
+            ("circular_b.py", "import circular_a\n"),
+            ("broken.py", "def (\n"),
+            ("sourceless.py", "import pkg\nvalue = pkg.value + 1\n"),
+        ];
+        for (name, source) in files {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        // Only the compiled file of a module is left:
+        let sourceless = directory.join("sourceless.py");
+        let code = pycache::compile_source_file(&sourceless, Default::default()).unwrap();
+        pycache::write_cache(&sourceless, &code, false, pycache::Invalidation::Timestamp).unwrap();
+        let cached = pycache::cache_path(&sourceless, false);
+        fs::rename(cached, directory.join("sourceless.pyc")).unwrap();
+        fs::remove_file(&sourceless).unwrap();
+
+        let source = "
+import pkg.sub.leaf
+from pkg.sub import *
+from pkg.sub import leaf as same_leaf
+import circular_a
+import sourceless
+import sys
+try:
+    import broken
+except SyntaxError:
+    failed = 'broken' not in sys.modules
+try:
+    import pkg.nothing
+except ModuleNotFoundError:
+    failed = failed and 'pkg' in sys.modules
+x = [pkg.sub.leaf.name, shared, same_leaf is pkg.sub.leaf, circular_a.loaded, failed,
+     sourceless.value, sourceless.__cached__ == sourceless.__file__]
+";
+        let mut vm = VirtualMachine::new();
+        let path = vm.get_attribute(vm.sys_module.clone(), "path").unwrap();
+        let directory_name = new_string(&directory.to_string_lossy());
+        vm.call_method(path, "append", vec![directory_name]).unwrap();
+        let code = compile(parse_source(source).unwrap()).unwrap();
+        let scope = Scope::new(None);
+        let result = vm.run_code(code, scope.clone());
+        let cached = pycache::cache_path(&directory.join("pkg/sub/leaf.py"), false);
+        let cache_written = cached.is_file();
+        fs::remove_dir_all(&directory).unwrap();
+        if let Err(exception) = result {
+            vm.print_exception(&exception);
+            panic!("Program raised an exception");
+        }
+        let x = scope.borrow().get("x").unwrap();
+        assert_eq!(
+            x.borrow().repr(),
+            "['pkg.sub.leaf pkg.sub', 42, True, True, True, 41, True]"
+        );
+        assert!(cache_written);
+    }
+}
@@ -1,40 +1,46 @@
 use std::str::FromStr;
-use std::str::CharIndices;
+use std::str::Chars;
 pub use super::token::Tok;
-use std::iter::FromIterator;
+use super::ast::Location;
 use std::collections::HashMap;
 
 pub struct Lexer<'input> {
-    chars: CharIndices<'input>,
+    chars: Chars<'input>,
     at_begin_of_line: bool,
     nesting: usize, // Amount of parenthesis
     indentation_stack: Vec<usize>,
+    pending_dedents: usize,
     chr0: Option<char>,
     chr1: Option<char>,
-    location: usize,
+    // The position of chr0:
+    location: Location,
 }
 
 #[derive(Debug)]
 pub enum LexicalError {
     StringError,
+    IndentationError,
+    UnrecognizedCharacter,
 }
 
-pub type Spanned<Tok> = Result<(usize, Tok, usize), LexicalError>;
+pub type Spanned<Tok> = Result<(Location, Tok, Location), LexicalError>;
 
-pub fn lex_source(source: &String) -> Vec<Tok> {
+#[cfg(test)]
+pub fn lex_source(source: &str) -> Vec<Tok> {
     let lexer = Lexer::new(source);
-    Vec::from_iter(lexer.map(|x| x.unwrap().1))
+    lexer.map(|x| x.unwrap().1).collect()
 }
 
 impl<'input> Lexer<'input> {
     pub fn new(input: &'input str) -> Self {
         let mut lxr = Lexer {
-            chars: input.char_indices(),
+            chars: input.chars(),
             at_begin_of_line: true,
             nesting: 0,
             indentation_stack: vec![0],
+            pending_dedents: 0,
             chr0: None,
-            location: 0,
+            location: Location::new(1, 0),
             chr1: None,
         };
         lxr.next_char();
@@ -46,7 +52,7 @@ impl<'input> Lexer<'input> {
     fn lex_identifier(&mut self) -> Spanned<Tok> {
         let mut name = String::new();
         let start_pos = self.location;
-        while self.is_char() {
+        while self.is_identifier_continuation() {
             name.push(self.next_char().unwrap());
         }
         let end_pos = self.location;
@@ -92,7 +98,7 @@ impl<'input> Lexer<'input> {
         if keywords.contains_key(&name) {
             Ok((start_pos, keywords.remove(&name).unwrap(), end_pos))
         } else {
-            Ok((start_pos, Tok::Name { name: name }, end_pos))
+            Ok((start_pos, Tok::Name { name }, end_pos))
         }
     }
 
@@ -107,31 +113,43 @@ impl<'input> Lexer<'input> {
 
         let value = i32::from_str(&value_text).unwrap();
 
-        return Ok((start_pos, Tok::Number { value: value }, end_pos));
+        Ok((start_pos, Tok::Number { value }, end_pos))
     }
 
     fn lex_comment(&mut self) {
-        // Skip everything until end of line
-        self.next_char();
+        // Skip everything until end of line, but leave the newline itself
+        // for the tokenizer:
         loop {
-            match self.next_char() {
-                Some(c) => {
-                    if c == '\n' {
-                        return;
-                    }
+            match self.chr0 {
+                Some('\n') | None => return,
+                Some(_) => {
+                    self.next_char();
                 }
-                None => return,
             }
         }
     }
 
     fn lex_string(&mut self) -> Spanned<Tok> {
+        let start_pos = self.location;
         let quote_char = self.next_char().unwrap();
         let mut string_content = String::new();
-        let start_pos = self.location;
 
         loop {
             match self.next_char() {
+                Some('\\') => match self.next_char() {
+                    Some('n') => string_content.push('\n'),
+                    Some('t') => string_content.push('\t'),
+                    Some('r') => string_content.push('\r'),
+                    Some('0') => string_content.push('\0'),
+                    Some('\n') => {}
+                    Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => string_content.push(c),
+                    Some(c) => {
+                        // Unknown escapes are kept as they are:
+                        string_content.push('\\');
+                        string_content.push(c);
+                    }
+                    None => return Err(LexicalError::StringError),
+                },
                 Some(c) => {
                     if c == quote_char {
                         break;
@@ -146,38 +164,106 @@ impl<'input> Lexer<'input> {
         }
         let end_pos = self.location;
 
-        return Ok((
+        Ok((
             start_pos,
             Tok::String {
                 value: string_content,
             },
             end_pos,
-        ));
+        ))
     }
 
-    fn is_char(&self) -> bool {
+    fn is_identifier_start(&self) -> bool {
         match self.chr0 {
-            Some('a'...'z') => return true,
-            _ => return false,
+            Some(c) => c == '_' || c.is_alphabetic(),
+            None => false,
         }
     }
 
-    fn is_number(&self) -> bool {
+    fn is_identifier_continuation(&self) -> bool {
         match self.chr0 {
-            Some('0'...'9') => return true,
-            _ => return false,
+            Some(c) => c == '_' || c.is_alphanumeric(),
+            None => false,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self.chr0, Some('0'..='9'))
+    }
+
+    // Determine the indentation of a new line, and emit the indent and
+    // dedent tokens that go with it. Lines containing only whitespace or a
+    // comment do not take part in this.
+    fn handle_indentation(&mut self) -> Option<Spanned<Tok>> {
+        loop {
+            let mut col: usize = 0;
+            loop {
+                match self.chr0 {
+                    Some(' ') => {
+                        self.next_char();
+                        col += 1;
+                    }
+                    Some('\t') => {
+                        self.next_char();
+                        col = (col / 8 + 1) * 8;
+                    }
+                    Some('\r') => {
+                        self.next_char();
+                    }
+                    _ => {
+                        break;
+                    }
+                }
+            }
+
+            match self.chr0 {
+                Some('#') => {
+                    self.lex_comment();
+                }
+                Some('\n') => {}
+                _ => {
+                    self.at_begin_of_line = false;
+                    if self.chr0.is_none() || self.nesting > 0 {
+                        return None;
+                    }
+
+                    // Determine indent or dedent:
+                    let current_indentation = *self.indentation_stack.last().unwrap();
+                    if col > current_indentation {
+                        // New indentation level:
+                        self.indentation_stack.push(col);
+                        return Some(Ok((self.location, Tok::Indent, self.location)));
+                    } else if col < current_indentation {
+                        // One or more dedentations
+                        while col < *self.indentation_stack.last().unwrap() {
+                            self.indentation_stack.pop();
+                            self.pending_dedents += 1;
+                        }
+                        if col != *self.indentation_stack.last().unwrap() {
+                            return Some(Err(LexicalError::IndentationError));
+                        }
+                    }
+                    return None;
+                }
+            }
+
+            // Skip the empty line:
+            self.next_char();
         }
     }
 
     fn next_char(&mut self) -> Option<char> {
         let c = self.chr0;
-        let nxt = self.chars.next();
+        match c {
+            Some('\n') => {
+                self.location.row += 1;
+                self.location.column = 0;
+            }
+            Some(_) => self.location.column += 1,
+            None => {}
+        }
         self.chr0 = self.chr1;
-        self.chr1 = nxt.map(|x| x.1);
-        self.location = match nxt {
-            Some(p) => p.0,
-            None => 99999,
-        };
+        self.chr1 = self.chars.next();
         c
     }
 }
@@ -198,42 +284,20 @@ impl<'input> Iterator for Lexer<'input> {
         // Detect indentation levels
         loop {
             if self.at_begin_of_line {
-                self.at_begin_of_line = false;
-
-                // Determine indentation:
-                let mut col: usize = 0;
-                loop {
-                    match self.chr0 {
-                        Some(' ') => {
-                            self.next_char();
-                            col += 1;
-                        }
-                        _ => {
-                            break;
-                        }
-                    }
+                if let Some(tok) = self.handle_indentation() {
+                    return Some(tok);
                 }
+            }
 
-                if self.nesting == 0 {
-                    // Determine indent or dedent:
-                    let current_indentation = *self.indentation_stack.last().unwrap();
-                    if col == current_indentation {
-                        // Same same
-                    } else if col > current_indentation {
-                        // New indentation level:
-                        self.indentation_stack.push(col);
-                        return Some(Ok((0, Tok::Indent, 0)));
-                    } else if col < current_indentation {
-                        // One or more dedentations
-                        return Some(Ok((0, Tok::Dedent, 0)));
-                    }
-                }
+            if self.pending_dedents > 0 {
+                self.pending_dedents -= 1;
+                return Some(Ok((self.location, Tok::Dedent, self.location)));
             }
 
+            let tok_start = self.location;
             match self.chr0 {
-                Some('0'...'9') => return Some(self.lex_number()),
-                // TODO: 'A'...'Z'
-                Some('a'...'z') => return Some(self.lex_identifier()),
+                Some('0'..='9') => return Some(self.lex_number()),
+                Some(_) if self.is_identifier_start() => return Some(self.lex_identifier()),
                 Some('#') => {
                     self.lex_comment();
                     continue;
@@ -249,9 +313,35 @@ impl<'input> Iterator for Lexer<'input> {
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((self.location, Tok::EqEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::EqEqual, self.location)));
+                        }
+                        _ => return Some(Ok((tok_start, Tok::Equal, self.location))),
+                    }
+                }
+                Some('!') => {
+                    self.next_char();
+                    match self.chr0 {
+                        Some('=') => {
+                            self.next_char();
+                            return Some(Ok((tok_start, Tok::NotEqual, self.location)));
+                        }
+                        _ => {
+                            return Some(Err(LexicalError::UnrecognizedCharacter))
+                        }
+                    }
+                }
+                Some('~') => {
+                    self.next_char();
+                    return Some(Ok((tok_start, Tok::Tilde, self.location)));
+                }
+                Some('@') => {
+                    self.next_char();
+                    match self.chr0 {
+                        Some('=') => {
+                            self.next_char();
+                            return Some(Ok((tok_start, Tok::AtEqual, self.location)));
                         }
-                        _ => return Some(Ok((self.location, Tok::Equal, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::At, self.location))),
                     }
                 }
                 Some('+') => {
@@ -259,33 +349,43 @@ impl<'input> Iterator for Lexer<'input> {
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((self.location, Tok::PlusEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::PlusEqual, self.location)));
                         }
-                        _ => return Some(Ok((self.location, Tok::Plus, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Plus, self.location))),
                     }
                 }
                 Some('*') => {
-                    let tok_start = self.location;
                     self.next_char();
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((tok_start, Tok::StarEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::StarEqual, self.location)));
                         }
                         Some('*') => {
                             self.next_char();
-                            return Some(Ok((tok_start, Tok::DoubleStar, self.location + 1)));
+                            match self.chr0 {
+                                Some('=') => {
+                                    self.next_char();
+                                    return Some(Ok((
+                                        tok_start,
+                                        Tok::DoubleStarEqual,
+                                        self.location,
+                                    )));
+                                }
+                                _ => {
+                                    return Some(Ok((tok_start, Tok::DoubleStar, self.location)))
+                                }
+                            }
                         }
-                        _ => return Some(Ok((tok_start, Tok::Star, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Star, self.location))),
                     }
                 }
                 Some('/') => {
-                    let tok_start = self.location;
                     self.next_char();
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((tok_start, Tok::SlashEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::SlashEqual, self.location)));
                         }
                         Some('/') => {
                             self.next_char();
@@ -295,19 +395,19 @@ impl<'input> Iterator for Lexer<'input> {
                                     return Some(Ok((
                                         tok_start,
                                         Tok::DoubleSlashEqual,
-                                        self.location + 1,
+                                        self.location,
                                     )));
                                 }
                                 _ => {
                                     return Some(Ok((
                                         tok_start,
                                         Tok::DoubleSlash,
-                                        self.location + 1,
+                                        self.location,
                                     )))
                                 }
                             }
                         }
-                        _ => return Some(Ok((tok_start, Tok::Slash, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Slash, self.location))),
                     }
                 }
                 Some('%') => {
@@ -315,9 +415,9 @@ impl<'input> Iterator for Lexer<'input> {
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((self.location, Tok::PercentEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::PercentEqual, self.location)));
                         }
-                        _ => return Some(Ok((self.location, Tok::Percent, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Percent, self.location))),
                     }
                 }
                 Some('|') => {
@@ -325,9 +425,9 @@ impl<'input> Iterator for Lexer<'input> {
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((self.location, Tok::VbarEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::VbarEqual, self.location)));
                         }
-                        _ => return Some(Ok((self.location, Tok::Vbar, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Vbar, self.location))),
                     }
                 }
                 Some('^') => {
@@ -336,12 +436,12 @@ impl<'input> Iterator for Lexer<'input> {
                         Some('=') => {
                             self.next_char();
                             return Some(Ok((
-                                self.location,
+                                tok_start,
                                 Tok::CircumflexEqual,
-                                self.location + 1,
+                                self.location,
                             )));
                         }
-                        _ => return Some(Ok((self.location, Tok::CircumFlex, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::CircumFlex, self.location))),
                     }
                 }
                 Some('&') => {
@@ -349,115 +449,128 @@ impl<'input> Iterator for Lexer<'input> {
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((self.location, Tok::AmperEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::AmperEqual, self.location)));
                         }
-                        _ => return Some(Ok((self.location, Tok::Amper, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Amper, self.location))),
                     }
                 }
                 Some('-') => {
-                    let tok_start = self.location;
                     self.next_char();
                     match self.chr0 {
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((tok_start, Tok::MinusEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::MinusEqual, self.location)));
+                        }
+                        Some('>') => {
+                            self.next_char();
+                            return Some(Ok((tok_start, Tok::Rarrow, self.location)));
                         }
-                        _ => return Some(Ok((tok_start, Tok::Minus, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Minus, self.location))),
                     }
                 }
                 Some('(') => {
                     self.next_char();
                     self.nesting += 1;
-                    return Some(Ok((0, Tok::Lpar, 0)));
+                    return Some(Ok((tok_start, Tok::Lpar, self.location)));
                 }
                 Some(')') => {
                     self.next_char();
-                    self.nesting -= 1;
-                    return Some(Ok((0, Tok::Rpar, 0)));
+                    // An unmatched bracket is left to the parser to report:
+                    self.nesting = self.nesting.saturating_sub(1);
+                    return Some(Ok((tok_start, Tok::Rpar, self.location)));
                 }
                 Some('[') => {
                     self.next_char();
                     self.nesting += 1;
-                    return Some(Ok((0, Tok::Lsqb, 0)));
+                    return Some(Ok((tok_start, Tok::Lsqb, self.location)));
                 }
                 Some(']') => {
                     self.next_char();
-                    self.nesting -= 1;
-                    return Some(Ok((self.location, Tok::Rsqb, self.location + 1)));
+                    self.nesting = self.nesting.saturating_sub(1);
+                    return Some(Ok((tok_start, Tok::Rsqb, self.location)));
                 }
                 Some('{') => {
                     self.next_char();
                     self.nesting += 1;
-                    return Some(Ok((0, Tok::Lbrace, 0)));
+                    return Some(Ok((tok_start, Tok::Lbrace, self.location)));
                 }
                 Some('}') => {
                     self.next_char();
-                    self.nesting -= 1;
-                    return Some(Ok((self.location, Tok::Rbrace, self.location + 1)));
+                    self.nesting = self.nesting.saturating_sub(1);
+                    return Some(Ok((tok_start, Tok::Rbrace, self.location)));
                 }
                 Some(':') => {
                     self.next_char();
-                    return Some(Ok((self.location, Tok::Colon, self.location + 1)));
+                    return Some(Ok((tok_start, Tok::Colon, self.location)));
                 }
                 Some('<') => {
-                    let tok_start = self.location;
                     self.next_char();
                     match self.chr0 {
                         Some('<') => {
                             self.next_char();
                             match self.chr0 {
                                 Some('=') => {
+                                    self.next_char();
                                     return Some(Ok((
                                         tok_start,
                                         Tok::LeftShiftEqual,
-                                        self.location + 1,
+                                        self.location,
                                     )))
                                 }
                                 _ => {
-                                    return Some(Ok((tok_start, Tok::LeftShift, self.location + 1)))
+                                    return Some(Ok((tok_start, Tok::LeftShift, self.location)))
                                 }
                             }
                         }
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((tok_start, Tok::LessEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::LessEqual, self.location)));
                         }
-                        _ => return Some(Ok((tok_start, Tok::Less, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Less, self.location))),
                     }
                 }
                 Some('>') => {
-                    let tok_start = self.location;
                     self.next_char();
                     match self.chr0 {
                         Some('>') => {
                             self.next_char();
                             match self.chr0 {
                                 Some('=') => {
+                                    self.next_char();
                                     return Some(Ok((
                                         tok_start,
                                         Tok::RightShiftEqual,
-                                        self.location + 1,
+                                        self.location,
                                     )))
                                 }
                                 _ => {
-                                    return Some(Ok((tok_start, Tok::RightShift, self.location + 1)))
+                                    return Some(Ok((tok_start, Tok::RightShift, self.location)))
                                 }
                             }
                         }
                         Some('=') => {
                             self.next_char();
-                            return Some(Ok((tok_start, Tok::GreaterEqual, self.location + 1)));
+                            return Some(Ok((tok_start, Tok::GreaterEqual, self.location)));
                         }
-                        _ => return Some(Ok((tok_start, Tok::Greater, self.location + 1))),
+                        _ => return Some(Ok((tok_start, Tok::Greater, self.location))),
                     }
                 }
                 Some(',') => {
                     self.next_char();
-                    return Some(Ok((self.location, Tok::Comma, self.location + 1)));
+                    return Some(Ok((tok_start, Tok::Comma, self.location)));
+                }
+                Some(';') => {
+                    self.next_char();
+                    return Some(Ok((tok_start, Tok::Semi, self.location)));
                 }
                 Some('.') => {
                     self.next_char();
-                    return Some(Ok((self.location, Tok::Dot, self.location + 1)));
+                    if let (Some('.'), Some('.')) = (self.chr0, self.chr1) {
+                        self.next_char();
+                        self.next_char();
+                        return Some(Ok((tok_start, Tok::Ellipses, self.location)));
+                    }
+                    return Some(Ok((tok_start, Tok::Dot, self.location)));
                 }
                 Some('\n') => {
                     self.next_char();
@@ -465,21 +578,41 @@ impl<'input> Iterator for Lexer<'input> {
                     // Depending on the nesting level, we emit newline or not:
                     if self.nesting == 0 {
                         self.at_begin_of_line = true;
-                        return Some(Ok((self.location, Tok::Newline, self.location + 1)));
+                        return Some(Ok((tok_start, Tok::Newline, self.location)));
                     } else {
                         continue;
                     }
                 }
-                Some(' ') => {
+                Some(' ') | Some('\t') | Some('\r') => {
                     // Skip whitespaces
                     self.next_char();
                     continue;
                 }
-                None => return None,
-                _ => {
-                    let c = self.next_char();
-                    panic!("Not impl {:?}", c)
-                } // Ignore all the rest..
+                Some('\\') => {
+                    // Explicit line joining:
+                    self.next_char();
+                    match self.chr0 {
+                        Some('\n') => {
+                            self.next_char();
+                            continue;
+                        }
+                        _ => {
+                            return Some(Err(LexicalError::UnrecognizedCharacter))
+                        }
+                    }
+                }
+                None => {
+                    // Close all open indentation levels at the end of the file:
+                    if self.indentation_stack.len() > 1 {
+                        self.indentation_stack.pop();
+                        return Some(Ok((self.location, Tok::Dedent, self.location)));
+                    }
+                    return None;
+                }
+                Some(_) => {
+                    self.next_char();
+                    return Some(Err(LexicalError::UnrecognizedCharacter));
+                }
             }
         }
     }
@@ -487,8 +620,8 @@ impl<'input> Iterator for Lexer<'input> {
 
 #[cfg(test)]
 mod tests {
-    use super::Tok;
-    use super::lex_source;
+    use super::super::ast::Location;
+    use super::{lex_source, Lexer, Tok};
 
     #[test]
     fn test_line_comment() {
@@ -578,4 +711,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_locations() {
+        let source = "if x:\n    y = 'ab'\n";
+        let spans: Vec<(Location, Location)> = Lexer::new(source)
+            .map(|x| {
+                let (start, _, end) = x.unwrap();
+                (start, end)
+            })
+            .collect();
+        // The name `y` follows the indent, the string includes its quotes:
+        assert_eq!(spans[0], (Location::new(1, 0), Location::new(1, 2)));
+        assert_eq!(spans[5], (Location::new(2, 4), Location::new(2, 5)));
+        assert_eq!(spans[7], (Location::new(2, 8), Location::new(2, 12)));
+    }
 }
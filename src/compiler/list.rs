@@ -0,0 +1,131 @@
+/*
+ * Methods of list objects.
+ */
+
+use super::builtins::{check_arg_count, check_positional_count};
+use super::bytecode;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::vm::VirtualMachine;
+
+pub fn get_method(name: &str) -> Option<RustPyFunc> {
+    match name {
+        "append" => Some(list_append),
+        "extend" => Some(list_extend),
+        "sort" => Some(list_sort),
+        _ => None,
+    }
+}
+
+pub fn append(list: &PyObjectRef, element: PyObjectRef) {
+    match *list.borrow_mut() {
+        PyObject::List { ref mut elements } => elements.push(element),
+        _ => panic!("Expected a list"),
+    }
+}
+
+fn list_append(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "append", &args, 2, 2)?;
+    append(&args.args[0], args.args[1].clone());
+    Ok(vm.get_none())
+}
+
+fn list_extend(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "extend", &args, 2, 2)?;
+    for element in vm.extract_elements(&args.args[1])? {
+        append(&args.args[0], element);
+    }
+    Ok(vm.get_none())
+}
+
+fn list_sort(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_positional_count(vm, "sort", &args, 1, 1)?;
+    let (key, reverse) = sort_options(vm, "sort", &args)?;
+    let elements = match *args.args[0].borrow() {
+        PyObject::List { ref elements } => elements.clone(),
+        _ => panic!("Expected a list"),
+    };
+    let elements = sort(vm, elements, key, reverse)?;
+    if let PyObject::List {
+        elements: ref mut list_elements,
+    } = *args.args[0].borrow_mut()
+    {
+        *list_elements = elements;
+    }
+    Ok(vm.get_none())
+}
+
+// Get the `key` and `reverse` keyword arguments of a sort:
+pub fn sort_options(
+    vm: &mut VirtualMachine,
+    name: &str,
+    args: &PyFuncArgs,
+) -> Result<(Option<PyObjectRef>, bool), PyObjectRef> {
+    let mut key = None;
+    let mut reverse = false;
+    for (kwarg, value) in &args.kwargs {
+        match kwarg.as_str() {
+            "key" => {
+                if !matches!(*value.borrow(), PyObject::None) {
+                    key = Some(value.clone());
+                }
+            }
+            "reverse" => reverse = vm.is_true(value.clone())?,
+            _ => {
+                let msg = format!("'{}' is an invalid keyword argument for {}()", kwarg, name);
+                return Err(vm.new_exception("TypeError", msg));
+            }
+        }
+    }
+    Ok((key, reverse))
+}
+
+// A stable sort, which compares the elements (or their keys) with `<`.
+pub fn sort(
+    vm: &mut VirtualMachine,
+    elements: Vec<PyObjectRef>,
+    key: Option<PyObjectRef>,
+    reverse: bool,
+) -> Result<Vec<PyObjectRef>, PyObjectRef> {
+    let mut items = vec![];
+    for element in elements {
+        let sort_key = match key {
+            Some(ref key) => vm.invoke(key.clone(), vec![element.clone()])?,
+            None => element.clone(),
+        };
+        items.push((sort_key, element));
+    }
+    let items = merge_sort(vm, items, reverse)?;
+    Ok(items.into_iter().map(|item| item.1).collect())
+}
+
+type SortItem = (PyObjectRef, PyObjectRef);
+
+// The comparisons can raise an exception, so the sort is done by hand:
+fn merge_sort(vm: &mut VirtualMachine, items: Vec<SortItem>, reverse: bool) -> Result<Vec<SortItem>, PyObjectRef> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let mut left = items;
+    let right = left.split_off(left.len() / 2);
+    let mut left = merge_sort(vm, left, reverse)?.into_iter().peekable();
+    let mut right = merge_sort(vm, right, reverse)?.into_iter().peekable();
+
+    let mut result = vec![];
+    loop {
+        // Take from the right only when it is strictly ordered before the
+        // left, which keeps equal elements in their original order:
+        let take_right = match (left.peek(), right.peek()) {
+            (Some(l), Some(r)) => {
+                let (a, b) = if reverse { (&l.0, &r.0) } else { (&r.0, &l.0) };
+                let less = vm.compare(a.clone(), b.clone(), &bytecode::ComparisonOperator::Less)?;
+                vm.is_true(less)?
+            }
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => break,
+        };
+        let item = if take_right { right.next() } else { left.next() };
+        result.push(item.unwrap());
+    }
+    Ok(result)
+}
@@ -0,0 +1,277 @@
+/*
+ * Serialize code objects, with their constants and nested code, to bytes.
+ * Like the marshal format of CPython, every value starts with a type byte.
+ * Numbers are little endian, and lengths are 32 bit. The format has a
+ * version, which changes whenever the format or the instructions change.
+ */
+
+use std::rc::Rc;
+
+use super::bytecode::{CodeObject, Constant, LineTable};
+
+pub const VERSION: u32 = 1;
+
+// Nested tuples and code objects are read recursively, a limit on the depth
+// keeps bad data from overflowing the stack:
+const MAX_DEPTH: usize = 200;
+
+pub fn dumps(code: &CodeObject) -> Vec<u8> {
+    let mut data = vec![];
+    write_code(&mut data, code);
+    data
+}
+
+pub fn loads(data: &[u8]) -> Result<CodeObject, String> {
+    let mut reader = Reader { data, offset: 0 };
+    if reader.byte()? != b'c' {
+        return Err(String::from("marshal data is not a code object"));
+    }
+    let code = reader.code(0)?;
+    if reader.offset != data.len() {
+        return Err(String::from("extra data after the code object"));
+    }
+    Ok(code)
+}
+
+fn write_code(data: &mut Vec<u8>, code: &CodeObject) {
+    data.push(b'c');
+    write_u32(data, code.arg_count as u32);
+    data.push(code.is_generator as u8);
+    write_bytes(data, &code.code);
+    write_u32(data, code.consts.len() as u32);
+    for constant in &code.consts {
+        write_constant(data, constant);
+    }
+    for table in &[&code.names, &code.varnames, &code.freevars, &code.cellvars] {
+        write_u32(data, table.len() as u32);
+        for name in table.iter() {
+            write_bytes(data, name.as_bytes());
+        }
+    }
+    write_bytes(data, code.filename.as_bytes());
+    write_bytes(data, code.name.as_bytes());
+    write_bytes(data, code.qualname.as_bytes());
+    write_u32(data, code.firstlineno as u32);
+    write_bytes(data, code.linetable.as_bytes());
+}
+
+fn write_constant(data: &mut Vec<u8>, constant: &Constant) {
+    match *constant {
+        Constant::None => data.push(b'N'),
+        Constant::Ellipsis => data.push(b'.'),
+        Constant::Boolean { value } => data.push(if value { b'T' } else { b'F' }),
+        Constant::Integer { value } => {
+            data.push(b'i');
+            write_u32(data, value as u32);
+        }
+        Constant::Float { value } => {
+            data.push(b'g');
+            write_f64(data, value);
+        }
+        Constant::Complex { real, imag } => {
+            data.push(b'y');
+            write_f64(data, real);
+            write_f64(data, imag);
+        }
+        Constant::String { ref value } => {
+            data.push(b'u');
+            write_bytes(data, value.as_bytes());
+        }
+        Constant::Bytes { ref value } => {
+            data.push(b's');
+            write_bytes(data, value);
+        }
+        Constant::Tuple { ref elements } | Constant::FrozenSet { ref elements } => {
+            data.push(match *constant {
+                Constant::Tuple { .. } => b'(',
+                _ => b'>',
+            });
+            write_u32(data, elements.len() as u32);
+            for element in elements {
+                write_constant(data, element);
+            }
+        }
+        Constant::Code { ref code } => write_code(data, code),
+    }
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_f64(data: &mut Vec<u8>, value: f64) {
+    data.extend_from_slice(&value.to_bits().to_le_bytes());
+}
+
+fn write_bytes(data: &mut Vec<u8>, value: &[u8]) {
+    write_u32(data, value.len() as u32);
+    data.extend_from_slice(value);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        match self.data.get(self.offset..self.offset + size) {
+            Some(bytes) => {
+                self.offset += size;
+                Ok(bytes)
+            }
+            None => Err(String::from("marshal data too short")),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let bytes = self.take(8)?;
+        let mut bits = [0; 8];
+        bits.copy_from_slice(bytes);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let size = self.u32()? as usize;
+        Ok(self.take(size)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| String::from("bad utf-8 in marshal data"))
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, String> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+
+    fn constant(&mut self, depth: usize) -> Result<Constant, String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("marshal data nested too deeply"));
+        }
+        let constant = match self.byte()? {
+            b'N' => Constant::None,
+            b'.' => Constant::Ellipsis,
+            b'T' => Constant::Boolean { value: true },
+            b'F' => Constant::Boolean { value: false },
+            b'i' => Constant::Integer {
+                value: self.u32()? as i32,
+            },
+            b'g' => Constant::Float { value: self.f64()? },
+            b'y' => Constant::Complex {
+                real: self.f64()?,
+                imag: self.f64()?,
+            },
+            b'u' => Constant::String {
+                value: self.string()?,
+            },
+            b's' => Constant::Bytes {
+                value: self.bytes()?,
+            },
+            tag @ b'(' | tag @ b'>' => {
+                let count = self.u32()?;
+                let elements = (0..count)
+                    .map(|_| self.constant(depth + 1))
+                    .collect::<Result<Vec<Constant>, String>>()?;
+                if tag == b'(' {
+                    Constant::Tuple { elements }
+                } else {
+                    Constant::FrozenSet { elements }
+                }
+            }
+            b'c' => Constant::Code {
+                code: Rc::new(self.code(depth + 1)?),
+            },
+            tag => return Err(format!("bad marshal type {:?}", tag as char)),
+        };
+        Ok(constant)
+    }
+
+    fn code(&mut self, depth: usize) -> Result<CodeObject, String> {
+        let arg_count = self.u32()? as usize;
+        let is_generator = self.byte()? != 0;
+        let code_bytes = self.bytes()?;
+        let count = self.u32()?;
+        let consts = (0..count)
+            .map(|_| self.constant(depth + 1))
+            .collect::<Result<Vec<Constant>, String>>()?;
+        let names = self.strings()?;
+        let varnames = self.strings()?;
+        let freevars = self.strings()?;
+        let cellvars = self.strings()?;
+        let filename = self.string()?;
+        let name = self.string()?;
+        let qualname = self.string()?;
+        let firstlineno = self.u32()? as usize;
+        let linetable = LineTable::from_bytes(self.bytes()?)?;
+
+        let mut code = CodeObject::new(varnames, name);
+        code.code = code_bytes;
+        code.arg_count = arg_count;
+        code.consts = consts;
+        code.names = names;
+        code.freevars = freevars;
+        code.cellvars = cellvars;
+        code.is_generator = is_generator;
+        code.linetable = linetable;
+        code.filename = filename;
+        code.qualname = qualname;
+        code.firstlineno = firstlineno;
+        code.validate()?;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bytecode::Constant;
+    use super::super::compile::compile;
+    use super::super::parser::parse_source;
+    use super::{dumps, loads};
+
+    #[test]
+    fn test_marshal_code() {
+        let source = "
+def f(a, b=None):
+    x = (1, 'é', None, True)
+    def g():
+        return a
+    return g
+";
+        let code = compile(parse_source(source).unwrap()).unwrap();
+        let data = dumps(&code);
+        let loaded = loads(&data).unwrap();
+        assert_eq!(dumps(&loaded), data);
+        assert_eq!(loaded.names, code.names);
+        let f = loaded
+            .consts
+            .iter()
+            .filter_map(|constant| match *constant {
+                Constant::Code { ref code } => Some(code.clone()),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        assert_eq!(f.qualname, "f");
+        assert_eq!(f.cellvars, vec!["a"]);
+        assert_eq!(f.linetable.locations().len(), f.code.len() / 2);
+
+        assert!(loads(&data[..data.len() - 1]).is_err());
+        let mut extended = data.clone();
+        extended.push(0);
+        assert!(loads(&extended).is_err());
+        // A name index out of range is refused:
+        let mut code = code;
+        code.names.clear();
+        assert!(loads(&dumps(&code)).is_err());
+    }
+}
@@ -1,16 +1,48 @@
 // This file makes this directory a submodule.
 
 mod parser;
+#[allow(unused_parens, clippy::all)]
 mod python;
-mod ast;
+pub mod ast;
 mod token;
 mod lexer;
+mod assembler;
 mod compile;
+mod convert;
+mod cpython;
 mod bytecode;
 mod builtins;
+mod code;
+mod compileall;
+mod descriptor;
+mod dict;
+mod dis;
+mod exceptions;
+mod fileio;
+mod frame;
+mod generator;
+mod import;
+mod list;
+mod marshal;
+mod native;
+mod pycache;
 mod pyobject;
+mod set;
+mod symboltable;
 mod vm;
 
-pub use self::parser::parse;
-pub use self::compile::compile;
-pub use self::vm::evaluate;
+pub use self::parser::{parse, parse_interactive, parse_source};
+pub use self::lexer::{LexicalError, Lexer, Spanned};
+pub use self::token::Tok;
+pub use self::compile::{compile, compile_with_options, CompileOptions};
+pub use self::assembler::assemble;
+pub use self::bytecode::CodeObject;
+pub use self::dis::disassemble;
+pub use self::convert::{
+    bind_args, missing_argument, FromPyArg, FromPyObject, IntoNativeFunc, IntoPyObject,
+};
+pub use self::native::{
+    new_native_instance, with_payload, FunctionDef, NativeModule, NativeType, PyClass, PyMethods,
+};
+pub use self::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult};
+pub use self::vm::{Settings, VirtualMachine};
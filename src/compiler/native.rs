@@ -0,0 +1,612 @@
+/*
+ * Modules implemented in Rust. A native module declares its functions,
+ * constants and types, and is registered with the virtual machine. Importing
+ * it creates the module object, before the file system is searched.
+ *
+ * Instances of a native type carry a rust value, which the methods and
+ * attributes of the type work on.
+ */
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::convert::{FromPyObject, IntoNativeFunc, IntoPyObject};
+use super::exceptions;
+use super::import::new_module;
+use super::pyobject::{NativeFunc, PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::vm::VirtualMachine;
+
+// A function with its name and documentation, as declared by #[pyfunction]:
+pub struct FunctionDef {
+    pub name: &'static str,
+    pub doc: Option<&'static str>,
+    pub function: NativeFunc,
+}
+
+pub struct NativeModule {
+    pub name: String,
+    doc: Option<String>,
+    functions: Vec<(String, NativeFunc, Option<String>)>,
+    constants: Vec<(String, PyObjectRef)>,
+    // Exception classes, with the names of their builtin bases:
+    exceptions: Vec<(String, Vec<String>)>,
+    types: Vec<NativeType>,
+}
+
+impl NativeModule {
+    pub fn new(name: &str) -> NativeModule {
+        NativeModule {
+            name: String::from(name),
+            doc: None,
+            functions: vec![],
+            constants: vec![],
+            exceptions: vec![],
+            types: vec![],
+        }
+    }
+
+    // The docstring of the module:
+    pub fn doc(mut self, doc: &str) -> NativeModule {
+        self.doc = Some(String::from(doc));
+        self
+    }
+
+    // Functions may take the call arguments as they are, or have arguments
+    // and a result which are converted:
+    pub fn function<F, Args>(mut self, name: &str, function: F) -> NativeModule
+    where
+        F: IntoNativeFunc<Args>,
+    {
+        self.functions
+            .push((String::from(name), function.into_func(), None));
+        self
+    }
+
+    pub fn function_def(mut self, def: FunctionDef) -> NativeModule {
+        let doc = def.doc.map(String::from);
+        self.functions.push((String::from(def.name), def.function, doc));
+        self
+    }
+
+    pub fn constant(mut self, name: &str, value: PyObjectRef) -> NativeModule {
+        self.constants.push((String::from(name), value));
+        self
+    }
+
+    // An exception class, derived from builtin exception classes:
+    pub fn exception(mut self, name: &str, bases: Vec<&str>) -> NativeModule {
+        let bases = bases.into_iter().map(String::from).collect();
+        self.exceptions.push((String::from(name), bases));
+        self
+    }
+
+    // A class with the given methods:
+    pub fn class(self, name: &str, methods: Vec<(&str, RustPyFunc)>) -> NativeModule {
+        let native_type = methods
+            .into_iter()
+            .fold(NativeType::new(name), |native_type, (name, function)| {
+                native_type.function(name, function)
+            });
+        self.native_type(native_type)
+    }
+
+    pub fn native_type(mut self, native_type: NativeType) -> NativeModule {
+        self.types.push(native_type);
+        self
+    }
+
+    // Create the module object, with fresh functions and types:
+    pub fn make_module(&self, vm: &mut VirtualMachine) -> PyObjectRef {
+        let module = new_module(&self.name);
+        let mut values = vec![];
+        if let Some(ref doc) = self.doc {
+            let doc = PyObject::String { value: doc.clone() }.into_ref();
+            values.push((String::from("__doc__"), doc));
+        }
+        for (name, function, doc) in &self.functions {
+            values.push((name.clone(), new_function(function, doc)));
+        }
+        for (name, value) in &self.constants {
+            values.push((name.clone(), value.clone()));
+        }
+        for (name, bases) in &self.exceptions {
+            let bases = bases.iter().map(|base| vm.get_builtin(base)).collect();
+            values.push((name.clone(), exceptions::new_class(name, bases, vec![])));
+        }
+        for native_type in &self.types {
+            values.push((native_type.name.clone(), native_type.make_class(vm)));
+        }
+        if let PyObject::Module { ref scope, .. } = *module.borrow() {
+            scope.borrow_mut().locals.extend(values);
+        }
+        module
+    }
+}
+
+/*
+ * A type implemented in Rust. Its methods and attributes get the rust value
+ * of the instance, which is created by `init`.
+ */
+pub struct NativeType {
+    name: String,
+    doc: Option<String>,
+    functions: Vec<(String, NativeFunc, Option<String>)>,
+    // Attributes are properties with a getter and an optional setter:
+    attributes: Vec<(String, NativeFunc, Option<NativeFunc>)>,
+}
+
+impl NativeType {
+    pub fn new(name: &str) -> NativeType {
+        NativeType {
+            name: String::from(name),
+            doc: None,
+            functions: vec![],
+            attributes: vec![],
+        }
+    }
+
+    // The docstring of the class:
+    pub fn doc(mut self, doc: &str) -> NativeType {
+        self.doc = Some(String::from(doc));
+        self
+    }
+
+    // Set the docstring of a method:
+    pub fn document(mut self, name: &str, doc: &str) -> NativeType {
+        match self.functions.iter_mut().find(|function| function.0 == name) {
+            Some(function) => function.2 = Some(String::from(doc)),
+            None => panic!("Docstring for the unknown method '{}'", name),
+        }
+        self
+    }
+
+    // A method which gets the instance as its first argument, like methods
+    // defined in python:
+    pub fn function<F, Args>(mut self, name: &str, function: F) -> NativeType
+    where
+        F: IntoNativeFunc<Args>,
+    {
+        self.functions
+            .push((String::from(name), function.into_func(), None));
+        self
+    }
+
+    // Create the rust value of a new instance from the call arguments:
+    pub fn init<T, F>(self, init: F) -> NativeType
+    where
+        T: Any,
+        F: Fn(&mut VirtualMachine, PyFuncArgs) -> PyResult<T> + 'static,
+    {
+        self.function("__init__", move |vm: &mut VirtualMachine, args: PyFuncArgs| {
+            let (obj, args) = split_self(vm, "__init__", args)?;
+            let value = init(vm, args)?;
+            set_payload(vm, &obj, value)?;
+            Ok(vm.get_none())
+        })
+    }
+
+    // A method working on the rust value of the instance:
+    pub fn method<T, F>(self, name: &str, method: F) -> NativeType
+    where
+        T: Any,
+        F: Fn(&mut VirtualMachine, &mut T, PyFuncArgs) -> PyResult + 'static,
+    {
+        let method_name = String::from(name);
+        self.function(name, move |vm: &mut VirtualMachine, args: PyFuncArgs| {
+            let (obj, args) = split_self(vm, &method_name, args)?;
+            with_payload(vm, &obj, |vm, value| method(vm, value, args))
+        })
+    }
+
+    // A read only attribute, computed from the rust value of the instance:
+    pub fn attribute<T, R, F>(mut self, name: &str, getter: F) -> NativeType
+    where
+        T: Any,
+        R: IntoPyObject,
+        F: Fn(&T) -> R + 'static,
+    {
+        let attribute_name = String::from(name);
+        let getter = move |vm: &mut VirtualMachine, args: PyFuncArgs| {
+            let (obj, _) = split_self(vm, &attribute_name, args)?;
+            let value = with_payload(vm, &obj, |_vm, value: &mut T| Ok(getter(value)))?;
+            value.into_py_object(vm)
+        };
+        self.attributes
+            .push((String::from(name), Rc::new(getter), None));
+        self
+    }
+
+    // Make an attribute writable. The assigned value is converted:
+    pub fn setter<T, V, F>(mut self, name: &str, setter: F) -> NativeType
+    where
+        T: Any,
+        V: FromPyObject,
+        F: Fn(&mut T, V) + 'static,
+    {
+        let attribute_name = String::from(name);
+        let setter = move |vm: &mut VirtualMachine, args: PyFuncArgs| {
+            let (obj, args) = split_self(vm, &attribute_name, args)?;
+            let new_value = V::from_py_object(vm, &args.args[0])?;
+            with_payload(vm, &obj, |_vm, value: &mut T| {
+                setter(value, new_value);
+                Ok(())
+            })?;
+            Ok(vm.get_none())
+        };
+        match self.attributes.iter_mut().find(|attribute| attribute.0 == name) {
+            Some(attribute) => attribute.2 = Some(Rc::new(setter)),
+            None => panic!("Setter for the unknown attribute '{}'", name),
+        }
+        self
+    }
+
+    pub fn make_class(&self, vm: &mut VirtualMachine) -> PyObjectRef {
+        let mut dict = HashMap::new();
+        if let Some(ref doc) = self.doc {
+            let doc = PyObject::String { value: doc.clone() }.into_ref();
+            dict.insert(String::from("__doc__"), doc);
+        }
+        for (name, function, doc) in &self.functions {
+            dict.insert(name.clone(), new_function(function, doc));
+        }
+        for (name, getter, setter) in &self.attributes {
+            let getter = PyObject::RustFunction {
+                function: getter.clone(),
+                doc: None,
+            }.into_ref();
+            let setter = match setter {
+                Some(setter) => PyObject::RustFunction {
+                    function: setter.clone(),
+                    doc: None,
+                }.into_ref(),
+                None => vm.get_none(),
+            };
+            let property = vm.get_builtin("property");
+            let property = vm
+                .invoke(property, vec![getter, setter])
+                .expect("Creating a property should not fail");
+            dict.insert(name.clone(), property);
+        }
+        PyObject::Class {
+            name: self.name.clone(),
+            dict,
+            bases: vec![],
+        }.into_ref()
+    }
+}
+
+/*
+ * The types declared with #[pyclass], and their methods declared with
+ * #[pymethods]. A class needs both to be added to a module.
+ */
+pub trait PyClass: Any {
+    fn class_name() -> &'static str;
+    fn class_doc() -> Option<&'static str>;
+}
+
+pub trait PyMethods: PyClass {
+    fn native_type() -> NativeType;
+}
+
+fn new_function(function: &NativeFunc, doc: &Option<String>) -> PyObjectRef {
+    PyObject::RustFunction {
+        function: function.clone(),
+        doc: doc.clone(),
+    }.into_ref()
+}
+
+// Separate the instance from the other arguments of a method:
+fn split_self(
+    vm: &mut VirtualMachine,
+    name: &str,
+    args: PyFuncArgs,
+) -> PyResult<(PyObjectRef, PyFuncArgs)> {
+    if args.args.is_empty() {
+        let msg = format!("{}() needs an argument", name);
+        return Err(vm.new_exception("TypeError", msg));
+    }
+    let mut positional = args.args;
+    let obj = positional.remove(0);
+    Ok((obj, PyFuncArgs::new(positional, args.kwargs)))
+}
+
+// An instance of a native type, with the given rust value:
+pub fn new_native_instance<T: Any>(class: &PyObjectRef, value: T) -> PyObjectRef {
+    PyObject::Instance {
+        class: class.clone(),
+        dict: HashMap::new(),
+        payload: Some(Box::new(value)),
+    }.into_ref()
+}
+
+fn set_payload<T: Any>(vm: &mut VirtualMachine, obj: &PyObjectRef, value: T) -> PyResult<()> {
+    if let PyObject::Instance { ref mut payload, .. } = *obj.borrow_mut() {
+        *payload = Some(Box::new(value));
+        return Ok(());
+    }
+    let msg = format!("'{}' object cannot hold a native value", obj.borrow().type_name());
+    Err(vm.new_exception("TypeError", msg))
+}
+
+// Run a function on the rust value of an instance. The value is taken out of
+// the instance meanwhile, so that the function can use the instance freely.
+pub fn with_payload<T, R, F>(vm: &mut VirtualMachine, obj: &PyObjectRef, f: F) -> PyResult<R>
+where
+    T: Any,
+    F: FnOnce(&mut VirtualMachine, &mut T) -> PyResult<R>,
+{
+    let payload = match *obj.borrow_mut() {
+        PyObject::Instance { ref mut payload, .. } => payload.take(),
+        _ => None,
+    };
+    let mut value = match payload.map(|payload| payload.downcast::<T>()) {
+        Some(Ok(value)) => value,
+        other => {
+            // Put back a value of another type:
+            if let Some(Err(payload)) = other {
+                restore_payload(obj, payload);
+            }
+            let msg = format!(
+                "'{}' object does not have the expected native value",
+                obj.borrow().type_name()
+            );
+            return Err(vm.new_exception("TypeError", msg));
+        }
+    };
+    let result = f(vm, &mut value);
+    restore_payload(obj, value);
+    result
+}
+
+fn restore_payload(obj: &PyObjectRef, value: Box<dyn Any>) {
+    if let PyObject::Instance { ref mut payload, .. } = *obj.borrow_mut() {
+        *payload = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bytecode::BinaryOperator;
+    use super::super::compile::compile;
+    use super::super::frame::Scope;
+    use super::super::parser::parse_source;
+    use super::super::pyobject::{PyFuncArgs, PyResult};
+    use super::super::vm::VirtualMachine;
+    use super::*;
+    use pymodule;
+    use std::cell::RefCell;
+
+    fn double(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+        let value = args.args[0].clone();
+        vm.binary_op(value.clone(), value, &BinaryOperator::Add, false)
+    }
+
+    fn counter_init(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+        vm.set_attribute(args.args[0].clone(), "count", args.args[1].clone())
+    }
+
+    #[test]
+    fn test_native_module() {
+        let mut vm = VirtualMachine::new();
+        vm.add_native_module(
+            NativeModule::new("ourlib")
+                .function("double", double)
+                .constant("VERSION", PyObject::Integer { value: 3 }.into_ref())
+                .class("Counter", vec![("__init__", counter_init)]),
+        );
+        vm.add_native_module(NativeModule::new("ourlib.extra").function("double", double));
+
+        let source = "
+import ourlib
+from ourlib import Counter
+import ourlib.extra as extra
+import io
+counter = Counter(5)
+x = [ourlib.double(21), ourlib.VERSION, counter.count, isinstance(counter, ourlib.Counter), extra.double('a'), open is io.TextIOWrapper]
+";
+        let code = compile(parse_source(source).unwrap()).unwrap();
+        let scope = Scope::new(None);
+        vm.run_code(code, scope.clone()).unwrap();
+        let x = scope.borrow().get("x").unwrap();
+        assert_eq!(x.borrow().repr(), "[42, 3, 5, True, 'aa', True]");
+    }
+
+    struct Account {
+        owner: String,
+        balance: i32,
+    }
+
+    fn account_type() -> NativeType {
+        NativeType::new("Account")
+            .init(|vm, args| {
+                let owner = String::from_py_object(vm, &args.args[0])?;
+                Ok(Account { owner, balance: 0 })
+            })
+            .method("deposit", |vm, account: &mut Account, args| {
+                account.balance += i32::from_py_object(vm, &args.args[0])?;
+                Ok(vm.get_none())
+            })
+            // The method runs while the value is taken out of the instance:
+            .method("call", |vm, _account: &mut Account, args| {
+                vm.invoke(args.args[0].clone(), vec![])
+            })
+            .attribute("balance", |account: &Account| account.balance)
+            .attribute("owner", |account: &Account| account.owner.clone())
+            .setter("owner", |account: &mut Account, owner: String| account.owner = owner)
+    }
+
+    #[test]
+    fn test_native_types() {
+        let mut vm = VirtualMachine::new();
+        let log = Rc::new(RefCell::new(vec![]));
+        let host_log = log.clone();
+        vm.add_native_module(
+            NativeModule::new("bank")
+                .native_type(account_type())
+                .function("log", move |message: String| {
+                    host_log.borrow_mut().push(message);
+                    Ok(())
+                }),
+        );
+        let counter = Rc::new(RefCell::new(0));
+        let host_counter = counter.clone();
+        let next = vm.new_function(move || {
+            *host_counter.borrow_mut() += 1;
+            Ok(*host_counter.borrow())
+        });
+        vm.set_global("next_id", next);
+
+        let source = "
+import bank
+account = bank.Account('ann')
+account.deposit(5)
+account.deposit(next_id())
+account.owner = account.owner + '!'
+bank.log(account.owner)
+errors = []
+calls = [lambda: account.call(lambda: account.balance), lambda: bank.Account.deposit(1, 2)]
+for call in calls:
+    try:
+        call()
+    except TypeError as e:
+        errors.append('expected native value' in str(e))
+try:
+    account.balance = 3
+except AttributeError:
+    errors.append('read only')
+x = [account.balance, next_id(), errors]
+";
+        vm.run_source(source).unwrap();
+        let x = vm.get_global("x").unwrap();
+        assert_eq!(
+            x.borrow().repr(),
+            "[6, 2, [True, True, 'read only']]"
+        );
+        assert_eq!(*log.borrow(), vec![String::from("ann!")]);
+        assert_eq!(*counter.borrow(), 2);
+
+        // The host can use the rust value of an instance:
+        let account = vm.get_global("account").unwrap();
+        let owner = with_payload(&mut vm, &account, |_vm, account: &mut Account| {
+            Ok(account.owner.clone())
+        });
+        assert_eq!(owner.unwrap(), "ann!");
+
+        // And give scripts new instances:
+        let class = vm.get_global("bank").unwrap();
+        let class = vm.get_attribute(class, "Account").unwrap();
+        let account = Account {
+            owner: String::from("bob"),
+            balance: 7,
+        };
+        vm.set_global("other", new_native_instance(&class, account));
+        vm.run_source("other.deposit(1)\nx = other.balance").unwrap();
+        assert_eq!(vm.get_global("x").unwrap().borrow().repr(), "8");
+    }
+
+    /// Shapes in the plane.
+    #[pymodule]
+    mod geometry {
+        use compiler::pyobject::PyResult;
+        use compiler::vm::VirtualMachine;
+        use {pyclass, pyfunction, pymethods};
+
+        /// The distance to the origin.
+        #[pyfunction]
+        #[args(y = 0)]
+        fn norm(x: i32, y: i32) -> PyResult<f64> {
+            Ok(f64::from(x * x + y * y).sqrt())
+        }
+
+        #[pyfunction(name = "describe")]
+        fn describe_shape(vm: &mut VirtualMachine, shape: String, sides: Option<i32>) -> PyResult<String> {
+            match sides {
+                Some(sides) => Ok(format!("{} with {} sides", shape, sides)),
+                None => Err(vm.new_exception("ValueError", String::from("no sides"))),
+            }
+        }
+
+        #[pyfunction]
+        #[args(unit = "cm")]
+        fn label(name: &str, unit: &str) -> PyResult<String> {
+            Ok(format!("{} ({})", name.trim(), unit))
+        }
+
+        /// A rectangle with a width and a height.
+        #[pyclass]
+        pub struct Rectangle {
+            width: i32,
+            height: i32,
+        }
+
+        #[pymethods]
+        impl Rectangle {
+            #[new]
+            #[args(height = 1)]
+            fn new(width: i32, height: i32) -> PyResult<Rectangle> {
+                Ok(Rectangle { width, height })
+            }
+
+            /// Multiply the size.
+            fn scale(&mut self, factor: i32) -> PyResult<()> {
+                self.width *= factor;
+                self.height *= factor;
+                Ok(())
+            }
+
+            fn area(&self) -> PyResult<i32> {
+                Ok(self.width * self.height)
+            }
+
+            #[getter]
+            fn width(&self) -> i32 {
+                self.width
+            }
+
+            #[setter]
+            fn set_width(&mut self, width: i32) {
+                self.width = width;
+            }
+        }
+    }
+
+    #[test]
+    fn test_declaration_macros() {
+        let mut vm = VirtualMachine::new();
+        vm.add_native_module(geometry::make_module());
+        let source = "
+import geometry
+from geometry import Rectangle
+r = Rectangle(2, height=3)
+r.scale(2)
+r.width = 5
+errors = []
+calls = [lambda: geometry.norm(), lambda: geometry.norm(1, x=2), lambda: geometry.describe('square'), lambda: geometry.label(unit='m'), lambda: geometry.label(1)]
+for call in calls:
+    try:
+        call()
+    except TypeError as e:
+        errors.append(str(e))
+    except ValueError as e:
+        errors.append(str(e))
+x = [geometry.norm(3, 4), geometry.norm(y=2, x=0), geometry.describe('square', sides=4), r.area(), Rectangle(4).area(), geometry.label(' side '), geometry.label('side', unit='m'), errors]
+docs = [geometry.__doc__, geometry.norm.__doc__, Rectangle.__doc__, Rectangle.scale.__doc__, Rectangle.area.__doc__]
+";
+        vm.run_source(source).unwrap();
+        let x = vm.get_global("x").unwrap();
+        assert_eq!(
+            x.borrow().repr(),
+            "[5.0, 2.0, 'square with 4 sides', 30, 4, 'side (cm)', 'side (m)', \
+             [\"norm() missing required argument: 'x'\", \
+             \"norm() got multiple values for argument 'x'\", 'no sides', \
+             \"label() missing required argument: 'name'\", 'expected str, got int']]"
+        );
+        let docs = vm.get_global("docs").unwrap();
+        assert_eq!(
+            docs.borrow().repr(),
+            "['Shapes in the plane.', 'The distance to the origin.', \
+             'A rectangle with a width and a height.', 'Multiply the size.', None]"
+        );
+    }
+}
@@ -1,23 +1,24 @@
-use std::error::Error;
 use std::path::Path;
 use std::fs::File;
 use std::io::Read;
 
+use lalrpop_util::ParseError;
+
 use super::python;
 use super::ast;
 use super::lexer;
 
 fn read_file(filename: &Path) -> Result<String, String> {
-    match File::open(&filename) {
+    match File::open(filename) {
         Ok(mut file) => {
             let mut s = String::new();
 
             match file.read_to_string(&mut s) {
-                Err(why) => Err(String::from("Reading file failed: ") + why.description()),
+                Err(why) => Err(format!("Reading file failed: {}", why)),
                 Ok(_) => Ok(s),
             }
         }
-        Err(why) => Err(String::from("Opening file failed: ") + why.description()),
+        Err(why) => Err(format!("Opening file failed: {}", why)),
     }
 }
 
@@ -38,19 +39,47 @@ pub fn parse(filename: &Path) -> Result<ast::Program, String> {
     }
 }
 
-pub fn parse_source(source: &String) -> Result<ast::Program, String> {
+pub fn parse_source(source: &str) -> Result<ast::Program, String> {
+    // The grammar expects every statement to be terminated by a newline:
+    let mut source = String::from(source);
+    if !source.ends_with('\n') {
+        source.push('\n');
+    }
     let lxr = lexer::Lexer::new(&source);
     match python::ProgramParser::new().parse(lxr) {
-        Err(why) => Err(String::from(format!("{:?}", why))),
+        Err(why) => Err(format!("{:?}", why)),
         Ok(p) => Ok(p),
     }
 }
 
+// Parse code typed at the interactive prompt. Input which ends before the
+// statement is complete, like in an open block or bracket, gives None, so
+// that more lines can be read.
+pub fn parse_interactive(source: &str) -> Result<Option<ast::Program>, String> {
+    let mut source = String::from(source);
+    if !source.ends_with('\n') {
+        source.push('\n');
+    }
+    let lxr = lexer::Lexer::new(&source);
+    match python::ProgramParser::new().parse(lxr) {
+        Err(ParseError::UnrecognizedToken { token: None, .. }) => Ok(None),
+        Err(why) => Err(format!("{:?}", why)),
+        Ok(p) => Ok(Some(p)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_source;
+    use super::{parse_interactive, parse_source};
     use super::ast;
 
+    fn located(row: usize, column: usize, node: ast::Statement) -> ast::LocatedStatement {
+        ast::LocatedStatement {
+            location: ast::Location::new(row, column),
+            node,
+        }
+    }
+
     #[test]
     fn test_parse_print_hello() {
         let source = String::from("print('Hello world')\n");
@@ -59,18 +88,23 @@ mod tests {
             parse_ast,
             ast::Program {
                 statements: vec![
-                    ast::Statement::Expression {
-                        expression: ast::Expression::Call {
-                            function: Box::new(ast::Expression::Identifier {
-                                name: String::from("print"),
-                            }),
-                            args: vec![
-                                ast::Expression::String {
-                                    value: String::from("Hello world"),
-                                },
-                            ],
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Call {
+                                function: Box::new(ast::Expression::Identifier {
+                                    name: String::from("print"),
+                                }),
+                                args: vec![
+                                    ast::Expression::String {
+                                        value: String::from("Hello world"),
+                                    },
+                                ],
+                                keywords: vec![],
+                            },
                         },
-                    },
+                    ),
                 ],
             }
         );
@@ -84,21 +118,441 @@ mod tests {
             parse_ast,
             ast::Program {
                 statements: vec![
-                    ast::Statement::Expression {
-                        expression: ast::Expression::Call {
-                            function: Box::new(ast::Expression::Identifier {
-                                name: String::from("print"),
-                            }),
-                            args: vec![
-                                ast::Expression::String {
-                                    value: String::from("Hello world"),
-                                },
-                                ast::Expression::Number { value: 2 },
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Call {
+                                function: Box::new(ast::Expression::Identifier {
+                                    name: String::from("print"),
+                                }),
+                                args: vec![
+                                    ast::Expression::String {
+                                        value: String::from("Hello world"),
+                                    },
+                                    ast::Expression::Number { value: 2 },
+                                ],
+                                keywords: vec![],
+                            },
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_yield_and_generator_expression() {
+        let source = String::from("yield\nx = yield from (y for y in z)\n");
+        let parse_ast = parse_source(&source).unwrap();
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Yield { value: None },
+                        },
+                    ),
+                    located(
+                        2,
+                        0,
+                        ast::Statement::Assign {
+                            targets: vec![ast::Expression::Identifier {
+                                name: String::from("x"),
+                            }],
+                            value: ast::Expression::YieldFrom {
+                                value: Box::new(ast::Expression::Comprehension {
+                                    kind: Box::new(ast::ComprehensionKind::GeneratorExpression {
+                                        element: ast::Expression::Identifier {
+                                            name: String::from("y"),
+                                        },
+                                    }),
+                                    generators: vec![ast::Comprehension {
+                                        target: ast::Expression::Identifier {
+                                            name: String::from("y"),
+                                        },
+                                        iter: ast::Expression::Identifier {
+                                            name: String::from("z"),
+                                        },
+                                        ifs: vec![],
+                                    }],
+                                }),
+                            },
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dict_and_set_display() {
+        let source = String::from("{}\n{1: 2}\n{1}\n");
+        let parse_ast = parse_source(&source).unwrap();
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Dict { elements: vec![] },
+                        },
+                    ),
+                    located(
+                        2,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Dict {
+                                elements: vec![(
+                                    ast::Expression::Number { value: 1 },
+                                    ast::Expression::Number { value: 2 },
+                                )],
+                            },
+                        },
+                    ),
+                    located(
+                        3,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Set {
+                                elements: vec![ast::Expression::Number { value: 1 }],
+                            },
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_lambda_and_if_expression() {
+        let source = String::from("f(key=lambda x: x if x else 0)\n");
+        let parse_ast = parse_source(&source).unwrap();
+        let x = || ast::Expression::Identifier {
+            name: String::from("x"),
+        };
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Expression {
+                            expression: ast::Expression::Call {
+                                function: Box::new(ast::Expression::Identifier {
+                                    name: String::from("f"),
+                                }),
+                                args: vec![],
+                                keywords: vec![ast::Keyword {
+                                    name: String::from("key"),
+                                    value: ast::Expression::Lambda {
+                                        args: ast::Parameters {
+                                            args: vec![String::from("x")],
+                                            defaults: vec![],
+                                        },
+                                        body: Box::new(ast::Expression::IfExp {
+                                            test: Box::new(x()),
+                                            body: Box::new(x()),
+                                            orelse: Box::new(ast::Expression::Number { value: 0 }),
+                                        }),
+                                    },
+                                }],
+                            },
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_augmented_and_annotated_assignment() {
+        let source = String::from("x //= 2\ny: int = 1\n(z): int\n");
+        let parse_ast = parse_source(&source).unwrap();
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::AugAssign {
+                            target: ast::Expression::Identifier {
+                                name: String::from("x"),
+                            },
+                            op: ast::Operator::FloorDiv,
+                            value: ast::Expression::Number { value: 2 },
+                        },
+                    ),
+                    located(
+                        2,
+                        0,
+                        ast::Statement::AnnAssign {
+                            target: ast::Expression::Identifier {
+                                name: String::from("y"),
+                            },
+                            annotation: ast::Expression::Identifier {
+                                name: String::from("int"),
+                            },
+                            value: Some(ast::Expression::Number { value: 1 }),
+                            simple: true,
+                        },
+                    ),
+                    located(
+                        3,
+                        0,
+                        ast::Statement::AnnAssign {
+                            target: ast::Expression::Identifier {
+                                name: String::from("z"),
+                            },
+                            annotation: ast::Expression::Identifier {
+                                name: String::from("int"),
+                            },
+                            value: None,
+                            simple: false,
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_del_global_nonlocal() {
+        let source = String::from("del a, b\nglobal c, d\nnonlocal e\n");
+        let parse_ast = parse_source(&source).unwrap();
+        let name = |name: &str| ast::Expression::Identifier {
+            name: String::from(name),
+        };
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Delete {
+                            targets: vec![name("a"), name("b")],
+                        },
+                    ),
+                    located(
+                        2,
+                        0,
+                        ast::Statement::Global {
+                            names: vec![String::from("c"), String::from("d")],
+                        },
+                    ),
+                    located(
+                        3,
+                        0,
+                        ast::Statement::Nonlocal {
+                            names: vec![String::from("e")],
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_semicolons() {
+        let source = String::from("del a; pass;\nif b: pass; del c\n");
+        let parse_ast = parse_source(&source).unwrap();
+        let name = |name: &str| ast::Expression::Identifier {
+            name: String::from(name),
+        };
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Delete {
+                            targets: vec![name("a")],
+                        },
+                    ),
+                    located(1, 7, ast::Statement::Pass),
+                    located(
+                        2,
+                        0,
+                        ast::Statement::If {
+                            test: name("b"),
+                            body: vec![
+                                located(2, 6, ast::Statement::Pass),
+                                located(
+                                    2,
+                                    12,
+                                    ast::Statement::Delete {
+                                        targets: vec![name("c")],
+                                    },
+                                ),
                             ],
+                            or_else: None,
+                        },
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_decorators() {
+        let source = String::from("@a.b\n@c[0]\ndef f():\n    pass\n");
+        let parse_ast = parse_source(&source).unwrap();
+        let name = |name: &str| ast::Expression::Identifier {
+            name: String::from(name),
+        };
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![located(
+                    1,
+                    0,
+                    ast::Statement::FunctionDef {
+                        name: String::from("f"),
+                        args: ast::Parameters {
+                            args: vec![],
+                            defaults: vec![],
                         },
+                        body: vec![located(4, 4, ast::Statement::Pass)],
+                        decorator_list: vec![
+                            ast::Expression::Attribute {
+                                value: Box::new(name("a")),
+                                name: String::from("b"),
+                            },
+                            ast::Expression::Subscript {
+                                a: Box::new(name("c")),
+                                b: Box::new(ast::Expression::Number { value: 0 }),
+                            },
+                        ],
                     },
+                )],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_with() {
+        let source = String::from("with a as (b, c), d:\n    pass\nwith (e as f, g,):\n    pass\nwith (h, i):\n    pass\nwith (j, k as l):\n    pass\n");
+        let parse_ast = parse_source(&source).unwrap();
+        let name = |name: &str| ast::Expression::Identifier {
+            name: String::from(name),
+        };
+        let item = |context: &str, target: Option<ast::Expression>| ast::WithItem {
+            context_expr: name(context),
+            optional_vars: target,
+        };
+        let with = |row, items| {
+            located(
+                row,
+                0,
+                ast::Statement::With {
+                    items,
+                    body: vec![located(row + 1, 4, ast::Statement::Pass)],
+                },
+            )
+        };
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    with(
+                        1,
+                        vec![
+                            item(
+                                "a",
+                                Some(ast::Expression::Tuple {
+                                    elements: vec![name("b"), name("c")],
+                                }),
+                            ),
+                            item("d", None),
+                        ],
+                    ),
+                    with(3, vec![item("e", Some(name("f"))), item("g", None)]),
+                    with(5, vec![item("h", None), item("i", None)]),
+                    with(7, vec![item("j", None), item("k", Some(name("l")))]),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_imports() {
+        let source = String::from(
+            "import a.b.c as d, e\nfrom x import y as z, w\nfrom . import m\nfrom ..pkg import *\nfrom ...p.q import (r,\n    s,)\n",
+        );
+        let parse_ast = parse_source(&source).unwrap();
+        let symbol = |symbol: &str, alias: Option<&str>| ast::ImportSymbol {
+            symbol: String::from(symbol),
+            alias: alias.map(String::from),
+        };
+        assert_eq!(
+            parse_ast,
+            ast::Program {
+                statements: vec![
+                    located(
+                        1,
+                        0,
+                        ast::Statement::Import {
+                            names: vec![symbol("a.b.c", Some("d")), symbol("e", None)],
+                        },
+                    ),
+                    located(
+                        2,
+                        0,
+                        ast::Statement::ImportFrom {
+                            level: 0,
+                            module: Some(String::from("x")),
+                            names: vec![symbol("y", Some("z")), symbol("w", None)],
+                        },
+                    ),
+                    located(
+                        3,
+                        0,
+                        ast::Statement::ImportFrom {
+                            level: 1,
+                            module: None,
+                            names: vec![symbol("m", None)],
+                        },
+                    ),
+                    located(
+                        4,
+                        0,
+                        ast::Statement::ImportFrom {
+                            level: 2,
+                            module: Some(String::from("pkg")),
+                            names: vec![symbol("*", None)],
+                        },
+                    ),
+                    located(
+                        5,
+                        0,
+                        ast::Statement::ImportFrom {
+                            level: 3,
+                            module: Some(String::from("p.q")),
+                            names: vec![symbol("r", None), symbol("s", None)],
+                        },
+                    ),
                 ],
             }
         );
     }
+
+    #[test]
+    fn test_parse_interactive() {
+        for source in &["if x:", "if x:\n    y = 1\nelse:", "f(1,", "x = [1,\n2", "def f():\n"] {
+            assert_eq!(parse_interactive(source), Ok(None), "{}", source);
+        }
+        for source in &["x = 1", "f(1,\n2)", "if x:\n    y = 1\n"] {
+            assert!(parse_interactive(source).unwrap().is_some(), "{}", source);
+        }
+        assert!(parse_interactive("x = )").is_err());
+    }
 }
@@ -0,0 +1,342 @@
+/*
+ * The cache of compiled modules on disk. The code of `dir/name.py` is kept in
+ * `dir/__pycache__/name.rspython-N.pyc`, where N is the version of the
+ * marshal format, with `.opt-1` before `.pyc` when asserts are left out.
+ *
+ * A cache file starts with a header: the magic bytes, the version, flags
+ * telling how the cache is checked, and 16 bytes to check it with. These are
+ * the modification time and size of the source, or a hash of the source.
+ * Then follows the marshalled code of the module.
+ */
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use super::bytecode::{CodeObject, Constant};
+use super::compile::{compile_with_options, CompileOptions};
+use super::cpython;
+use super::marshal;
+use super::parser::parse_source;
+
+const MAGIC: &[u8; 4] = b"RSPY";
+const HEADER_SIZE: usize = 28;
+
+// The flags of CPython's pyc files: bit 0 is set for a hash based cache,
+// and bit 1 when that hash is checked against the source.
+const FLAG_HASH: u32 = 1;
+const FLAG_CHECK_SOURCE: u32 = 2;
+
+// How a cache file is found to be out of date:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Invalidation {
+    // The source has another modification time or size:
+    Timestamp,
+    // The source has another hash:
+    CheckedHash,
+    // Never, the cache is used until it is compiled again:
+    UncheckedHash,
+}
+
+impl Invalidation {
+    pub fn from_name(name: &str) -> Option<Invalidation> {
+        match name {
+            "timestamp" => Some(Invalidation::Timestamp),
+            "checked-hash" => Some(Invalidation::CheckedHash),
+            "unchecked-hash" => Some(Invalidation::UncheckedHash),
+            _ => None,
+        }
+    }
+
+    fn flags(self) -> u32 {
+        match self {
+            Invalidation::Timestamp => 0,
+            Invalidation::CheckedHash => FLAG_HASH | FLAG_CHECK_SOURCE,
+            Invalidation::UncheckedHash => FLAG_HASH,
+        }
+    }
+}
+
+pub fn cache_path(source: &Path, optimize: bool) -> PathBuf {
+    let directory = source.parent().unwrap_or_else(|| Path::new(""));
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let optimization = if optimize { ".opt-1" } else { "" };
+    directory.join("__pycache__").join(format!(
+        "{}.rspython-{}{}.pyc",
+        stem,
+        marshal::VERSION,
+        optimization
+    ))
+}
+
+// FNV-1a, which is simple and does not change between rust versions:
+fn source_hash(source: &[u8]) -> u64 {
+    source.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+// The modification time in nanoseconds, and the size of the source:
+fn source_stamp(source: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(source)?;
+    let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos()),
+        Err(_) => 0,
+    };
+    Ok((mtime, metadata.len()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// The code in the cache of a source file, unless there is no cache, or it is
+// out of date or broken:
+pub fn read_cache(source: &Path, optimize: bool) -> Option<CodeObject> {
+    let data = fs::read(cache_path(source, optimize)).ok()?;
+    if data.len() < HEADER_SIZE || &data[..4] != MAGIC || read_u32(&data, 4) != marshal::VERSION {
+        return None;
+    }
+    let flags = read_u32(&data, 8);
+    let fresh = if flags & FLAG_HASH == 0 {
+        source_stamp(source).ok()? == (read_u64(&data, 12), read_u64(&data, 20))
+    } else if flags & FLAG_CHECK_SOURCE != 0 {
+        source_hash(&fs::read(source).ok()?) == read_u64(&data, 12)
+    } else {
+        true
+    };
+    if !fresh {
+        return None;
+    }
+    let mut code = marshal::loads(&data[HEADER_SIZE..]).ok()?;
+
+    // The module may have been moved since it was compiled:
+    let filename = source.to_string_lossy();
+    if code.filename != filename {
+        set_filename(&mut code, &filename);
+    }
+    Some(code)
+}
+
+fn set_filename(code: &mut CodeObject, filename: &str) {
+    code.filename = String::from(filename);
+    for constant in &mut code.consts {
+        if let Constant::Code { ref mut code } = *constant {
+            if let Some(code) = Rc::get_mut(code) {
+                set_filename(code, filename);
+            }
+        }
+    }
+}
+
+// The code of a compiled file without its source. This is either a cache
+// file of rspython, or a pyc file of CPython 3.11.
+pub fn load_compiled_file(path: &Path) -> Result<CodeObject, String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(error) => return Err(format!("can't read {}: {}", path.display(), error)),
+    };
+    let mut code = if data.starts_with(MAGIC) {
+        if data.len() < HEADER_SIZE || read_u32(&data, 4) != marshal::VERSION {
+            return Err(format!("bad version of compiled file {}", path.display()));
+        }
+        marshal::loads(&data[HEADER_SIZE..])?
+    } else if data.starts_with(&cpython::MAGIC) {
+        cpython::load_pyc(&data)?
+    } else {
+        return Err(format!("bad magic number in {}", path.display()));
+    };
+    // Without source, tracebacks can only name the compiled file:
+    if code.filename.is_empty() {
+        set_filename(&mut code, &path.to_string_lossy());
+    }
+    Ok(code)
+}
+
+// Write the cache of a source file. The file is written under another name
+// first, so that a reader never sees half of it.
+pub fn write_cache(
+    source: &Path,
+    code: &CodeObject,
+    optimize: bool,
+    invalidation: Invalidation,
+) -> io::Result<()> {
+    let (first, second) = match invalidation {
+        Invalidation::Timestamp => source_stamp(source)?,
+        _ => (source_hash(&fs::read(source)?), 0),
+    };
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&marshal::VERSION.to_le_bytes());
+    data.extend_from_slice(&invalidation.flags().to_le_bytes());
+    data.extend_from_slice(&first.to_le_bytes());
+    data.extend_from_slice(&second.to_le_bytes());
+    data.extend(marshal::dumps(code));
+
+    let path = cache_path(source, optimize);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let temporary = path.with_extension(format!("pyc.{}", std::process::id()));
+    fs::write(&temporary, data)?;
+    fs::rename(&temporary, &path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+pub fn compile_source_file(source: &Path, options: CompileOptions) -> Result<CodeObject, String> {
+    let text = match fs::read_to_string(source) {
+        Ok(text) => text,
+        Err(error) => return Err(format!("can't read {}: {}", source.display(), error)),
+    };
+    let program = parse_source(&text)?;
+    compile_with_options(program, &source.to_string_lossy(), options)
+}
+
+// The code of a module file, from the cache when it is up to date. Otherwise
+// the source is compiled, and the cache written when `write` is set. Failing
+// to write the cache is no error, the directory may be read only.
+pub fn load_source_file(
+    source: &Path,
+    options: CompileOptions,
+    write: bool,
+) -> Result<CodeObject, String> {
+    if let Some(code) = read_cache(source, options.optimize) {
+        return Ok(code);
+    }
+    let code = compile_source_file(source, options)?;
+    if write {
+        if let Err(error) = write_cache(source, &code, options.optimize, Invalidation::Timestamp) {
+            debug!("Not writing the cache of {}: {}", source.display(), error);
+        }
+    }
+    Ok(code)
+}
+
+// Compile a source file into its cache, unless the cache is up to date and
+// `force` is not set. Like compileall, the progress is printed unless `quiet`
+// is 1 or more, and errors unless it is 2.
+pub fn compile_file(
+    source: &Path,
+    options: CompileOptions,
+    invalidation: Invalidation,
+    force: bool,
+    quiet: u32,
+) -> bool {
+    if !force && read_cache(source, options.optimize).is_some() {
+        return true;
+    }
+    if quiet == 0 {
+        println!("Compiling '{}'...", source.display());
+    }
+    let result = compile_source_file(source, options).and_then(|code| {
+        write_cache(source, &code, options.optimize, invalidation).map_err(|error| error.to_string())
+    });
+    match result {
+        Ok(()) => true,
+        Err(msg) => {
+            if quiet < 2 {
+                println!("*** Error compiling '{}': {}", source.display(), msg);
+            }
+            false
+        }
+    }
+}
+
+// Compile the python files in a directory and its subdirectories. Gives
+// whether all of them compiled.
+pub fn compile_dir(
+    directory: &Path,
+    options: CompileOptions,
+    invalidation: Invalidation,
+    force: bool,
+    quiet: u32,
+) -> bool {
+    if quiet < 2 {
+        println!("Listing '{}'...", directory.display());
+    }
+    let mut entries: Vec<PathBuf> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect(),
+        Err(_) => {
+            if quiet < 2 {
+                println!("Can't list '{}'", directory.display());
+            }
+            return false;
+        }
+    };
+    entries.sort();
+
+    let mut success = true;
+    for path in entries {
+        if path.is_dir() {
+            if path.file_name().is_some_and(|name| name == "__pycache__") {
+                continue;
+            }
+            success &= compile_dir(&path, options, invalidation, force, quiet);
+        } else if path.extension().is_some_and(|extension| extension == "py") {
+            success &= compile_file(&path, options, invalidation, force, quiet);
+        }
+    }
+    success
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_module_cache() {
+        let directory = env::temp_dir().join(format!("rspython_pycache_{}", process::id()));
+        let source = directory.join("pkg").join("cached.py");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "x = 1\n").unwrap();
+        let options = CompileOptions::default();
+        assert_eq!(
+            cache_path(&source, true),
+            directory.join("pkg/__pycache__/cached.rspython-1.opt-1.pyc")
+        );
+
+        // A fresh cache is used, even when it holds other code:
+        let other = compile_source_file(&source, options).unwrap();
+        fs::write(&source, "y = 2\n").unwrap();
+        write_cache(&source, &other, false, Invalidation::Timestamp).unwrap();
+        let names = |code: Option<CodeObject>| code.map(|code| code.names);
+        assert_eq!(names(read_cache(&source, false)), Some(vec![String::from("x")]));
+        assert!(read_cache(&source, true).is_none());
+
+        // A changed source is noticed by its size or hash:
+        fs::write(&source, "zz = 3\n").unwrap();
+        assert!(read_cache(&source, false).is_none());
+        let code = load_source_file(&source, options, true).unwrap();
+        assert_eq!(code.names, vec!["zz"]);
+        assert_eq!(names(read_cache(&source, false)), Some(code.names));
+        write_cache(&source, &other, false, Invalidation::CheckedHash).unwrap();
+        assert!(read_cache(&source, false).is_some());
+        fs::write(&source, "zz = 4\n").unwrap();
+        assert!(read_cache(&source, false).is_none());
+        write_cache(&source, &other, false, Invalidation::UncheckedHash).unwrap();
+        fs::write(&source, "zz = 5\n").unwrap();
+        assert!(read_cache(&source, false).is_some());
+
+        // Directories are compiled recursively, broken files are reported:
+        fs::write(directory.join("top.py"), "def f():\n    pass\n").unwrap();
+        assert!(compile_dir(&directory, options, Invalidation::Timestamp, true, 2));
+        let top = read_cache(&directory.join("top.py"), false).unwrap();
+        assert_eq!(top.filename, directory.join("top.py").to_string_lossy());
+        assert_eq!(names(read_cache(&source, false)), Some(vec![String::from("zz")]));
+        fs::write(directory.join("broken.py"), "def (\n").unwrap();
+        assert!(!compile_dir(&directory, options, Invalidation::Timestamp, false, 2));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,7 +1,14 @@
+use std::any::Any;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
 use std::ops::{Add, Mul, Sub};
 
+use super::bytecode;
+use super::frame::{Frame, ScopeRef};
+use super::vm::VirtualMachine;
+
 /* Python objects and references.
 
 Okay, so each python object itself is an class itself (PyObject). Each
@@ -19,7 +26,59 @@ https://doc.rust-lang.org/std/cell/index.html#introducing-mutability-inside-of-s
 pub type PyRef<T> = Rc<RefCell<T>>;
 pub type PyObjectRef = PyRef<PyObject>;
 
-#[derive(Debug)]
+/*
+ * The result of a python operation. The error value is the raised
+ * exception object.
+ */
+pub type PyResult<T = PyObjectRef> = Result<T, PyObjectRef>;
+
+pub type RustPyFunc = fn(&mut VirtualMachine, PyFuncArgs) -> PyResult;
+
+// Any function implemented in rust, also a wrapped one:
+pub type NativeFunc = Rc<dyn Fn(&mut VirtualMachine, PyFuncArgs) -> PyResult>;
+
+/*
+ * The arguments of a call. Keyword arguments are kept in the order in which
+ * they were passed.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct PyFuncArgs {
+    pub args: Vec<PyObjectRef>,
+    pub kwargs: Vec<(String, PyObjectRef)>,
+}
+
+impl PyFuncArgs {
+    pub fn new(args: Vec<PyObjectRef>, kwargs: Vec<(String, PyObjectRef)>) -> PyFuncArgs {
+        PyFuncArgs { args, kwargs }
+    }
+
+    // Prepend an argument, like the object a method is bound to:
+    pub fn insert(&self, item: PyObjectRef) -> PyFuncArgs {
+        let mut args = self.args.clone();
+        args.insert(0, item);
+        PyFuncArgs {
+            args,
+            kwargs: self.kwargs.clone(),
+        }
+    }
+
+    pub fn get_kwarg(&self, name: &str) -> Option<PyObjectRef> {
+        self.kwargs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+impl From<Vec<PyObjectRef>> for PyFuncArgs {
+    fn from(args: Vec<PyObjectRef>) -> PyFuncArgs {
+        PyFuncArgs {
+            args,
+            kwargs: vec![],
+        }
+    }
+}
+
 pub enum PyObject {
     String {
         value: String,
@@ -27,23 +86,129 @@ pub enum PyObject {
     Integer {
         value: i32,
     },
+    Float {
+        value: f64,
+    },
+    Complex {
+        real: f64,
+        imag: f64,
+    },
+    Bytes {
+        value: Vec<u8>,
+    },
+    Boolean {
+        value: bool,
+    },
     List {
         elements: Vec<PyObjectRef>,
     },
     Tuple {
         elements: Vec<PyObjectRef>,
     },
-    Dict,
+    // Dictionaries and sets keep their entries in insertion order:
+    Dict {
+        elements: Vec<(PyObjectRef, PyObjectRef)>,
+    },
+    Set {
+        elements: Vec<PyObjectRef>,
+    },
+    FrozenSet {
+        elements: Vec<PyObjectRef>,
+    },
+    Range {
+        start: i32,
+        stop: i32,
+        step: i32,
+    },
     Iterator {
         position: usize,
         iterated_obj: PyObjectRef,
     },
     None,
+    Ellipsis,
+    Code {
+        code: Rc<bytecode::CodeObject>,
+    },
+    Function {
+        code: Rc<bytecode::CodeObject>,
+        scope: ScopeRef,
+        defaults: Vec<PyObjectRef>,
+        // The cells of the free variables of the code:
+        closure: Vec<PyObjectRef>,
+    },
+    // A variable shared between a function and its nested functions:
+    Cell {
+        value: Option<PyObjectRef>,
+    },
+    // A generator owns the frame of its function while it is suspended. The
+    // frame is taken out while the generator runs, and dropped once it is
+    // exhausted.
+    Generator {
+        frame: Option<Frame>,
+        running: bool,
+    },
+    BoundMethod {
+        function: PyObjectRef,
+        object: PyObjectRef,
+    },
+    // A running frame as seen from python, the line is updated when the
+    // object is asked for again:
+    Frame {
+        code: Rc<bytecode::CodeObject>,
+        scope: ScopeRef,
+        lineno: usize,
+        // The frame of the caller:
+        back: Option<PyObjectRef>,
+    },
+    // An entry of the traceback of an exception. The entries are linked from
+    // the outermost frame to the frame where the exception was raised.
+    Traceback {
+        frame: PyObjectRef,
+        lineno: usize,
+        next: Option<PyObjectRef>,
+    },
+    Class {
+        name: String,
+        dict: HashMap<String, PyObjectRef>,
+        bases: Vec<PyObjectRef>,
+    },
+    // Instances of native types carry a rust value:
+    Instance {
+        class: PyObjectRef,
+        dict: HashMap<String, PyObjectRef>,
+        payload: Option<Box<dyn Any>>,
+    },
+    // The attributes of a module are the globals of its code:
+    Module {
+        name: String,
+        scope: ScopeRef,
+    },
     RustFunction {
-        function: fn(Vec<PyObjectRef>),
+        function: NativeFunc,
+        doc: Option<String>,
     },
 }
 
+impl fmt::Debug for PyObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PyObject::Code { ref code } => write!(f, "<code object {}>", code.name),
+            PyObject::Function { ref code, .. } => write!(f, "<function {}>", code.name),
+            PyObject::Generator { .. } => write!(f, "<generator object>"),
+            PyObject::BoundMethod { .. } => write!(f, "<bound method>"),
+            PyObject::Frame { .. } | PyObject::Traceback { .. } => {
+                write!(f, "<{} object>", self.type_name())
+            }
+            PyObject::Class { ref name, .. } => write!(f, "<class '{}'>", name),
+            PyObject::RustFunction { .. } => write!(f, "<built-in function>"),
+            PyObject::Instance { .. } | PyObject::Iterator { .. } => {
+                write!(f, "<{} object>", self.type_name())
+            }
+            _ => write!(f, "{}", self.repr()),
+        }
+    }
+}
+
 /*
 impl PyObjectRef {
     pub fn steal(&self) -> &mut PyObject {
@@ -52,29 +217,120 @@ impl PyObjectRef {
 }*/
 
 impl PyObject {
-    pub fn call(&self, args: Vec<PyObjectRef>) {
+    pub fn str(&self) -> String {
         match *self {
-            PyObject::RustFunction { ref function } => {
-                function(args);
-            }
-            _ => {
-                println!("Not impl {:?}", self);
-                panic!("Not impl");
-            }
+            PyObject::String { ref value } => value.clone(),
+            _ => self.repr(),
         }
     }
 
-    pub fn str(&self) -> String {
+    pub fn repr(&self) -> String {
         match *self {
-            PyObject::String { ref value } => value.clone(),
+            PyObject::String { ref value } => str_repr(value),
             PyObject::Integer { ref value } => format!("{:?}", value),
-            PyObject::List { ref elements } => format!("{:?}", elements),
-            PyObject::Tuple { ref elements } => format!("{:?}", elements),
-            PyObject::None => String::from("None"),
-            _ => {
-                println!("Not impl {:?}", self);
-                panic!("Not impl");
+            PyObject::Float { value } => float_repr(value),
+            PyObject::Complex { real, imag } => {
+                // Like CPython, a purely imaginary number has no real part:
+                if real == 0.0 && real.is_sign_positive() {
+                    format!("{}j", complex_part_repr(imag))
+                } else {
+                    let sign = if imag.is_sign_negative() { "-" } else { "+" };
+                    format!(
+                        "({}{}{}j)",
+                        complex_part_repr(real),
+                        sign,
+                        complex_part_repr(imag.abs())
+                    )
+                }
             }
+            PyObject::Bytes { ref value } => bytes_repr(value),
+            PyObject::Boolean { value } => String::from(if value { "True" } else { "False" }),
+            PyObject::List { ref elements } => format!("[{}]", seq_repr(elements)),
+            PyObject::Tuple { ref elements } => {
+                if elements.len() == 1 {
+                    format!("({},)", seq_repr(elements))
+                } else {
+                    format!("({})", seq_repr(elements))
+                }
+            }
+            PyObject::Dict { ref elements } => {
+                let parts: Vec<String> = elements
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}: {}", key.borrow().repr(), value.borrow().repr())
+                    })
+                    .collect();
+                format!("{{{}}}", parts.join(", "))
+            }
+            PyObject::Set { ref elements } => {
+                if elements.is_empty() {
+                    String::from("set()")
+                } else {
+                    format!("{{{}}}", seq_repr(elements))
+                }
+            }
+            PyObject::FrozenSet { ref elements } => {
+                if elements.is_empty() {
+                    String::from("frozenset()")
+                } else {
+                    format!("frozenset({{{}}})", seq_repr(elements))
+                }
+            }
+            PyObject::Range { start, stop, step } => {
+                if step == 1 {
+                    format!("range({}, {})", start, stop)
+                } else {
+                    format!("range({}, {}, {})", start, stop, step)
+                }
+            }
+            PyObject::None => String::from("None"),
+            PyObject::Ellipsis => String::from("Ellipsis"),
+            PyObject::Code { ref code } => format!(
+                "<code object {}, file \"{}\", line {}>",
+                code.name, code.filename, code.firstlineno
+            ),
+            PyObject::Function { ref code, .. } => format!("<function {}>", code.name),
+            PyObject::Generator { .. } => String::from("<generator object>"),
+            PyObject::Class { ref name, .. } => format!("<class '{}'>", name),
+            PyObject::Module { ref name, ref scope } => match scope.borrow().locals.get("__file__") {
+                Some(file) => format!("<module '{}' from '{}'>", name, file.borrow().str()),
+                None => format!("<module '{}'>", name),
+            },
+            _ => format!("<{} object>", self.type_name()),
+        }
+    }
+
+    pub fn type_name(&self) -> String {
+        match *self {
+            PyObject::String { .. } => String::from("str"),
+            PyObject::Integer { .. } => String::from("int"),
+            PyObject::Float { .. } => String::from("float"),
+            PyObject::Complex { .. } => String::from("complex"),
+            PyObject::Bytes { .. } => String::from("bytes"),
+            PyObject::Boolean { .. } => String::from("bool"),
+            PyObject::List { .. } => String::from("list"),
+            PyObject::Tuple { .. } => String::from("tuple"),
+            PyObject::Dict { .. } => String::from("dict"),
+            PyObject::Set { .. } => String::from("set"),
+            PyObject::FrozenSet { .. } => String::from("frozenset"),
+            PyObject::Range { .. } => String::from("range"),
+            PyObject::Iterator { .. } => String::from("iterator"),
+            PyObject::None => String::from("NoneType"),
+            PyObject::Ellipsis => String::from("ellipsis"),
+            PyObject::Code { .. } => String::from("code"),
+            PyObject::Function { .. } => String::from("function"),
+            PyObject::Cell { .. } => String::from("cell"),
+            PyObject::Generator { .. } => String::from("generator"),
+            PyObject::BoundMethod { .. } => String::from("method"),
+            PyObject::Frame { .. } => String::from("frame"),
+            PyObject::Traceback { .. } => String::from("traceback"),
+            PyObject::Class { .. } => String::from("type"),
+            PyObject::Instance { ref class, .. } => match *class.borrow() {
+                PyObject::Class { ref name, .. } => name.clone(),
+                _ => panic!("Instance of something which is not a class"),
+            },
+            PyObject::RustFunction { .. } => String::from("builtin_function_or_method"),
+            PyObject::Module { .. } => String::from("module"),
         }
     }
 
@@ -85,9 +341,11 @@ impl PyObject {
                 ref mut position,
                 iterated_obj: ref iterated_obj_ref,
             } => {
-                let iterated_obj = &*iterated_obj_ref.borrow_mut();
-                match iterated_obj {
-                    &PyObject::List { ref elements } => {
+                match *iterated_obj_ref.borrow() {
+                    PyObject::List { ref elements }
+                    | PyObject::Tuple { ref elements }
+                    | PyObject::Set { ref elements }
+                    | PyObject::FrozenSet { ref elements } => {
                         if *position < elements.len() {
                             let obj_ref = elements[*position].clone();
                             *position += 1;
@@ -96,6 +354,43 @@ impl PyObject {
                             None
                         }
                     }
+                    PyObject::Dict { ref elements } => {
+                        // Iterating a dictionary gives its keys:
+                        if *position < elements.len() {
+                            let key = elements[*position].0.clone();
+                            *position += 1;
+                            Some(key)
+                        } else {
+                            None
+                        }
+                    }
+                    PyObject::Bytes { ref value } => match value.get(*position) {
+                        Some(&byte) => {
+                            *position += 1;
+                            Some(PyObject::Integer { value: i32::from(byte) }.into_ref())
+                        }
+                        None => None,
+                    },
+                    PyObject::Range { start, stop, step } => {
+                        let value = start + (*position as i32) * step;
+                        if (step > 0 && value < stop) || (step < 0 && value > stop) {
+                            *position += 1;
+                            Some(PyObject::Integer { value }.into_ref())
+                        } else {
+                            None
+                        }
+                    }
+                    PyObject::String { ref value } => match value.chars().nth(*position) {
+                        Some(c) => {
+                            *position += 1;
+                            Some(
+                                PyObject::String {
+                                    value: c.to_string(),
+                                }.into_ref(),
+                            )
+                        }
+                        None => None,
+                    },
                     _ => {
                         panic!("NOT IMPL");
                     }
@@ -113,15 +408,101 @@ impl PyObject {
     }
 }
 
+fn seq_repr(elements: &[PyObjectRef]) -> String {
+    let parts: Vec<String> = elements.iter().map(|e| e.borrow().repr()).collect();
+    parts.join(", ")
+}
+
+fn float_repr(value: f64) -> String {
+    if value.is_nan() {
+        String::from("nan")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "inf" } else { "-inf" })
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+// The parts of a complex number leave out the fraction of whole numbers:
+fn complex_part_repr(value: f64) -> String {
+    let text = float_repr(value);
+    match text.strip_suffix(".0") {
+        Some(whole) => String::from(whole),
+        None => text,
+    }
+}
+
+// Like CPython, the quotes are those which need no escaping, and characters
+// which are not printable are escaped by their code point:
+fn str_repr(value: &str) -> String {
+    let quote = if value.contains('\'') && !value.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut text = String::new();
+    text.push(quote);
+    for c in value.chars() {
+        match c {
+            '\t' => text.push_str("\\t"),
+            '\n' => text.push_str("\\n"),
+            '\r' => text.push_str("\\r"),
+            '\\' => text.push_str("\\\\"),
+            _ if c == quote => {
+                text.push('\\');
+                text.push(quote);
+            }
+            _ if c.is_control() || (c.is_whitespace() && c != ' ') => {
+                let code = c as u32;
+                if code < 0x100 {
+                    text.push_str(&format!("\\x{:02x}", code));
+                } else if code < 0x10000 {
+                    text.push_str(&format!("\\u{:04x}", code));
+                } else {
+                    text.push_str(&format!("\\U{:08x}", code));
+                }
+            }
+            _ => text.push(c),
+        }
+    }
+    text.push(quote);
+    text
+}
+
+fn bytes_repr(value: &[u8]) -> String {
+    let quote = if value.contains(&b'\'') && !value.contains(&b'"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut text = format!("b{}", quote);
+    for &byte in value {
+        match byte {
+            b'\t' => text.push_str("\\t"),
+            b'\n' => text.push_str("\\n"),
+            b'\r' => text.push_str("\\r"),
+            b'\\' => text.push_str("\\\\"),
+            _ if byte == quote as u8 => {
+                text.push('\\');
+                text.push(quote);
+            }
+            0x20..=0x7e => text.push(byte as char),
+            _ => text.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    text.push(quote);
+    text
+}
+
 impl<'a> Add<&'a PyObject> for &'a PyObject {
     type Output = PyObject;
 
     fn add(self, rhs: &'a PyObject) -> Self::Output {
-        match self {
-            &PyObject::Integer { ref value } => {
+        match *self {
+            PyObject::Integer { ref value } => {
                 let value1 = value;
-                match rhs {
-                    &PyObject::Integer { ref value } => {
+                match *rhs {
+                    PyObject::Integer { ref value } => {
                         let value2 = value;
                         PyObject::Integer {
                             value: value1 + value2,
@@ -144,11 +525,11 @@ impl<'a> Sub<&'a PyObject> for &'a PyObject {
     type Output = PyObject;
 
     fn sub(self, rhs: &'a PyObject) -> Self::Output {
-        match self {
-            &PyObject::Integer { value } => {
+        match *self {
+            PyObject::Integer { value } => {
                 let value1 = value;
-                match rhs {
-                    &PyObject::Integer { value } => {
+                match *rhs {
+                    PyObject::Integer { value } => {
                         let value2 = value;
                         PyObject::Integer {
                             value: value1 - value2,
@@ -170,11 +551,11 @@ impl<'a> Mul<&'a PyObject> for &'a PyObject {
     type Output = PyObject;
 
     fn mul(self, rhs: &'a PyObject) -> Self::Output {
-        match self {
-            &PyObject::Integer { value } => {
+        match *self {
+            PyObject::Integer { value } => {
                 let value1 = value;
-                match rhs {
-                    &PyObject::Integer { value } => {
+                match *rhs {
+                    PyObject::Integer { value } => {
                         let value2 = value;
                         PyObject::Integer {
                             value: value1 * value2,
@@ -185,10 +566,10 @@ impl<'a> Mul<&'a PyObject> for &'a PyObject {
                     }
                 }
             }
-            &PyObject::String { ref value } => {
+            PyObject::String { ref value } => {
                 let value1 = value;
-                match rhs {
-                    &PyObject::Integer { value } => {
+                match *rhs {
+                    PyObject::Integer { value } => {
                         let value2 = value;
                         let mut result = String::new();
                         for _x in 0..value2 {
@@ -209,6 +590,7 @@ impl<'a> Mul<&'a PyObject> for &'a PyObject {
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::PyObject;
 
@@ -223,6 +605,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_constant_reprs() {
+        let objects = vec![
+            (PyObject::Complex { real: 0.0, imag: 2.0 }, "2j"),
+            (PyObject::Complex { real: 1.5, imag: -1.0 }, "(1.5-1j)"),
+            (PyObject::Bytes { value: b"it's\n\x00".to_vec() }, "b\"it's\\n\\x00\""),
+            (PyObject::String { value: String::from("it's") }, "\"it's\""),
+            (
+                PyObject::String {
+                    value: String::from("'\"\\\t\u{7}\u{a0}é"),
+                },
+                "'\\'\"\\\\\\t\\x07\\xa0é'",
+            ),
+            (PyObject::FrozenSet { elements: vec![] }, "frozenset()"),
+            (PyObject::Ellipsis, "Ellipsis"),
+        ];
+        for (object, repr) in objects {
+            assert_eq!(object.repr(), repr);
+        }
+    }
+
     #[test]
     fn test_multiply_str() {
         let a = PyObject::String {
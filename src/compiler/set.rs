@@ -0,0 +1,42 @@
+/*
+ * Set objects. Like dictionary keys, the elements must be immutable.
+ */
+
+use super::builtins::check_arg_count;
+use super::dict;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::vm::VirtualMachine;
+
+pub fn new_set() -> PyObjectRef {
+    PyObject::Set { elements: vec![] }.into_ref()
+}
+
+pub fn get_method(name: &str) -> Option<RustPyFunc> {
+    match name {
+        "add" => Some(set_add),
+        _ => None,
+    }
+}
+
+pub fn add(vm: &mut VirtualMachine, set: &PyObjectRef, element: PyObjectRef) -> Result<(), PyObjectRef> {
+    dict::check_hashable(vm, &element)?;
+    let present = match *set.borrow() {
+        PyObject::Set { ref elements } => {
+            let elements: Vec<&PyObjectRef> = elements.iter().collect();
+            dict::find(vm, &elements, &element).is_some()
+        }
+        _ => panic!("Expected a set"),
+    };
+    if !present {
+        if let PyObject::Set { ref mut elements } = *set.borrow_mut() {
+            elements.push(element);
+        }
+    }
+    Ok(())
+}
+
+fn set_add(vm: &mut VirtualMachine, args: PyFuncArgs) -> PyResult {
+    check_arg_count(vm, "add", &args, 2, 2)?;
+    add(vm, &args.args[0], args.args[1].clone())?;
+    Ok(vm.get_none())
+}
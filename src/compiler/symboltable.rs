@@ -0,0 +1,698 @@
+/*
+ * The symbol table pass runs before the compiler. It determines for every
+ * name in every code block, whether it is a local, global, free or cell
+ * variable, so that the compiler can emit the right instruction to access it.
+ *
+ * See also:
+ * https://github.com/python/cpython/blob/master/Python/symtable.c
+ */
+
+use std::collections::{HashMap, HashSet};
+
+use super::ast;
+
+// With optimize, assert statements are left out, like the compiler does.
+pub fn make_symbol_table(program: &ast::Program, optimize: bool) -> Result<SymbolTable, String> {
+    let mut builder = SymbolTableBuilder {
+        tables: vec![],
+        optimize,
+    };
+    builder.enter_scope(SymbolTableType::Module);
+    builder.scan_statements(&program.statements)?;
+    let mut table = builder.tables.pop().unwrap();
+    analyze_table(&mut table, &HashSet::new())?;
+    Ok(table)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolTableType {
+    Module,
+    Class,
+    Function,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolScope {
+    Local,
+    // Declared with a global statement:
+    GlobalExplicit,
+    // Not bound in the enclosing functions, so either a global or a builtin:
+    GlobalImplicit,
+    // Bound in an enclosing function:
+    Free,
+    // A local, which is also used by a nested function:
+    Cell,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub scope: SymbolScope,
+    pub is_referenced: bool,
+    pub is_assigned: bool,
+    pub is_parameter: bool,
+    pub is_global: bool,
+    pub is_nonlocal: bool,
+    // A name bound in a class body, which is also a free variable of the
+    // methods. The class passes the variable of the enclosing function on.
+    pub is_free_class: bool,
+}
+
+impl Symbol {
+    fn new(name: &str) -> Symbol {
+        Symbol {
+            name: String::from(name),
+            scope: SymbolScope::GlobalImplicit,
+            is_referenced: false,
+            is_assigned: false,
+            is_parameter: false,
+            is_global: false,
+            is_nonlocal: false,
+            is_free_class: false,
+        }
+    }
+}
+
+/*
+ * The symbols of a single code block. The tables of nested code blocks are
+ * kept in the order in which the compiler encounters them.
+ */
+#[derive(Debug)]
+pub struct SymbolTable {
+    pub typ: SymbolTableType,
+    pub symbols: HashMap<String, Symbol>,
+    pub sub_tables: Vec<SymbolTable>,
+}
+
+impl SymbolTable {
+    fn new(typ: SymbolTableType) -> SymbolTable {
+        SymbolTable {
+            typ,
+            symbols: HashMap::new(),
+            sub_tables: vec![],
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+}
+
+// Determine the scope of all symbols, given the names bound by the enclosing
+// functions. Returns the names which this table needs from those functions.
+fn analyze_table(table: &mut SymbolTable, bound: &HashSet<String>) -> Result<HashSet<String>, String> {
+    let mut free = HashSet::new();
+    for symbol in table.symbols.values_mut() {
+        symbol.scope = if symbol.is_global {
+            SymbolScope::GlobalExplicit
+        } else if symbol.is_nonlocal {
+            if !bound.contains(&symbol.name) {
+                return Err(format!("no binding for nonlocal '{}' found", symbol.name));
+            }
+            free.insert(symbol.name.clone());
+            SymbolScope::Free
+        } else if symbol.is_assigned || symbol.is_parameter {
+            SymbolScope::Local
+        } else if bound.contains(&symbol.name) {
+            free.insert(symbol.name.clone());
+            SymbolScope::Free
+        } else {
+            SymbolScope::GlobalImplicit
+        };
+    }
+
+    // Names bound in a class or module are not visible in nested functions:
+    let mut child_bound = match table.typ {
+        SymbolTableType::Module => HashSet::new(),
+        SymbolTableType::Class | SymbolTableType::Function => bound.clone(),
+    };
+    for symbol in table.symbols.values() {
+        match symbol.scope {
+            SymbolScope::Local if table.typ == SymbolTableType::Function => {
+                child_bound.insert(symbol.name.clone());
+            }
+            SymbolScope::GlobalExplicit => {
+                child_bound.remove(&symbol.name);
+            }
+            _ => {}
+        }
+    }
+
+    let mut child_free = HashSet::new();
+    for sub_table in &mut table.sub_tables {
+        child_free.extend(analyze_table(sub_table, &child_bound)?);
+    }
+
+    // Locals used by nested functions become cells, the other names are
+    // passed on from the enclosing functions:
+    for name in child_free {
+        let symbol = table
+            .symbols
+            .entry(name.clone())
+            .or_insert_with(|| Symbol::new(&name));
+        match symbol.scope {
+            SymbolScope::Local if table.typ == SymbolTableType::Function => {
+                symbol.scope = SymbolScope::Cell;
+            }
+            SymbolScope::Cell => {}
+            SymbolScope::Local => {
+                symbol.is_free_class = true;
+                free.insert(name);
+            }
+            _ => {
+                symbol.scope = SymbolScope::Free;
+                free.insert(name);
+            }
+        }
+    }
+    Ok(free)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SymbolUsage {
+    Global,
+    Nonlocal,
+    Parameter,
+    Assigned,
+    Referenced,
+}
+
+struct SymbolTableBuilder {
+    // The tables of the code blocks being scanned, innermost last:
+    tables: Vec<SymbolTable>,
+    optimize: bool,
+}
+
+impl SymbolTableBuilder {
+    fn enter_scope(&mut self, typ: SymbolTableType) {
+        self.tables.push(SymbolTable::new(typ));
+    }
+
+    fn leave_scope(&mut self) {
+        let table = self.tables.pop().unwrap();
+        self.tables.last_mut().unwrap().sub_tables.push(table);
+    }
+
+    fn current_type(&self) -> SymbolTableType {
+        self.tables.last().unwrap().typ
+    }
+
+    fn scan_statements(&mut self, statements: &[ast::LocatedStatement]) -> Result<(), String> {
+        for statement in statements {
+            self.scan_statement(&statement.node)?;
+        }
+        Ok(())
+    }
+
+    // The nested code blocks must be scanned in the same order as the
+    // compiler visits them.
+    fn scan_statement(&mut self, statement: &ast::Statement) -> Result<(), String> {
+        match *statement {
+            ast::Statement::Break | ast::Statement::Continue | ast::Statement::Pass => {}
+            ast::Statement::Return { ref value } => {
+                if let Some(value) = value {
+                    self.scan_expression(value)?;
+                }
+            }
+            ast::Statement::Import { ref names } => {
+                for name in names {
+                    // Importing a dotted name binds the first part:
+                    let bound = match name.alias {
+                        Some(ref alias) => alias.as_str(),
+                        None => name.symbol.split('.').next().unwrap(),
+                    };
+                    self.register_name(bound, SymbolUsage::Assigned)?;
+                }
+            }
+            ast::Statement::ImportFrom { ref names, .. } => {
+                for name in names {
+                    if name.symbol == "*" {
+                        if self.tables.last().unwrap().typ == SymbolTableType::Function {
+                            return Err(String::from("import * only allowed at module level"));
+                        }
+                        continue;
+                    }
+                    let bound = name.alias.as_ref().unwrap_or(&name.symbol);
+                    self.register_name(bound, SymbolUsage::Assigned)?;
+                }
+            }
+            ast::Statement::Global { ref names } => {
+                for name in names {
+                    self.register_name(name, SymbolUsage::Global)?;
+                }
+            }
+            ast::Statement::Nonlocal { ref names } => {
+                for name in names {
+                    self.register_name(name, SymbolUsage::Nonlocal)?;
+                }
+            }
+            ast::Statement::Assert { .. } if self.optimize => {}
+            ast::Statement::Assert { ref test, ref msg } => {
+                self.scan_expression(test)?;
+                if let Some(msg) = msg {
+                    self.scan_expression(msg)?;
+                }
+            }
+            ast::Statement::Delete { ref targets } => {
+                for target in targets {
+                    self.scan_target(target)?;
+                }
+            }
+            ast::Statement::Assign {
+                ref targets,
+                ref value,
+            } => {
+                self.scan_expression(value)?;
+                for target in targets {
+                    self.scan_target(target)?;
+                }
+            }
+            ast::Statement::AugAssign {
+                ref target,
+                ref value,
+                ..
+            } => {
+                if let ast::Expression::Identifier { ref name } = *target {
+                    self.register_name(name, SymbolUsage::Referenced)?;
+                }
+                self.scan_target(target)?;
+                self.scan_expression(value)?;
+            }
+            ast::Statement::AnnAssign {
+                ref target,
+                ref annotation,
+                ref value,
+                ..
+            } => {
+                if let Some(value) = value {
+                    self.scan_expression(value)?;
+                }
+                self.scan_target(target)?;
+                // Annotations in functions are not evaluated:
+                if self.current_type() != SymbolTableType::Function {
+                    self.scan_expression(annotation)?;
+                }
+            }
+            ast::Statement::Expression { ref expression } => {
+                self.scan_expression(expression)?;
+            }
+            ast::Statement::If {
+                ref test,
+                ref body,
+                ref or_else,
+            }
+            | ast::Statement::While {
+                ref test,
+                ref body,
+                ref or_else,
+            } => {
+                self.scan_expression(test)?;
+                self.scan_statements(body)?;
+                if let Some(or_else) = or_else {
+                    self.scan_statements(or_else)?;
+                }
+            }
+            ast::Statement::With {
+                ref items,
+                ref body,
+            } => {
+                for item in items {
+                    self.scan_expression(&item.context_expr)?;
+                    if let Some(ref target) = item.optional_vars {
+                        self.scan_target(target)?;
+                    }
+                }
+                self.scan_statements(body)?;
+            }
+            ast::Statement::For {
+                ref target,
+                ref iter,
+                ref body,
+                ref or_else,
+            } => {
+                self.scan_expression(iter)?;
+                self.scan_target(target)?;
+                self.scan_statements(body)?;
+                if let Some(or_else) = or_else {
+                    self.scan_statements(or_else)?;
+                }
+            }
+            ast::Statement::Raise {
+                ref exception,
+                ref cause,
+            } => {
+                if let Some(exception) = exception {
+                    self.scan_expression(exception)?;
+                }
+                if let Some(cause) = cause {
+                    self.scan_expression(cause)?;
+                }
+            }
+            ast::Statement::Try {
+                ref body,
+                ref handlers,
+                ref or_else,
+                ref finalbody,
+            } => {
+                self.scan_statements(body)?;
+                for handler in handlers {
+                    if let Some(typ) = &handler.typ {
+                        self.scan_expression(typ)?;
+                    }
+                    if let Some(name) = &handler.name {
+                        self.register_name(name, SymbolUsage::Assigned)?;
+                    }
+                    self.scan_statements(&handler.body)?;
+                }
+                if let Some(or_else) = or_else {
+                    self.scan_statements(or_else)?;
+                }
+                if let Some(finalbody) = finalbody {
+                    self.scan_statements(finalbody)?;
+                }
+            }
+            ast::Statement::ClassDef {
+                ref name,
+                ref bases,
+                ref body,
+                ref decorator_list,
+            } => {
+                for decorator in decorator_list {
+                    self.scan_expression(decorator)?;
+                }
+                self.enter_scope(SymbolTableType::Class);
+                self.scan_statements(body)?;
+                self.leave_scope();
+                for base in bases {
+                    self.scan_expression(base)?;
+                }
+                self.register_name(name, SymbolUsage::Assigned)?;
+            }
+            ast::Statement::FunctionDef {
+                ref name,
+                ref args,
+                ref body,
+                ref decorator_list,
+            } => {
+                for decorator in decorator_list {
+                    self.scan_expression(decorator)?;
+                }
+                self.scan_function(args)?;
+                self.scan_statements(body)?;
+                self.leave_scope();
+                self.register_name(name, SymbolUsage::Assigned)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Scan the default values, and enter the scope of the function:
+    fn scan_function(&mut self, args: &ast::Parameters) -> Result<(), String> {
+        for default in &args.defaults {
+            self.scan_expression(default)?;
+        }
+        self.enter_scope(SymbolTableType::Function);
+        for arg in &args.args {
+            self.register_name(arg, SymbolUsage::Parameter)?;
+        }
+        Ok(())
+    }
+
+    // Scan an expression which is assigned to, or deleted:
+    fn scan_target(&mut self, target: &ast::Expression) -> Result<(), String> {
+        match *target {
+            ast::Expression::Identifier { ref name } => {
+                self.register_name(name, SymbolUsage::Assigned)?;
+            }
+            ast::Expression::Tuple { ref elements } | ast::Expression::List { ref elements } => {
+                for element in elements {
+                    self.scan_target(element)?;
+                }
+            }
+            _ => {
+                self.scan_expression(target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_expression(&mut self, expression: &ast::Expression) -> Result<(), String> {
+        match *expression {
+            ast::Expression::Binop { ref a, ref b, .. }
+            | ast::Expression::BoolOp { ref a, ref b, .. }
+            | ast::Expression::Subscript { ref a, ref b } => {
+                self.scan_expression(a)?;
+                self.scan_expression(b)?;
+            }
+            ast::Expression::Unop { ref a, .. } => {
+                self.scan_expression(a)?;
+            }
+            ast::Expression::Compare { ref vals, .. }
+            | ast::Expression::List { elements: ref vals }
+            | ast::Expression::Tuple { elements: ref vals }
+            | ast::Expression::Set { elements: ref vals } => {
+                for value in vals {
+                    self.scan_expression(value)?;
+                }
+            }
+            ast::Expression::Call {
+                ref function,
+                ref args,
+                ref keywords,
+            } => {
+                self.scan_expression(function)?;
+                for arg in args {
+                    self.scan_expression(arg)?;
+                }
+                for keyword in keywords {
+                    self.scan_expression(&keyword.value)?;
+                }
+            }
+            ast::Expression::Attribute { ref value, .. }
+            | ast::Expression::YieldFrom { ref value } => {
+                self.scan_expression(value)?;
+            }
+            ast::Expression::Yield { ref value } => {
+                if let Some(value) = value {
+                    self.scan_expression(value)?;
+                }
+            }
+            ast::Expression::Comprehension {
+                ref kind,
+                ref generators,
+            } => {
+                self.scan_comprehension(kind, generators)?;
+            }
+            ast::Expression::Lambda { ref args, ref body } => {
+                self.scan_function(args)?;
+                self.scan_expression(body)?;
+                self.leave_scope();
+            }
+            ast::Expression::IfExp {
+                ref test,
+                ref body,
+                ref orelse,
+            } => {
+                self.scan_expression(test)?;
+                self.scan_expression(body)?;
+                self.scan_expression(orelse)?;
+            }
+            ast::Expression::Dict { ref elements } => {
+                for (key, value) in elements {
+                    self.scan_expression(key)?;
+                    self.scan_expression(value)?;
+                }
+            }
+            ast::Expression::Identifier { ref name } => {
+                self.register_name(name, SymbolUsage::Referenced)?;
+            }
+            ast::Expression::Number { .. }
+            | ast::Expression::String { .. }
+            | ast::Expression::True
+            | ast::Expression::False
+            | ast::Expression::None => {}
+        }
+        Ok(())
+    }
+
+    // A comprehension is an implicit function, which gets the iterator of
+    // the outermost loop as argument:
+    fn scan_comprehension(
+        &mut self,
+        kind: &ast::ComprehensionKind,
+        generators: &[ast::Comprehension],
+    ) -> Result<(), String> {
+        self.enter_scope(SymbolTableType::Function);
+        self.register_name(".0", SymbolUsage::Parameter)?;
+        for (i, generator) in generators.iter().enumerate() {
+            if i > 0 {
+                self.scan_expression(&generator.iter)?;
+            }
+            self.scan_target(&generator.target)?;
+            for condition in &generator.ifs {
+                self.scan_expression(condition)?;
+            }
+        }
+        match *kind {
+            ast::ComprehensionKind::GeneratorExpression { ref element }
+            | ast::ComprehensionKind::List { ref element }
+            | ast::ComprehensionKind::Set { ref element } => {
+                self.scan_expression(element)?;
+            }
+            ast::ComprehensionKind::Dict { ref key, ref value } => {
+                self.scan_expression(key)?;
+                self.scan_expression(value)?;
+            }
+        }
+        self.leave_scope();
+
+        // The outermost iterator is evaluated in the enclosing scope:
+        self.scan_expression(&generators[0].iter)
+    }
+
+    fn register_name(&mut self, name: &str, usage: SymbolUsage) -> Result<(), String> {
+        let table = self.tables.last_mut().unwrap();
+        let typ = table.typ;
+        let symbol = table
+            .symbols
+            .entry(String::from(name))
+            .or_insert_with(|| Symbol::new(name));
+        match usage {
+            SymbolUsage::Global | SymbolUsage::Nonlocal => {
+                let keyword = if let SymbolUsage::Global = usage {
+                    "global"
+                } else {
+                    "nonlocal"
+                };
+                if let SymbolUsage::Nonlocal = usage {
+                    if typ == SymbolTableType::Module {
+                        return Err(String::from(
+                            "nonlocal declaration not allowed at module level",
+                        ));
+                    }
+                }
+                if symbol.is_parameter {
+                    return Err(format!("name '{}' is parameter and {}", name, keyword));
+                }
+                if symbol.is_global || symbol.is_nonlocal {
+                    let same = symbol.is_global == matches!(usage, SymbolUsage::Global);
+                    if !same {
+                        return Err(format!("name '{}' is nonlocal and global", name));
+                    }
+                }
+                if symbol.is_assigned {
+                    return Err(format!(
+                        "name '{}' is assigned to before {} declaration",
+                        name, keyword
+                    ));
+                }
+                if symbol.is_referenced {
+                    return Err(format!(
+                        "name '{}' is used prior to {} declaration",
+                        name, keyword
+                    ));
+                }
+                match usage {
+                    SymbolUsage::Global => symbol.is_global = true,
+                    _ => symbol.is_nonlocal = true,
+                }
+            }
+            SymbolUsage::Parameter => {
+                if symbol.is_parameter {
+                    return Err(format!(
+                        "duplicate argument '{}' in function definition",
+                        name
+                    ));
+                }
+                symbol.is_parameter = true;
+            }
+            SymbolUsage::Assigned => symbol.is_assigned = true,
+            SymbolUsage::Referenced => symbol.is_referenced = true,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::parse_source;
+    use super::{make_symbol_table, SymbolScope, SymbolTable};
+
+    fn scope_of(table: &SymbolTable, name: &str) -> SymbolScope {
+        table.lookup(name).unwrap().scope
+    }
+
+    #[test]
+    fn test_symbol_scopes() {
+        let source = "
+x = 1
+def f(a):
+    global y
+    b = a
+    def g():
+        nonlocal b
+        b = x + y + len(b)
+    return g
+";
+        let program = parse_source(source).unwrap();
+        let table = make_symbol_table(&program, false).unwrap();
+        assert_eq!(scope_of(&table, "x"), SymbolScope::Local);
+
+        let f = &table.sub_tables[0];
+        assert_eq!(scope_of(f, "a"), SymbolScope::Local);
+        assert_eq!(scope_of(f, "b"), SymbolScope::Cell);
+        assert_eq!(scope_of(f, "y"), SymbolScope::GlobalExplicit);
+        assert_eq!(scope_of(f, "g"), SymbolScope::Local);
+
+        let g = &f.sub_tables[0];
+        assert_eq!(scope_of(g, "b"), SymbolScope::Free);
+        assert_eq!(scope_of(g, "x"), SymbolScope::GlobalImplicit);
+        assert_eq!(scope_of(g, "y"), SymbolScope::GlobalImplicit);
+        assert_eq!(scope_of(g, "len"), SymbolScope::GlobalImplicit);
+    }
+
+    #[test]
+    fn test_free_variables_skip_classes() {
+        let source = "
+def f():
+    x = 1
+    class C:
+        x = 2
+        def m(self):
+            return x
+";
+        let program = parse_source(source).unwrap();
+        let table = make_symbol_table(&program, false).unwrap();
+        let f = &table.sub_tables[0];
+        assert_eq!(scope_of(f, "x"), SymbolScope::Cell);
+        let c = &f.sub_tables[0];
+        assert_eq!(scope_of(c, "x"), SymbolScope::Local);
+        assert!(c.lookup("x").unwrap().is_free_class);
+        assert_eq!(scope_of(&c.sub_tables[0], "x"), SymbolScope::Free);
+    }
+
+    #[test]
+    fn test_declaration_errors() {
+        let check = |source: &str| make_symbol_table(&parse_source(source).unwrap(), false).unwrap_err();
+        assert_eq!(
+            check("def f():\n    nonlocal x\n"),
+            "no binding for nonlocal 'x' found"
+        );
+        assert_eq!(
+            check("nonlocal x\n"),
+            "nonlocal declaration not allowed at module level"
+        );
+        assert_eq!(
+            check("def f(a):\n    global a\n"),
+            "name 'a' is parameter and global"
+        );
+        assert_eq!(
+            check("def f():\n    x = 1\n    global x\n"),
+            "name 'x' is assigned to before global declaration"
+        );
+        assert_eq!(
+            check("def f():\n    from a import *\n"),
+            "import * only allowed at module level"
+        );
+    }
+}
@@ -1,203 +1,2395 @@
-
 /*
  * Implement virtual machine to run instructions.
  */
 
 use std::collections::HashMap;
-use std::cell::RefMut;
-use std::ops::Deref;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
 
-use super::bytecode;
 use super::builtins;
-use super::pyobject::{PyObject, PyObjectRef};
+use super::bytecode;
+use super::code;
+use super::compileall;
+use super::ast;
+use super::compile::{compile_with_options, CompileOptions};
+use super::convert::IntoNativeFunc;
+use super::dict;
+use super::dis;
+use super::frame::{ExecutionResult, Frame, FrameInfo, Scope, ScopeRef};
+use super::fileio;
+use super::generator;
+use super::import;
+use super::list;
+use super::native::NativeModule;
+use super::parser::parse_source;
+use super::pycache;
+use super::pyobject::{PyFuncArgs, PyObject, PyObjectRef, PyResult, RustPyFunc};
+use super::set;
 
 // use objects::objects;
 
-// Container of the virtual machine state:
-pub fn evaluate(code: bytecode::CodeObject) {
-    let mut vm = VirtualMachine::new();
-
-    // Register built in function:
-    vm.scope.insert(String::from("print"), PyObject::RustFunction { function: builtins::print }.into_ref());
+// Objects are live when they are on stack, or referenced by a name (for now)
 
-    // { stack: Vec::new() };
-    vm.run(code);
+// Settings of the interpreter, which are mostly given on the command line:
+#[derive(Debug, Default, Clone)]
+pub struct Settings {
+    // Leave out assert statements (-O):
+    pub optimize: bool,
+    // Ignore environment variables like PYTHONPATH (-E):
+    pub ignore_environment: bool,
+    // Do not search the directory of the main program for modules (-I):
+    pub safe_path: bool,
+    // Do not write the compiled code of imported modules to disk (-B):
+    pub dont_write_bytecode: bool,
+    // The value of sys.argv:
+    pub argv: Vec<String>,
 }
 
-// Objects are live when they are on stack, or referenced by a name (for now)
+pub struct VirtualMachine {
+    pub settings: Settings,
+    pub builtins: HashMap<String, PyObjectRef>,
+    pub sys_module: PyObjectRef,
+    pub native_modules: HashMap<String, Rc<NativeModule>>,
+    // The namespace of the __main__ module:
+    globals: ScopeRef,
+    // The running frames, innermost last:
+    pub frames: Vec<FrameInfo>,
+    // The function given to sys.settrace(), which is not traced itself:
+    trace_function: Option<PyObjectRef>,
+    tracing: bool,
+    none: PyObjectRef,
+    ellipsis: PyObjectRef,
+    true_value: PyObjectRef,
+    false_value: PyObjectRef,
+}
 
-struct VirtualMachine {
-    stack: Vec<PyObjectRef>,
-    // A stack of block to keep track of continue break locations.
-    block_stack: Vec<(bytecode::Label, bytecode::Label)>,
-    scope: HashMap<String, PyObjectRef>,
-    program_counter: usize,
+impl Default for VirtualMachine {
+    fn default() -> VirtualMachine {
+        VirtualMachine::new()
+    }
 }
 
 impl VirtualMachine {
-    fn new() -> VirtualMachine {
-        VirtualMachine {
-            stack: Vec::new(),
-            block_stack: Vec::new(),
-            scope: HashMap::new(),
-            program_counter: 0,
+    pub fn new() -> VirtualMachine {
+        VirtualMachine::with_settings(Settings::default())
+    }
+
+    pub fn with_settings(settings: Settings) -> VirtualMachine {
+        let mut vm = VirtualMachine {
+            settings,
+            builtins: HashMap::new(),
+            sys_module: PyObject::None.into_ref(),
+            native_modules: HashMap::new(),
+            globals: Scope::new(None),
+            frames: vec![],
+            trace_function: None,
+            tracing: false,
+            none: PyObject::None.into_ref(),
+            ellipsis: PyObject::Ellipsis.into_ref(),
+            true_value: PyObject::Boolean { value: true }.into_ref(),
+            false_value: PyObject::Boolean { value: false }.into_ref(),
+        };
+
+        // Register built in functions and types:
+        builtins::fill_scope(&mut vm.builtins);
+        vm.builtins
+            .insert(String::from("Ellipsis"), vm.ellipsis.clone());
+        vm.sys_module = import::make_sys_module(&mut vm);
+        vm.add_native_module(fileio::make_module());
+        vm.add_native_module(compileall::make_module());
+        vm.add_native_module(dis::make_module());
+        let io = import::import_module(&mut vm, "io").unwrap();
+        let open = vm.get_attribute(io, "TextIOWrapper").unwrap();
+        vm.builtins.insert(String::from("open"), open);
+
+        let main_module = import::new_module("__main__");
+        if let PyObject::Module { ref scope, .. } = *main_module.borrow() {
+            vm.globals = scope.clone();
         }
+        import::set_cached_module(&mut vm, "__main__", main_module).unwrap();
+        vm
+    }
+
+    pub fn compile_options(&self) -> CompileOptions {
+        CompileOptions {
+            interactive: false,
+            optimize: self.settings.optimize,
+        }
+    }
+
+    // Parse and compile source code, a failure is raised as SyntaxError:
+    pub fn compile_source(
+        &mut self,
+        source: &str,
+        filename: &str,
+    ) -> Result<bytecode::CodeObject, PyObjectRef> {
+        let options = self.compile_options();
+        let code = parse_source(source)
+            .and_then(|program| compile_with_options(program, filename, options));
+        match code {
+            Ok(code) => Ok(code),
+            Err(msg) => Err(self.new_exception("SyntaxError", msg)),
+        }
+    }
+
+    // Run source code in the namespace of the __main__ module:
+    pub fn run_source(&mut self, source: &str) -> PyResult {
+        self.run_source_as(source, "<string>")
+    }
+
+    // Like `run_source`, for source read from a file, whose name shows in
+    // tracebacks:
+    pub fn run_source_as(&mut self, source: &str, filename: &str) -> PyResult {
+        let code = self.compile_source(source, filename)?;
+        let globals = self.globals.clone();
+        self.run_code(code, globals)
+    }
+
+    // Run code typed at the interactive prompt, in the namespace of the
+    // __main__ module. The values of expression statements are printed.
+    pub fn run_interactive(&mut self, program: ast::Program) -> PyResult {
+        let options = CompileOptions {
+            interactive: true,
+            ..self.compile_options()
+        };
+        let code = match compile_with_options(program, "<stdin>", options) {
+            Ok(code) => code,
+            Err(msg) => return Err(self.new_exception("SyntaxError", msg)),
+        };
+        let globals = self.globals.clone();
+        self.run_code(code, globals)
+    }
+
+    // The names in the namespace of the __main__ module, and the builtins:
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.globals.borrow().locals.keys().cloned().collect();
+        names.extend(self.builtins.keys().cloned());
+        names.sort();
+        names.dedup();
+        names
     }
 
-    fn run(&mut self, code: bytecode::CodeObject) {
-        // Process instruction at the program counter until exception or finished.
-        loop {
-            // for instruction in code.instructions {
-            if self.program_counter >= code.instructions.len() {
-                break;
+    // Modules are searched for in the directory of the main program first,
+    // unless the settings say otherwise. An empty path is the current
+    // directory.
+    pub fn insert_main_path(&mut self, directory: &str) -> PyResult {
+        if !self.settings.safe_path {
+            let sys_path = self.get_attribute(self.sys_module.clone(), "path")?;
+            let directory = PyObject::String {
+                value: String::from(directory),
+            }.into_ref();
+            if let PyObject::List { ref mut elements } = *sys_path.borrow_mut() {
+                elements.insert(0, directory);
+            };
+        }
+        Ok(self.get_none())
+    }
+
+    // Run a script as the __main__ module. Modules are searched for next to
+    // the script first.
+    pub fn run_script(&mut self, filename: &Path) -> PyResult {
+        let directory = filename.parent().unwrap_or_else(|| Path::new(""));
+        self.insert_main_path(&directory.to_string_lossy())?;
+        self.run_file(filename)
+    }
+
+    // Run a module found on sys.path as the __main__ module, like
+    // `python -m`. A package is run by its __main__ submodule. As in
+    // CPython, sys.argv[0] becomes the file of the module, and relative
+    // imports are from its package.
+    pub fn run_module(&mut self, name: &str) -> PyResult {
+        let directory = env::current_dir().unwrap_or_default();
+        self.insert_main_path(&directory.to_string_lossy())?;
+        // A native module runs as its main function:
+        if self.native_modules.contains_key(name) {
+            let module = import::import_module(self, name)?;
+            let main = self.get_attribute(module, "main")?;
+            return self.invoke(main, vec![]);
+        }
+        let (name, filename) = import::find_module_file(self, name)?;
+        let file = PyObject::String {
+            value: filename.to_string_lossy().into_owned(),
+        }.into_ref();
+        let argv = self.get_attribute(self.sys_module.clone(), "argv")?;
+        if let PyObject::List { ref mut elements } = *argv.borrow_mut() {
+            if !elements.is_empty() {
+                elements[0] = file;
             }
+        }
+        let package = match name.rfind('.') {
+            Some(position) => &name[..position],
+            None => "",
+        };
+        let package = PyObject::String {
+            value: String::from(package),
+        }.into_ref();
+        self.set_global("__package__", package);
+        self.run_file(&filename)
+    }
 
-            let ref instruction = code.instructions[self.program_counter];
-            trace!("Executing instruction: {:?} (stacksize={:?}, pc={:?}/{:?}", instruction, self.stack.len(), self.program_counter, code.instructions.len());
-            self.program_counter += 1;
-            self.execute_instruction(&instruction);
+    // The code of a script file. A compiled file is loaded without its
+    // source:
+    pub fn load_file(&mut self, filename: &Path) -> Result<bytecode::CodeObject, PyObjectRef> {
+        if filename.extension().is_some_and(|extension| extension == "pyc") {
+            return match pycache::load_compiled_file(filename) {
+                Ok(code) => Ok(code),
+                Err(msg) => Err(self.new_exception("RuntimeError", msg)),
+            };
         }
+        let source = match fs::read_to_string(filename) {
+            Ok(source) => source,
+            Err(error) => {
+                let msg = format!("can't open file '{}': {}", filename.display(), error);
+                return Err(self.new_exception("OSError", msg));
+            }
+        };
+        self.compile_source(&source, &filename.to_string_lossy())
     }
 
-    // Execute a single instruction:
-    fn execute_instruction(&mut self, instruction: &bytecode::Instruction) {
-        // trace!("Executing instruction: {:?} (stacksize={:?}", instruction, self.stack.len());
-        match instruction {
-            &bytecode::Instruction::LoadStringConstant { ref value } => {
-                let obj = PyObject::String { value: value.clone() }.into_ref();
-                self.stack.push(obj.clone()); // Put reference on stack
-            },
-            &bytecode::Instruction::LoadConst { ref value } => {
-                let obj = PyObject::Integer { value: *value }.into_ref();
-                self.stack.push(obj.clone());
-            },
-            &bytecode::Instruction::LoadName { ref name } => {
-                // Lookup name in scope and put it onto the stack!
-                let obj = &self.scope[name];
-                self.stack.push(obj.clone());
-            },
-            &bytecode::Instruction::StoreName { ref name } => {
-                // take top of stack and assign in scope:
-                let obj = self.stack.pop().unwrap();
-                self.scope.insert(name.clone(), obj);
-            },
-            &bytecode::Instruction::Pop => {
-                // Pop value from stack and ignore.
-                self.stack.pop();
-            },
-            &bytecode::Instruction::BuildList { size } => {
-                let mut elements = Vec::new();
-                for _x in 0..size {
-                    let obj = self.stack.pop().unwrap();
-                    elements.push(obj);
-                }
-                let list_obj = PyObject::List { elements: elements }.into_ref();
-                self.stack.push(list_obj);
-            },
-            &bytecode::Instruction::BuildTuple { size } => {
-                let mut elements = Vec::new();
-                for _x in 0..size {
-                    let obj = self.stack.pop().unwrap();
-                    elements.push(obj);
-                }
-                let list_obj = PyObject::Tuple { elements: elements }.into_ref();
-                self.stack.push(list_obj);
-            },
-            &bytecode::Instruction::BuildMap { size } => {
-                let mut elements = Vec::new();
-                for _x in 0..size {
-                    let obj = self.stack.pop().unwrap();
-                    elements.push(obj);
-                }
-                let list_obj = PyObject::Tuple { elements: elements }.into_ref();
-                self.stack.push(list_obj);
-            },
-            &bytecode::Instruction::BinaryOperation { ref op } => {
-                self.execute_binop(op);
-            },
-            &bytecode::Instruction::ReturnValue => {
-                self.stack.pop();
-            },
-            &bytecode::Instruction::PushBlock { start, end } => {
-                self.block_stack.push((start, end));
+    fn run_file(&mut self, filename: &Path) -> PyResult {
+        let code = self.load_file(filename)?;
+        let file = PyObject::String {
+            value: filename.to_string_lossy().into_owned(),
+        }.into_ref();
+        self.set_global("__file__", file);
+        let globals = self.globals.clone();
+        self.run_code(code, globals)
+    }
+
+    // The exit status of a program which ended with the exception. The code
+    // of a SystemExit gives the status, other exceptions are printed.
+    pub fn exit_status(&mut self, exception: &PyObjectRef) -> i32 {
+        let system_exit = self.get_builtin("SystemExit");
+        if !self.isinstance(exception, &system_exit) {
+            self.print_exception(exception);
+            return 1;
+        }
+        let code = match self.get_attribute(exception.clone(), "code") {
+            Ok(code) => code,
+            Err(_) => return 1,
+        };
+        let status = match *code.borrow() {
+            PyObject::None => Some(0),
+            PyObject::Integer { value } => Some(value),
+            PyObject::Boolean { value } => Some(value as i32),
+            _ => None,
+        };
+        match status {
+            Some(status) => status,
+            None => {
+                // Like CPython, any other code is printed:
+                match self.str_of(code) {
+                    Ok(msg) => eprintln!("{}", msg),
+                    Err(exception) => self.print_exception(&exception),
+                }
+                1
+            }
+        }
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<PyObjectRef> {
+        self.globals.borrow().locals.get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: PyObjectRef) {
+        self.globals
+            .borrow_mut()
+            .locals
+            .insert(String::from(name), value);
+    }
+
+    // Make a module implemented in Rust importable:
+    pub fn add_native_module(&mut self, module: NativeModule) {
+        self.native_modules
+            .insert(module.name.clone(), Rc::new(module));
+    }
+
+    // A callable for a rust function or closure, which may hold state of
+    // the host:
+    pub fn new_function<F, Args>(&self, function: F) -> PyObjectRef
+    where
+        F: IntoNativeFunc<Args>,
+    {
+        PyObject::RustFunction {
+            function: function.into_func(),
+            doc: None,
+        }.into_ref()
+    }
+
+    // Run a module level code object in the given global scope.
+    pub fn run_code(&mut self, code: bytecode::CodeObject, scope: ScopeRef) -> PyResult {
+        let mut frame = Frame::new(Rc::new(code), scope);
+        match frame.run(self)? {
+            ExecutionResult::Return(value) => Ok(value),
+            ExecutionResult::Yield(_) => panic!("Cannot yield from module level code"),
+        }
+    }
+
+    pub fn get_none(&self) -> PyObjectRef {
+        self.none.clone()
+    }
+
+    pub fn new_bool(&self, value: bool) -> PyObjectRef {
+        if value {
+            self.true_value.clone()
+        } else {
+            self.false_value.clone()
+        }
+    }
+
+    pub fn get_builtin(&self, name: &str) -> PyObjectRef {
+        self.builtins[name].clone()
+    }
+
+    // Create an instance of the builtin exception class with the given name.
+    pub fn new_exception(&mut self, name: &str, msg: String) -> PyObjectRef {
+        let class = self.get_builtin(name);
+        let args = if msg.is_empty() {
+            vec![]
+        } else {
+            vec![PyObject::String { value: msg }.into_ref()]
+        };
+        self.invoke(class, args)
+            .expect("Creating an exception should not fail")
+    }
+
+    // A KeyError holds the missing key itself:
+    pub fn new_key_error(&mut self, key: PyObjectRef) -> PyObjectRef {
+        let class = self.get_builtin("KeyError");
+        self.invoke(class, vec![key])
+            .expect("Creating an exception should not fail")
+    }
+
+    // Turn the operand of a raise statement into an exception instance:
+    pub fn make_exception(&mut self, obj: PyObjectRef) -> PyResult {
+        let base_exception = self.get_builtin("BaseException");
+        let is_class = matches!(*obj.borrow(), PyObject::Class { .. });
+        if is_class && self.issubclass(&obj, &base_exception) {
+            self.invoke(obj, vec![])
+        } else if self.isinstance(&obj, &base_exception) {
+            Ok(obj)
+        } else {
+            Err(self.new_exception(
+                "TypeError",
+                String::from("exceptions must derive from BaseException"),
+            ))
+        }
+    }
+
+    pub fn trace_function(&self) -> Option<PyObjectRef> {
+        self.trace_function.clone()
+    }
+
+    // Is a trace function running?
+    pub fn is_tracing(&self) -> bool {
+        self.tracing
+    }
+
+    pub fn set_trace_function(&mut self, function: Option<PyObjectRef>) {
+        self.trace_function = function;
+    }
+
+    // Call a trace function with the innermost frame, the event and its
+    // argument. Nothing is traced meanwhile.
+    pub fn call_tracer(
+        &mut self,
+        tracer: PyObjectRef,
+        event: &str,
+        arg: Option<PyObjectRef>,
+    ) -> PyResult {
+        let frame = self.frame_object(0).unwrap();
+        let event = PyObject::String {
+            value: String::from(event),
+        }.into_ref();
+        let arg = arg.unwrap_or_else(|| self.get_none());
+        self.tracing = true;
+        let result = self.invoke(tracer, vec![frame, event, arg]);
+        self.tracing = false;
+        result
+    }
+
+    // The frame object of a running frame, `depth` frames below the
+    // innermost one. The frame objects of the callers are created along,
+    // and their lines brought up to date.
+    pub fn frame_object(&mut self, depth: usize) -> Option<PyObjectRef> {
+        if depth >= self.frames.len() {
+            return None;
+        }
+        let count = self.frames.len() - depth;
+        let mut back: Option<PyObjectRef> = None;
+        for info in &mut self.frames[..count] {
+            let lineno = info.lineno();
+            let object = match info.object {
+                Some(ref object) => {
+                    if let PyObject::Frame {
+                        lineno: ref mut frame_lineno,
+                        ..
+                    } = *object.borrow_mut()
+                    {
+                        *frame_lineno = lineno;
+                    }
+                    object.clone()
+                }
+                None => PyObject::Frame {
+                    code: info.code.clone(),
+                    scope: info.scope.clone(),
+                    lineno,
+                    back: back.clone(),
+                }.into_ref(),
+            };
+            info.object = Some(object.clone());
+            back = Some(object);
+        }
+        back
+    }
+
+    // The lines of the traceback of an exception, like CPython prints them.
+    // The source line is shown when the file can be read.
+    pub fn format_traceback(&self, exception: &PyObjectRef) -> Vec<String> {
+        let mut traceback = match *exception.borrow() {
+            PyObject::Instance { ref dict, .. } => dict.get("__traceback__").cloned(),
+            _ => None,
+        };
+        let mut lines = vec![];
+        while let Some(entry) = traceback {
+            traceback = match *entry.borrow() {
+                PyObject::Traceback {
+                    ref frame,
+                    lineno,
+                    ref next,
+                } => {
+                    if let PyObject::Frame { ref code, .. } = *frame.borrow() {
+                        lines.push(format!(
+                            "  File \"{}\", line {}, in {}",
+                            code.filename, lineno, code.name
+                        ));
+                        if let Ok(source) = fs::read_to_string(&code.filename) {
+                            if let Some(line) = source.lines().nth(lineno - 1) {
+                                lines.push(format!("    {}", line.trim()));
+                            }
+                        }
+                    }
+                    next.clone()
+                }
+                _ => None,
+            };
+        }
+        if !lines.is_empty() {
+            lines.insert(0, String::from("Traceback (most recent call last):"));
+        }
+        lines
+    }
+
+    pub fn print_exception(&mut self, exception: &PyObjectRef) {
+        for line in self.format_traceback(exception) {
+            eprintln!("{}", line);
+        }
+        let name = exception.borrow().type_name();
+        match self.str_of(exception.clone()) {
+            Ok(ref msg) if !msg.is_empty() => eprintln!("{}: {}", name, msg),
+            _ => eprintln!("{}", name),
+        }
+    }
+
+    pub fn unwrap_constant(&self, value: &bytecode::Constant) -> PyObjectRef {
+        match *value {
+            bytecode::Constant::Integer { value } => PyObject::Integer { value }.into_ref(),
+            bytecode::Constant::String { ref value } => PyObject::String {
+                value: value.clone(),
+            }.into_ref(),
+            bytecode::Constant::Boolean { value } => self.new_bool(value),
+            bytecode::Constant::Float { value } => PyObject::Float { value }.into_ref(),
+            bytecode::Constant::Complex { real, imag } => {
+                PyObject::Complex { real, imag }.into_ref()
+            }
+            bytecode::Constant::Bytes { ref value } => PyObject::Bytes {
+                value: value.clone(),
+            }.into_ref(),
+            bytecode::Constant::Tuple { ref elements } => PyObject::Tuple {
+                elements: elements.iter().map(|e| self.unwrap_constant(e)).collect(),
+            }.into_ref(),
+            bytecode::Constant::FrozenSet { ref elements } => PyObject::FrozenSet {
+                elements: elements.iter().map(|e| self.unwrap_constant(e)).collect(),
+            }.into_ref(),
+            bytecode::Constant::Code { ref code } => {
+                PyObject::Code { code: code.clone() }.into_ref()
+            }
+            bytecode::Constant::None => self.get_none(),
+            bytecode::Constant::Ellipsis => self.ellipsis.clone(),
+        }
+    }
+
+    pub fn invoke<T: Into<PyFuncArgs>>(&mut self, func_ref: PyObjectRef, args: T) -> PyResult {
+        let args = args.into();
+
+        // Take what we need from the callable, so that it is not borrowed
+        // during the call:
+        let (code, scope, defaults, closure) = match *func_ref.borrow() {
+            PyObject::RustFunction { ref function, .. } => return function(self, args),
+            PyObject::Function {
+                ref code,
+                ref scope,
+                ref defaults,
+                ref closure,
+            } => (code.clone(), scope.clone(), defaults.clone(), closure.clone()),
+            PyObject::BoundMethod {
+                ref function,
+                ref object,
+            } => {
+                let args = args.insert(object.clone());
+                let function = function.clone();
+                return self.invoke(function, args);
+            }
+            PyObject::Class { .. } => return self.new_instance(func_ref.clone(), args),
+            ref obj => {
+                let msg = format!("'{}' object is not callable", obj.type_name());
+                return Err(self.new_exception("TypeError", msg));
+            }
+        };
+        self.invoke_python_function(code, scope, defaults, closure, args)
+    }
+
+    fn invoke_python_function(
+        &mut self,
+        code: Rc<bytecode::CodeObject>,
+        scope: ScopeRef,
+        defaults: Vec<PyObjectRef>,
+        closure: Vec<PyObjectRef>,
+        args: PyFuncArgs,
+    ) -> PyResult {
+        let nargs = code.arg_names().len();
+        if args.args.len() > nargs {
+            let msg = format!(
+                "{}() takes {} positional arguments but {} were given",
+                code.name,
+                nargs,
+                args.args.len()
+            );
+            return Err(self.new_exception("TypeError", msg));
+        }
+
+        // Bind positional arguments, then keyword arguments by name:
+        let mut values: Vec<Option<PyObjectRef>> = vec![None; nargs];
+        for (i, value) in args.args.into_iter().enumerate() {
+            values[i] = Some(value);
+        }
+        for (name, value) in args.kwargs {
+            let msg = match code.arg_names().iter().position(|arg_name| *arg_name == name) {
+                Some(i) if values[i].is_none() => {
+                    values[i] = Some(value);
+                    continue;
+                }
+                Some(_) => format!("{}() got multiple values for argument '{}'", code.name, name),
+                None => format!(
+                    "{}() got an unexpected keyword argument '{}'",
+                    code.name, name
+                ),
+            };
+            return Err(self.new_exception("TypeError", msg));
+        }
+
+        // Use defaults for the missing ones:
+        let required = nargs - defaults.len();
+        let scope = Scope::new(Some(scope));
+        for (i, (name, value)) in code.arg_names().iter().zip(values).enumerate() {
+            let value = match value {
+                Some(value) => value,
+                None if i >= required => defaults[i - required].clone(),
+                None => {
+                    let msg = format!(
+                        "{}() missing required argument: '{}'",
+                        code.name, name
+                    );
+                    return Err(self.new_exception("TypeError", msg));
+                }
+            };
+            scope.borrow_mut().locals.insert(name.clone(), value);
+        }
+
+        let mut frame = Frame::with_closure(code.clone(), scope, closure);
+        if code.is_generator {
+            return Ok(generator::new_generator(frame));
+        }
+        match frame.run(self)? {
+            ExecutionResult::Return(value) => Ok(value),
+            ExecutionResult::Yield(_) => panic!("Only generators can yield"),
+        }
+    }
+
+    fn new_instance(&mut self, class: PyObjectRef, args: PyFuncArgs) -> PyResult {
+        let instance = PyObject::Instance {
+            class: class.clone(),
+            dict: HashMap::new(),
+            payload: None,
+        }.into_ref();
+        match class_get_attr(&class, "__init__") {
+            Some(init) => {
+                let args = args.insert(instance.clone());
+                self.invoke(init, args)?;
+            }
+            None => {
+                if !args.args.is_empty() || !args.kwargs.is_empty() {
+                    let msg = format!("{}() takes no arguments", instance.borrow().type_name());
+                    return Err(self.new_exception("TypeError", msg));
+                }
+            }
+        }
+        Ok(instance)
+    }
+
+    pub fn import(
+        &mut self,
+        name: &str,
+        symbols: &[String],
+        level: usize,
+        globals: &ScopeRef,
+    ) -> PyResult {
+        import::import(self, name, symbols, level, globals)
+    }
+
+    pub fn call_method(
+        &mut self,
+        obj: PyObjectRef,
+        name: &str,
+        args: Vec<PyObjectRef>,
+    ) -> PyResult {
+        let method = self.get_attribute(obj, name)?;
+        self.invoke(method, args)
+    }
+
+    pub fn get_attribute(&mut self, obj: PyObjectRef, name: &str) -> PyResult {
+        // Attributes found in the class are handled separately:
+        let class_attribute = match *obj.borrow() {
+            PyObject::Instance {
+                ref class,
+                ref dict,
+                ..
+            } => match dict.get(name) {
+                Some(value) => return Ok(value.clone()),
+                None => class_get_attr(class, name).map(|value| (value, class.clone(), true)),
             },
-            &bytecode::Instruction::PopBlock => {
-                self.block_stack.pop();
-            }
-            &bytecode::Instruction::GetIter => {
-                let iterated_obj = self.stack.pop().unwrap();
-                let iter_obj = PyObject::Iterator {
-                    position: 0, iterated_obj: iterated_obj
-                }.into_ref();
-                self.stack.push(iter_obj);
+            PyObject::Class { .. } => {
+                class_get_attr(&obj, name).map(|value| (value, obj.clone(), false))
+            }
+            _ => None,
+        };
+        if let Some((value, class, is_instance)) = class_attribute {
+            let instance = if is_instance { Some(&obj) } else { None };
+            return self.get_class_attribute(value, &class, instance);
+        }
+
+        let attribute = match *obj.borrow() {
+            PyObject::Module {
+                name: ref module_name,
+                ref scope,
+            } => match scope.borrow().locals.get(name) {
+                Some(value) => Some(value.clone()),
+                None => {
+                    let msg = format!("module '{}' has no attribute '{}'", module_name, name);
+                    return Err(self.new_exception("AttributeError", msg));
+                }
             },
-            &bytecode::Instruction::ForIter => {
-                // The top of stack contains the iterator, lets push it forward:
-                let next_obj: Option<PyObjectRef> = {
-                    let top_of_stack = self.stack.last().unwrap();
-                    let mut ref_mut: RefMut<PyObject> = top_of_stack.deref().borrow_mut();
-                    // We require a mutable pyobject here to update the iterator:
-                    let mut iterator = ref_mut; // &mut PyObject = ref_mut.;
-                    // let () = iterator;
-                    iterator.nxt()
-                };
+            PyObject::RustFunction { ref doc, .. } if name == "__doc__" => Some(match *doc {
+                Some(ref doc) => PyObject::String { value: doc.clone() }.into_ref(),
+                None => self.get_none(),
+            }),
+            PyObject::Generator { .. } => builtin_method(generator::get_method(name), &obj),
+            PyObject::List { .. } => builtin_method(list::get_method(name), &obj),
+            PyObject::Dict { .. } => builtin_method(dict::get_method(name), &obj),
+            PyObject::Set { .. } => builtin_method(set::get_method(name), &obj),
+            PyObject::Code { .. }
+            | PyObject::Function { .. }
+            | PyObject::Frame { .. }
+            | PyObject::Traceback { .. } => code::get_attribute(self, &obj, name),
+            _ => None,
+        };
+
+        match attribute {
+            Some(value) => Ok(value),
+            None => {
+                let msg = format!(
+                    "'{}' object has no attribute '{}'",
+                    obj.borrow().type_name(),
+                    name
+                );
+                Err(self.new_exception("AttributeError", msg))
+            }
+        }
+    }
+
+    // What looking up an attribute of a class gives depends on the kind of
+    // attribute. `obj` is the instance, if it was not looked up on the class.
+    fn get_class_attribute(
+        &mut self,
+        value: PyObjectRef,
+        class: &PyObjectRef,
+        obj: Option<&PyObjectRef>,
+    ) -> PyResult {
+        if self.is_builtin_instance(&value, "staticmethod") {
+            return self.get_attribute(value, "__func__");
+        }
+        if self.is_builtin_instance(&value, "classmethod") {
+            let function = self.get_attribute(value, "__func__")?;
+            return Ok(PyObject::BoundMethod {
+                function,
+                object: class.clone(),
+            }.into_ref());
+        }
+        match obj {
+            Some(obj) => {
+                if self.is_builtin_instance(&value, "property") {
+                    let fget = self.get_attribute(value, "fget")?;
+                    if let PyObject::None = *fget.borrow() {
+                        return Err(self.new_exception(
+                            "AttributeError",
+                            String::from("unreadable attribute"),
+                        ));
+                    }
+                    return self.invoke(fget, vec![obj.clone()]);
+                }
+                Ok(bind_method(value, obj))
+            }
+            None => Ok(value),
+        }
+    }
+
+    fn is_builtin_instance(&self, obj: &PyObjectRef, class_name: &str) -> bool {
+        self.isinstance(obj, &self.builtins[class_name])
+    }
+
+    pub fn set_attribute(&mut self, obj: PyObjectRef, name: &str, value: PyObjectRef) -> PyResult {
+        // Assigning to a property calls its setter:
+        let class_attribute = match *obj.borrow() {
+            PyObject::Instance { ref class, .. } => class_get_attr(class, name),
+            _ => None,
+        };
+        if let Some(attribute) = class_attribute {
+            if self.is_builtin_instance(&attribute, "property") {
+                let fset = self.get_attribute(attribute, "fset")?;
+                if let PyObject::None = *fset.borrow() {
+                    return Err(self.new_exception(
+                        "AttributeError",
+                        String::from("can't set attribute"),
+                    ));
+                }
+                return self.invoke(fset, vec![obj.clone(), value]);
+            }
+        }
+
+        match *obj.borrow_mut() {
+            PyObject::Instance { ref mut dict, .. } | PyObject::Class { ref mut dict, .. } => {
+                dict.insert(String::from(name), value);
+                return Ok(self.get_none());
+            }
+            PyObject::Module { ref scope, .. } => {
+                scope.borrow_mut().locals.insert(String::from(name), value);
+                return Ok(self.get_none());
+            }
+            _ => {}
+        }
+        let msg = format!(
+            "'{}' object has no attribute '{}'",
+            obj.borrow().type_name(),
+            name
+        );
+        Err(self.new_exception("AttributeError", msg))
+    }
+
+    pub fn del_attribute(&mut self, obj: PyObjectRef, name: &str) -> PyResult {
+        let removed = match *obj.borrow_mut() {
+            PyObject::Instance { ref mut dict, .. } | PyObject::Class { ref mut dict, .. } => {
+                dict.remove(name).is_some()
+            }
+            PyObject::Module { ref scope, .. } => scope.borrow_mut().locals.remove(name).is_some(),
+            _ => false,
+        };
+        if removed {
+            return Ok(self.get_none());
+        }
+        let msg = format!(
+            "'{}' object has no attribute '{}'",
+            obj.borrow().type_name(),
+            name
+        );
+        Err(self.new_exception("AttributeError", msg))
+    }
+
+    pub fn isinstance(&self, obj: &PyObjectRef, class: &PyObjectRef) -> bool {
+        match *obj.borrow() {
+            PyObject::Instance { class: ref obj_class, .. } => self.issubclass(obj_class, class),
+            _ => false,
+        }
+    }
+
+    pub fn issubclass(&self, subclass: &PyObjectRef, class: &PyObjectRef) -> bool {
+        if Rc::ptr_eq(subclass, class) {
+            return true;
+        }
+        match *subclass.borrow() {
+            PyObject::Class { ref bases, .. } => {
+                bases.iter().any(|base| self.issubclass(base, class))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_true(&mut self, obj: PyObjectRef) -> Result<bool, PyObjectRef> {
+        let value = match *obj.borrow() {
+            PyObject::Boolean { value } => value,
+            PyObject::Integer { value } => value != 0,
+            PyObject::Float { value } => value != 0.0,
+            PyObject::Complex { real, imag } => real != 0.0 || imag != 0.0,
+            PyObject::None => false,
+            PyObject::String { ref value } => !value.is_empty(),
+            PyObject::Bytes { ref value } => !value.is_empty(),
+            PyObject::List { ref elements }
+            | PyObject::Tuple { ref elements }
+            | PyObject::Set { ref elements }
+            | PyObject::FrozenSet { ref elements } => !elements.is_empty(),
+            PyObject::Dict { ref elements } => !elements.is_empty(),
+            PyObject::Range { start, stop, step } => {
+                (step > 0 && start < stop) || (step < 0 && start > stop)
+            }
+            _ => true,
+        };
+        Ok(value)
+    }
+
+    pub fn str_of(&mut self, obj: PyObjectRef) -> Result<String, PyObjectRef> {
+        let is_instance = matches!(*obj.borrow(), PyObject::Instance { .. });
+        if is_instance {
+            if let Ok(method) = self.get_attribute(obj.clone(), "__str__") {
+                let value = self.invoke(method, vec![])?;
+                return Ok(value.borrow().str());
+            }
+        }
+        Ok(obj.borrow().str())
+    }
+
+    pub fn repr_of(&mut self, obj: PyObjectRef) -> Result<String, PyObjectRef> {
+        let is_instance = matches!(*obj.borrow(), PyObject::Instance { .. });
+        if is_instance {
+            if let Ok(method) = self.get_attribute(obj.clone(), "__repr__") {
+                let value = self.invoke(method, vec![])?;
+                return Ok(value.borrow().str());
+            }
+        }
+        Ok(obj.borrow().repr())
+    }
+
+    // Get the elements of a sequence, or all the values of an iterable:
+    pub fn extract_elements(&mut self, obj: &PyObjectRef) -> Result<Vec<PyObjectRef>, PyObjectRef> {
+        if let PyObject::List { ref elements } | PyObject::Tuple { ref elements } = *obj.borrow() {
+            return Ok(elements.clone());
+        }
+        let iter = self.get_iter(obj.clone())?;
+        let mut elements = vec![];
+        while let Some(element) = self.next(iter.clone())? {
+            elements.push(element);
+        }
+        Ok(elements)
+    }
+
+    pub fn get_iter(&mut self, obj: PyObjectRef) -> PyResult {
+        let iterable = match *obj.borrow() {
+            PyObject::List { .. }
+            | PyObject::Tuple { .. }
+            | PyObject::String { .. }
+            | PyObject::Dict { .. }
+            | PyObject::Set { .. }
+            | PyObject::FrozenSet { .. }
+            | PyObject::Bytes { .. }
+            | PyObject::Range { .. } => true,
+            PyObject::Iterator { .. } | PyObject::Generator { .. } => return Ok(obj.clone()),
+            _ => false,
+        };
+        if iterable {
+            return Ok(PyObject::Iterator {
+                position: 0,
+                iterated_obj: obj,
+            }.into_ref());
+        }
+        // An instance is iterable when its __iter__ gives an iterator:
+        let is_instance = matches!(*obj.borrow(), PyObject::Instance { .. });
+        if is_instance {
+            if let Ok(method) = self.get_attribute(obj.clone(), "__iter__") {
+                let iter = self.invoke(method, vec![])?;
+                if matches!(*iter.borrow(), PyObject::Iterator { .. } | PyObject::Generator { .. }) {
+                    return Ok(iter);
+                }
+                let msg = format!(
+                    "iter() returned non-iterator of type '{}'",
+                    iter.borrow().type_name()
+                );
+                return Err(self.new_exception("TypeError", msg));
+            }
+        }
+        let msg = format!("'{}' object is not iterable", obj.borrow().type_name());
+        Err(self.new_exception("TypeError", msg))
+    }
+
+    // Advance an iterator, None means the iterator is exhausted.
+    pub fn next(&mut self, iter: PyObjectRef) -> Result<Option<PyObjectRef>, PyObjectRef> {
+        let is_generator = matches!(*iter.borrow(), PyObject::Generator { .. });
+        if !is_generator {
+            let mut iter_obj = iter.borrow_mut();
+            return match *iter_obj {
+                PyObject::Iterator { .. } => Ok(iter_obj.nxt()),
+                ref obj => {
+                    let msg = format!("'{}' object is not an iterator", obj.type_name());
+                    Err(self.new_exception("TypeError", msg))
+                }
+            };
+        }
+
+        let none = self.get_none();
+        match generator::send(self, &iter, none) {
+            Ok(value) => Ok(Some(value)),
+            Err(exception) => {
+                let stop_iteration = self.get_builtin("StopIteration");
+                if self.isinstance(&exception, &stop_iteration) {
+                    Ok(None)
+                } else {
+                    Err(exception)
+                }
+            }
+        }
+    }
+
+    // Send a value into an iterator. When the iterator is exhausted,
+    // StopIteration is raised.
+    pub fn send(&mut self, iter: PyObjectRef, value: PyObjectRef) -> PyResult {
+        let is_generator = matches!(*iter.borrow(), PyObject::Generator { .. });
+        if is_generator {
+            return generator::send(self, &iter, value);
+        }
+
+        let is_none = matches!(*value.borrow(), PyObject::None);
+        if !is_none {
+            return self.call_method(iter, "send", vec![value]);
+        }
+        match self.next(iter)? {
+            Some(value) => Ok(value),
+            None => Err(self.new_exception("StopIteration", String::new())),
+        }
+    }
 
-                // Check the next object:
-                match next_obj {
-                    Some(v) => {
-                        self.stack.push(v);
-                    },
+    pub fn unary_op(&mut self, a: PyObjectRef, op: &bytecode::UnaryOperator) -> PyResult {
+        if let bytecode::UnaryOperator::Not = *op {
+            let value = self.is_true(a)?;
+            return Ok(self.new_bool(!value));
+        }
+
+        let float = match *a.borrow() {
+            PyObject::Float { value } => Some(value),
+            _ => None,
+        };
+        if let Some(value) = float {
+            let value = match *op {
+                bytecode::UnaryOperator::Minus => -value,
+                bytecode::UnaryOperator::Plus => value,
+                _ => {
+                    let msg = String::from("bad operand type for unary ~: 'float'");
+                    return Err(self.new_exception("TypeError", msg));
+                }
+            };
+            return Ok(PyObject::Float { value }.into_ref());
+        }
+
+        let value = match to_int(&a.borrow()) {
+            Some(value) => value,
+            None => {
+                let msg = format!("bad operand type for unary operator: '{}'", a.borrow().type_name());
+                return Err(self.new_exception("TypeError", msg));
+            }
+        };
+        let result = match *op {
+            bytecode::UnaryOperator::Minus => value.checked_neg(),
+            bytecode::UnaryOperator::Plus => Some(value),
+            bytecode::UnaryOperator::Invert => Some(!value),
+            bytecode::UnaryOperator::Not => unreachable!(),
+        };
+        match result {
+            Some(value) => Ok(PyObject::Integer { value }.into_ref()),
+            None => Err(self.new_exception("OverflowError", String::from("integer overflow"))),
+        }
+    }
+
+    // In-place operations try the `__iadd__` like method first, and fall back
+    // to the normal operation, which creates a new object.
+    pub fn binary_op(
+        &mut self,
+        a_ref: PyObjectRef,
+        b_ref: PyObjectRef,
+        op: &bytecode::BinaryOperator,
+        inplace: bool,
+    ) -> PyResult {
+        if let bytecode::BinaryOperator::Subscript = *op {
+            return self.get_item(a_ref, b_ref);
+        }
+
+        if let Some(name) = special_method_name(op) {
+            if inplace {
+                let method = format!("__i{}__", name);
+                if let Some(result) = self.call_special_method(&a_ref, &method, &b_ref)? {
+                    return Ok(result);
+                }
+            }
+            let method = format!("__{}__", name);
+            if let Some(result) = self.call_special_method(&a_ref, &method, &b_ref)? {
+                return Ok(result);
+            }
+            let method = format!("__r{}__", name);
+            if let Some(result) = self.call_special_method(&b_ref, &method, &a_ref)? {
+                return Ok(result);
+            }
+        }
+
+        // Lists are extended in place, by anything iterable:
+        let is_list = matches!(*a_ref.borrow(), PyObject::List { .. });
+        if inplace && is_list {
+            if let bytecode::BinaryOperator::Add = *op {
+                for element in self.extract_elements(&b_ref)? {
+                    list::append(&a_ref, element);
+                }
+                return Ok(a_ref);
+            }
+        }
+
+        let result = {
+            let a = &*a_ref.borrow();
+            let b = &*b_ref.borrow();
+            match (to_int(a), to_int(b), to_float(a), to_float(b)) {
+                (Some(value1), Some(value2), _, _) => self.int_op(value1, value2, op)?,
+                (_, _, Some(value1), Some(value2)) => self.float_op(value1, value2, op)?,
+                _ => sequence_op(a, b, op),
+            }
+        };
+
+        match result {
+            Some(value) => Ok(value.into_ref()),
+            None => {
+                let msg = format!(
+                    "unsupported operand type(s) for {:?}: '{}' and '{}'",
+                    op,
+                    a_ref.borrow().type_name(),
+                    b_ref.borrow().type_name()
+                );
+                Err(self.new_exception("TypeError", msg))
+            }
+        }
+    }
+
+    // Call a method defined by the class of an instance, if there is one:
+    fn call_special_method(
+        &mut self,
+        obj: &PyObjectRef,
+        name: &str,
+        arg: &PyObjectRef,
+    ) -> Result<Option<PyObjectRef>, PyObjectRef> {
+        let method = match *obj.borrow() {
+            PyObject::Instance { ref class, .. } => class_get_attr(class, name),
+            _ => None,
+        };
+        match method {
+            Some(method) => Ok(Some(self.invoke(method, vec![obj.clone(), arg.clone()])?)),
+            None => Ok(None),
+        }
+    }
+
+    fn int_op(
+        &mut self,
+        a: i32,
+        b: i32,
+        op: &bytecode::BinaryOperator,
+    ) -> Result<Option<PyObject>, PyObjectRef> {
+        let needs_divisor = matches!(*op, bytecode::BinaryOperator::FloorDivide | bytecode::BinaryOperator::Modulo);
+        if needs_divisor && b == 0 {
+            return Err(self.new_exception(
+                "ZeroDivisionError",
+                String::from("integer division or modulo by zero"),
+            ));
+        }
+
+        let value = match *op {
+            bytecode::BinaryOperator::Add => a.checked_add(b),
+            bytecode::BinaryOperator::Subtract => a.checked_sub(b),
+            bytecode::BinaryOperator::Multiply => a.checked_mul(b),
+            bytecode::BinaryOperator::FloorDivide => Some(floor_div(a, b)),
+            bytecode::BinaryOperator::Modulo => Some(a - floor_div(a, b) * b),
+            bytecode::BinaryOperator::Power => {
+                if b < 0 {
+                    return self.float_op(f64::from(a), f64::from(b), op);
+                }
+                a.checked_pow(b as u32)
+            }
+            bytecode::BinaryOperator::Divide => {
+                return self.float_op(f64::from(a), f64::from(b), op);
+            }
+            bytecode::BinaryOperator::Lshift => {
+                if b < 0 {
+                    return Err(self.new_exception("ValueError", String::from("negative shift count")));
+                }
+                a.checked_shl(b as u32)
+            }
+            bytecode::BinaryOperator::Rshift => {
+                if b < 0 {
+                    return Err(self.new_exception("ValueError", String::from("negative shift count")));
+                }
+                Some(a >> (b.min(31)))
+            }
+            bytecode::BinaryOperator::And => Some(a & b),
+            bytecode::BinaryOperator::Xor => Some(a ^ b),
+            bytecode::BinaryOperator::Or => Some(a | b),
+            _ => return Ok(None),
+        };
+        match value {
+            Some(value) => Ok(Some(PyObject::Integer { value })),
+            None => Err(self.new_exception("OverflowError", String::from("integer overflow"))),
+        }
+    }
+
+    fn float_op(
+        &mut self,
+        a: f64,
+        b: f64,
+        op: &bytecode::BinaryOperator,
+    ) -> Result<Option<PyObject>, PyObjectRef> {
+        let needs_divisor = matches!(
+            *op,
+            bytecode::BinaryOperator::Divide
+                | bytecode::BinaryOperator::FloorDivide
+                | bytecode::BinaryOperator::Modulo
+        );
+        if needs_divisor && b == 0.0 {
+            return Err(self.new_exception("ZeroDivisionError", String::from("division by zero")));
+        }
+
+        let value = match *op {
+            bytecode::BinaryOperator::Add => a + b,
+            bytecode::BinaryOperator::Subtract => a - b,
+            bytecode::BinaryOperator::Multiply => a * b,
+            bytecode::BinaryOperator::Divide => a / b,
+            bytecode::BinaryOperator::FloorDivide => (a / b).floor(),
+            // The result has the sign of the divisor:
+            bytecode::BinaryOperator::Modulo => a - (a / b).floor() * b,
+            bytecode::BinaryOperator::Power => a.powf(b),
+            _ => return Ok(None),
+        };
+        Ok(Some(PyObject::Float { value }))
+    }
+
+    pub fn compare(
+        &mut self,
+        a: PyObjectRef,
+        b: PyObjectRef,
+        op: &bytecode::ComparisonOperator,
+    ) -> PyResult {
+        let value = match *op {
+            bytecode::ComparisonOperator::Equal => self.equals(&a, &b),
+            bytecode::ComparisonOperator::NotEqual => !self.equals(&a, &b),
+            bytecode::ComparisonOperator::Is => Rc::ptr_eq(&a, &b),
+            bytecode::ComparisonOperator::IsNot => !Rc::ptr_eq(&a, &b),
+            bytecode::ComparisonOperator::In => self.contains(b, a)?,
+            bytecode::ComparisonOperator::NotIn => !self.contains(b, a)?,
+            bytecode::ComparisonOperator::ExceptionMatch => self.exception_matches(&a, &b)?,
+            _ => {
+                let ordering = {
+                    let a = &*a.borrow();
+                    let b = &*b.borrow();
+                    match (to_int(a), to_int(b), to_float(a), to_float(b)) {
+                        (Some(value1), Some(value2), _, _) => Some(value1.cmp(&value2)),
+                        (_, _, Some(value1), Some(value2)) => match value1.partial_cmp(&value2) {
+                            Some(ordering) => Some(ordering),
+                            // Nothing is ordered with respect to nan:
+                            None => return Ok(self.new_bool(false)),
+                        },
+                        _ => match (a, b) {
+                            (
+                                PyObject::String { value: value1 },
+                                PyObject::String { value: value2 },
+                            ) => Some(value1.cmp(value2)),
+                            _ => None,
+                        },
+                    }
+                };
+                let ordering = match ordering {
+                    Some(ordering) => ordering,
                     None => {
-                        // End of for loop
-                        let end_label = self.block_stack.last().unwrap().1;
-                        self.jump(end_label);
+                        let msg = format!(
+                            "'{:?}' not supported between instances of '{}' and '{}'",
+                            op,
+                            a.borrow().type_name(),
+                            b.borrow().type_name()
+                        );
+                        return Err(self.new_exception("TypeError", msg));
                     }
+                };
+                match *op {
+                    bytecode::ComparisonOperator::Less => ordering.is_lt(),
+                    bytecode::ComparisonOperator::LessOrEqual => ordering.is_le(),
+                    bytecode::ComparisonOperator::Greater => ordering.is_gt(),
+                    bytecode::ComparisonOperator::GreaterOrEqual => ordering.is_ge(),
+                    _ => unreachable!(),
                 }
+            }
+        };
+        Ok(self.new_bool(value))
+    }
+
+    pub fn equals(&self, a: &PyObjectRef, b: &PyObjectRef) -> bool {
+        if Rc::ptr_eq(a, b) {
+            return true;
+        }
+        let a = &*a.borrow();
+        let b = &*b.borrow();
+        if let (Some(value1), Some(value2)) = (to_int(a), to_int(b)) {
+            return value1 == value2;
+        }
+        if let (Some(value1), Some(value2)) = (to_float(a), to_float(b)) {
+            return value1 == value2;
+        }
+        match (a, b) {
+            (PyObject::String { value: value1 }, PyObject::String { value: value2 }) => {
+                value1 == value2
+            }
+            (PyObject::Bytes { value: value1 }, PyObject::Bytes { value: value2 }) => {
+                value1 == value2
+            }
+            (
+                PyObject::Complex {
+                    real: real1,
+                    imag: imag1,
+                },
+                PyObject::Complex {
+                    real: real2,
+                    imag: imag2,
+                },
+            ) => real1 == real2 && imag1 == imag2,
+            (PyObject::None, PyObject::None) | (PyObject::Ellipsis, PyObject::Ellipsis) => true,
+            (
+                PyObject::List {
+                    elements: elements1,
+                },
+                PyObject::List {
+                    elements: elements2,
+                },
+            )
+            | (
+                PyObject::Tuple {
+                    elements: elements1,
+                },
+                PyObject::Tuple {
+                    elements: elements2,
+                },
+            ) => {
+                elements1.len() == elements2.len()
+                    && elements1
+                        .iter()
+                        .zip(elements2.iter())
+                        .all(|(e1, e2)| self.equals(e1, e2))
+            }
+            (PyObject::Set { elements: elements1 }, PyObject::Set { elements: elements2 })
+            | (PyObject::Set { elements: elements1 }, PyObject::FrozenSet { elements: elements2 })
+            | (PyObject::FrozenSet { elements: elements1 }, PyObject::Set { elements: elements2 })
+            | (
+                PyObject::FrozenSet {
+                    elements: elements1,
+                },
+                PyObject::FrozenSet {
+                    elements: elements2,
+                },
+            ) => {
+                elements1.len() == elements2.len()
+                    && elements1
+                        .iter()
+                        .all(|e1| elements2.iter().any(|e2| self.equals(e1, e2)))
+            }
+            (PyObject::Dict { elements: elements1 }, PyObject::Dict { elements: elements2 }) => {
+                elements1.len() == elements2.len()
+                    && elements1.iter().all(|(k1, v1)| {
+                        elements2
+                            .iter()
+                            .any(|(k2, v2)| self.equals(k1, k2) && self.equals(v1, v2))
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    fn contains(&mut self, container: PyObjectRef, item: PyObjectRef) -> Result<bool, PyObjectRef> {
+        if let PyObject::String { ref value } = *container.borrow() {
+            return match *item.borrow() {
+                PyObject::String { value: ref part } => Ok(value.contains(part.as_str())),
+                _ => Err(self.new_exception(
+                    "TypeError",
+                    String::from("'in <string>' requires string as left operand"),
+                )),
+            };
+        }
+        let elements = self.extract_elements(&container)?;
+        Ok(elements.iter().any(|element| self.equals(element, &item)))
+    }
+
+    fn exception_matches(&mut self, exception: &PyObjectRef, class: &PyObjectRef) -> Result<bool, PyObjectRef> {
+        if let PyObject::Tuple { ref elements } = *class.borrow() {
+            return Ok(elements.iter().any(|class| self.isinstance(exception, class)));
+        }
+        match *class.borrow() {
+            PyObject::Class { .. } => {}
+            _ => {
+                return Err(self.new_exception(
+                    "TypeError",
+                    String::from("catching classes that do not inherit from BaseException is not allowed"),
+                ))
+            }
+        }
+        Ok(self.isinstance(exception, class))
+    }
+
+    pub fn get_item(&mut self, obj: PyObjectRef, index: PyObjectRef) -> PyResult {
+        let is_dict = matches!(*obj.borrow(), PyObject::Dict { .. });
+        if is_dict {
+            return match dict::get_item(self, &obj, &index)? {
+                Some(value) => Ok(value),
+                None => Err(self.new_key_error(index)),
+            };
+        }
+
+        let index_value = match to_int(&index.borrow()) {
+            Some(value) => value,
+            None => {
+                let msg = format!("indices must be integers, not {}", index.borrow().type_name());
+                return Err(self.new_exception("TypeError", msg));
+            }
+        };
+        let element = match *obj.borrow() {
+            PyObject::List { ref elements } | PyObject::Tuple { ref elements } => {
+                get_index(elements.len(), index_value).map(|position| elements[position].clone())
+            }
+            PyObject::String { ref value } => {
+                let chars: Vec<char> = value.chars().collect();
+                get_index(chars.len(), index_value).map(|position| {
+                    PyObject::String {
+                        value: chars[position].to_string(),
+                    }.into_ref()
+                })
+            }
+            ref obj => {
+                let msg = format!("'{}' object is not subscriptable", obj.type_name());
+                return Err(self.new_exception("TypeError", msg));
+            }
+        };
+        match element {
+            Some(element) => Ok(element),
+            None => Err(self.new_exception("IndexError", String::from("index out of range"))),
+        }
+    }
+
+    pub fn set_item(&mut self, obj: PyObjectRef, index: PyObjectRef, value: PyObjectRef) -> PyResult {
+        let is_dict = matches!(*obj.borrow(), PyObject::Dict { .. });
+        if is_dict {
+            dict::set_item(self, &obj, index, value)?;
+            return Ok(self.get_none());
+        }
+
+        let index_value = to_int(&index.borrow());
+        let stored = match *obj.borrow_mut() {
+            PyObject::List { ref mut elements } => match index_value {
+                Some(index_value) => match get_index(elements.len(), index_value) {
+                    Some(position) => {
+                        elements[position] = value;
+                        true
+                    }
+                    None => false,
+                },
+                None => false,
             },
-            &bytecode::Instruction::CallFunction { count } => {
-                let mut args: Vec<PyObjectRef> = Vec::new();
+            ref obj => {
+                let msg = format!("'{}' object does not support item assignment", obj.type_name());
+                return Err(self.new_exception("TypeError", msg));
+            }
+        };
+        if stored {
+            Ok(self.get_none())
+        } else {
+            Err(self.new_exception(
+                "IndexError",
+                String::from("list assignment index out of range"),
+            ))
+        }
+    }
+
+    pub fn del_item(&mut self, obj: PyObjectRef, index: PyObjectRef) -> PyResult {
+        let is_dict = matches!(*obj.borrow(), PyObject::Dict { .. });
+        if is_dict {
+            if dict::del_item(self, &obj, &index)? {
+                return Ok(self.get_none());
+            }
+            return Err(self.new_key_error(index));
+        }
 
-                for _x in 0..count {
-                    args.push(self.stack.pop().unwrap());
+        let index_value = to_int(&index.borrow());
+        let removed = match *obj.borrow_mut() {
+            PyObject::List { ref mut elements } => {
+                match index_value.and_then(|value| get_index(elements.len(), value)) {
+                    Some(position) => {
+                        elements.remove(position);
+                        true
+                    }
+                    None => false,
                 }
+            }
+            ref obj => {
+                let msg = format!("'{}' object doesn't support item deletion", obj.type_name());
+                return Err(self.new_exception("TypeError", msg));
+            }
+        };
+        if removed {
+            Ok(self.get_none())
+        } else {
+            Err(self.new_exception(
+                "IndexError",
+                String::from("list assignment index out of range"),
+            ))
+        }
+    }
+}
+
+// Lookup an attribute in a class, and its base classes.
+pub fn class_get_attr(class: &PyObjectRef, name: &str) -> Option<PyObjectRef> {
+    match *class.borrow() {
+        PyObject::Class {
+            ref dict,
+            ref bases,
+            ..
+        } => match dict.get(name) {
+            Some(value) => Some(value.clone()),
+            None => bases.iter().filter_map(|base| class_get_attr(base, name)).next(),
+        },
+        _ => None,
+    }
+}
+
+// Methods of builtin types are implemented by rust functions:
+fn builtin_method(function: Option<RustPyFunc>, obj: &PyObjectRef) -> Option<PyObjectRef> {
+    function.map(|function| {
+        let function = PyObject::RustFunction {
+            function: Rc::new(function),
+            doc: None,
+        };
+        bind_method(function.into_ref(), obj)
+    })
+}
 
-                args.reverse();
-                let func_ref = self.stack.pop().unwrap();
-                let f=func_ref.borrow();// = &*func_ref.borrow();
-                f.call(args);
-                // call_stack.push();
-                // If a builtin function, then call directly, otherwise, execute it?
-                // execute(function.code);
+// Functions looked up via an object are bound to that object:
+fn bind_method(value: PyObjectRef, obj: &PyObjectRef) -> PyObjectRef {
+    let is_function = matches!(*value.borrow(), PyObject::Function { .. } | PyObject::RustFunction { .. });
+    if is_function {
+        PyObject::BoundMethod {
+            function: value,
+            object: obj.clone(),
+        }.into_ref()
+    } else {
+        value
+    }
+}
+
+// The name of the special methods of an operator, without underscores:
+fn special_method_name(op: &bytecode::BinaryOperator) -> Option<&'static str> {
+    let name = match *op {
+        bytecode::BinaryOperator::Add => "add",
+        bytecode::BinaryOperator::Subtract => "sub",
+        bytecode::BinaryOperator::Multiply => "mul",
+        bytecode::BinaryOperator::MatrixMultiply => "matmul",
+        bytecode::BinaryOperator::Divide => "truediv",
+        bytecode::BinaryOperator::FloorDivide => "floordiv",
+        bytecode::BinaryOperator::Modulo => "mod",
+        bytecode::BinaryOperator::Power => "pow",
+        bytecode::BinaryOperator::Lshift => "lshift",
+        bytecode::BinaryOperator::Rshift => "rshift",
+        bytecode::BinaryOperator::And => "and",
+        bytecode::BinaryOperator::Xor => "xor",
+        bytecode::BinaryOperator::Or => "or",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Booleans take part in arithmetic as integers:
+fn to_int(obj: &PyObject) -> Option<i32> {
+    match *obj {
+        PyObject::Integer { value } => Some(value),
+        PyObject::Boolean { value } => Some(if value { 1 } else { 0 }),
+        _ => None,
+    }
+}
+
+fn to_float(obj: &PyObject) -> Option<f64> {
+    match *obj {
+        PyObject::Float { value } => Some(value),
+        _ => to_int(obj).map(f64::from),
+    }
+}
+
+// Division rounding towards negative infinity, like python does:
+fn floor_div(a: i32, b: i32) -> i32 {
+    let quotient = a / b;
+    if (a % b != 0) && ((a < 0) != (b < 0)) {
+        quotient - 1
+    } else {
+        quotient
+    }
+}
+
+// Translate a python index, which may be negative, into a position:
+fn get_index(length: usize, index: i32) -> Option<usize> {
+    let position = if index < 0 {
+        length as i64 + i64::from(index)
+    } else {
+        i64::from(index)
+    };
+    if position >= 0 && position < length as i64 {
+        Some(position as usize)
+    } else {
+        None
+    }
+}
+
+fn sequence_op(a: &PyObject, b: &PyObject, op: &bytecode::BinaryOperator) -> Option<PyObject> {
+    match (op, a, b) {
+        (
+            bytecode::BinaryOperator::Add,
+            PyObject::String { value: value1 },
+            PyObject::String { value: value2 },
+        ) => Some(PyObject::String {
+            value: format!("{}{}", value1, value2),
+        }),
+        (bytecode::BinaryOperator::Multiply, PyObject::String { .. }, PyObject::Integer { .. }) => {
+            Some(a * b)
+        }
+        (
+            bytecode::BinaryOperator::Add,
+            PyObject::List {
+                elements: elements1,
             },
-            &bytecode::Instruction::Break => {
-                let end_label = self.block_stack.last().unwrap().1;
-                self.jump(end_label);
+            PyObject::List {
+                elements: elements2,
             },
-            &bytecode::Instruction::Pass => {
-                // Ah, this is nice, just relax!
+        ) => Some(PyObject::List {
+            elements: elements1.iter().chain(elements2.iter()).cloned().collect(),
+        }),
+        (
+            bytecode::BinaryOperator::Add,
+            PyObject::Tuple {
+                elements: elements1,
             },
-            &bytecode::Instruction::Continue => {
-                let start_label = self.block_stack.last().unwrap().0;
-                self.jump(start_label);
+            PyObject::Tuple {
+                elements: elements2,
             },
+        ) => Some(PyObject::Tuple {
+            elements: elements1.iter().chain(elements2.iter()).cloned().collect(),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::rc::Rc;
+    use super::super::ast::Location;
+    use super::super::bytecode::{CodeObject, Constant, Instruction, InstructionList};
+    use super::super::compile::compile;
+    use super::super::frame::{ExecutionResult, Frame, Scope};
+    use super::super::parser::parse_source;
+    use super::super::pyobject::PyObject;
+    use super::{Settings, VirtualMachine};
+
+    // Run a program, and return the repr of one of its global variables:
+    pub fn run_and_get(source: &str, name: &str) -> String {
+        let program = parse_source(source).unwrap();
+        let code = compile(program).unwrap();
+        let mut vm = VirtualMachine::new();
+        let scope = Scope::new(None);
+        let mut frame = Frame::new(Rc::new(code), scope.clone());
+        if let Err(exception) = frame.run(&mut vm) {
+            vm.print_exception(&exception);
+            panic!("Program raised an exception");
+        }
+        let value = scope.borrow().get(name).unwrap();
+        let repr = value.borrow().repr();
+        repr
+    }
+
+    #[test]
+    fn test_list_comprehension() {
+        let source = "
+data = [1, 2, 3, 4, 5]
+x = [y * y for y in data if y % 2]
+";
+        assert_eq!(run_and_get(source, "x"), "[1, 9, 25]");
+    }
+
+    #[test]
+    fn test_nested_comprehension() {
+        let source = "
+x = [(a, b) for a in range(3) for b in range(a) if b != 1]
+";
+        assert_eq!(run_and_get(source, "x"), "[(1, 0), (2, 0)]");
+    }
+
+    #[test]
+    fn test_dict_and_set_comprehension() {
+        let source = "
+pairs = [('a', 1), ('b', 2), ('a', 3)]
+x = ({k: v for k, v in pairs}, {v % 2 for k, v in pairs})
+";
+        assert_eq!(run_and_get(source, "x"), "({'a': 3, 'b': 2}, {1, 0})");
+    }
+
+    #[test]
+    fn test_comprehension_variable_does_not_leak() {
+        let source = "
+y = 'outer'
+x = [y for y in range(3)]
+x = (y, x)
+";
+        assert_eq!(run_and_get(source, "x"), "('outer', [0, 1, 2])");
+    }
+
+    #[test]
+    fn test_sort_with_lambda_key() {
+        let source = "
+rows = [('b', 3), ('a', 1), ('c', 2), ('d', 1)]
+x = sorted(rows, key=lambda r: r[1])
+rows.sort(key=lambda r: r[0], reverse=True)
+x = (x, rows)
+";
+        assert_eq!(
+            run_and_get(source, "x"),
+            "([('a', 1), ('d', 1), ('c', 2), ('b', 3)], [('d', 1), ('c', 2), ('b', 3), ('a', 1)])"
+        );
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        let source = "
+def f(a, b=2, c=3):
+    return (a, b, c)
+
+x = [f(1, c=9), f(b=0, a=1)]
+try:
+    f(1, a=2)
+except TypeError:
+    x.append('duplicate')
+try:
+    f(1, d=2)
+except TypeError:
+    x.append('unexpected')
+";
+        assert_eq!(
+            run_and_get(source, "x"),
+            "[(1, 2, 9), (1, 0, 3), 'duplicate', 'unexpected']"
+        );
+    }
+
+    #[test]
+    fn test_conditional_expression() {
+        let source = "
+n = 5
+x = ['big' if n > 3 else 'small', 'a' if n < 3 else 'b' if n < 4 else 'c']
+";
+        assert_eq!(run_and_get(source, "x"), "['big', 'c']");
+    }
+
+    #[test]
+    fn test_augmented_assignment() {
+        let source = "
+class A:
+    pass
+a = A()
+a.n = 10
+a.n -= 3
+d = {'k': 2}
+d['k'] **= 5
+l = [1, 6]
+l[1] //= 4
+x = [a.n, d['k'], l, 7 % 4, 6 & 3, 5 | 2, 5 ^ 1, 1 << 3, 16 >> 2]
+";
+        assert_eq!(run_and_get(source, "x"), "[7, 32, [1, 1], 3, 2, 7, 4, 8, 4]");
+    }
+
+    #[test]
+    fn test_inplace_special_methods() {
+        let source = "
+class Number:
+    def __init__(self, value):
+        self.value = value
+    def __add__(self, other):
+        return Number(self.value + other)
+class Accumulator(Number):
+    def __iadd__(self, other):
+        self.value += other
+        return self
+a = b = Number(1)
+a += 2
+c = d = Accumulator(1)
+c += 2
+l = m = [1]
+l += (2, 3)
+x = [a.value, b.value, c is d, d.value, m]
+";
+        assert_eq!(run_and_get(source, "x"), "[3, 1, True, 3, [1, 2, 3]]");
+    }
+
+    #[test]
+    fn test_annotated_assignment() {
+        let source = "
+x: int = 5
+y: str
+(v): int = 3
+class C:
+    z: bool = True
+def f():
+    w: undefined = 1
+    return w
+a = [__annotations__['x'] is int, list(__annotations__.keys()), C.__annotations__['z'] is bool, f(), v]
+";
+        assert_eq!(run_and_get(source, "a"), "[True, ['x', 'y'], True, 1, 3]");
+
+        // Bodies with annotations of other targets still get __annotations__:
+        let source = "
+d = {}
+d['k']: int = 3
+class D:
+    if True:
+        (u): int = 1
+b = [__annotations__, D.__annotations__, d]
+";
+        assert_eq!(run_and_get(source, "b"), "[{}, {}, {'k': 3}]");
+    }
+
+    #[test]
+    fn test_global_and_nonlocal() {
+        let source = "
+count = 0
+def increment():
+    global count
+    count += 1
+def counter():
+    n = 0
+    def bump():
+        nonlocal n
+        n += 1
+        return n
+    bump()
+    return bump()
+increment()
+increment()
+x = [count, counter()]
+";
+        assert_eq!(run_and_get(source, "x"), "[2, 2]");
+    }
+
+    #[test]
+    fn test_unbound_local() {
+        let source = "
+x = 10
+def f():
+    y = x
+    x = 1
+def g():
+    z = 1
+    del z
+    return z
+errors = []
+for function, name in [(f, 'x'), (g, 'z')]:
+    try:
+        function()
+    except UnboundLocalError as e:
+        errors = errors + [str(e) == \"local variable '\" + name + \"' referenced before assignment\"]
+";
+        assert_eq!(run_and_get(source, "errors"), "[True, True]");
+    }
+
+    #[test]
+    fn test_delete() {
+        let source = "
+class A:
+    pass
+a = A()
+a.v = 1
+l = [1, 2, 3]
+d = {'a': 1, 'b': 2}
+t = 1
+del a.v, l[0], d['a']
+del t
+try:
+    t
+except NameError:
+    t = 'deleted'
+x = [l, d, t]
+";
+        assert_eq!(run_and_get(source, "x"), "[[2, 3], {'b': 2}, 'deleted']");
+    }
+
+    #[test]
+    fn test_key_error() {
+        let source = "
+errors = []
+d = {'': 1}
+for key in ['k', (1, 2)]:
+    try:
+        d[key]
+    except KeyError as e:
+        errors.append(str(e))
+try:
+    del d['']
+    del d['']
+except KeyError as e:
+    errors.append(str(e))
+    errors.append(repr(e))
+x = errors + [str(KeyError()), str(KeyError('a', 'b'))]
+";
+        assert_eq!(
+            run_and_get(source, "x"),
+            "[\"'k'\", '(1, 2)', \"''\", \"KeyError('')\", '', \"('a', 'b')\"]"
+        );
+    }
+
+    #[test]
+    fn test_closures() {
+        let source = "
+def make_adder(n):
+    def add(x):
+        return x + n
+    return add
+def make_counter():
+    count = 0
+    def increment():
+        nonlocal count
+        count += 1
+    def get():
+        return count
+    return increment, get
+increment, get = make_counter()
+increment()
+increment()
+def method_closure():
+    x = 'enclosing'
+    class C:
+        x = 'class'
+        def m(self):
+            return x
+    return C().m()
+x = [make_adder(3)(4), get(), method_closure()]
+";
+        assert_eq!(run_and_get(source, "x"), "[7, 2, 'enclosing']");
+    }
+
+    #[test]
+    fn test_class_scope() {
+        let source = "
+v = 'global'
+class A:
+    v = 'A'
+    class B:
+        w = v
+def f():
+    y = 'local'
+    class C:
+        z = y
+        u = v
+    return C.z, C.u
+x = [A.B.w, f()]
+";
+        assert_eq!(run_and_get(source, "x"), "['global', ('local', 'global')]");
+    }
+
+    #[test]
+    fn test_closure_late_binding() {
+        let source = "
+def in_loop():
+    functions = []
+    for i in range(3):
+        functions.append(lambda: i)
+    return [f() for f in functions]
+def with_default():
+    return [f() for f in [lambda i=i: i for i in range(3)]]
+x = [[f() for f in [lambda: i for i in range(3)]], in_loop(), with_default()]
+";
+        assert_eq!(run_and_get(source, "x"), "[[2, 2, 2], [2, 2, 2], [0, 1, 2]]");
+    }
+
+    #[test]
+    fn test_decorators() {
+        let source = "
+registry = []
+def register(f):
+    registry.append('registered')
+    return f
+def twice(f):
+    return lambda x: f(f(x))
+def add_one(f):
+    return lambda x: f(x + 1)
+decorators = {'twice': twice}
+@register
+@decorators['twice']
+@add_one
+def double(x):
+    return x * 2
+@register
+class C:
+    pass
+x = [double(1), registry]
+";
+        assert_eq!(run_and_get(source, "x"), "[10, ['registered', 'registered']]");
+    }
+
+    #[test]
+    fn test_with_statement() {
+        let source = "
+log = []
+class Manager:
+    def __init__(self, name, suppress):
+        self.name = name
+        self.suppress = suppress
+    def __enter__(self):
+        log.append('enter ' + self.name)
+        return self.name, len(log)
+    def __exit__(self, typ, value, tb):
+        log.append('exit ' + self.name + ' ' + str(typ is not None and tb is not None))
+        return self.suppress
+with Manager('a', True) as (name, count), Manager('b', False):
+    log.append(name + str(count))
+    raise ValueError()
+def leave():
+    for i in [1, 2]:
+        with (Manager('c', False), Manager('d', False) as c):
+            if i == 1:
+                continue
+            return c
+log.append(leave()[0])
+try:
+    with Manager('e', False):
+        raise KeyError()
+except KeyError:
+    log.append('caught')
+x = log
+";
+        assert_eq!(
+            run_and_get(source, "x"),
+            "['enter a', 'enter b', 'a1', 'exit b True', 'exit a True', \
+             'enter c', 'enter d', 'exit d False', 'exit c False', \
+             'enter c', 'enter d', 'exit d False', 'exit c False', 'd', \
+             'enter e', 'exit e True', 'caught']"
+        );
+    }
+
+    #[test]
+    fn test_open() {
+        let path = env::temp_dir().join(format!("rspython_open_{}.txt", process::id()));
+        let mut vm = VirtualMachine::new();
+        let path_value = PyObject::String {
+            value: path.to_string_lossy().into_owned(),
+        }.into_ref();
+        vm.set_global("path", path_value);
+        let source = "
+import io
+results = []
+with open(path, 'w') as f:
+    results.append(f.write('ab'))
+    try:
+        f.read()
+    except io.UnsupportedOperation as e:
+        results.append(str(e))
+with open(path, mode='a') as f:
+    f.write('cd')
+f = open(path)
+f._position = 'x'
+results.append(f.read())
+results.append(f.read())
+try:
+    f.write('e')
+except OSError as e:
+    results.append(str(e))
+f.close()
+try:
+    f.read()
+except ValueError as e:
+    results.append([f.closed, str(e)])
+";
+        let result = vm.run_source(source);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(
+            vm.get_global("results").unwrap().borrow().repr(),
+            "[2, 'not readable', 'abcd', '', 'not writable', \
+             [True, 'I/O operation on closed file.']]"
+        );
+    }
+
+    #[test]
+    fn test_failing_imports() {
+        let source = "
+errors = []
+try:
+    import no.such.module as m
+except ModuleNotFoundError as e:
+    errors.append(str(e) == \"No module named 'no'\")
+try:
+    from . import sibling
+except ImportError as e:
+    errors.append(str(e))
+x = errors
+";
+        assert_eq!(
+            run_and_get(source, "x"),
+            "[True, 'attempted relative import with no known parent package']"
+        );
+    }
+
+    #[test]
+    fn test_embedding() {
+        let mut vm = VirtualMachine::new();
+        vm.set_global("base", PyObject::Integer { value: 10 }.into_ref());
+        vm.run_source("def add(x):\n    return base + x\nresult = add(5)\n")
+            .unwrap();
+        assert_eq!(vm.get_global("result").unwrap().borrow().repr(), "15");
+
+        let add = vm.get_global("add").unwrap();
+        let value = vm
+            .invoke(add, vec![PyObject::Integer { value: 1 }.into_ref()])
+            .unwrap();
+        assert_eq!(value.borrow().repr(), "11");
+
+        let exception = vm.run_source("raise ValueError('bad')\n").unwrap_err();
+        assert_eq!(exception.borrow().type_name(), "ValueError");
+        let exception = vm.run_source("def (\n").unwrap_err();
+        assert_eq!(exception.borrow().type_name(), "SyntaxError");
+    }
+
+    #[test]
+    fn test_settings_and_exit() {
+        let settings = Settings {
+            optimize: true,
+            argv: vec![String::from("prog"), String::from("-x")],
+            ..Settings::default()
+        };
+        let mut vm = VirtualMachine::with_settings(settings);
+        vm.run_source("import sys\nassert False\nx = sys.argv\n").unwrap();
+        assert_eq!(vm.get_global("x").unwrap().borrow().repr(), "['prog', '-x']");
+
+        let sources = vec![
+            ("import sys\nsys.exit()\n", 0),
+            ("import sys\nsys.exit(3)\n", 3),
+            ("raise SystemExit(False)\n", 0),
+            ("raise SystemExit('failed')\n", 1),
+            ("raise ValueError()\n", 1),
+        ];
+        for (source, status) in sources {
+            let exception = vm.run_source(source).unwrap_err();
+            assert_eq!(vm.exit_status(&exception), status, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_run_module() {
+        let directory = env::temp_dir().join(format!("rspython_run_module_{}", process::id()));
+        let main = "from .helper import value\nimport sys\nx = [value, __package__, __name__, sys.argv]\n";
+        let files = vec![
+            ("pkg/__init__.py", ""),
+            ("pkg/helper.py", "value = 42\n"),
+            ("pkg/sub.py", main),
+            ("pkg/__main__.py", main),
+        ];
+        for (name, source) in files {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        // The module name is sys.argv[0] until the module is found:
+        let mut results = vec![];
+        for name in &["pkg.sub", "pkg"] {
+            let settings = Settings {
+                argv: vec![name.to_string(), String::from("-x")],
+                ..Settings::default()
+            };
+            let mut vm = VirtualMachine::with_settings(settings);
+            vm.insert_main_path(&directory.to_string_lossy()).unwrap();
+            let result = vm.run_module(name);
+            if let Err(exception) = result {
+                fs::remove_dir_all(&directory).unwrap();
+                vm.print_exception(&exception);
+                panic!("Module raised an exception");
+            }
+            results.push(vm.get_global("x").unwrap().borrow().repr());
         }
+        let file = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        assert_eq!(
+            results,
+            vec![
+                format!("[42, 'pkg', '__main__', ['{}', '-x']]", file("pkg/sub.py")),
+                format!("[42, 'pkg', '__main__', ['{}', '-x']]", file("pkg/__main__.py")),
+            ]
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_assert() {
+        let source = "
+def message():
+    calls.append(1)
+    return 'too small'
+calls = []
+errors = []
+assert 1 < 2, message()
+for value in [0, 5]:
+    try:
+        assert value > 2, message()
+    except AssertionError as e:
+        errors.append(str(e))
+try:
+    assert []
+except AssertionError as e:
+    errors.append(e.args)
+x = [calls, errors]
+";
+        assert_eq!(run_and_get(source, "x"), "[[1], ['too small', ()]]");
+
+        let settings = Settings {
+            optimize: true,
+            ..Settings::default()
+        };
+        let mut vm = VirtualMachine::with_settings(settings);
+        vm.run_source("assert False, undefined\nx = 1\n").unwrap();
+        assert_eq!(vm.get_global("x").unwrap().borrow().repr(), "1");
+    }
+
+    #[test]
+    fn test_tracebacks() {
+        let source = "
+def fail():
+    raise ValueError('bad')
+
+def outer():
+    try:
+        fail()
+    finally:
+        pass
+
+outer()
+";
+        let mut vm = VirtualMachine::new();
+        let exception = vm.run_source_as(source, "t.py").unwrap_err();
+        assert_eq!(
+            vm.format_traceback(&exception),
+            vec![
+                "Traceback (most recent call last):",
+                "  File \"t.py\", line 11, in <module>",
+                "  File \"t.py\", line 7, in outer",
+                "  File \"t.py\", line 3, in fail",
+            ]
+        );
+
+        let source = "
+def fail():
+    1 / 0
+
+lines = []
+try:
+    fail()
+except ZeroDivisionError as e:
+    tb = e.__traceback__
+    while tb is not None:
+        lines.append((tb.tb_frame.f_code.co_name, tb.tb_lineno))
+        tb = tb.tb_next
+";
+        assert_eq!(
+            run_and_get(source, "lines"),
+            "[('<module>', 7), ('fail', 3)]"
+        );
+    }
+
+    #[test]
+    fn test_traceback_after_nested_bodies() {
+        // The line of the module which raised:
+        let line = |source: &str| {
+            let mut vm = VirtualMachine::new();
+            let exception = vm.run_source_as(source, "t.py").unwrap_err();
+            vm.format_traceback(&exception)[1].clone()
+        };
+        let decorator = "
+def fail(f):
+    raise ValueError
+@fail
+def f():
+    a = 1
+    b = 2
+";
+        assert_eq!(line(decorator), "  File \"t.py\", line 4, in <module>");
+        let class = "
+class C(undefined):
+    a = 1
+    b = 2
+";
+        assert_eq!(line(class), "  File \"t.py\", line 2, in <module>");
+        let with = "
+class Failing:
+    def __enter__(self):
+        pass
+    def __exit__(self, typ, value, traceback):
+        raise ValueError
+with Failing():
+    if len('') == 0:
+        a = 1
+    else:
+        b = 2
+";
+        assert_eq!(line(with), "  File \"t.py\", line 7, in <module>");
+        let handler = "
+try:
+    raise ValueError
+except KeyError:
+    a = 1
+    b = 2
+except undefined:
+    pass
+";
+        assert_eq!(line(handler), "  File \"t.py\", line 7, in <module>");
+    }
+
+    #[test]
+    fn test_getframe_and_settrace() {
+        let source = "
+import sys
+
+def where():
+    frame = sys._getframe(1)
+    return (frame.f_code.co_name, frame.f_lineno, frame.f_back.f_lineno)
+
+def caller():
+    return where()
+
+x = caller()
+
+events = []
+def tracer(frame, event, arg):
+    events.append((event, frame.f_lineno, arg))
+    return tracer
+
+def traced(a):
+    for i in range(2):
+        a = a + i
+    return a
+
+sys.settrace(tracer)
+traced(1)
+sys.settrace(None)
+";
+        assert_eq!(run_and_get(source, "x"), "('caller', 9, 11)");
+        assert_eq!(
+            run_and_get(source, "events"),
+            "[('call', 18, None), ('line', 19, None), ('line', 20, None), \
+             ('line', 19, None), ('line', 20, None), ('line', 19, None), \
+             ('line', 21, None), ('return', 21, 2)]"
+        );
     }
 
-    fn jump(&mut self, label: bytecode::Label) {
-        // self.program_counter = self.label_map[end_label];
+    #[test]
+    fn test_extended_arguments() {
+        // More than 256 constants and names, and jumps over them:
+        let mut source = String::from("total = 0\nfor i in range(3):\n    if i == 1:\n        continue\n");
+        for n in 0..300 {
+            source.push_str(&format!("    v{} = {}\n", n, n));
+        }
+        source.push_str("    total = total + v299 + i\n");
+        assert_eq!(run_and_get(&source, "total"), "600");
     }
 
-    fn execute_binop(&mut self, op: &bytecode::BinaryOperator) {
-        let b_ref = self.stack.pop().unwrap();
-        let a_ref = self.stack.pop().unwrap();
-        let b = &*b_ref.borrow();
-        let a = &*a_ref.borrow();
-        let result = match op {
-            &bytecode::BinaryOperator::Subtract => a - b,
-            &bytecode::BinaryOperator::Add => a + b,
-            &bytecode::BinaryOperator::Multiply => a * b,
-            _ => panic!("NOT IMPL"),
+    #[test]
+    fn test_constants() {
+        let constants = vec![
+            Constant::Float { value: 2.5 },
+            Constant::Complex {
+                real: 1.0,
+                imag: -2.0,
+            },
+            Constant::Bytes {
+                value: b"ab".to_vec(),
+            },
+            Constant::FrozenSet {
+                elements: vec![Constant::Integer { value: 1 }],
+            },
+            Constant::Ellipsis,
+            Constant::Tuple { elements: vec![] },
+        ];
+        let mut code = CodeObject::new(vec![], String::from("<module>"));
+        let mut instructions = InstructionList::new();
+        let size = constants.len();
+        for constant in constants {
+            let consti = code.add_constant(constant);
+            instructions.push(Instruction::LoadConst { consti }, Location::default());
+        }
+        instructions.push(Instruction::BuildTuple { size }, Location::default());
+        instructions.push(Instruction::ReturnValue, Location::default());
+        instructions.assemble(&mut code).unwrap();
+
+        let mut vm = VirtualMachine::new();
+        let mut frame = Frame::new(Rc::new(code), Scope::new(None));
+        let value = match frame.run(&mut vm).unwrap() {
+            ExecutionResult::Return(value) => value,
+            ExecutionResult::Yield(_) => panic!("Expected a return value"),
         };
-        self.stack.push(result.into_ref());
+        assert_eq!(
+            value.borrow().repr(),
+            "(2.5, (1-2j), b'ab', frozenset({1}), Ellipsis, ())"
+        );
+        assert_eq!(run_and_get("x = Ellipsis\n", "x"), "Ellipsis");
+    }
+
+    #[test]
+    fn test_floats() {
+        let source = "
+a = float('2.5')
+two = float(2)
+x = [a * 2, 7 / 2, 7 // two, -7 % two, 2 ** -1, a > 2, a == 5 / 2, -a, int(a), float(3), bool(a - a)]
+";
+        assert_eq!(
+            run_and_get(source, "x"),
+            "[5.0, 3.5, 3.0, 1.0, 0.5, True, True, -2.5, 2, 3.0, False]"
+        );
+    }
+
+    #[test]
+    fn test_builtin_decorators() {
+        let source = "
+class Temperature:
+    def __init__(self):
+        self._celsius = 0
+    @property
+    def celsius(self):
+        return self._celsius
+    @celsius.setter
+    def celsius(self, value):
+        self._celsius = value
+    @staticmethod
+    def unit():
+        return 'C'
+    @classmethod
+    def create(cls):
+        return cls()
+t = Temperature.create()
+t.celsius = 21
+x = [t.celsius, t.unit(), Temperature.unit(), isinstance(t, Temperature)]
+";
+        assert_eq!(run_and_get(source, "x"), "[21, 'C', 'C', True]");
     }
 }
@@ -0,0 +1,17 @@
+/*
+ * The rspython library: a python lexer, parser, compiler and virtual
+ * machine, which can be embedded in rust programs.
+ */
+
+#[macro_use]
+extern crate log;
+extern crate lalrpop_util;
+extern crate rspython_derive;
+
+// The declaration macros refer to this crate as `rspython`, also within it:
+extern crate self as rspython;
+
+mod compiler;
+
+pub use compiler::*;
+pub use rspython_derive::{pyclass, pyfunction, pymethods, pymodule};
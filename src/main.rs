@@ -1,43 +1,192 @@
-
 extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-use clap::{Arg, App};
+extern crate rspython;
+extern crate rustyline;
+use clap::{App, AppSettings, Arg};
+use rspython::{disassemble, PyResult, Settings, VirtualMachine};
+use std::env;
+use std::io::{self, IsTerminal, Read};
 use std::path::Path;
-mod compiler;
+use std::process;
 
+mod repl;
 
 fn main() {
   env_logger::init();
+  let (options, program_args) = split_arguments(env::args().collect());
   let matches = App::new("RsPython")
       .version("0.0.1")
       .author("Windel Bouwman")
       .about("Rust implementation of the Python language")
-      .arg(Arg::with_name("script")
-          .required(true)
-          .index(1))
+      .setting(AppSettings::TrailingVarArg)
+      .arg(Arg::with_name("c")
+          .short("c")
+          .takes_value(true)
+          .value_name("cmd")
+          .help("Program passed in as string"))
+      .arg(Arg::with_name("m")
+          .short("m")
+          .takes_value(true)
+          .value_name("mod")
+          .conflicts_with("c")
+          .help("Run library module as a script"))
+      .arg(Arg::with_name("O")
+          .short("O")
+          .help("Remove assert statements"))
+      .arg(Arg::with_name("B")
+          .short("B")
+          .help("Don't write .pyc files on import"))
+      .arg(Arg::with_name("u")
+          .short("u")
+          .help("Force the stdout and stderr streams to be unbuffered"))
+      .arg(Arg::with_name("E")
+          .short("E")
+          .help("Ignore PYTHON* environment variables"))
+      .arg(Arg::with_name("I")
+          .short("I")
+          .help("Isolate from the user's environment, implies -E"))
+      .arg(Arg::with_name("dis")
+          .long("dis")
+          .help("Print the bytecode of the program instead of running it"))
       .arg(Arg::with_name("v")
           .short("v")
           .multiple(true)
           .help("Give the verbosity"))
-      .get_matches();
+      .arg(Arg::with_name("args")
+          .multiple(true)
+          .help("The script, or - to read it from stdin, and its arguments"))
+      .get_matches_from(options);
+
+  let mut args: Vec<String> = match matches.values_of("args") {
+    Some(values) => values.map(String::from).collect(),
+    None => vec![],
+  };
+  args.extend(program_args);
+  let isolated = matches.is_present("I");
+  // Output is written unbuffered already, so -u needs nothing:
+  let mut settings = Settings {
+    optimize: matches.is_present("O"),
+    ignore_environment: isolated || matches.is_present("E"),
+    safe_path: isolated,
+    dont_write_bytecode: matches.is_present("B"),
+    argv: vec![],
+  };
+  // PYTHONDONTWRITEBYTECODE does the same as -B:
+  if !settings.ignore_environment {
+    if let Some(value) = env::var_os("PYTHONDONTWRITEBYTECODE") {
+      settings.dont_write_bytecode |= !value.is_empty();
+    }
+  }
+
+  // Like CPython, sys.argv[0] tells what is run:
+  let status = if matches.is_present("dis") {
+    list_bytecode(settings, matches.value_of("c"), &args)
+  } else if let Some(command) = matches.value_of("c") {
+    args.insert(0, String::from("-c"));
+    settings.argv = args;
+    let mut vm = VirtualMachine::with_settings(settings);
+    let result = vm.insert_main_path("").and_then(|_| vm.run_source(command));
+    exit_status(&mut vm, result)
+  } else if let Some(module) = matches.value_of("m") {
+    // Until the file of the module is found:
+    args.insert(0, String::from(module));
+    settings.argv = args;
+    let mut vm = VirtualMachine::with_settings(settings);
+    let result = vm.run_module(module);
+    exit_status(&mut vm, result)
+  } else if args.is_empty() && io::stdin().is_terminal() {
+    settings.argv = vec![String::new()];
+    let mut vm = VirtualMachine::with_settings(settings);
+    match vm.insert_main_path("") {
+      Ok(_) => repl::run(&mut vm),
+      Err(exception) => vm.exit_status(&exception),
+    }
+  } else if args.is_empty() || args[0] == "-" {
+    settings.argv = if args.is_empty() { vec![String::from("-")] } else { args };
+    let mut vm = VirtualMachine::with_settings(settings);
+    let mut source = String::new();
+    if let Err(error) = io::stdin().read_to_string(&mut source) {
+      eprintln!("rspython: can't read from stdin: {}", error);
+      process::exit(1);
+    }
+    let result = vm.insert_main_path("").and_then(|_| vm.run_source_as(&source, "<stdin>"));
+    exit_status(&mut vm, result)
+  } else {
+    let script_file = args[0].clone();
+    info!("Running file {}", script_file);
+    if !Path::new(&script_file).is_file() {
+      eprintln!("rspython: can't open file '{}': No such file", script_file);
+      process::exit(2);
+    }
+    settings.argv = args;
+    let mut vm = VirtualMachine::with_settings(settings);
+    let result = vm.run_script(Path::new(&script_file));
+    exit_status(&mut vm, result)
+  };
+  process::exit(status);
+}
 
-  // Figure out the filename:
-  let script_file = matches.value_of("script").unwrap_or("foo");
-  info!("Running file {}", script_file);
+// Print the bytecode of the command, the script or stdin. A compiled file
+// shows the code it holds.
+fn list_bytecode(settings: Settings, command: Option<&str>, args: &[String]) -> i32 {
+  let mut vm = VirtualMachine::with_settings(settings);
+  let code = if let Some(command) = command {
+    vm.compile_source(command, "<string>")
+  } else if args.is_empty() || args[0] == "-" {
+    let mut source = String::new();
+    if let Err(error) = io::stdin().read_to_string(&mut source) {
+      eprintln!("rspython: can't read from stdin: {}", error);
+      return 1;
+    }
+    vm.compile_source(&source, "<stdin>")
+  } else if Path::new(&args[0]).is_file() {
+    vm.load_file(Path::new(&args[0]))
+  } else {
+    eprintln!("rspython: can't open file '{}': No such file", args[0]);
+    return 2;
+  };
+  let text = code.and_then(|code| {
+    disassemble(&code).map_err(|msg| vm.new_exception("ValueError", msg))
+  });
+  match text {
+    Ok(text) => {
+      print!("{}", text);
+      0
+    }
+    Err(exception) => vm.exit_status(&exception),
+  }
+}
 
-  // Parse an ast from it:
-  let filepath = Path::new(script_file);
-  match compiler::parse(filepath) {
-    Ok(program) => {
-      debug!("Got ast: {:?}", program);
-      let bytecode = compiler::compile(program);
-      debug!("Code object: {:?}", bytecode);
-      compiler::evaluate(bytecode);
+// Uncaught exceptions are printed, and give exit status 1, unless the
+// program exits with SystemExit:
+fn exit_status(vm: &mut VirtualMachine, result: PyResult) -> i32 {
+  match result {
+    Ok(_) => {
       info!("Great succes!!");
-    },
-    Err(msg) => error!("Parsing went horribly wrong: {}", msg),
+      0
+    }
+    Err(exception) => vm.exit_status(&exception),
   }
 }
 
+// Like CPython, the arguments after `-c cmd` or `-m mod` are for the program,
+// also when they look like options:
+fn split_arguments(mut arguments: Vec<String>) -> (Vec<String>, Vec<String>) {
+  for i in 1..arguments.len() {
+    let argument = &arguments[i];
+    if argument == "--" || !argument.starts_with('-') || argument == "-" {
+      break;
+    }
+    if argument == "-c" || argument == "-m" {
+      let rest = arguments.split_off((i + 2).min(arguments.len()));
+      return (arguments, rest);
+    }
+    if argument.starts_with("-c") || argument.starts_with("-m") {
+      let rest = arguments.split_off(i + 1);
+      return (arguments, rest);
+    }
+  }
+  (arguments, vec![])
+}
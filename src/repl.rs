@@ -0,0 +1,149 @@
+/*
+ * The interactive prompt. Lines are read until they form complete
+ * statements, which are run in the namespace of the __main__ module. The
+ * values of expressions are printed.
+ */
+
+use std::cell::RefCell;
+use std::env;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use rspython::{ast, parse_interactive, VirtualMachine};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+// Completes names from the namespace, as it was after the last input:
+struct NameCompleter {
+    names: Rc<RefCell<Vec<String>>>,
+}
+
+impl Completer for NameCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |position| position + 1);
+        let prefix = &line[start..pos];
+        // Attributes are not completed:
+        if prefix.is_empty() || line[..start].ends_with('.') {
+            return Ok((pos, vec![]));
+        }
+        let candidates = self
+            .names
+            .borrow()
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for NameCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for NameCompleter {}
+
+impl Validator for NameCompleter {}
+
+impl Helper for NameCompleter {}
+
+fn history_file() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rspython_history"))
+}
+
+// A compound statement is only complete after an empty line, since its
+// block may go on:
+fn needs_empty_line(program: &ast::Program, line: &str) -> bool {
+    let is_compound = matches!(
+        program.statements.first().map(|statement| &statement.node),
+        Some(ast::Statement::If { .. })
+            | Some(ast::Statement::While { .. })
+            | Some(ast::Statement::For { .. })
+            | Some(ast::Statement::Try { .. })
+            | Some(ast::Statement::With { .. })
+            | Some(ast::Statement::ClassDef { .. })
+            | Some(ast::Statement::FunctionDef { .. })
+    );
+    is_compound && !line.trim().is_empty()
+}
+
+// Run the prompt until the end of the input, or until the program exits,
+// giving the exit status:
+pub fn run(vm: &mut VirtualMachine) -> i32 {
+    println!("RsPython {}", env!("CARGO_PKG_VERSION"));
+    let names = Rc::new(RefCell::new(vm.global_names()));
+    let mut editor = Editor::<NameCompleter>::new();
+    editor.set_helper(Some(NameCompleter {
+        names: names.clone(),
+    }));
+    let history = history_file();
+    if let Some(ref history) = history {
+        // There is no history the first time:
+        let _ = editor.load_history(history);
+    }
+
+    let mut input = String::new();
+    let mut status = 0;
+    loop {
+        let prompt = if input.is_empty() { ">>> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                println!("KeyboardInterrupt");
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("Error reading input: {}", error);
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str());
+        }
+        if input.is_empty() && line.trim().is_empty() {
+            continue;
+        }
+        input.push_str(&line);
+        input.push('\n');
+
+        match parse_interactive(&input) {
+            Ok(None) => continue,
+            Ok(Some(ref program)) if needs_empty_line(program, &line) => continue,
+            Ok(Some(program)) => {
+                if let Err(exception) = vm.run_interactive(program) {
+                    let system_exit = vm.get_builtin("SystemExit");
+                    if vm.isinstance(&exception, &system_exit) {
+                        status = vm.exit_status(&exception);
+                        break;
+                    }
+                    vm.print_exception(&exception);
+                }
+            }
+            Err(msg) => eprintln!("SyntaxError: {}", msg),
+        }
+        input.clear();
+        *names.borrow_mut() = vm.global_names();
+    }
+
+    if let Some(ref history) = history {
+        if let Err(error) = editor.save_history(history) {
+            eprintln!("Could not save the history: {}", error);
+        }
+    }
+    status
+}
@@ -0,0 +1,7 @@
+# Compiled by CPython 3.11 into handlers.pyc, which has an exception table.
+
+def guarded():
+    try:
+        return 1
+    except ValueError:
+        return 2
@@ -0,0 +1,47 @@
+# Compiled by CPython 3.11 into program.pyc, which the tests translate and run.
+
+def gen(n):
+    i = 0
+    while i < n:
+        yield i
+        i += 1
+    yield from tail()
+
+
+def tail():
+    yield 10
+
+
+def outer(a, b=2):
+    def inner():
+        return a + b
+    return inner
+
+
+def check(value):
+    assert value, 'no value'
+    return value
+
+
+class C:
+    x = 1
+
+    def m(self, y):
+        def g():
+            pass
+        return g.__qualname__
+
+
+squares = [v * v for v in range(4)]
+d = {'a': 1, 'b': squares}
+n = 7
+text = f'{n!r}-{[1, 2]}'
+flag = 1 < n <= 10 and not None
+counter = {'k': 0}
+counter['k'] += 10
+numbers = [1, 2, 3]
+unique = {x for x in [5, 5]}
+word = 'ok' if n is not None else 'no'
+pair = (len(squares) - 1, sorted([n, 12])[1])
+result = [list(gen(3)), outer(3, b=4)(), squares, d, text, flag, C().m(1),
+          counter['k'], numbers, unique, check(word), pair]
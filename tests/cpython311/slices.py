@@ -0,0 +1,4 @@
+# Compiled by CPython 3.11 into slices.pyc, which builds a slice.
+
+def sliced(x):
+    return x[1:2]